@@ -129,8 +129,7 @@ where
             .and_then(|header| header.to_str().ok());
 
         if let Some(auth_header) = auth_header {
-            if auth_header.starts_with("Bearer ") {
-                let token = &auth_header[7..];
+            if let Some(token) = auth_header.strip_prefix("Bearer ") {
                 let jwt_secret = match env::var("JWT_SECRET") {
                     Ok(secret) => secret,
                     Err(_) => return Err(AuthError::InvalidToken),
@@ -20,6 +20,7 @@ pub struct BlockInfo {
     previous_hash: String,
     timestamp: i64, // Changed from u64 to i64
     transaction_count: usize,
+    pruned: bool, // body deleted by a pruned node, transaction_count unknown
 }
 
 #[derive(Serialize)]
@@ -41,7 +42,7 @@ pub async fn get_info(
 ) -> Result<Json<BlockchainInfo>, StatusCode> {
     let blockchain = blockchain.lock().await;
     let height = blockchain.get_block_height();
-    let block_count = blockchain.blocks.len() as u64;
+    let block_count = blockchain.chain_length();
     let pending_transactions = blockchain.pending_transactions.len();
     let latest_block_hash = blockchain.get_latest_block().hash();
 
@@ -61,13 +62,14 @@ pub async fn get_blocks(
     let mut blocks_info = Vec::new();
 
     // Get blocks data directly from the blockchain
-    for (index, block) in blockchain.blocks.iter().enumerate() {
+    for block in &blockchain.blocks {
         blocks_info.push(BlockInfo {
-            height: index as u64,
+            height: block.header.height,
             hash: block.hash(),
             previous_hash: block.header.previous_hash.clone(),
             timestamp: block.header.timestamp as i64, // Cast to i64
             transaction_count: block.transactions.len(),
+            pruned: blockchain.is_body_pruned(block.header.height),
         });
     }
 
@@ -119,14 +121,17 @@ pub async fn mine_block(
         previous_hash: new_block.header.previous_hash,
         timestamp: new_block.header.timestamp as i64, // Cast to i64
         transaction_count: new_block.transactions.len(),
+        pruned: false,
     };
 
     // Log the mining event
-    if let Err(_) = audit_log::log_system_event(
+    if audit_log::log_system_event(
         "Block mined".to_string(),
         format!("New block {} mined successfully", block_info.hash),
         "success".to_string(),
-    ) {
+    )
+    .is_err()
+    {
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
@@ -30,12 +30,6 @@ pub struct TransferRequest {
     private_key: String,
 }
 
-#[derive(Deserialize)]
-pub struct MintRequest {
-    to: String,
-    amount: u64,
-}
-
 #[derive(Serialize)]
 pub struct TransactionResponse {
     hash: String,
@@ -102,7 +96,7 @@ pub async fn transfer(
         Transaction::new(sender_address, payload.to.clone(), payload.amount, None);
 
     // Sign the transaction
-    if let Err(_) = transaction.sign(&signing_key) {
+    if transaction.sign(&signing_key).is_err() {
         audit_log::log_security_event(
             "Transfer failed".to_string(),
             format!(
@@ -156,53 +150,6 @@ pub async fn transfer(
     Ok(Json(response))
 }
 
-pub async fn mint(
-    State(blockchain): State<Arc<Mutex<Blockchain>>>,
-    Path(address): Path<String>,
-    Json(amount): Json<u64>,
-) -> Result<Json<BalanceResponse>, StatusCode> {
-    let mut blockchain = blockchain.lock().await;
-    blockchain
-        .mint(address.clone(), amount)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let balance = blockchain.get_balance(&address);
-
-    audit_log::log_key_management_event(
-        "Tokens minted".to_string(),
-        format!("Minted {} tokens to address {}", amount, address),
-        "success".to_string(),
-        None,
-    )
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    Ok(Json(BalanceResponse { address, balance }))
-}
-
-// Alternative mint endpoint that matches test expectations
-pub async fn mint_tokens(
-    State(blockchain): State<Arc<Mutex<Blockchain>>>,
-    Json(payload): Json<MintRequest>,
-) -> Result<Json<BalanceResponse>, StatusCode> {
-    let mut blockchain = blockchain.lock().await;
-    blockchain
-        .mint(payload.to.clone(), payload.amount)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let balance = blockchain.get_balance(&payload.to);
-
-    audit_log::log_key_management_event(
-        "Tokens minted".to_string(),
-        format!("Minted {} tokens to address {}", payload.amount, payload.to),
-        "success".to_string(),
-        None,
-    )
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    Ok(Json(BalanceResponse {
-        address: payload.to,
-        balance,
-    }))
-}
-
 pub async fn get_transactions(
     State(blockchain): State<Arc<Mutex<Blockchain>>>,
 ) -> Result<Json<Vec<TransactionResponse>>, StatusCode> {
@@ -225,7 +172,7 @@ pub async fn get_transactions(
     }
 
     // Sort by timestamp (newest first)
-    transactions.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    transactions.sort_by_key(|tx| std::cmp::Reverse(tx.timestamp));
 
     audit_log::log_system_event(
         "Transactions queried".to_string(),
@@ -0,0 +1,103 @@
+// Export a node's chain to a file, or rebuild a data directory from one.
+//
+// Usage:
+//   owami-chain export <file> [--data-dir DIR]
+//   owami-chain import <file> [--data-dir DIR]
+//
+// DIR is a node data directory, `database.data_dir` in CONFIG_PATH by
+// default; the chain store is opened inside it the way the node does.
+
+use owami_network::chain_io::{self, ImportError};
+use owami_network::config::AppConfig;
+use owami_network::db_pure_rust::PureRustDatabase;
+use owami_network::storage::ChainStore;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::process;
+use std::sync::Arc;
+
+fn usage() -> ! {
+    eprintln!("Usage: owami-chain <export|import> <file> [--data-dir DIR]");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        usage();
+    }
+    let command = args[0].as_str();
+    let file = &args[1];
+
+    if std::env::var("CONFIG_PATH").is_err() {
+        std::env::set_var("CONFIG_PATH", "config/testnet.toml");
+    }
+    let config = match AppConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load config: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let data_dir = match args.get(2).map(String::as_str) {
+        Some("--data-dir") => args.get(3).cloned().unwrap_or_else(|| usage()),
+        Some(_) => usage(),
+        None => config.database.data_dir.clone(),
+    };
+    let db = match PureRustDatabase::open_in(&data_dir) {
+        Ok(db) => Arc::new(db),
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", data_dir, e);
+            process::exit(1);
+        }
+    };
+
+    match command {
+        "export" => {
+            let mut writer = match File::create(file) {
+                Ok(f) => BufWriter::new(f),
+                Err(e) => {
+                    eprintln!("Failed to create {}: {}", file, e);
+                    process::exit(1);
+                }
+            };
+            match ChainStore::open(db)
+                .map_err(|e| e.to_string())
+                .and_then(|store| chain_io::export_chain(&store, &mut writer))
+            {
+                Ok(count) => println!("Exported {} blocks to {}", count, file),
+                Err(e) => {
+                    eprintln!("Export failed: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        "import" => {
+            let reader = match File::open(file) {
+                Ok(f) => BufReader::new(f),
+                Err(e) => {
+                    eprintln!("Failed to open {}: {}", file, e);
+                    process::exit(1);
+                }
+            };
+            match chain_io::import_chain(&config, db, reader) {
+                Ok(report) => {
+                    println!("Imported {} blocks", report.blocks_imported);
+                    println!("Tip height: {}", report.tip_height);
+                    println!("Tip hash: {}", report.tip_hash);
+                    println!("State root: {}", report.state_root);
+                }
+                Err(ImportError::InvalidBlock { height, reason }) => {
+                    eprintln!("Import stopped at block {}: {}", height, reason);
+                    process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Import failed: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => usage(),
+    }
+}
@@ -7,6 +7,6 @@ fn main() {
 
     println!("Private Key (hex): {}", private_key_hex);
     println!("Public Key (hex): {}", public_key_hex);
-    println!("");
+    println!();
     println!("Use this private key in your test script for transfer operations.");
 }
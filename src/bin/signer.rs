@@ -0,0 +1,39 @@
+#[cfg(unix)]
+use {
+    owami_network::key_management::KeyManager,
+    owami_network::signer::{self, BlockSigner, LocalSigner},
+    std::env,
+    std::path::PathBuf,
+};
+
+/// Holds a validator's consensus key and signs for its node over a Unix
+/// socket, refusing any block or vote conflicting with one it signed
+#[cfg(unix)]
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let socket = PathBuf::from(
+        env::var("SIGNER_SOCKET").unwrap_or_else(|_| "./keys/signer.sock".to_string()),
+    );
+    let keys = KeyManager::new(Some(
+        env::var("CONSENSUS_KEY_PATH").unwrap_or_else(|_| "./keys/consensus.key".to_string()),
+    ))?;
+    let state_path =
+        env::var("SIGNER_STATE_PATH").unwrap_or_else(|_| "./keys/signer_state.json".to_string());
+    let mut local = LocalSigner::new(keys, state_path)?;
+    let public_key = local.public_key().map_err(anyhow::Error::msg)?;
+
+    let listener = signer::bind(&socket)?;
+    println!(
+        "Signing for consensus key {} on {}",
+        public_key,
+        socket.display()
+    );
+    signer::serve(&listener, &mut local)
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("owami-signer serves over a Unix domain socket and only runs on Unix");
+    std::process::exit(1);
+}
@@ -1,12 +1,11 @@
+use crate::crypto_utils;
+use crate::merkle::{MerkleProof, MerkleTree};
 use crate::transaction::Transaction;
 use blake3;
-use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
-#[cfg(test)]
-use crate::crypto_utils;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BlockHeader {
     pub height: u64,
     pub previous_hash: String,
@@ -14,6 +13,8 @@ pub struct BlockHeader {
     pub timestamp: u64,
     pub nonce: u64,
     pub producer: String,
+    #[serde(default)]
+    pub fallback_rank: u32, // 0 for the scheduled producer, r for the r-th fallback
     pub signature: String,
 }
 
@@ -21,17 +22,64 @@ impl std::fmt::Display for BlockHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}{}{}{}{}{}",
+            "{}{}{}{}{}{}{}",
             self.height,
             self.previous_hash,
             self.merkle_root,
             self.timestamp,
             self.nonce,
-            self.producer
+            self.producer,
+            self.fallback_rank
         )
     }
 }
 
+impl BlockHeader {
+    pub fn hash(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.height.to_le_bytes());
+        hasher.update(self.previous_hash.as_bytes());
+        hasher.update(self.merkle_root.as_bytes());
+        hasher.update(&self.timestamp.to_le_bytes());
+        hasher.update(&self.nonce.to_le_bytes());
+        hasher.update(self.producer.as_bytes());
+        hasher.update(&self.fallback_rank.to_le_bytes());
+        hasher.finalize().to_hex().to_string()
+    }
+
+    /// Message the producer signs. It covers the header only, so a header
+    /// is checkable on its own; the merkle root commits to the body.
+    pub fn signing_hash(&self) -> Vec<u8> {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.height.to_be_bytes());
+        hasher.update(self.previous_hash.as_bytes());
+        hasher.update(self.merkle_root.as_bytes());
+        hasher.update(&self.timestamp.to_be_bytes());
+        hasher.update(&self.nonce.to_be_bytes());
+        hasher.update(self.producer.as_bytes());
+        hasher.update(&self.fallback_rank.to_be_bytes());
+        hasher.finalize().as_bytes().to_vec()
+    }
+
+    /// Whether the hex `signature` is the named producer's signature
+    pub fn verify_signature(&self) -> bool {
+        let producer = match crypto_utils::hex_to_verifying_key(&self.producer) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature_bytes: [u8; 64] = match hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+        {
+            Some(bytes) => bytes,
+            None => return false,
+        };
+        let signature = Signature::from_bytes(&signature_bytes);
+
+        producer.verify(&self.signing_hash(), &signature).is_ok()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub header: BlockHeader,
@@ -51,102 +99,159 @@ impl Block {
             .expect("Time went backwards")
             .as_secs();
 
+        Self::new_at(
+            height,
+            previous_hash,
+            transactions,
+            timestamp,
+            0,
+            signing_key,
+        )
+    }
+
+    /// Build and sign a block with an explicit timestamp and fallback rank
+    pub fn new_at(
+        height: u64,
+        previous_hash: String,
+        transactions: Vec<Transaction>,
+        timestamp: u64,
+        fallback_rank: u32,
+        signing_key: &SigningKey,
+    ) -> Self {
+        let mut block = Self::unsigned_at(
+            height,
+            previous_hash,
+            transactions,
+            timestamp,
+            fallback_rank,
+            hex::encode(signing_key.verifying_key().to_bytes()),
+        );
+
+        // Sign the block
+        let signature = signing_key.sign(&block.hash_data());
+        block.set_signature(&signature);
+
+        block
+    }
+
+    /// Block made by the hex key `producer`, still to be signed with
+    /// `set_signature`
+    pub fn unsigned_at(
+        height: u64,
+        previous_hash: String,
+        transactions: Vec<Transaction>,
+        timestamp: u64,
+        fallback_rank: u32,
+        producer: String,
+    ) -> Self {
         let merkle_root = Self::calculate_merkle_root(&transactions);
 
-        let mut block = Block {
+        Block {
             header: BlockHeader {
                 height,
                 previous_hash,
                 merkle_root,
                 timestamp,
                 nonce: 0,
-                producer: hex::encode(signing_key.verifying_key().to_bytes()),
+                producer,
+                fallback_rank,
                 signature: String::new(), // Will be filled after signing
             },
             transactions,
             signature: Vec::new(),
-        };
+        }
+    }
 
-        // Sign the block
-        let message = block.hash_data();
-        let signature = signing_key.sign(&message);
-        block.signature = signature.to_bytes().to_vec();
-        block.header.signature = hex::encode(signature.to_bytes());
+    /// Genesis block at `timestamp`. It has no producer, signature or
+    /// transactions, so nodes with the same genesis config build it alike;
+    /// in place of a parent it names the root of the genesis state.
+    pub fn genesis(timestamp: u64, state_root: String) -> Self {
+        Self::unsigned_at(0, state_root, Vec::new(), timestamp, 0, String::new())
+    }
 
-        block
+    /// Attach the producer's signature over `hash_data`
+    pub fn set_signature(&mut self, signature: &Signature) {
+        self.signature = signature.to_bytes().to_vec();
+        self.header.signature = hex::encode(signature.to_bytes());
     }
 
     pub fn hash_data(&self) -> Vec<u8> {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(&self.header.height.to_be_bytes());
-        hasher.update(self.header.previous_hash.as_bytes());
-        hasher.update(self.header.merkle_root.as_bytes());
-        hasher.update(&self.header.timestamp.to_be_bytes());
-        hasher.update(&self.header.nonce.to_be_bytes());
-        hasher.update(self.header.producer.as_bytes());
-
-        for tx in &self.transactions {
-            hasher.update(tx.hash().as_bytes());
-        }
-
-        hasher.finalize().as_bytes().to_vec()
+        self.header.signing_hash()
     }
 
     pub fn verify_signature(&self, public_key: &VerifyingKey) -> bool {
         let message = self.hash_data();
-        let signature_bytes = match hex::decode(&self.header.signature) {
-            Ok(bytes) => {
-                if bytes.len() != 64 {
-                    return false;
-                }
-                let mut sig_bytes = [0u8; 64];
-                sig_bytes.copy_from_slice(&bytes);
-                sig_bytes
-            }
+        // The header carries a hex copy of the raw signature; both must agree
+        if self.header.signature != hex::encode(&self.signature) {
+            return false;
+        }
+        let signature_bytes: [u8; 64] = match self.signature.as_slice().try_into() {
+            Ok(bytes) => bytes,
             Err(_) => return false,
         };
-        let signature = ed25519_dalek::Signature::from_bytes(&signature_bytes);
+        let signature = Signature::from_bytes(&signature_bytes);
 
         public_key.verify(&message, &signature).is_ok()
     }
 
+    /// Tree whose leaves are the transaction hashes, in block order
+    fn transaction_tree(transactions: &[Transaction]) -> MerkleTree {
+        let leaves: Vec<Vec<u8>> = transactions.iter().map(|tx| tx.hash_data()).collect();
+        MerkleTree::new(&leaves)
+    }
+
     fn calculate_merkle_root(transactions: &[Transaction]) -> String {
-        if transactions.is_empty() {
-            return "0".repeat(64);
-        }
+        hex::encode(Self::transaction_tree(transactions).root())
+    }
 
-        let mut hashes: Vec<String> = transactions.iter().map(|tx| tx.hash()).collect();
+    /// Proof that the transaction with hex hash `transaction_hash` is in
+    /// this block, checkable against the header's merkle root
+    pub fn transaction_proof(&self, transaction_hash: &str) -> Option<MerkleProof> {
+        let index = self
+            .transactions
+            .iter()
+            .position(|tx| tx.hash() == transaction_hash)?;
+        Self::transaction_tree(&self.transactions).proof(index)
+    }
 
-        while hashes.len() > 1 {
-            if hashes.len() % 2 != 0 {
-                hashes.push(hashes.last().unwrap().clone());
-            }
+    /// Block identifier. Only header fields are hashed, so the hash stays
+    /// checkable after the body is pruned; the merkle root commits to the
+    /// transactions.
+    pub fn hash(&self) -> String {
+        self.header.hash()
+    }
 
-            let mut new_hashes = Vec::new();
-            for chunk in hashes.chunks(2) {
-                let combined = format!("{}{}", chunk[0], chunk[1]);
-                let hash = blake3::hash(combined.as_bytes()).to_hex().to_string();
-                new_hashes.push(hash);
-            }
-            hashes = new_hashes;
+    /// Whether the header's merkle root matches the transactions carried
+    pub fn has_valid_merkle_root(&self) -> bool {
+        self.header.merkle_root == Self::calculate_merkle_root(&self.transactions)
+    }
+
+    /// Checks that need no chain: merkle root, producer signature and
+    /// transaction signatures
+    pub fn check_integrity(&self) -> Result<(), String> {
+        // The header hash only commits to the body through the merkle root
+        if !self.has_valid_merkle_root() {
+            return Err("merkle root does not match transactions".to_string());
         }
 
-        hashes[0].clone()
-    }
+        // Verify block signature against the producer named in the header
+        let producer = crypto_utils::hex_to_verifying_key(&self.header.producer)
+            .map_err(|e| format!("invalid producer key: {}", e))?;
+        if !self.verify_signature(&producer) {
+            return Err("invalid producer signature".to_string());
+        }
 
-    pub fn hash(&self) -> String {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(&self.header.height.to_le_bytes());
-        hasher.update(self.header.previous_hash.as_bytes());
-        hasher.update(self.header.merkle_root.as_bytes());
-        hasher.update(&self.header.timestamp.to_le_bytes());
-        hasher.update(&self.header.nonce.to_le_bytes());
-
-        for tx in &self.transactions {
-            hasher.update(tx.hash().as_bytes());
+        for (index, transaction) in self.transactions.iter().enumerate() {
+            if !transaction.verify() {
+                return Err(format!(
+                    "transaction {} ({}) has an invalid signature",
+                    index,
+                    transaction.hash()
+                ));
+            }
         }
 
-        hasher.finalize().to_hex().to_string()
+        Ok(())
     }
 }
 
@@ -243,6 +348,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_header_signature_verifies_without_body() {
+        let signing_key = crypto_utils::default_signing_key();
+        let block = Block::new(1, "0".repeat(64), Vec::new(), &signing_key);
+        assert!(block.header.verify_signature());
+
+        let mut header = block.header.clone();
+        header.timestamp += 1;
+        assert!(!header.verify_signature());
+
+        let mut header = block.header;
+        header.producer = hex::encode(crypto_utils::generate_keypair().1.to_bytes());
+        assert!(!header.verify_signature());
+    }
+
     #[test]
     fn test_hash_block_with_consistent_output() -> Result<(), Box<dyn std::error::Error>> {
         let signing_key = crypto_utils::default_signing_key();
@@ -287,8 +407,8 @@ mod tests {
 
         let root = Block::calculate_merkle_root(&[tx.clone()]);
 
-        // For a single transaction, merkle root should be the transaction hash
-        assert_eq!(root, tx.hash());
+        // A lone transaction is still hashed as a leaf
+        assert_ne!(root, tx.hash());
         assert_eq!(root.len(), 64);
 
         Ok(())
@@ -340,7 +460,8 @@ mod tests {
                 timestamp: 1234567890,
                 nonce: 42,
                 producer: hex::encode(public_key.to_bytes()),
-                signature: hex::encode(&[1, 2, 3, 4]),
+                fallback_rank: 0,
+                signature: hex::encode([1, 2, 3, 4]),
             },
             transactions: vec![tx],
             signature: vec![1, 2, 3, 4], // dummy signature for testing
@@ -405,12 +526,37 @@ mod tests {
         let mut tx3 = Transaction::new(from, "to3".to_string(), 300, None);
         tx3.sign(&signing_key)?;
 
-        let root = Block::calculate_merkle_root(&[tx1, tx2, tx3]);
-
-        // Should handle odd number of transactions by duplicating the last one
+        let root = Block::calculate_merkle_root(&[tx1.clone(), tx2.clone(), tx3.clone()]);
         assert_eq!(root.len(), 64);
         assert!(root.chars().all(|c| c.is_ascii_hexdigit()));
 
+        // Repeating the last transaction must not give the same root
+        assert_ne!(
+            root,
+            Block::calculate_merkle_root(&[tx1, tx2, tx3.clone(), tx3])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_proof_checks_against_header() -> Result<(), Box<dyn std::error::Error>> {
+        let signing_key = crypto_utils::default_signing_key();
+        let from = hex::encode(signing_key.verifying_key().to_bytes());
+        let mut transactions = Vec::new();
+        for amount in 1..=5 {
+            let mut tx = Transaction::new(from.clone(), "to".to_string(), amount, None);
+            tx.sign(&signing_key)?;
+            transactions.push(tx);
+        }
+        let block = Block::new(1, "0".repeat(64), transactions.clone(), &signing_key);
+        let root: [u8; 32] = hex::decode(&block.header.merkle_root)?.try_into().unwrap();
+
+        for tx in &transactions {
+            let proof = block.transaction_proof(&tx.hash()).unwrap();
+            assert!(proof.verify(&hex::decode(tx.hash())?, &root));
+        }
+        assert!(block.transaction_proof(&"ab".repeat(32)).is_none());
         Ok(())
     }
 
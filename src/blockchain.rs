@@ -1,83 +1,672 @@
-use crate::block::Block;
-use crate::config::AppConfig as Config;
-use crate::consensus::dpos::{DposConsensus, SerializableVerifyingKey, Validator};
-use crate::crypto_utils;
-use crate::transaction::Transaction;
+use crate::block::{Block, BlockHeader};
+use crate::config::{AppConfig as Config, NodeConfig, NodeMode, SnapshotConfig};
+use crate::consensus::dpos::{prefer_block, Validator};
+use crate::consensus::engine::{self, ConsensusEngine};
+use crate::consensus::finality::{CommitCertificate, CommitVote, VotePool};
+use crate::signer::BlockSigner;
+use crate::slashing;
+use crate::snapshot::{self, SnapshotManifest};
+use crate::staking::StakingParams;
+use crate::state::ChainState;
+use crate::storage::{ChainStore, KvStore};
+use crate::transaction::{DoubleSign, Transaction, TxPayload};
 use chrono::Utc;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Result of looking up a block that may have been pruned
+pub enum BlockLookup<'a> {
+    Found(&'a Block),
+    Pruned(BlockHeader), // header kept, body deleted
+    NotFound,
+}
+
+/// State after a block, and the active set the block was verified against
+type Executed = (ChainState, Vec<Validator>);
 
 pub struct Blockchain {
+    /// Blocks held in memory, up to the tip: those whose bodies this node
+    /// keeps, and the parent of the oldest of them. Older headers are
+    /// read from the store by `lookup_block`.
     pub blocks: Vec<Block>,
     pub pending_transactions: Vec<Transaction>,
-    pub balances: HashMap<String, u64>, // Track token balances
-    pub consensus: DposConsensus,
+    pub state: ChainState, // Balances and validators after the latest block
+    /// State before the latest block, so a competing block for the tip's
+    /// height can be applied instead. Not kept across restarts.
+    previous_state: Option<ChainState>,
+    engine: Box<dyn ConsensusEngine>,
     pub validator_set: HashMap<String, Validator>,
+    /// Latest block with a commit certificate; it and its ancestors can
+    /// never be replaced
+    finalized_height: u64,
+    votes: VotePool,
+    /// Voting power of the active set for each block that is not final
+    /// yet. After a restart only the tip's is known.
+    vote_power: BTreeMap<u64, BTreeMap<String, u64>>,
+    /// Double-signs seen by this node that have not been reported yet
+    detected_evidence: Vec<TxPayload>,
+    store: ChainStore,
+    genesis_hash: String,
+    base_height: u64, // genesis, or the snapshot the chain was restored from
+    staking_params: StakingParams,
+    node_config: NodeConfig,
+    snapshot_config: SnapshotConfig,
+    pruned_below: u64, // bodies of blocks below this height are gone
 }
 
 impl Blockchain {
+    /// Create a chain backed by an in-memory store
     pub fn new(config: &Config) -> Self {
-        // Create initial validator set
-        let initial_validators = vec![Validator {
-            address: SerializableVerifyingKey(crypto_utils::default_verifying_key()),
-            stake: 100000,
-            uptime: 1.0,
-            missed_blocks: 0,
-            last_active: Utc::now().timestamp(),
-        }];
+        Self::with_store(config, ChainStore::in_memory())
+            .expect("in-memory store cannot fail to initialize")
+    }
 
-        let consensus = DposConsensus::new(&config.consensus.dpos, initial_validators);
+    /// Open a chain persisted in `kv`. An empty store is initialised from
+    /// `snapshots.restore_from` when configured, otherwise from genesis.
+    pub fn open(config: &Config, kv: Arc<dyn KvStore>) -> Result<Self, String> {
+        let store = Self::open_store(kv.clone())?;
+        if let Some(dir) = &config.snapshots.restore_from {
+            let is_empty = !store
+                .has_blocks()
+                .map_err(|e| format!("Failed to load blocks: {}", e))?;
+            if is_empty {
+                return Self::restore_from_snapshot(config, kv, Path::new(dir));
+            }
+        }
+        Self::with_store(config, store)
+    }
+
+    fn open_store(kv: Arc<dyn KvStore>) -> Result<ChainStore, String> {
+        ChainStore::open(kv).map_err(|e| format!("Failed to open chain store: {}", e))
+    }
+
+    fn with_store(config: &Config, store: ChainStore) -> Result<Self, String> {
+        let engine = engine::from_config(&config.consensus)?;
 
         let mut blockchain = Blockchain {
             blocks: Vec::new(),
             pending_transactions: Vec::new(),
-            balances: HashMap::new(), // Initialize balance tracking
-            consensus,
+            state: ChainState::default(),
+            previous_state: None,
+            engine,
             validator_set: HashMap::new(),
+            finalized_height: 0,
+            votes: VotePool::default(),
+            vote_power: BTreeMap::new(),
+            detected_evidence: Vec::new(),
+            store,
+            genesis_hash: String::new(),
+            base_height: 0,
+            staking_params: StakingParams::from(&config.consensus.dpos),
+            node_config: config.node.clone(),
+            snapshot_config: config.snapshots.clone(),
+            pruned_below: 0,
         };
 
-        // Create genesis block
-        let genesis_block = Block::new(
-            0,
-            "0".repeat(64),
-            Vec::new(),
-            &crypto_utils::default_signing_key(),
+        let has_blocks = blockchain
+            .store
+            .has_blocks()
+            .map_err(|e| format!("Failed to load blocks: {}", e))?;
+
+        if !has_blocks {
+            let (genesis_block, state) = Self::genesis(config, blockchain.engine.as_ref())?;
+            blockchain.state = state;
+            blockchain
+                .store
+                .commit_block(
+                    &genesis_block,
+                    &blockchain.state.validators,
+                    &blockchain.state,
+                )
+                .map_err(|e| format!("Failed to store genesis block: {}", e))?;
+            blockchain.blocks.push(genesis_block);
+        } else {
+            let load_err = |e: anyhow::Error| format!("Failed to load blocks: {}", e);
+            blockchain.base_height = blockchain.store.base_height().map_err(load_err)?;
+            blockchain.pruned_below = blockchain
+                .store
+                .pruned_below()
+                .map_err(|e| format!("Failed to load pruning marker: {}", e))?;
+            // Pruned headers stay on disk; only the parent of the oldest
+            // block with a body is kept, as the tip may be replaced
+            let from = blockchain
+                .pruned_below
+                .saturating_sub(1)
+                .max(blockchain.base_height);
+            blockchain.blocks = blockchain.store.load_blocks_from(from).map_err(load_err)?;
+            if let Some(state) = blockchain
+                .store
+                .load_state()
+                .map_err(|e| format!("Failed to load state: {}", e))?
+            {
+                blockchain.state = state;
+            }
+            blockchain.finalized_height = blockchain
+                .store
+                .finalized_height()
+                .map_err(|e| format!("Failed to load finalized height: {}", e))?;
+        }
+        blockchain.genesis_hash = match blockchain
+            .store
+            .genesis_hash()
+            .map_err(|e| format!("Failed to load genesis hash: {}", e))?
+        {
+            Some(hash) => hash,
+            None if blockchain.base_height == 0 => {
+                let hash = match blockchain.lookup_block(0) {
+                    BlockLookup::Found(block) => block.hash(),
+                    BlockLookup::Pruned(header) => header.hash(),
+                    BlockLookup::NotFound => return Err("Genesis block is missing".to_string()),
+                };
+                blockchain
+                    .store
+                    .put_genesis_hash(&hash)
+                    .map_err(|e| format!("Failed to store genesis hash: {}", e))?;
+                hash
+            }
+            None => {
+                return Err("Chain starts after genesis but its genesis hash is unknown".to_string())
+            }
+        };
+        blockchain.vote_power.insert(
+            blockchain.get_block_height(),
+            blockchain.state.consensus_power(),
         );
 
-        blockchain.blocks.push(genesis_block);
+        Ok(blockchain)
+    }
+
+    /// Genesis block and state described by `config`
+    fn genesis(
+        config: &Config,
+        engine: &dyn ConsensusEngine,
+    ) -> Result<(Block, ChainState), String> {
+        let state = ChainState::genesis(config, config.genesis.timestamp, engine)?;
+        Ok((
+            Block::genesis(config.genesis.timestamp, state.state_root()?),
+            state,
+        ))
+    }
 
-        // Update consensus state
-        blockchain.consensus.last_block_time = Utc::now().timestamp();
+    /// Start a chain in an empty `kv` from an existing genesis block, as
+    /// when importing a chain exported by another node. It must be the
+    /// genesis block `config` describes.
+    pub fn open_with_genesis(
+        config: &Config,
+        kv: Arc<dyn KvStore>,
+        genesis: &Block,
+    ) -> Result<Self, String> {
+        let store = Self::open_store(kv)?;
+        let has_blocks = store
+            .has_blocks()
+            .map_err(|e| format!("Failed to load blocks: {}", e))?;
+        if has_blocks {
+            return Err("Cannot import into a non-empty data directory".to_string());
+        }
+        let (expected, _) =
+            Self::genesis(config, engine::from_config(&config.consensus)?.as_ref())?;
+        if genesis.hash() != expected.hash() || !genesis.transactions.is_empty() {
+            return Err(format!(
+                "genesis block {} does not match the configured genesis {}",
+                genesis.hash(),
+                expected.hash()
+            ));
+        }
 
-        blockchain
+        Self::with_store(config, store)
+    }
+
+    /// Open a chain from a verified snapshot so it can continue from the
+    /// block after the snapshot height. The snapshot's manifest must hash
+    /// to `snapshots.trusted_hash`. `kv` must not hold a chain yet.
+    pub fn restore_from_snapshot(
+        config: &Config,
+        kv: Arc<dyn KvStore>,
+        snapshot_dir: &Path,
+    ) -> Result<Self, String> {
+        let store = Self::open_store(kv)?;
+        let has_blocks = store
+            .has_blocks()
+            .map_err(|e| format!("Failed to load blocks: {}", e))?;
+        if has_blocks {
+            return Err("Cannot restore a snapshot into a non-empty data directory".to_string());
+        }
+
+        let trusted_hash = config.snapshots.trusted_hash.as_deref().ok_or_else(|| {
+            "Restoring a snapshot needs snapshots.trusted_hash, the manifest hash \
+             published by a node you trust"
+                .to_string()
+        })?;
+        let verified = snapshot::read_snapshot(snapshot_dir, trusted_hash)?;
+        store
+            .commit_snapshot(
+                &verified.block,
+                &verified.state,
+                &verified.manifest.genesis_hash,
+            )
+            .map_err(|e| format!("Failed to store snapshot: {}", e))?;
+
+        info!(
+            "Restored state at height {} from snapshot {}",
+            verified.manifest.height,
+            snapshot_dir.display()
+        );
+
+        Self::with_store(config, store)
+    }
+
+    /// Consensus engine chosen by `consensus_type`
+    pub fn engine(&self) -> &dyn ConsensusEngine {
+        self.engine.as_ref()
+    }
+
+    /// Staking parameters in force after the latest block, including
+    /// changes made by governance
+    pub fn staking_params(&self) -> StakingParams {
+        self.state.staking_params(&self.staking_params)
+    }
+
+    /// Check that `header`, for a block above the tip, names the producer
+    /// the schedule gives its height and fallback rank. Jailing and
+    /// slashing can shrink the active set before a later header's height,
+    /// shifting the schedule, so beyond the next block the producer only
+    /// has to be in the set. Nobody joins it before the next epoch, whose
+    /// headers pass unchecked until the blocks before them are applied.
+    pub fn check_header_producer(&self, header: &BlockHeader) -> Result<(), String> {
+        let params = self.staking_params();
+        let tip_height = self.get_block_height();
+        let next_epoch = (params.epoch_of(tip_height) + 1) * params.epoch_length.max(1);
+        if header.height <= tip_height || header.height >= next_epoch {
+            return Ok(());
+        }
+        let (height, rank) = (header.height, header.fallback_rank);
+        if height > tip_height + 1 {
+            let size = self.state.validators.len() as u32;
+            let in_set = (0..size).any(|rank| {
+                self.engine.producer(&self.state, height, rank).as_deref()
+                    == Some(header.producer.as_str())
+            });
+            if !in_set {
+                return Err(format!(
+                    "producer {} of header {} is not in the active set",
+                    header.producer, height
+                ));
+            }
+            return Ok(());
+        }
+        let expected = self
+            .engine
+            .producer(&self.state, height, rank)
+            .ok_or_else(|| format!("no validator at fallback rank {}", rank))?;
+        if header.producer != expected {
+            return Err(format!(
+                "producer {} is not scheduled for height {} at fallback rank {} ({} is)",
+                header.producer, height, rank, expected
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn store(&self) -> &ChainStore {
+        &self.store
+    }
+
+    /// Write a snapshot of the current state into the configured directory
+    pub fn create_snapshot(&self) -> Result<SnapshotManifest, String> {
+        let root = Path::new(&self.snapshot_config.dir);
+        let manifest = snapshot::write_snapshot(
+            root,
+            &self.genesis_hash,
+            self.get_latest_block(),
+            &self.state,
+            self.snapshot_config.chunk_size,
+        )?;
+        snapshot::prune_snapshots(root, self.snapshot_config.keep)?;
+        Ok(manifest)
+    }
+
+    fn maybe_snapshot(&self) {
+        let interval = self.snapshot_config.interval;
+        let height = self.get_block_height();
+        if interval == 0 || height == 0 || !height.is_multiple_of(interval) {
+            return;
+        }
+
+        // A failed snapshot must never stop block processing
+        match self.create_snapshot() {
+            Ok(manifest) => info!(
+                "Created state snapshot at height {} ({} chunks, manifest hash {})",
+                manifest.height,
+                manifest.chunk_hashes.len(),
+                manifest.hash().unwrap_or_default()
+            ),
+            Err(e) => warn!("Failed to create snapshot at height {}: {}", height, e),
+        }
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<(), String> {
-        // Verify block
-        if !self.verify_block(&block) {
-            return Err("Invalid block".to_string());
+        // A competing block for the tip's height goes through fork choice
+        let tip = self.get_latest_block();
+        if block.header.height == tip.header.height && block.hash() != tip.hash() {
+            self.detect_double_sign(&block.header);
+            return self.replace_tip(block);
+        }
+
+        // Verify block and compute the state it leads to
+        let executed = self
+            .execute_block(&block)
+            .map_err(|reason| format!("Invalid block: {}", reason))?;
+        self.commit_next(block, executed)
+    }
+
+    /// Check `block` as the next block and commit it, without weighing it
+    /// against the tip. Import uses this so each block runs once and a
+    /// failure names the rule it broke.
+    pub fn append_block(&mut self, block: Block) -> Result<(), String> {
+        let executed = self.execute_block(&block)?;
+        self.commit_next(block, executed)
+    }
+
+    /// Make `block`, already executed, the new tip
+    fn commit_next(&mut self, block: Block, (state, producers): Executed) -> Result<(), String> {
+        // Persist before exposing the block in memory
+        self.store
+            .commit_block(&block, &producers, &state)
+            .map_err(|e| format!("Failed to store block: {}", e))?;
+
+        self.vote_power
+            .insert(block.header.height, state.consensus_power());
+        self.previous_state = Some(std::mem::replace(&mut self.state, state));
+
+        // Pending transactions the block carried are settled
+        if !self.pending_transactions.is_empty() {
+            let included: HashSet<String> = block.transactions.iter().map(|tx| tx.hash()).collect();
+            self.pending_transactions
+                .retain(|tx| !included.contains(&tx.hash()));
         }
 
         // Add to chain
         self.blocks.push(block);
+        self.maybe_snapshot();
+
+        Ok(())
+    }
+
+    /// Keep evidence if `header` and the tip were signed by one producer
+    fn detect_double_sign(&mut self, header: &BlockHeader) {
+        let tip = &self.get_latest_block().header;
+        if slashing::verify_evidence(tip, header).is_err() {
+            return;
+        }
+        warn!(
+            "Producer {} signed two blocks at height {}",
+            header.producer, header.height
+        );
+        self.detected_evidence
+            .push(TxPayload::Evidence(Box::new(DoubleSign {
+                first: tip.clone(),
+                second: header.clone(),
+            })));
+    }
+
+    /// Evidence payloads for double-signs this node has seen, to be sent
+    /// in signed transactions; each is handed out once
+    pub fn take_detected_evidence(&mut self) -> Vec<TxPayload> {
+        std::mem::take(&mut self.detected_evidence)
+    }
+
+    /// Swap the tip for `block`, a competing block for the same height,
+    /// when fork choice prefers it
+    fn replace_tip(&mut self, block: Block) -> Result<(), String> {
+        let height = block.header.height;
+        if height <= self.finalized_height {
+            return Err(format!(
+                "Block at height {} is final and cannot be replaced",
+                height
+            ));
+        }
+        if !prefer_block(&block.header, &self.get_latest_block().header) {
+            return Err(format!(
+                "Block at height {} loses fork choice to the current tip",
+                height
+            ));
+        }
+        let (parent, base) = match (self.blocks.len().checked_sub(2), &self.previous_state) {
+            (Some(index), Some(base)) => (&self.blocks[index], base),
+            _ => {
+                return Err(format!(
+                    "Cannot replace block {}: the state before it is not available",
+                    height
+                ))
+            }
+        };
 
-        // Update consensus
-        self.consensus.last_block_time = Utc::now().timestamp();
-        self.consensus.update_validator_set();
+        let (state, producers) = self
+            .execute_on(Some(parent), base, &block)
+            .map_err(|reason| format!("Invalid block: {}", reason))?;
+        self.store
+            .commit_block(&block, &producers, &state)
+            .map_err(|e| format!("Failed to store block: {}", e))?;
+
+        self.vote_power.insert(height, state.consensus_power());
+        self.state = state;
+        info!(
+            "Replaced block {} with a fallback rank {} block",
+            height, block.header.fallback_rank
+        );
+        *self.blocks.last_mut().unwrap() = block;
 
         Ok(())
     }
 
+    /// Record a commit vote for a block on this chain. Returns the new
+    /// certificate when the vote brings the block to two-thirds of stake.
+    pub fn add_commit_vote(
+        &mut self,
+        vote: CommitVote,
+    ) -> Result<Option<CommitCertificate>, String> {
+        let height = vote.height;
+        if height <= self.finalized_height {
+            return Err(format!("block {} is already final", height));
+        }
+        let on_chain = match self.lookup_block(height) {
+            BlockLookup::Found(block) => block.hash() == vote.block_hash,
+            _ => false,
+        };
+        if !on_chain {
+            return Err(format!(
+                "block {} is not on this chain at height {}",
+                vote.block_hash, height
+            ));
+        }
+        let voting_power = self
+            .vote_power
+            .get(&height)
+            .ok_or_else(|| format!("validator set for height {} is not known", height))?;
+        if !voting_power.contains_key(&vote.validator) {
+            return Err(format!(
+                "{} is not an active validator at height {}",
+                vote.validator, height
+            ));
+        }
+        vote.verify()?;
+
+        let block_hash = vote.block_hash.clone();
+        let validator = vote.validator.clone();
+        if !self.votes.insert(vote) {
+            return Err(format!("duplicate vote from {}", validator));
+        }
+        match self.votes.certificate(height, &block_hash, voting_power) {
+            Some(certificate) => {
+                self.finalize(&certificate)?;
+                Ok(Some(certificate))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Sign and record a commit vote for the tip with `signing_key`
+    pub fn vote_on_tip(
+        &mut self,
+        signing_key: &ed25519_dalek::SigningKey,
+    ) -> Result<Option<CommitCertificate>, String> {
+        self.vote_on_tip_with(&mut signing_key.clone())
+    }
+
+    /// Have `signer` vote for the tip and record the vote
+    pub fn vote_on_tip_with(
+        &mut self,
+        signer: &mut dyn BlockSigner,
+    ) -> Result<Option<CommitCertificate>, String> {
+        let vote = self.sign_tip_vote(signer)?;
+        self.add_commit_vote(vote)
+    }
+
+    /// Whether consensus key `validator` is in the active set voting on
+    /// the tip
+    pub fn may_vote_on_tip(&self, validator: &str) -> bool {
+        self.vote_power
+            .get(&self.get_block_height())
+            .is_some_and(|power| power.contains_key(validator))
+    }
+
+    /// Have `signer` sign a commit vote for the tip, for the caller to
+    /// record and pass on. The last vote of each key is stored, and a vote
+    /// for a lower height or for another block at the same height is
+    /// refused whatever the signer would allow.
+    pub fn sign_tip_vote(&mut self, signer: &mut dyn BlockSigner) -> Result<CommitVote, String> {
+        let (height, hash) = (self.get_block_height(), self.get_latest_block().hash());
+        let validator = signer.public_key()?;
+        let last = self
+            .store
+            .last_vote(&validator)
+            .map_err(|e| format!("Failed to load last vote: {}", e))?;
+        if let Some((voted_height, voted_hash)) = last {
+            if voted_height > height || (voted_height == height && voted_hash != hash) {
+                return Err(format!(
+                    "{} already voted for block {} at height {}",
+                    validator, voted_hash, voted_height
+                ));
+            }
+        }
+
+        let vote = signer.sign_vote(height, &hash)?;
+        if vote.height != height || vote.block_hash != hash || vote.validator != validator {
+            return Err("signer returned a vote for something else".to_string());
+        }
+        self.store
+            .put_last_vote(&validator, height, &hash)
+            .map_err(|e| format!("Failed to record vote: {}", e))?;
+        Ok(vote)
+    }
+
+    fn finalize(&mut self, certificate: &CommitCertificate) -> Result<(), String> {
+        self.store
+            .put_certificate(certificate)
+            .map_err(|e| format!("Failed to store commit certificate: {}", e))?;
+        self.finalized_height = certificate.height;
+        self.votes.prune_through(certificate.height);
+        self.vote_power = self.vote_power.split_off(&(certificate.height + 1));
+        info!(
+            "Finalized block {} with {} commit votes",
+            certificate.height,
+            certificate.votes.len()
+        );
+        Ok(())
+    }
+
+    /// Height of the latest block with a commit certificate
+    pub fn finalized_height(&self) -> u64 {
+        self.finalized_height
+    }
+
+    pub fn commit_certificate(&self, height: u64) -> Result<Option<CommitCertificate>, String> {
+        self.store
+            .get_certificate(height)
+            .map_err(|e| format!("Failed to load commit certificate: {}", e))
+    }
+
     pub fn verify_block(&self, block: &Block) -> bool {
+        self.validate_block(block).is_ok()
+    }
+
+    /// Check `block` as the next block of this chain, explaining any failure
+    pub fn validate_block(&self, block: &Block) -> Result<(), String> {
+        self.execute_block(block).map(|_| ())
+    }
+
+    /// Validate `block` and apply it to a copy of the current state
+    fn execute_block(&self, block: &Block) -> Result<Executed, String> {
+        self.execute_on(self.blocks.last(), &self.state, block)
+    }
+
+    /// Validate `block` as the child of `parent` and apply it to a copy of
+    /// `base`, the state after `parent`
+    fn execute_on(
+        &self,
+        parent: Option<&Block>,
+        base: &ChainState,
+        block: &Block,
+    ) -> Result<Executed, String> {
+        self.check_block(parent, block)?;
+
+        let height = block.header.height;
+        let mut state = base.clone();
+        state.begin_block(
+            height,
+            block.header.timestamp,
+            &self.staking_params,
+            self.engine.as_ref(),
+        )?;
+
+        // The scheduled producer, or a fallback once its timeout has passed
+        self.engine
+            .verify_block(&state, parent.map(|parent| &parent.header), &block.header)?;
+        let producers = state.validators.clone();
+        let params = state.staking_params(&self.staking_params);
+        self.engine
+            .on_block(&mut state, height, block.header.fallback_rank, &params);
+
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            state
+                .apply_transaction(transaction, height, &params)
+                .map_err(|e| {
+                    format!(
+                        "transaction {} ({}) rejected: {}",
+                        index,
+                        transaction.hash(),
+                        e
+                    )
+                })?;
+        }
+
+        Ok((state, producers))
+    }
+
+    /// Checks that need no state: linkage, clock drift, merkle root and
+    /// signatures
+    fn check_block(&self, parent: Option<&Block>, block: &Block) -> Result<(), String> {
+        engine::check_not_in_future(&block.header, Utc::now().timestamp() as u64)?;
         // Check if block connects to chain
-        if let Some(last_block) = self.blocks.last() {
-            if block.header.previous_hash != last_block.hash() {
-                return false;
+        if let Some(parent) = parent {
+            let expected_height = parent.header.height + 1;
+            if block.header.height != expected_height {
+                return Err(format!(
+                    "expected height {}, got {}",
+                    expected_height, block.header.height
+                ));
+            }
+            if block.header.previous_hash != parent.hash() {
+                return Err("previous hash does not match the chain tip".to_string());
+            }
+            if block.header.timestamp < parent.header.timestamp {
+                return Err("timestamp is before the parent block".to_string());
             }
         }
 
-        // Verify block signature
-        // This would typically involve checking against expected validator
-        true
+        block.check_integrity()
     }
 
     pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
@@ -85,56 +674,1208 @@ impl Blockchain {
         if !transaction.verify() {
             return Err("Invalid transaction".to_string());
         }
+        transaction.payload()?;
+        self.state.check_not_replayed(&transaction)?;
+        let hash = transaction.hash();
+        if self.pending_transactions.iter().any(|tx| tx.hash() == hash) {
+            return Err("transaction is already pending".to_string());
+        }
 
         self.pending_transactions.push(transaction);
         Ok(())
     }
 
+    /// State at the start of the next block, before its transactions
+    fn next_block_state(&self) -> Result<ChainState, String> {
+        self.next_block_state_at(Utc::now().timestamp() as u64)
+    }
+
+    /// State at the start of a next block made at `timestamp`
+    fn next_block_state_at(&self, timestamp: u64) -> Result<ChainState, String> {
+        let mut state = self.state.clone();
+        state.begin_block(
+            self.get_block_height() + 1,
+            timestamp,
+            &self.staking_params,
+            self.engine.as_ref(),
+        )?;
+        Ok(state)
+    }
+
+    /// Hex key of the validator scheduled to produce the next block
+    pub fn next_producer(&self) -> Result<String, String> {
+        let state = self.next_block_state()?;
+        let height = self.get_block_height() + 1;
+        self.engine
+            .producer(&state, height, 0)
+            .ok_or_else(|| "no validator is scheduled".to_string())
+    }
+
+    /// Lowest fallback rank at which the hex key `producer` may make the
+    /// next block at `timestamp`, if it is among the fallbacks at all
+    pub fn producer_rank_at(&self, producer: &str, timestamp: u64) -> Result<Option<u32>, String> {
+        let state = self.next_block_state_at(timestamp)?;
+        let height = self.get_block_height() + 1;
+        Ok(self.engine.producer_rank(&state, height, producer))
+    }
+
+    /// Consensus key `operator`'s validator signs the next block with,
+    /// including a rotation taking effect at that block
+    pub fn next_consensus_key(&self, operator: &str) -> Result<Option<String>, String> {
+        let state = self.next_block_state()?;
+        Ok(state
+            .candidates
+            .get(operator)
+            .map(|candidate| candidate.signing_key(operator).to_string()))
+    }
+
     pub fn mine_block(&mut self, signing_key: &ed25519_dalek::SigningKey) -> Result<Block, String> {
-        // Select block producer
-        let _producer = self.consensus.elect_block_producer();
+        self.mine_block_with(&mut signing_key.clone())
+    }
 
-        // Create new block with pending transactions
-        let new_block = Block::new(
-            self.blocks.len() as u64,
-            self.blocks.last().unwrap().hash(),
-            self.pending_transactions.clone(),
-            signing_key,
-        );
+    /// Produce the next block, signed by `signer`
+    pub fn mine_block_with(&mut self, signer: &mut dyn BlockSigner) -> Result<Block, String> {
+        self.mine_block_at(signer, Utc::now().timestamp() as u64)
+    }
+
+    /// Produce the next block as if the time were `now`, signed by `signer`
+    pub fn mine_block_at(
+        &mut self,
+        signer: &mut dyn BlockSigner,
+        now: u64,
+    ) -> Result<Block, String> {
+        // Only the scheduled validator may produce the next block, or a
+        // fallback once the validators ahead of it have timed out
+        let height = self.get_block_height() + 1;
+        let local = signer.public_key()?;
+        let mut scratch = self.next_block_state_at(now)?;
+        let scheduled = self
+            .engine
+            .producer(&scratch, height, 0)
+            .ok_or_else(|| "no validator is scheduled".to_string())?;
+        let rank = self
+            .engine
+            .producer_rank(&scratch, height, &local)
+            .ok_or_else(|| {
+                format!(
+                    "local key {} is not scheduled to produce block {} ({} is)",
+                    local, height, scheduled
+                )
+            })?;
+        let parent_timestamp = self.get_latest_block().header.timestamp;
+        let eligible_at = parent_timestamp + self.engine.fallback_delay(&scratch, rank);
+        if now < eligible_at {
+            return Err(format!(
+                "local key {} is not scheduled to produce block {} ({} is); as fallback rank {} it may produce from timestamp {}",
+                local, height, scheduled, rank, eligible_at
+            ));
+        }
+        let params = scratch.staking_params(&self.staking_params);
+        self.engine.on_block(&mut scratch, height, rank, &params);
+
+        // Include the pending transactions that still apply cleanly. The
+        // pool is left alone until the block is added, so a block that
+        // fails to seal loses none of them.
+        let mut transactions = Vec::new();
+        let mut rejected = HashSet::new();
+        for transaction in &self.pending_transactions {
+            match scratch.apply_transaction(transaction, height, &params) {
+                Ok(()) => transactions.push(transaction.clone()),
+                Err(e) => {
+                    warn!("Dropping transaction {}: {}", transaction.hash(), e);
+                    rejected.insert(transaction.hash());
+                }
+            }
+        }
 
-        // Clear pending transactions
-        self.pending_transactions.clear();
+        // Create new block with pending transactions
+        let new_block = self.engine.seal(
+            height,
+            self.get_latest_block().hash(),
+            transactions,
+            now.max(parent_timestamp),
+            rank,
+            signer,
+        )?;
 
-        // Add block to chain
+        // Adding the block settles the included transactions; the ones
+        // that would have made it invalid go too
         self.add_block(new_block.clone())?;
+        self.pending_transactions
+            .retain(|tx| !rejected.contains(&tx.hash()));
 
         Ok(new_block)
     }
 
     pub fn get_block_height(&self) -> u64 {
-        self.blocks.len() as u64 - 1
+        self.get_latest_block().header.height
+    }
+
+    /// Lowest block height this node holds
+    pub fn get_base_height(&self) -> u64 {
+        self.base_height
+    }
+
+    /// Number of blocks from the base height to the tip
+    pub fn chain_length(&self) -> u64 {
+        self.get_block_height() - self.base_height + 1
+    }
+
+    /// Hash of the genesis block, also known to a node restored from a
+    /// snapshot that never held the block
+    pub fn genesis_hash(&self) -> &str {
+        &self.genesis_hash
     }
 
     pub fn get_latest_block(&self) -> &Block {
         self.blocks.last().unwrap()
     }
 
+    /// Full block at `height`; `None` if unknown or pruned (see `lookup_block`)
     pub fn get_block_by_height(&self, height: u64) -> Option<&Block> {
-        self.blocks.get(height as usize)
+        match self.lookup_block(height) {
+            BlockLookup::Found(block) => Some(block),
+            _ => None,
+        }
+    }
+
+    pub fn lookup_block(&self, height: u64) -> BlockLookup<'_> {
+        if height < self.base_height || height > self.get_block_height() {
+            return BlockLookup::NotFound;
+        }
+        let held = height
+            .checked_sub(self.blocks[0].header.height)
+            .and_then(|index| self.blocks.get(index as usize));
+
+        match held {
+            Some(block) if !self.is_body_pruned(height) => BlockLookup::Found(block),
+            Some(block) => BlockLookup::Pruned(block.header.clone()),
+            None => match self.store.get_header(height) {
+                Ok(Some(header)) => BlockLookup::Pruned(header),
+                Ok(None) => BlockLookup::NotFound,
+                Err(e) => {
+                    warn!("Failed to read header {}: {}", height, e);
+                    BlockLookup::NotFound
+                }
+            },
+        }
+    }
+
+    /// Active sets that verified the blocks from `from` to `to`, each with
+    /// the first height it applies to. A node restored from a snapshot
+    /// lacks the sets before it.
+    pub fn validator_sets(&self, from: u64, to: u64) -> Result<Vec<(u64, Vec<Validator>)>, String> {
+        self.store
+            .validator_sets(from, to)
+            .map_err(|e| format!("Failed to load validator sets: {}", e))
+    }
+
+    pub fn is_body_pruned(&self, height: u64) -> bool {
+        height < self.pruned_below
+    }
+
+    /// Height below which this node no longer holds block bodies
+    pub fn pruned_below(&self) -> u64 {
+        self.pruned_below
+    }
+
+    pub fn node_config(&self) -> &NodeConfig {
+        &self.node_config
+    }
+
+    /// Delete block bodies older than the configured retention window.
+    /// Returns how many bodies were removed; archive nodes never prune.
+    pub fn prune(&mut self) -> Result<u64, String> {
+        if self.node_config.mode != NodeMode::Pruned {
+            return Ok(0);
+        }
+
+        let keep = self.node_config.keep_blocks.max(1);
+        let target = (self.get_block_height() + 1).saturating_sub(keep);
+        let from = self.pruned_below.max(self.get_base_height());
+        if target <= from {
+            return Ok(0);
+        }
+
+        self.store
+            .prune_bodies_below(from, target)
+            .map_err(|e| format!("Failed to prune blocks: {}", e))?;
+
+        // Drop pruned blocks from memory but the newest, the parent of the
+        // oldest block with a body
+        let first = self.blocks[0].header.height;
+        let keep_from = target - 1;
+        self.blocks
+            .drain(..keep_from.saturating_sub(first) as usize);
+        self.blocks[0].transactions = Vec::new();
+        self.pruned_below = target;
+
+        Ok(target - from)
     }
 
     // Token functionality
     pub fn get_balance(&self, address: &str) -> u64 {
-        // Get balance from tracking map
-        *self.balances.get(address).unwrap_or(&0)
+        self.state.balance(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::dpos::SerializableVerifyingKey;
+    use crate::crypto_utils;
+    use crate::db_pure_rust::PureRustDatabase;
+    use crate::governance::{Param, ParamChange, ProposalStatus, ProposalVote};
+    use crate::test_support::{mine_scheduled, test_config, validator_key};
+    use crate::transaction::{TxPayload, TX_VALIDITY_SECS};
+    use ed25519_dalek::SigningKey;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_reopen_restores_blocks_and_balances() -> Result<(), String> {
+        let dir = tempdir().map_err(|e| e.to_string())?;
+        let path = dir.path().to_str().unwrap();
+        let mut config = test_config();
+        config.genesis.balances.insert("alice".to_string(), 42);
+        let signing_key = validator_key();
+
+        let (tip_hash, height) = {
+            let db = Arc::new(PureRustDatabase::new(path).map_err(|e| e.to_string())?);
+            let mut blockchain = Blockchain::open(&config, db)?;
+            blockchain.mine_block(&signing_key)?;
+            (
+                blockchain.get_latest_block().hash(),
+                blockchain.get_block_height(),
+            )
+        };
+
+        let db = Arc::new(PureRustDatabase::new(path).map_err(|e| e.to_string())?);
+        let blockchain = Blockchain::open(&config, db)?;
+        assert_eq!(blockchain.get_block_height(), height);
+        assert_eq!(blockchain.get_latest_block().hash(), tip_hash);
+        assert_eq!(blockchain.get_balance("alice"), 42);
+        Ok(())
+    }
+
+    fn snapshot_config(dir: &Path, interval: u64) -> Config {
+        let mut config = test_config();
+        config.snapshots = SnapshotConfig {
+            interval,
+            chunk_size: 32,
+            dir: dir.to_str().unwrap().to_string(),
+            keep: 2,
+            restore_from: None,
+            trusted_hash: None,
+        };
+        config
+    }
+
+    #[test]
+    fn test_restore_from_snapshot_continues_chain() -> Result<(), String> {
+        let snapshots = tempdir().map_err(|e| e.to_string())?;
+        let mut config = snapshot_config(snapshots.path(), 2);
+        config.genesis.balances.insert("alice".to_string(), 75);
+        let signing_key = validator_key();
+
+        let mut source = Blockchain::new(&config);
+        source.mine_block(&signing_key)?;
+        source.mine_block(&signing_key)?;
+
+        let dir = snapshot::snapshot_dir(snapshots.path(), 2);
+        let restore = |config: &Config| {
+            Blockchain::restore_from_snapshot(
+                config,
+                Arc::new(crate::storage::MemoryStore::new()),
+                &dir,
+            )
+        };
+        assert!(restore(&config).is_err());
+        config.snapshots.trusted_hash = Some(source.create_snapshot()?.hash()?);
+        let mut restored = restore(&config)?;
+        assert_eq!(restored.genesis_hash(), source.genesis_hash());
+        assert_eq!(restored.get_base_height(), 2);
+        assert_eq!(restored.get_block_height(), 2);
+        assert_eq!(restored.get_balance("alice"), 75);
+        assert_eq!(restored.state, source.state);
+        assert!(restored.get_block_by_height(1).is_none());
+
+        // The restored node accepts the block that follows the snapshot
+        let next = source.mine_block(&signing_key)?;
+        restored.add_block(next.clone())?;
+        assert_eq!(restored.get_latest_block().hash(), next.hash());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_restores_configured_snapshot_only_when_empty() -> Result<(), String> {
+        let snapshots = tempdir().map_err(|e| e.to_string())?;
+        let mut config = snapshot_config(snapshots.path(), 1);
+        let signing_key = validator_key();
+
+        let mut source = Blockchain::new(&config);
+        source.mine_block(&signing_key)?;
+        config.snapshots.trusted_hash = Some(source.create_snapshot()?.hash()?);
+        config.snapshots.restore_from = Some(
+            snapshot::snapshot_dir(snapshots.path(), 1)
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+
+        let kv: Arc<dyn KvStore> = Arc::new(crate::storage::MemoryStore::new());
+        let restored = Blockchain::open(&config, kv.clone())?;
+        assert_eq!(restored.get_base_height(), 1);
+        assert_eq!(restored.genesis_hash(), source.genesis_hash());
+
+        // Reopening an initialised store ignores the snapshot setting
+        let mut node = Blockchain::open(&config, kv.clone())?;
+        node.mine_block(&signing_key)?;
+        let reopened = Blockchain::open(&config, kv)?;
+        assert_eq!(reopened.get_block_height(), 2);
+        Ok(())
     }
 
-    pub fn mint(&mut self, address: String, amount: u64) -> Result<(), String> {
-        let current_balance = *self.balances.get(&address).unwrap_or(&0);
-        let _ = self
+    #[test]
+    fn test_pruned_mode_keeps_recent_bodies() -> Result<(), String> {
+        let mut config = test_config();
+        config.node = NodeConfig {
+            mode: NodeMode::Pruned,
+            keep_blocks: 2,
+            prune_interval_secs: 60,
+        };
+        let kv: Arc<dyn KvStore> = Arc::new(crate::storage::MemoryStore::new());
+        let signing_key = validator_key();
+
+        let mut blockchain = Blockchain::open(&config, kv.clone())?;
+        for _ in 0..4 {
+            blockchain.mine_block(&signing_key)?;
+        }
+
+        assert_eq!(blockchain.prune()?, 3);
+        assert_eq!(blockchain.prune()?, 0);
+        assert_eq!(blockchain.pruned_below(), 3);
+        assert!(matches!(blockchain.lookup_block(2), BlockLookup::Pruned(_)));
+        assert!(matches!(blockchain.lookup_block(3), BlockLookup::Found(_)));
+        assert!(matches!(blockchain.lookup_block(9), BlockLookup::NotFound));
+        // Only blocks 2 to 4 stay in memory; older headers come from disk
+        assert_eq!(blockchain.blocks.len(), 3);
+        assert!(matches!(blockchain.lookup_block(0), BlockLookup::Pruned(_)));
+
+        // Pruning survives a restart and the chain keeps growing
+        let mut reopened = Blockchain::open(&config, kv)?;
+        assert_eq!(reopened.pruned_below(), 3);
+        assert_eq!(reopened.blocks.len(), 3);
+        assert_eq!(reopened.chain_length(), 5);
+        assert!(reopened.get_block_by_height(1).is_none());
+        reopened.mine_block(&signing_key)?;
+        assert_eq!(reopened.get_block_height(), 5);
+        Ok(())
+    }
+
+    fn staking_tx(
+        signing_key: &ed25519_dalek::SigningKey,
+        payload: TxPayload,
+        amount: u64,
+    ) -> Transaction {
+        let mut tx = Transaction::new(
+            hex::encode(signing_key.verifying_key().to_bytes()),
+            "staking".to_string(),
+            amount,
+            Some(payload.to_data()),
+        );
+        tx.sign(signing_key).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_genesis_validators_come_from_config() -> Result<(), String> {
+        let mut config = test_config();
+        let (_, key) = crypto_utils::generate_keypair();
+        let address = hex::encode(key.to_bytes());
+        config.genesis.validators = vec![crate::config::GenesisValidator {
+            address: address.clone(),
+            stake: 5000,
+            consensus_key: None,
+        }];
+        config.genesis.balances.insert("alice".to_string(), 7);
+
+        let blockchain = Blockchain::new(&config);
+        assert_eq!(blockchain.get_balance("alice"), 7);
+        assert_eq!(blockchain.state.validators.len(), 1);
+        assert_eq!(blockchain.state.validators[0].address.0, key);
+        assert_eq!(blockchain.state.voting_power[&address], 5000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validator_registration_bonding_and_unbonding() -> Result<(), String> {
+        let mut config = test_config();
+        config.consensus.dpos.unbonding_period = 2;
+        config.consensus.dpos.epoch_length = 1;
+        let producer = validator_key();
+        let (operator, _) = crypto_utils::generate_keypair();
+        let address = hex::encode(operator.verifying_key().to_bytes());
+
+        config.genesis.balances.insert(address.clone(), 3000);
+        let mut blockchain = Blockchain::new(&config);
+
+        // Below the threshold the registration is dropped from the block
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::RegisterValidator, 999))?;
+        assert!(mine_scheduled(&mut blockchain, &[&producer, &operator])?
+            .transactions
+            .is_empty());
+
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::RegisterValidator, 1000))?;
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::Bond, 1000))?;
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        assert_eq!(blockchain.get_balance(&address), 1000);
+
+        // The new candidate joins the active set at the next epoch
+        assert_eq!(blockchain.state.validators.len(), 1);
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        assert_eq!(blockchain.state.validators.len(), 2);
+        assert!(blockchain
+            .state
+            .validators
+            .iter()
+            .any(|v| v.address.0 == operator.verifying_key() && v.stake == 2000));
+
+        // Unbonding below the threshold leaves the active set at the next
+        // epoch but only releases the funds after the unbonding period
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::Unbond, 1500))?;
+        let unbond_height = mine_scheduled(&mut blockchain, &[&producer, &operator])?
+            .header
+            .height;
+        assert_eq!(blockchain.state.validators.len(), 2);
+
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        assert_eq!(blockchain.state.validators.len(), 1);
+        assert_eq!(blockchain.get_balance(&address), 1000);
+        let released = mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        assert_eq!(released.header.height, unbond_height + 2);
+        assert_eq!(blockchain.get_balance(&address), 2500);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delegations_set_validator_voting_power() -> Result<(), String> {
+        let mut config = test_config();
+        config.consensus.dpos.unbonding_period = 1;
+        config.consensus.dpos.epoch_length = 1;
+        let producer = validator_key();
+        let (operator, _) = crypto_utils::generate_keypair();
+        let (delegator, _) = crypto_utils::generate_keypair();
+        let validator = hex::encode(operator.verifying_key().to_bytes());
+        let delegator_address = hex::encode(delegator.verifying_key().to_bytes());
+
+        config.genesis.balances.insert(validator.clone(), 1000);
+        config
+            .genesis
+            .balances
+            .insert(delegator_address.clone(), 600);
+        let mut blockchain = Blockchain::new(&config);
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::RegisterValidator, 1000))?;
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+
+        let delegate = TxPayload::Delegate {
+            validator: validator.clone(),
+        };
+        blockchain.add_transaction(staking_tx(&delegator, delegate, 600))?;
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        assert_eq!(blockchain.get_balance(&delegator_address), 0);
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        assert_eq!(blockchain.state.voting_power[&validator], 1600);
+
+        let undelegate = TxPayload::Undelegate {
+            validator: validator.clone(),
+        };
+        blockchain.add_transaction(staking_tx(&delegator, undelegate, 600))?;
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        assert_eq!(blockchain.state.voting_power[&validator], 1000);
+        assert_eq!(blockchain.get_balance(&delegator_address), 600);
+        Ok(())
+    }
+
+    #[test]
+    fn test_epoch_rewards_are_minted_to_validators_and_delegators() -> Result<(), String> {
+        let mut config = test_config();
+        config.consensus.dpos.epoch_length = 2;
+        config.consensus.dpos.epoch_reward = 10_000;
+        config.consensus.dpos.commission_bps = 1000;
+        let producer = validator_key();
+        let validator = hex::encode(producer.verifying_key().to_bytes());
+        let (delegator, _) = crypto_utils::generate_keypair();
+        let delegator_address = hex::encode(delegator.verifying_key().to_bytes());
+
+        config
+            .genesis
             .balances
-            .entry(address)
-            .or_insert(current_balance + amount);
+            .insert(delegator_address.clone(), 100_000);
+        let mut blockchain = Blockchain::new(&config);
+        let delegate = TxPayload::Delegate {
+            validator: validator.clone(),
+        };
+        blockchain.add_transaction(staking_tx(&delegator, delegate, 100_000))?;
+        blockchain.mine_block(&producer)?;
+        assert_eq!(blockchain.get_balance(&validator), 0);
+
+        // Epoch 0 ends at height 2: 10% commission, the rest split evenly
+        // between the self-stake and the delegation
+        blockchain.mine_block(&producer)?;
+        assert_eq!(blockchain.get_balance(&validator), 1000 + 4500);
+        assert_eq!(blockchain.get_balance(&delegator_address), 4500);
+
+        let reward = &blockchain.state.last_rewards[&validator];
+        assert_eq!(reward.epoch, 0);
+        assert_eq!(reward.commission, 1000);
+        assert_eq!(reward.total, 10_000);
+        assert_eq!(blockchain.state.rewards_earned[&delegator_address], 4500);
+
+        blockchain.mine_block(&producer)?;
+        blockchain.mine_block(&producer)?;
+        assert_eq!(blockchain.state.rewards_earned[&validator], 2 * 5500);
+        Ok(())
+    }
+
+    #[test]
+    fn test_vested_tokens_are_locked_until_they_unlock() -> Result<(), String> {
+        let now = Utc::now().timestamp() as u64;
+        let producer = validator_key();
+        let (investor, _) = crypto_utils::generate_keypair();
+        let (grantee, _) = crypto_utils::generate_keypair();
+        let address = |key: &ed25519_dalek::SigningKey| hex::encode(key.verifying_key().to_bytes());
+
+        // Linear from the epoch over twice the time elapsed: about half is
+        // unlocked today
+        let mut config = test_config();
+        config.genesis.vesting = vec![crate::config::GenesisVesting {
+            address: address(&investor),
+            amount: 1_000_000,
+            start_time: Some(0),
+            cliff_secs: 0,
+            duration_secs: 2 * now,
+        }];
+        let mut blockchain = Blockchain::new(&config);
+        let transfer = |from: &ed25519_dalek::SigningKey, to: String, amount: u64| {
+            let mut tx = Transaction::new(address(from), to, amount, None);
+            tx.sign(from).unwrap();
+            tx
+        };
+
+        blockchain.add_transaction(transfer(&investor, address(&grantee), 600_000))?;
+        assert!(blockchain.mine_block(&producer)?.transactions.is_empty());
+        assert_eq!(blockchain.get_balance(&address(&investor)), 1_000_000);
+
+        blockchain.add_transaction(transfer(&investor, address(&grantee), 400_000))?;
+        assert_eq!(blockchain.mine_block(&producer)?.transactions.len(), 1);
+        assert_eq!(blockchain.get_balance(&address(&grantee)), 400_000);
+
+        // A grant by transaction locks the tokens for the beneficiary
+        let grant = TxPayload::CreateVesting(crate::vesting::VestingGrant {
+            beneficiary: address(&investor),
+            cliff_secs: 1_000_000,
+            duration_secs: 2_000_000,
+        });
+        blockchain.add_transaction(staking_tx(&grantee, grant, 100_000))?;
+        blockchain.mine_block(&producer)?;
+        assert_eq!(blockchain.get_balance(&address(&grantee)), 300_000);
+        assert_eq!(blockchain.state.vesting[&address(&investor)].len(), 2);
+        assert!(blockchain.state.locked_balance(&address(&investor)) >= 100_000 + 499_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_active_set_rotates_only_at_epoch_boundaries() -> Result<(), String> {
+        let mut config = test_config();
+        config.consensus.dpos.epoch_length = 3;
+        config.consensus.dpos.validator_count = 1;
+        let producer = validator_key();
+        let (operator, _) = crypto_utils::generate_keypair();
+        let address = hex::encode(operator.verifying_key().to_bytes());
+
+        config.genesis.balances.insert(address.clone(), 500_000);
+        let mut blockchain = Blockchain::new(&config);
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::RegisterValidator, 500_000))?;
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+
+        // Still epoch 0: the genesis validator keeps the only slot
+        assert_eq!(blockchain.state.epoch.number, 0);
+        assert_ne!(
+            blockchain.state.validators[0].address.0,
+            operator.verifying_key()
+        );
+
+        mine_scheduled(&mut blockchain, &[&producer, &operator])?;
+        assert_eq!(blockchain.state.epoch.number, 1);
+        assert_eq!(blockchain.state.epoch.start_height, 3);
+        assert_eq!(blockchain.state.validators.len(), 1);
+        assert_eq!(
+            blockchain.state.validators[0].address.0,
+            operator.verifying_key()
+        );
+
+        // The outvoted genesis validator is still a candidate
+        assert_eq!(blockchain.state.candidates.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_governance_changes_params_at_activation() -> Result<(), String> {
+        let mut config = test_config();
+        config.governance.min_deposit = 100;
+        config.governance.voting_period = 2;
+        config.governance.activation_delay = 1;
+        let validator = validator_key();
+        let address = hex::encode(validator.verifying_key().to_bytes());
+        config.genesis.balances.insert(address.clone(), 1000);
+        let mut blockchain = Blockchain::new(&config);
+
+        let change = ParamChange {
+            param: Param::BlockInterval,
+            value: 30,
+        };
+        blockchain.add_transaction(staking_tx(
+            &validator,
+            TxPayload::SubmitProposal(change),
+            100,
+        ))?;
+        mine_scheduled(&mut blockchain, &[&validator])?;
+        assert_eq!(blockchain.get_balance(&address), 900);
+        let vote = ProposalVote {
+            proposal_id: 0,
+            approve: true,
+        };
+        blockchain.add_transaction(staking_tx(&validator, TxPayload::VoteProposal(vote), 0))?;
+        mine_scheduled(&mut blockchain, &[&validator])?;
+
+        // Voting ends after height 3, the change applies from height 5
+        mine_scheduled(&mut blockchain, &[&validator])?;
+        mine_scheduled(&mut blockchain, &[&validator])?;
+        assert_eq!(
+            blockchain.state.governance.proposals[&0].status,
+            ProposalStatus::Passed
+        );
+        assert_eq!(blockchain.get_balance(&address), 1000);
+        let configured = config.consensus.dpos.block_interval;
+        assert_eq!(
+            blockchain.engine().block_interval(&blockchain.state),
+            configured
+        );
+
+        mine_scheduled(&mut blockchain, &[&validator])?;
+        assert_eq!(blockchain.engine().block_interval(&blockchain.state), 30);
+        assert_eq!(blockchain.engine().fallback_delay(&blockchain.state, 1), 60);
+        Ok(())
+    }
+
+    #[test]
+    fn test_poa_signers_produce_in_turn() -> Result<(), String> {
+        let mut config = test_config();
+        config.consensus.dpos.epoch_length = 2;
+        let signers = [validator_key(), SigningKey::from_bytes(&[8u8; 32])];
+        config.consensus.consensus_type = "poa".to_string();
+        config.consensus.poa.signers = signers
+            .iter()
+            .map(|key| hex::encode(key.verifying_key().to_bytes()))
+            .collect();
+        let (operator, _) = crypto_utils::generate_keypair();
+        let address = hex::encode(operator.verifying_key().to_bytes());
+        config.genesis.balances.insert(address, 500_000);
+        let mut blockchain = Blockchain::new(&config);
+        assert_eq!(blockchain.engine().name(), "poa");
+        assert_eq!(blockchain.state.voting_power.len(), 2);
+
+        // Bonded stake does not change who produces
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::RegisterValidator, 500_000))?;
+        for height in 1..=4u64 {
+            let block = mine_scheduled(&mut blockchain, &[&signers[0], &signers[1]])?;
+            assert_eq!(
+                block.header.producer,
+                config.consensus.poa.signers[height as usize % 2]
+            );
+        }
+        assert_eq!(blockchain.state.epoch.number, 2);
+        assert_eq!(blockchain.state.validators.len(), 2);
+        assert_eq!(blockchain.state.candidates.len(), 1);
+
+        config.consensus.consensus_type = "pow".to_string();
+        assert!(engine::from_config(&config.consensus).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_the_scheduled_validator_may_produce() -> Result<(), String> {
+        let config = test_config();
+        let (outsider, _) = crypto_utils::generate_keypair();
+        let mut blockchain = Blockchain::new(&config);
+
+        let err = blockchain.mine_block(&outsider).unwrap_err();
+        assert!(
+            err.contains("is not scheduled to produce block 1"),
+            "{}",
+            err
+        );
+
+        let block = Block::new(
+            1,
+            blockchain.get_latest_block().hash(),
+            Vec::new(),
+            &outsider,
+        );
+        let err = blockchain.validate_block(&block).unwrap_err();
+        assert!(err.contains("is not scheduled for height 1"), "{}", err);
+
+        blockchain.mine_block(&validator_key())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_from_the_future_is_rejected() -> Result<(), String> {
+        let config = test_config();
+        let key = validator_key();
+        let mut blockchain = Blockchain::new(&config);
+        let now = Utc::now().timestamp() as u64;
+
+        let block = Block::new_at(
+            1,
+            blockchain.get_latest_block().hash(),
+            Vec::new(),
+            now + engine::MAX_CLOCK_DRIFT_SECS + 60,
+            0,
+            &key,
+        );
+        let err = blockchain.add_block(block).unwrap_err();
+        assert!(err.contains("ahead of the local clock"), "{}", err);
+
+        // A little drift is tolerated
+        let block = Block::new_at(
+            1,
+            blockchain.get_latest_block().hash(),
+            Vec::new(),
+            now + 2,
+            0,
+            &key,
+        );
+        blockchain.add_block(block)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fallback_producer_and_fork_choice() -> Result<(), String> {
+        let mut config = test_config();
+        config.consensus.dpos.epoch_length = 1;
+        let genesis_key = validator_key();
+        let (operator, _) = crypto_utils::generate_keypair();
+        let operator_key = SerializableVerifyingKey(operator.verifying_key());
+
+        config
+            .genesis
+            .balances
+            .insert(hex::encode(operator.verifying_key().to_bytes()), 1000);
+        let mut blockchain = Blockchain::new(&config);
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::RegisterValidator, 1000))?;
+        mine_scheduled(&mut blockchain, &[&genesis_key])?;
+        mine_scheduled(&mut blockchain, &[&genesis_key])?;
+        while blockchain.next_producer()? != hex::encode(operator.verifying_key().to_bytes()) {
+            mine_scheduled(&mut blockchain, &[&genesis_key, &operator])?;
+        }
+
+        // The operator is scheduled but silent; the genesis validator is
+        // rank 1 and may only step in after the fallback timeout
+        let tip = blockchain.get_latest_block().clone();
+        let height = tip.header.height + 1;
+        let timeout = blockchain.engine().fallback_delay(&blockchain.state, 1);
+        let early = Block::new_at(
+            height,
+            tip.hash(),
+            Vec::new(),
+            tip.header.timestamp + timeout - 1,
+            1,
+            &genesis_key,
+        );
+        let err = blockchain.add_block(early).unwrap_err();
+        assert!(err.contains("may not produce before"), "{}", err);
+
+        let fallback = Block::new_at(
+            height,
+            tip.hash(),
+            Vec::new(),
+            tip.header.timestamp + timeout,
+            1,
+            &genesis_key,
+        );
+        blockchain.add_block(fallback.clone())?;
+        let missed = |chain: &Blockchain| {
+            chain
+                .state
+                .validators
+                .iter()
+                .find(|v| v.address == operator_key)
+                .unwrap()
+                .missed_blocks
+        };
+        assert_eq!(missed(&blockchain), 1);
+        assert_eq!(blockchain.vote_on_tip(&operator)?, None);
+
+        // The scheduled block arriving late still wins fork choice
+        let scheduled = Block::new_at(
+            height,
+            tip.hash(),
+            Vec::new(),
+            tip.header.timestamp + 1,
+            0,
+            &operator,
+        );
+        blockchain.add_block(scheduled.clone())?;
+        assert_eq!(blockchain.get_latest_block().hash(), scheduled.hash());
+        assert_eq!(missed(&blockchain), 0);
+
+        // Having voted for the fallback block, the operator may not vote
+        // for the block that replaced it
+        let err = blockchain.vote_on_tip(&operator).unwrap_err();
+        assert!(err.contains("already voted"), "{}", err);
+
+        let err = blockchain.add_block(fallback).unwrap_err();
+        assert!(err.contains("loses fork choice"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotated_consensus_key_signs_from_next_epoch() -> Result<(), String> {
+        let mut config = test_config();
+        config.consensus.dpos.epoch_length = 3;
+        let operator = validator_key();
+        let address = hex::encode(operator.verifying_key().to_bytes());
+        let hot = SigningKey::from_bytes(&[9u8; 32]);
+        let hot_hex = hex::encode(hot.verifying_key().to_bytes());
+        let mut blockchain = Blockchain::new(&config);
+
+        let rotation = TxPayload::RotateConsensusKey {
+            consensus_key: hot_hex.clone(),
+        };
+        blockchain.add_transaction(staking_tx(&operator, rotation, 0))?;
+        blockchain.mine_block(&operator)?;
+        assert_eq!(blockchain.next_producer()?, address);
+        assert!(blockchain.mine_block(&hot).is_err());
+        blockchain.mine_block(&operator)?;
+
+        // Height 3 starts the next epoch and the new key
+        assert_eq!(
+            blockchain.next_consensus_key(&address)?,
+            Some(hot_hex.clone())
+        );
+        let err = blockchain.mine_block(&operator).unwrap_err();
+        assert!(err.contains("not scheduled"), "{}", err);
+        let block = blockchain.mine_block(&hot)?;
+        assert_eq!(block.header.producer, hot_hex);
+        assert_eq!(
+            blockchain.state.validators[0].address.0,
+            operator.verifying_key()
+        );
+
+        // The operator keeps its stake and identity; votes need the new key
+        assert_eq!(blockchain.state.voting_power[&address], 100_000);
+        assert!(blockchain.vote_on_tip(&operator).is_err());
+        assert!(blockchain.vote_on_tip(&hot)?.is_some());
+
+        // Evidence signed with the retired key only counts for heights it
+        // was signing at
+        let params = blockchain.staking_params();
+        let conflicting = |key: &SigningKey, height| {
+            let block =
+                |timestamp| Block::new_at(height, "0".repeat(64), Vec::new(), timestamp, 0, key);
+            (block(1).header, block(2).header)
+        };
+        let mut state = blockchain.state.clone();
+        let (first, second) = conflicting(&operator, 3);
+        let err = slashing::apply_evidence(&mut state, "reporter", &first, &second, 3, &params);
+        assert!(err.unwrap_err().contains("no validator's consensus key"));
+        let (first, second) = conflicting(&operator, 2);
+        slashing::apply_evidence(&mut state, "reporter", &first, &second, 3, &params)?;
+        assert!(state.candidates[&address].is_jailed());
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_votes_finalize_blocks() -> Result<(), String> {
+        let mut config = test_config();
+        config.consensus.dpos.epoch_length = 1;
+        let genesis_key = validator_key();
+        let (operator, _) = crypto_utils::generate_keypair();
+        let keys = [&genesis_key, &operator];
+
+        // 100_000 of 160_000 is short of two-thirds on its own
+        config
+            .genesis
+            .balances
+            .insert(hex::encode(operator.verifying_key().to_bytes()), 60_000);
+        let mut blockchain = Blockchain::new(&config);
+        blockchain.add_transaction(staking_tx(&operator, TxPayload::RegisterValidator, 60_000))?;
+        mine_scheduled(&mut blockchain, &keys)?;
+        let block = mine_scheduled(&mut blockchain, &keys)?;
+        assert_eq!(blockchain.state.validators.len(), 2);
+
+        let height = block.header.height;
+        assert_eq!(blockchain.vote_on_tip(&genesis_key)?, None);
+        let err = blockchain.vote_on_tip(&genesis_key).unwrap_err();
+        assert!(err.contains("duplicate vote"), "{}", err);
+        let (outsider, _) = crypto_utils::generate_keypair();
+        let err = blockchain.vote_on_tip(&outsider).unwrap_err();
+        assert!(err.contains("not an active validator"), "{}", err);
+        assert_eq!(blockchain.finalized_height(), 0);
+
+        let certificate = blockchain.vote_on_tip(&operator)?.unwrap();
+        assert_eq!(
+            certificate.verify(&blockchain.state.consensus_power()),
+            Ok(160_000)
+        );
+        assert_eq!(blockchain.finalized_height(), height);
+        assert_eq!(blockchain.commit_certificate(height)?, Some(certificate));
+
+        // A competing block for a final height is refused outright
+        let producer = keys
+            .iter()
+            .find(|key| hex::encode(key.verifying_key().to_bytes()) == block.header.producer)
+            .unwrap();
+        let competing = Block::new_at(
+            height,
+            block.header.previous_hash.clone(),
+            Vec::new(),
+            block.header.timestamp + 1,
+            0,
+            producer,
+        );
+        let err = blockchain.add_block(competing).unwrap_err();
+        assert!(err.contains("is final"), "{}", err);
+
+        let err = blockchain.vote_on_tip(&operator).unwrap_err();
+        assert!(err.contains("already final"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_double_sign_is_detected_and_slashed() -> Result<(), String> {
+        let config = test_config();
+        let producer = validator_key();
+        let address = hex::encode(producer.verifying_key().to_bytes());
+        let mut blockchain = Blockchain::new(&config);
+        let block = blockchain.mine_block(&producer)?;
+
+        // The same producer signs a second block for height 1
+        let conflicting = Block::new_at(
+            1,
+            block.header.previous_hash.clone(),
+            Vec::new(),
+            block.header.timestamp + 1,
+            0,
+            &producer,
+        );
+        let _ = blockchain.add_block(conflicting);
+        let mut evidence = blockchain.take_detected_evidence();
+        assert_eq!(evidence.len(), 1);
+        assert!(blockchain.take_detected_evidence().is_empty());
+
+        let (reporter, _) = crypto_utils::generate_keypair();
+        let evidence = evidence.remove(0);
+        let report = staking_tx(&reporter, evidence.clone(), 0);
+        let tip = blockchain.get_latest_block();
+        let block = Block::new_at(
+            2,
+            tip.hash(),
+            vec![report.clone()],
+            tip.header.timestamp,
+            0,
+            &producer,
+        );
+        blockchain.add_block(block)?;
+
+        let candidate = &blockchain.state.candidates[&address];
+        assert_eq!(
+            candidate.self_stake,
+            100_000 - config.consensus.dpos.slashing_penalty
+        );
+        assert!(candidate.is_jailed());
+        assert_eq!(
+            blockchain
+                .state
+                .balance(&hex::encode(reporter.verifying_key().to_bytes())),
+            config.consensus.dpos.evidence_reward
+        );
+
+        // It was the only validator, so the chain halts rather than take
+        // another block from the tombstoned key
+        assert!(blockchain.state.validators.is_empty());
+        let tip = blockchain.get_latest_block();
+        let block = Block::new_at(
+            3,
+            tip.hash(),
+            Vec::new(),
+            tip.header.timestamp,
+            0,
+            &producer,
+        );
+        let err = blockchain.validate_block(&block).unwrap_err();
+        assert!(err.contains("no validator"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_with_invalid_staking_transaction_is_rejected() -> Result<(), String> {
+        let config = test_config();
+        let producer = validator_key();
+        let (operator, _) = crypto_utils::generate_keypair();
+        let blockchain = Blockchain::new(&config);
+
+        let block = Block::new(
+            1,
+            blockchain.get_latest_block().hash(),
+            vec![staking_tx(&operator, TxPayload::RegisterValidator, 1000)],
+            &producer,
+        );
+        let err = blockchain.validate_block(&block).unwrap_err();
+        let expected = format!(
+            "rejected: insufficient balance: {} has 0, needs 1000",
+            hex::encode(operator.verifying_key().to_bytes())
+        );
+        assert!(err.ends_with(&expected), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive_mode_never_prunes() -> Result<(), String> {
+        let config = test_config();
+        let signing_key = validator_key();
+        let mut blockchain = Blockchain::new(&config);
+        for _ in 0..3 {
+            blockchain.mine_block(&signing_key)?;
+        }
+
+        assert_eq!(blockchain.prune()?, 0);
+        assert!(blockchain.get_block_by_height(0).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_genesis_is_the_same_on_every_node() -> Result<(), String> {
+        let config = test_config();
+        let mut hashes = Vec::new();
+        for _ in 0..2 {
+            let dir = tempdir().map_err(|e| e.to_string())?;
+            let db =
+                PureRustDatabase::new(dir.path().to_str().unwrap()).map_err(|e| e.to_string())?;
+            let blockchain = Blockchain::open(&config, Arc::new(db))?;
+            assert_eq!(
+                blockchain.get_latest_block().header.timestamp,
+                config.genesis.timestamp
+            );
+            hashes.push(blockchain.genesis_hash().to_string());
+        }
+        assert_eq!(hashes[0], hashes[1]);
+
+        // The genesis block commits to the genesis state
+        let mut other = config.clone();
+        other.genesis.balances.insert("alice".to_string(), 1);
+        assert_ne!(Blockchain::new(&other).genesis_hash(), hashes[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_signed_transfer_cannot_be_replayed() -> Result<(), String> {
+        let key = validator_key();
+        let sender = hex::encode(key.verifying_key().to_bytes());
+        let mut config = test_config();
+        config.genesis.balances.insert(sender.clone(), 1000);
+        let mut blockchain = Blockchain::new(&config);
+
+        let mut transfer = Transaction::new(sender, "bob".to_string(), 100, None);
+        transfer.sign(&key).map_err(|e| e.to_string())?;
+        blockchain.add_transaction(transfer.clone())?;
+        let err = blockchain.add_transaction(transfer.clone()).unwrap_err();
+        assert!(err.contains("already pending"), "{}", err);
+        blockchain.mine_block(&key)?;
+        assert_eq!(blockchain.get_balance("bob"), 100);
+
+        // The same signed transfer again, in the mempool or in a block
+        let err = blockchain.add_transaction(transfer.clone()).unwrap_err();
+        assert!(err.contains("already applied"), "{}", err);
+        let tip = blockchain.get_latest_block();
+        let replay = Block::new_at(
+            2,
+            tip.hash(),
+            vec![transfer.clone()],
+            tip.header.timestamp,
+            0,
+            &key,
+        );
+        let err = blockchain.add_block(replay).unwrap_err();
+        assert!(err.contains("already applied"), "{}", err);
+        assert_eq!(blockchain.get_balance("bob"), 100);
+
+        // A transfer older than the validity window is refused as expired
+        let mut stale = Transaction::new(transfer.from.clone(), "bob".to_string(), 100, None);
+        stale.timestamp = blockchain.state.block_time - TX_VALIDITY_SECS - 1;
+        stale.sign(&key).map_err(|e| e.to_string())?;
+        let err = blockchain.add_transaction(stale.clone()).unwrap_err();
+        assert!(err.contains("expired"), "{}", err);
+        let tip = blockchain.get_latest_block();
+        let late = Block::new_at(2, tip.hash(), vec![stale], tip.header.timestamp, 0, &key);
+        let err = blockchain.add_block(late).unwrap_err();
+        assert!(err.contains("expired"), "{}", err);
+        Ok(())
+    }
+
+    /// Signer for the scheduled key that refuses to sign blocks
+    struct RefusingSigner(SigningKey);
+
+    impl BlockSigner for RefusingSigner {
+        fn public_key(&mut self) -> Result<String, String> {
+            self.0.public_key()
+        }
+
+        fn sign_block(
+            &mut self,
+            _header: &BlockHeader,
+        ) -> Result<ed25519_dalek::Signature, String> {
+            Err("signer unavailable".to_string())
+        }
+
+        fn sign_vote(&mut self, height: u64, block_hash: &str) -> Result<CommitVote, String> {
+            self.0.sign_vote(height, block_hash)
+        }
+    }
+
+    #[test]
+    fn test_failed_block_keeps_pending_transactions() -> Result<(), String> {
+        let key = validator_key();
+        let sender = hex::encode(key.verifying_key().to_bytes());
+        let mut config = test_config();
+        config.genesis.balances.insert(sender.clone(), 1000);
+        let mut blockchain = Blockchain::new(&config);
+
+        let mut transfer = Transaction::new(sender, "bob".to_string(), 100, None);
+        transfer.sign(&key).map_err(|e| e.to_string())?;
+        blockchain.add_transaction(transfer)?;
+        let mut overdraft = Transaction::new(
+            hex::encode(key.verifying_key().to_bytes()),
+            "bob".to_string(),
+            5000,
+            None,
+        );
+        overdraft.sign(&key).map_err(|e| e.to_string())?;
+        blockchain.add_transaction(overdraft)?;
+
+        let err = blockchain
+            .mine_block_with(&mut RefusingSigner(key.clone()))
+            .unwrap_err();
+        assert!(err.contains("signer unavailable"), "{}", err);
+        assert_eq!(blockchain.pending_transactions.len(), 2);
+
+        // A block that is added settles one and drops the other
+        let block = blockchain.mine_block(&key)?;
+        assert_eq!(block.transactions.len(), 1);
+        assert!(blockchain.pending_transactions.is_empty());
         Ok(())
     }
 }
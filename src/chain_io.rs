@@ -0,0 +1,290 @@
+// Chain export and validated re-import.
+//
+// The export format is a fixed header followed by one length-prefixed
+// record per block, from genesis to the tip:
+//
+//   b"OWAMICHN" | version: u32 LE | (len: u32 LE | bincode(Block))*
+//
+// Import replays the records into an empty store through the normal
+// block validation path and stops at the first block that fails.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::config::AppConfig;
+use crate::storage::{ChainStore, KvStore};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+pub const EXPORT_MAGIC: &[u8; 8] = b"OWAMICHN";
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+/// Upper bound on a single record, so a corrupt length can't exhaust memory
+const MAX_RECORD_LEN: u32 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub blocks_imported: u64,
+    pub tip_height: u64,
+    pub tip_hash: String,
+    pub state_root: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportError {
+    /// The file itself is unreadable or malformed
+    Format(String),
+    /// A block decoded fine but failed validation
+    InvalidBlock { height: u64, reason: String },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Format(reason) => write!(f, "invalid export file: {}", reason),
+            ImportError::InvalidBlock { height, reason } => {
+                write!(f, "block {} is invalid: {}", height, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// Stream every block in `store` from genesis to the tip into `writer`.
+/// Returns the number of blocks written.
+pub fn export_chain<W: Write>(store: &ChainStore, writer: &mut W) -> Result<u64, String> {
+    let pruned_below = store.pruned_below().map_err(|e| e.to_string())?;
+    if pruned_below > 0 {
+        return Err(format!(
+            "blocks below height {} have been pruned; export needs an archive node",
+            pruned_below
+        ));
+    }
+    if !store.has_blocks().map_err(|e| e.to_string())? {
+        return Err("store holds no blocks".to_string());
+    }
+    if store.get_header(0).map_err(|e| e.to_string())?.is_none() {
+        return Err("store does not start at genesis (restored from a snapshot?)".to_string());
+    }
+
+    let io_err = |e: io::Error| format!("Failed to write export: {}", e);
+    writer.write_all(EXPORT_MAGIC).map_err(io_err)?;
+    writer
+        .write_all(&EXPORT_FORMAT_VERSION.to_le_bytes())
+        .map_err(io_err)?;
+
+    let mut height = 0;
+    while let Some(block) = store.get_block(height).map_err(|e| e.to_string())? {
+        let record = bincode::serialize(&block).map_err(|e| e.to_string())?;
+        writer
+            .write_all(&(record.len() as u32).to_le_bytes())
+            .map_err(io_err)?;
+        writer.write_all(&record).map_err(io_err)?;
+        height += 1;
+    }
+    writer.flush().map_err(io_err)?;
+
+    Ok(height)
+}
+
+/// Reads block records from an export stream
+pub struct BlockReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> BlockReader<R> {
+    /// Check the header and return a reader positioned at the first block
+    pub fn new(mut reader: R) -> Result<Self, ImportError> {
+        let mut magic = [0u8; 8];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| ImportError::Format("missing header".to_string()))?;
+        if &magic != EXPORT_MAGIC {
+            return Err(ImportError::Format("not an Owami chain export".to_string()));
+        }
+
+        let mut version = [0u8; 4];
+        reader
+            .read_exact(&mut version)
+            .map_err(|_| ImportError::Format("missing format version".to_string()))?;
+        let version = u32::from_le_bytes(version);
+        if version != EXPORT_FORMAT_VERSION {
+            return Err(ImportError::Format(format!(
+                "unsupported format version {} (expected {})",
+                version, EXPORT_FORMAT_VERSION
+            )));
+        }
+
+        Ok(Self { reader })
+    }
+
+    /// Next block, or `None` at a clean end of file
+    pub fn next_block(&mut self) -> Result<Option<Block>, ImportError> {
+        let mut len = [0u8; 4];
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(ImportError::Format(e.to_string())),
+        }
+
+        let len = u32::from_le_bytes(len);
+        if len > MAX_RECORD_LEN {
+            return Err(ImportError::Format(format!(
+                "record length {} exceeds limit",
+                len
+            )));
+        }
+
+        let mut record = vec![0u8; len as usize];
+        self.reader
+            .read_exact(&mut record)
+            .map_err(|_| ImportError::Format("truncated block record".to_string()))?;
+        bincode::deserialize(&record)
+            .map(Some)
+            .map_err(|e| ImportError::Format(format!("undecodable block record: {}", e)))
+    }
+}
+
+/// Replay an export stream into the empty store `kv`, validating every
+/// block and transaction on the way
+pub fn import_chain<R: Read>(
+    config: &AppConfig,
+    kv: Arc<dyn KvStore>,
+    reader: R,
+) -> Result<ImportReport, ImportError> {
+    let mut blocks = BlockReader::new(reader)?;
+
+    let genesis = blocks
+        .next_block()?
+        .ok_or_else(|| ImportError::Format("export contains no blocks".to_string()))?;
+    let mut blockchain = Blockchain::open_with_genesis(config, kv, &genesis).map_err(|reason| {
+        ImportError::InvalidBlock {
+            height: genesis.header.height,
+            reason,
+        }
+    })?;
+
+    let mut blocks_imported = 1;
+    while let Some(block) = blocks.next_block()? {
+        let height = block.header.height;
+        blockchain
+            .append_block(block)
+            .map_err(|reason| ImportError::InvalidBlock { height, reason })?;
+        blocks_imported += 1;
+    }
+
+    Ok(ImportReport {
+        blocks_imported,
+        tip_height: blockchain.get_block_height(),
+        tip_hash: blockchain.get_latest_block().hash(),
+        state_root: blockchain.state.state_root().map_err(ImportError::Format)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStore;
+    use crate::test_support::{test_config, validator_key};
+    use crate::transaction::Transaction;
+
+    /// Test config that funds the validator at genesis for the transfers
+    fn config() -> AppConfig {
+        let mut config = test_config();
+        let validator = hex::encode(validator_key().verifying_key().to_bytes());
+        config.genesis.balances.insert(validator, 1000);
+        config
+    }
+
+    fn source_chain(blocks: usize) -> Blockchain {
+        let signing_key = validator_key();
+        let mut blockchain = Blockchain::new(&config());
+        for i in 0..blocks {
+            let mut tx = Transaction::new(
+                hex::encode(signing_key.verifying_key().to_bytes()),
+                format!("recipient-{}", i),
+                10,
+                None,
+            );
+            tx.sign(&signing_key).unwrap();
+            blockchain.add_transaction(tx).unwrap();
+            blockchain.mine_block(&signing_key).unwrap();
+        }
+        blockchain
+    }
+
+    #[test]
+    fn test_export_import_round_trip() -> Result<(), String> {
+        let source = source_chain(3);
+        let mut exported = Vec::new();
+        assert_eq!(export_chain(source.store(), &mut exported)?, 4);
+
+        let report = import_chain(&config(), Arc::new(MemoryStore::new()), exported.as_slice())
+            .map_err(|e| e.to_string())?;
+        assert_eq!(report.blocks_imported, 4);
+        assert_eq!(report.tip_height, 3);
+        assert_eq!(report.tip_hash, source.get_latest_block().hash());
+        assert_eq!(report.state_root, source.state.state_root()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_reports_first_invalid_block() -> Result<(), String> {
+        let source = source_chain(3);
+        let store = source.store();
+
+        // Re-export with block 2's transaction amount altered
+        let mut exported = Vec::new();
+        exported.extend_from_slice(EXPORT_MAGIC);
+        exported.extend_from_slice(&EXPORT_FORMAT_VERSION.to_le_bytes());
+        for height in 0..=3 {
+            let mut block = store.get_block(height).unwrap().unwrap();
+            if height == 2 {
+                block.transactions[0].amount += 1;
+            }
+            let record = bincode::serialize(&block).unwrap();
+            exported.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            exported.extend_from_slice(&record);
+        }
+
+        let err =
+            import_chain(&config(), Arc::new(MemoryStore::new()), exported.as_slice()).unwrap_err();
+        assert_eq!(
+            err,
+            ImportError::InvalidBlock {
+                height: 2,
+                reason: "merkle root does not match transactions".to_string()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_rejects_truncated_file() -> Result<(), String> {
+        let source = source_chain(1);
+        let mut exported = Vec::new();
+        export_chain(source.store(), &mut exported)?;
+        exported.truncate(exported.len() - 3);
+
+        let err =
+            import_chain(&config(), Arc::new(MemoryStore::new()), exported.as_slice()).unwrap_err();
+        assert_eq!(
+            err,
+            ImportError::Format("truncated block record".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_requires_empty_store() {
+        let source = source_chain(0);
+        let mut exported = Vec::new();
+        export_chain(source.store(), &mut exported).unwrap();
+
+        let kv: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        Blockchain::open(&test_config(), kv.clone()).unwrap();
+        let err = import_chain(&test_config(), kv, exported.as_slice()).unwrap_err();
+        assert!(matches!(err, ImportError::InvalidBlock { height: 0, .. }));
+    }
+}
@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -39,8 +40,29 @@ pub struct RateLimitingConfig {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ConsensusConfig {
-    pub consensus_type: String, // "poa", "dpos", etc.
-    pub dpos: DposConfig,
+    pub consensus_type: String, // "poa" or "dpos"
+    pub dpos: DposConfig,       // staking parameters apply under either engine
+    #[serde(default)]
+    pub poa: PoaConfig,
+}
+
+/// Proof of authority: a fixed list of signers takes turns producing blocks
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PoaConfig {
+    pub signers: Vec<String>, // hex ed25519 public keys, in rotation order
+    pub block_interval: u64,  // in seconds
+    pub fallback_timeout_multiplier: u64,
+}
+
+impl Default for PoaConfig {
+    fn default() -> Self {
+        PoaConfig {
+            signers: Vec::new(),
+            block_interval: 5,
+            fallback_timeout_multiplier: default_fallback_timeout_multiplier(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -49,6 +71,192 @@ pub struct DposConfig {
     pub block_interval: u64, // in seconds
     pub stake_threshold: u64,
     pub slashing_penalty: u64,
+    #[serde(default = "default_unbonding_period")]
+    pub unbonding_period: u64, // blocks unbonded stake stays locked
+    #[serde(default = "default_epoch_length")]
+    pub epoch_length: u64, // blocks between validator set rotations
+    #[serde(default = "default_fallback_timeout_multiplier")]
+    pub fallback_timeout_multiplier: u64, // block intervals before each fallback rank may produce
+    #[serde(default)]
+    pub evidence_reward: u64, // tokens paid to whoever reports a double-sign, at most the amount slashed
+    #[serde(default = "default_missed_slot_window")]
+    pub missed_slot_window: u64, // recent heights over which missed slots and uptime are counted
+    #[serde(default = "default_missed_slot_threshold")]
+    pub missed_slot_threshold: u64, // missed slots within the window that get a validator jailed
+    #[serde(default = "default_jail_cooldown")]
+    pub jail_cooldown: u64, // blocks before a jailed validator may unjail
+    #[serde(default)]
+    pub epoch_reward: u64, // minted and shared between the active validators each epoch
+    #[serde(default)]
+    pub commission_bps: u64, // validators' cut of their reward, in basis points
+}
+
+fn default_unbonding_period() -> u64 {
+    100
+}
+
+fn default_epoch_length() -> u64 {
+    100
+}
+
+fn default_fallback_timeout_multiplier() -> u64 {
+    2
+}
+
+fn default_missed_slot_window() -> u64 {
+    100
+}
+
+fn default_missed_slot_threshold() -> u64 {
+    50
+}
+
+fn default_jail_cooldown() -> u64 {
+    100
+}
+
+/// How parameter-change proposals are decided. Only read at genesis:
+/// afterwards these live in the chain state.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct GovernanceConfig {
+    pub min_deposit: u64,
+    pub voting_period: u64,    // blocks a proposal stays open for votes
+    pub quorum_bps: u64,       // share of bonded stake that must vote, in basis points
+    pub threshold_bps: u64,    // share of cast votes that must approve, in basis points
+    pub activation_delay: u64, // blocks between the end of voting and the change
+}
+
+impl Default for GovernanceConfig {
+    fn default() -> Self {
+        GovernanceConfig {
+            min_deposit: 1000,
+            voting_period: 100,
+            quorum_bps: 3340,
+            threshold_bps: 5000,
+            activation_delay: 10,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GenesisValidator {
+    pub address: String, // hex ed25519 operator key
+    pub stake: u64,
+    #[serde(default)]
+    pub consensus_key: Option<String>, // hex key signing blocks; the operator key if unset
+}
+
+/// Tokens credited at genesis under a vesting lockup
+#[derive(Debug, Deserialize, Clone)]
+pub struct GenesisVesting {
+    pub address: String,
+    pub amount: u64,
+    #[serde(default)]
+    pub start_time: Option<u64>, // unix seconds; the genesis block time if unset
+    #[serde(default)]
+    pub cliff_secs: u64,
+    pub duration_secs: u64, // from start until fully unlocked
+}
+
+/// Initial state of the chain. Every node configured alike builds the
+/// same genesis block and state, so DPoS genesis must name its validators.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct GenesisConfig {
+    pub timestamp: u64, // unix seconds of the genesis block
+    pub validators: Vec<GenesisValidator>,
+    pub balances: BTreeMap<String, u64>,
+    pub vesting: Vec<GenesisVesting>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeMode {
+    Archive, // keep every block body
+    Pruned,  // keep headers, recent bodies and current state
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct NodeConfig {
+    pub mode: NodeMode,
+    pub keep_blocks: u64, // bodies kept in pruned mode, counting back from the tip
+    pub prune_interval_secs: u64,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            mode: NodeMode::Archive,
+            keep_blocks: 10_000,
+            prune_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    pub interval: u64, // take a snapshot every N blocks, 0 disables
+    pub chunk_size: usize,
+    pub dir: String,
+    pub keep: usize, // number of most recent snapshots kept on disk
+    pub restore_from: Option<String>,
+    /// Manifest hash of the `restore_from` snapshot, taken from a node the
+    /// operator trusts; restoring without it is refused
+    pub trusted_hash: Option<String>,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            interval: 0,
+            chunk_size: 64 * 1024,
+            dir: "./data/snapshots".to_string(),
+            keep: 2,
+            restore_from: None,
+            trusted_hash: None,
+        }
+    }
+}
+
+/// Peer-to-peer transport. Off by default, so a single node opens no
+/// extra port.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct P2pConfig {
+    pub enabled: bool,
+    pub listen_addr: String,
+    pub network: String, // chain name peers must share, next to the genesis hash
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    pub keepalive_interval_secs: u64,
+    pub idle_timeout_secs: u64, // disconnect peers silent for this long
+    pub handshake_timeout_secs: u64,
+    pub bootstrap_peers: Vec<String>, // "host:port" addresses dialed at startup
+    pub address_book_path: String,    // known peers and their scores; empty keeps them in memory
+    pub ban_duration_secs: u64,
+    pub allowed_peers: Vec<String>, // hex node ids; empty lets any node connect
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        P2pConfig {
+            enabled: false,
+            listen_addr: "0.0.0.0:30333".to_string(),
+            network: "owami".to_string(),
+            max_inbound: 32,
+            max_outbound: 8,
+            keepalive_interval_secs: 15,
+            idle_timeout_secs: 60,
+            handshake_timeout_secs: 5,
+            bootstrap_peers: Vec::new(),
+            address_book_path: String::new(),
+            ban_duration_secs: 3600,
+            allowed_peers: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -59,6 +267,16 @@ pub struct AppConfig {
     pub monitoring: MonitoringConfig,
     pub security: SecurityConfig,
     pub consensus: ConsensusConfig,
+    #[serde(default)]
+    pub node: NodeConfig,
+    #[serde(default)]
+    pub snapshots: SnapshotConfig,
+    #[serde(default)]
+    pub genesis: GenesisConfig,
+    #[serde(default)]
+    pub governance: GovernanceConfig,
+    #[serde(default)]
+    pub p2p: P2pConfig,
 }
 
 impl AppConfig {
@@ -1,8 +1,17 @@
-use crate::{block::Block, transaction::Transaction};
-use chrono::Utc;
-use ed25519_dalek::{SignatureError, Verifier, VerifyingKey};
+use crate::config::GenesisConfig;
+use crate::consensus::engine::ConsensusEngine;
+use crate::slashing;
+use crate::staking::{self, Candidate, ConsensusKeyRecord, StakingParams};
+use crate::state::ChainState;
+use crate::{
+    block::{Block, BlockHeader},
+    consensus::finality::CommitVote,
+    crypto_utils,
+    transaction::Transaction,
+};
+use ed25519_dalek::VerifyingKey;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::net::SocketAddr;
 
 // Define a simple wrapper for VerifyingKey that implements Serialize and Deserialize
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -32,221 +41,200 @@ impl<'de> Deserialize<'de> for SerializableVerifyingKey {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Validator {
-    pub address: SerializableVerifyingKey,
+    pub address: SerializableVerifyingKey,       // operator key
+    pub consensus_key: SerializableVerifyingKey, // signs blocks and commit votes
     pub stake: u64,
-    pub uptime: f64,
-    pub missed_blocks: u64,
+    pub uptime: f64,        // produced / assigned slots within the missed-slot window
+    pub missed_blocks: u64, // missed slots within the window
     pub last_active: i64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DposConfig {
-    pub block_interval: u64,
-    pub stake_threshold: u64,
-    pub validator_count: u64, // Changed from u32 to u64
-}
-
+/// Delegated proof of stake: each epoch the candidates with the most
+/// bonded stake form the active set, which takes turns producing blocks
 #[derive(Debug, Clone)]
 pub struct DposConsensus {
-    pub validators: Vec<Validator>,
-    pub voting_power: HashMap<SerializableVerifyingKey, u64>,
-    pub current_round: u64,
-    pub block_producer: Option<SerializableVerifyingKey>,
-    pub last_block_time: i64,
-    pub block_interval: u64,
-    pub stake_threshold: u64,
-    pub validator_count: u64,
+    pub block_interval: u64,              // unless changed by governance
+    pub fallback_timeout_multiplier: u64, // block intervals between successive fallback ranks
 }
 
 impl DposConsensus {
-    pub fn new(config: &crate::config::DposConfig, initial_validators: Vec<Validator>) -> Self {
+    pub fn new(config: &crate::config::DposConfig) -> Self {
         DposConsensus {
-            validators: initial_validators,
-            voting_power: HashMap::new(),
-            current_round: 0,
-            block_producer: None,
-            last_block_time: Utc::now().timestamp(),
             block_interval: config.block_interval,
-            stake_threshold: config.stake_threshold,
-            validator_count: config.validator_count as u64, // Cast to u64
-        }
-    }
-
-    pub fn elect_block_producer(&mut self) -> SerializableVerifyingKey {
-        // Sort validators by total voting power (stake + delegated)
-        self.validators.sort_by(|a, b| {
-            let a_power = self
-                .voting_power
-                .get(&a.address)
-                .cloned()
-                .unwrap_or(a.stake);
-            let b_power = self
-                .voting_power
-                .get(&b.address)
-                .cloned()
-                .unwrap_or(b.stake);
-            b_power.cmp(&a_power)
-        });
-
-        // Keep only top N validators (configurable)
-        if self.validators.len() > self.validator_count as usize {
-            self.validators.truncate(self.validator_count as usize);
+            fallback_timeout_multiplier: config.fallback_timeout_multiplier,
         }
-
-        // Round-robin selection from top validators
-        let index = self.current_round as usize % self.validators.len();
-        let producer = self.validators[index].address.clone();
-        self.block_producer = Some(producer.clone());
-        self.current_round += 1;
-
-        // Update last active time
-        self.validators[index].last_active = Utc::now().timestamp();
-
-        producer
     }
+}
 
-    pub fn validate_block(&self, _block: &Block, validator: &SerializableVerifyingKey) -> bool {
-        // Check if validator is in current validator set
-        let is_validator = self.validators.iter().any(|v| &v.address == validator);
-
-        // Check if validator is the expected block producer for this round
-        let is_expected_producer = self.block_producer.as_ref() == Some(validator);
-
-        // Check block timing
-        let current_time = Utc::now().timestamp();
-        let time_diff = current_time - self.last_block_time;
-        let is_valid_time = time_diff >= self.block_interval as i64;
-
-        is_validator && is_expected_producer && is_valid_time
+impl ConsensusEngine for DposConsensus {
+    fn name(&self) -> &'static str {
+        "dpos"
     }
 
-    pub fn process_vote(&mut self, vote: &Transaction) -> Result<bool, String> {
-        // Process voting transaction
-        if vote.data.as_deref() != Some("vote") {
-            return Err("Invalid vote transaction".to_string());
+    /// Bond the genesis validators, or a generated one when none are
+    /// configured, and elect the first active set
+    fn init_genesis(
+        &self,
+        state: &mut ChainState,
+        genesis: &GenesisConfig,
+        params: &StakingParams,
+        timestamp: u64,
+    ) -> Result<(), String> {
+        if genesis.validators.is_empty() {
+            return Err("Genesis names no validators".to_string());
         }
-
-        // Extract validator address from 'to' field
-        let validator_address = match hex::decode(&vote.to) {
-            Ok(bytes) if bytes.len() == 32 => {
-                let mut key_bytes = [0u8; 32];
-                key_bytes.copy_from_slice(&bytes);
-                match VerifyingKey::from_bytes(&key_bytes) {
-                    Ok(pk) => SerializableVerifyingKey(pk),
-                    Err(_) => return Err("Invalid validator address".to_string()),
+        for validator in &genesis.validators {
+            crypto_utils::hex_to_verifying_key(&validator.address)
+                .map_err(|e| format!("Invalid genesis validator {}: {}", validator.address, e))?;
+            let mut candidate = Candidate::new(validator.stake, 0);
+            if let Some(key) = &validator.consensus_key {
+                crypto_utils::hex_to_verifying_key(key).map_err(|e| {
+                    format!("Invalid consensus key for {}: {}", validator.address, e)
+                })?;
+                if state.consensus_keys.contains_key(key) {
+                    return Err(format!("Consensus key {} is used twice", key));
                 }
+                state.consensus_keys.insert(
+                    key.clone(),
+                    ConsensusKeyRecord::new(&validator.address, Some(0)),
+                );
+                candidate.consensus_key = Some(key.clone());
             }
-            _ => return Err("Invalid validator address format".to_string()),
-        };
-
-        // Update voting power
-        *self
-            .voting_power
-            .entry(validator_address.clone())
-            .or_insert(0) += vote.amount;
+            state
+                .candidates
+                .insert(validator.address.clone(), candidate);
+        }
 
-        Ok(true)
+        if staking::active_set(state, params).is_empty() {
+            return Err("No genesis validator meets the stake threshold".to_string());
+        }
+        state.rotate_validators(0, params, timestamp)
     }
 
-    pub fn update_validator_set(&mut self) {
-        // Sort validators by total voting power (stake + delegated)
-        self.validators.sort_by(|a, b| {
-            let a_power = self
-                .voting_power
-                .get(&a.address)
-                .cloned()
-                .unwrap_or(a.stake);
-            let b_power = self
-                .voting_power
-                .get(&b.address)
-                .cloned()
-                .unwrap_or(b.stake);
-            b_power.cmp(&a_power)
-        });
-
-        // Keep only top N validators (configurable)
-        if self.validators.len() > self.validator_count as usize {
-            self.validators.truncate(self.validator_count as usize);
-        }
+    fn producer(&self, state: &ChainState, height: u64, rank: u32) -> Option<String> {
+        fallback_producer(&state.validators, state.epoch.start_height, height, rank)
+            .map(|validator| hex::encode(validator.consensus_key.0.to_bytes()))
+    }
 
-        // Update uptime metrics
-        let current_time = Utc::now().timestamp();
-        for validator in &mut self.validators {
-            let time_diff = current_time - validator.last_active;
-            if time_diff < 300 {
-                // 5 minutes
-                validator.uptime = (validator.uptime * 0.99) + 0.01;
-            } else {
-                validator.uptime = validator.uptime * 0.99;
-            }
-        }
+    fn block_interval(&self, state: &ChainState) -> u64 {
+        state.governance.block_interval(self.block_interval)
     }
 
-    pub fn get_active_validators(&self) -> Vec<SerializableVerifyingKey> {
-        self.validators.iter().map(|v| v.address.clone()).collect()
+    fn fallback_delay(&self, state: &ChainState, rank: u32) -> u64 {
+        self.block_interval(state)
+            .saturating_mul(self.fallback_timeout_multiplier)
+            .saturating_mul(rank as u64)
     }
 
-    pub fn get_validator_info(&self, address: &SerializableVerifyingKey) -> Option<&Validator> {
-        self.validators.iter().find(|v| &v.address == address)
+    /// Track produced and missed slots, jailing validators that miss too many
+    fn on_block(&self, state: &mut ChainState, height: u64, rank: u32, params: &StakingParams) {
+        slashing::record_slots(state, height, rank, params);
     }
 
-    pub fn slash_validator(&mut self, address: &SerializableVerifyingKey, penalty: u64) -> bool {
-        if let Some(validator) = self.validators.iter_mut().find(|v| &v.address == address) {
-            if validator.stake >= penalty {
-                validator.stake -= penalty;
-                validator.missed_blocks += 1;
-                true
-            } else {
-                false
-            }
-        } else {
-            false
-        }
+    /// Mint the ending epoch's rewards to its validators and delegators
+    fn on_epoch_end(&self, state: &mut ChainState, params: &StakingParams) -> Result<(), String> {
+        state.distribute_rewards(params)
     }
 
-    pub fn verify_block_signature(
+    /// Elect the new epoch's active set from the candidates
+    fn on_epoch_start(
         &self,
-        block: &Block,
-        signature: &[u8],
-    ) -> Result<bool, SignatureError> {
-        let public_key = match self.block_producer {
-            Some(ref pk) => &pk.0,
-            None => return Ok(false),
-        };
+        state: &mut ChainState,
+        height: u64,
+        timestamp: u64,
+        params: &StakingParams,
+    ) -> Result<(), String> {
+        state.rotate_validators(height, params, timestamp)
+    }
+}
 
-        let message = block.hash_data();
-        if signature.len() != 64 {
-            return Ok(false);
-        }
-        let mut sig_bytes = [0u8; 64];
-        sig_bytes.copy_from_slice(signature);
-        let signature = ed25519_dalek::Signature::from_bytes(&sig_bytes);
+/// Producer of the block at `height`, given the active set of the epoch
+/// starting at `epoch_start_height`. Slots go round-robin through the set
+/// in its recorded order, so the result depends only on chain data.
+pub fn scheduled_producer(
+    validators: &[Validator],
+    epoch_start_height: u64,
+    height: u64,
+) -> Option<&Validator> {
+    fallback_producer(validators, epoch_start_height, height, 0)
+}
 
-        Ok(public_key.verify(&message, &signature).is_ok())
+/// Validator allowed to produce `height` at fallback `rank`: the one `rank`
+/// places after the scheduled producer in the rotation
+pub fn fallback_producer(
+    validators: &[Validator],
+    epoch_start_height: u64,
+    height: u64,
+    rank: u32,
+) -> Option<&Validator> {
+    schedule_index(validators.len(), epoch_start_height, height, rank)
+        .map(|index| &validators[index])
+}
+
+/// Position in the active set of the producer for `height` at `rank`
+pub fn schedule_index(
+    set_size: usize,
+    epoch_start_height: u64,
+    height: u64,
+    rank: u32,
+) -> Option<usize> {
+    if set_size == 0 || height < epoch_start_height || rank as usize >= set_size {
+        return None;
     }
+    let slot = (height + rank as u64) % set_size as u64;
+    Some(slot as usize)
+}
+
+/// Fork choice between two blocks for the same height and parent: the
+/// lower fallback rank wins, and the lower hash breaks ties
+pub fn prefer_block(candidate: &BlockHeader, current: &BlockHeader) -> bool {
+    (candidate.fallback_rank, candidate.hash()) < (current.fallback_rank, current.hash())
 }
 
 // Network message types for P2P communication
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum NetworkMessage {
     NewBlock(Block),
     NewTransaction(Transaction),
+    /// A validator's commit vote, relayed once this node has counted it
+    CommitVote(CommitVote),
     ValidatorSetRequest,
     ValidatorSetResponse(Vec<ValidatorInfo>),
-    BlockRequest(String),
-    BlockResponse(Option<Block>),
+    /// Up to `count` blocks from height `from`; only their headers when
+    /// `headers_only` is set
+    BlockRequest {
+        from: u64,
+        count: u32,
+        headers_only: bool,
+    },
+    /// Answer to a `BlockRequest` starting at `from`; ends early at the
+    /// first block the peer cannot serve
+    BlockResponse {
+        from: u64,
+        headers: Vec<BlockHeader>,
+        blocks: Vec<Block>,
+    },
     StatusUpdate {
         height: u64,
         network: String,
         version: String,
+        genesis_hash: String,
+        listen_port: u16, // 0 if the node accepts no connections
     },
+    /// Echoed back in a `Pong` to measure latency
+    Ping(u64),
+    Pong(u64),
+    /// Ask for addresses of other peers
+    GetPeers,
+    Peers(Vec<SocketAddr>),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ValidatorInfo {
     pub address: String,
+    pub consensus_key: String,
     pub stake: u64,
     pub uptime: f64,
     pub missed_blocks: u64,
@@ -256,6 +244,7 @@ impl From<&Validator> for ValidatorInfo {
     fn from(validator: &Validator) -> Self {
         ValidatorInfo {
             address: hex::encode(validator.address.0.to_bytes()),
+            consensus_key: hex::encode(validator.consensus_key.0.to_bytes()),
             stake: validator.stake,
             uptime: validator.uptime,
             missed_blocks: validator.missed_blocks,
@@ -278,15 +267,12 @@ pub struct LightClientResponse {
 
 pub fn handle_light_request(
     request: LightClientRequest,
-    consensus: &DposConsensus,
+    validators: &[Validator],
 ) -> LightClientResponse {
     match request.method.as_str() {
         "get_validator_set" => {
-            let validators: Vec<ValidatorInfo> = consensus
-                .validators
-                .iter()
-                .map(ValidatorInfo::from)
-                .collect();
+            let validators: Vec<ValidatorInfo> =
+                validators.iter().map(ValidatorInfo::from).collect();
 
             LightClientResponse {
                 result: serde_json::to_value(validators).unwrap(),
@@ -299,3 +285,39 @@ pub fn handle_light_request(
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto_utils;
+
+    fn validator() -> Validator {
+        let key = SerializableVerifyingKey(crypto_utils::generate_keypair().1);
+        Validator {
+            address: key.clone(),
+            consensus_key: key,
+            stake: 1000,
+            uptime: 1.0,
+            missed_blocks: 0,
+            last_active: 0,
+        }
+    }
+
+    #[test]
+    fn test_schedule_is_round_robin_by_height() {
+        let validators = vec![validator(), validator(), validator()];
+        let producer = |height| scheduled_producer(&validators, 10, height).map(|v| &v.address);
+
+        assert_eq!(producer(12), Some(&validators[0].address));
+        assert_eq!(producer(13), Some(&validators[1].address));
+        assert_eq!(producer(16), Some(&validators[1].address));
+        assert_eq!(producer(9), None);
+        assert!(scheduled_producer(&[], 0, 5).is_none());
+
+        // Fallbacks continue along the rotation, one rank per validator
+        let fallback = |rank| fallback_producer(&validators, 10, 11, rank).map(|v| &v.address);
+        assert_eq!(fallback(1), Some(&validators[0].address));
+        assert_eq!(fallback(2), Some(&validators[1].address));
+        assert_eq!(fallback(3), None);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::block::{Block, BlockHeader};
+use crate::config::{ConsensusConfig, GenesisConfig};
+use crate::consensus::dpos::DposConsensus;
+use crate::consensus::poa::PoaEngine;
+use crate::signer::BlockSigner;
+use crate::staking::{EpochInfo, StakingParams};
+use crate::state::ChainState;
+use crate::transaction::Transaction;
+use std::fmt::Debug;
+
+/// Seconds a block's timestamp may run ahead of the local clock. A
+/// producer further ahead could claim a fallback slot before it opens.
+pub const MAX_CLOCK_DRIFT_SECS: u64 = 15;
+
+const AHEAD_OF_CLOCK: &str = "ahead of the local clock";
+
+/// Reject `header` if its timestamp is more than `MAX_CLOCK_DRIFT_SECS`
+/// past `now`
+pub fn check_not_in_future(header: &BlockHeader, now: u64) -> Result<(), String> {
+    if header.timestamp > now.saturating_add(MAX_CLOCK_DRIFT_SECS) {
+        return Err(format!(
+            "timestamp {} is more than {}s {} ({})",
+            header.timestamp, MAX_CLOCK_DRIFT_SECS, AHEAD_OF_CLOCK, now
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `error` came from `check_not_in_future`. Such a block may
+/// become valid once the local clock catches up.
+pub fn is_ahead_of_clock(error: &str) -> bool {
+    error.contains(AHEAD_OF_CLOCK)
+}
+
+/// Rules deciding who may produce each block and what happens to the
+/// producer set over time. Engines keep no chain data of their own: the
+/// active producers live in `ChainState::validators`, so every decision
+/// follows from the state at the start of the block.
+pub trait ConsensusEngine: Debug + Send + Sync {
+    /// Name as written in `consensus_type`
+    fn name(&self) -> &'static str;
+
+    /// Install the initial producer set into the genesis state
+    fn init_genesis(
+        &self,
+        state: &mut ChainState,
+        genesis: &GenesisConfig,
+        params: &StakingParams,
+        timestamp: u64,
+    ) -> Result<(), String>;
+
+    /// Hex key of the producer allowed to make the block at `height` at
+    /// fallback `rank`, given the state at the start of that block
+    fn producer(&self, state: &ChainState, height: u64, rank: u32) -> Option<String>;
+
+    /// Target seconds between blocks, as configured or changed by
+    /// governance in `state`
+    fn block_interval(&self, state: &ChainState) -> u64;
+
+    /// Seconds after its parent before a block at fallback `rank` may be made
+    fn fallback_delay(&self, state: &ChainState, rank: u32) -> u64;
+
+    /// Lowest fallback rank at which `producer` may make `height`
+    fn producer_rank(&self, state: &ChainState, height: u64, producer: &str) -> Option<u32> {
+        (0u32..)
+            .map_while(|rank| self.producer(state, height, rank).map(|key| (rank, key)))
+            .find(|(_, key)| key == producer)
+            .map(|(rank, _)| rank)
+    }
+
+    /// Build the block at `height` and have `signer` sign it
+    fn seal(
+        &self,
+        height: u64,
+        previous_hash: String,
+        transactions: Vec<Transaction>,
+        timestamp: u64,
+        rank: u32,
+        signer: &mut dyn BlockSigner,
+    ) -> Result<Block, String> {
+        let mut block = Block::unsigned_at(
+            height,
+            previous_hash,
+            transactions,
+            timestamp,
+            rank,
+            signer.public_key()?,
+        );
+        let signature = signer.sign_block(&block.header)?;
+        block.set_signature(&signature);
+        Ok(block)
+    }
+
+    /// Check that `header` was sealed by the producer of its height and
+    /// fallback rank, no earlier than that rank may produce. The clock
+    /// drift check is stateless and runs with the other checks beforehand.
+    fn verify_block(
+        &self,
+        state: &ChainState,
+        parent: Option<&BlockHeader>,
+        header: &BlockHeader,
+    ) -> Result<(), String> {
+        let (height, rank) = (header.height, header.fallback_rank);
+        let expected = self
+            .producer(state, height, rank)
+            .ok_or_else(|| format!("no validator at fallback rank {}", rank))?;
+        if header.producer != expected {
+            return Err(format!(
+                "producer {} is not scheduled for height {} at fallback rank {} ({} is)",
+                header.producer, height, rank, expected
+            ));
+        }
+        if let Some(parent) = parent {
+            let eligible_at = parent.timestamp + self.fallback_delay(state, rank);
+            if header.timestamp < eligible_at {
+                return Err(format!(
+                    "fallback rank {} may not produce before timestamp {}",
+                    rank, eligible_at
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bookkeeping for a verified block at `height` made at fallback
+    /// `rank`, before its transactions are applied
+    fn on_block(&self, _state: &mut ChainState, _height: u64, _rank: u32, _params: &StakingParams) {
+    }
+
+    /// Called at the last moment of an epoch, before the next one starts
+    fn on_epoch_end(&self, _state: &mut ChainState, _params: &StakingParams) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Start the epoch beginning at `height`
+    fn on_epoch_start(
+        &self,
+        state: &mut ChainState,
+        height: u64,
+        _timestamp: u64,
+        params: &StakingParams,
+    ) -> Result<(), String> {
+        state.epoch = EpochInfo {
+            number: params.epoch_of(height),
+            start_height: height,
+        };
+        Ok(())
+    }
+}
+
+/// The engine named by `config.consensus_type`
+pub fn from_config(config: &ConsensusConfig) -> Result<Box<dyn ConsensusEngine>, String> {
+    match config.consensus_type.to_ascii_lowercase().as_str() {
+        "dpos" => Ok(Box::new(DposConsensus::new(&config.dpos))),
+        "poa" => Ok(Box::new(PoaEngine::new(&config.poa)?)),
+        other => Err(format!(
+            "Unknown consensus type {:?}, expected \"dpos\" or \"poa\"",
+            other
+        )),
+    }
+}
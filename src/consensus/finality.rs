@@ -0,0 +1,215 @@
+use crate::crypto_utils;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Prefix of every signed commit vote, so a vote can never be replayed as
+/// a block or transaction signature
+const COMMIT_VOTE_DOMAIN: &[u8] = b"owami/commit-vote/v1";
+
+/// An active validator's signature committing to the block `block_hash`
+/// at `height`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommitVote {
+    pub height: u64,
+    pub block_hash: String,
+    pub validator: String, // hex public key
+    pub signature: Vec<u8>,
+}
+
+impl CommitVote {
+    pub fn new(height: u64, block_hash: String, signing_key: &SigningKey) -> Self {
+        let signature = signing_key.sign(&Self::sign_bytes(height, &block_hash));
+        CommitVote {
+            height,
+            block_hash,
+            validator: hex::encode(signing_key.verifying_key().to_bytes()),
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    fn sign_bytes(height: u64, block_hash: &str) -> Vec<u8> {
+        [
+            COMMIT_VOTE_DOMAIN,
+            &height.to_be_bytes(),
+            block_hash.as_bytes(),
+        ]
+        .concat()
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        let key = crypto_utils::hex_to_verifying_key(&self.validator)
+            .map_err(|e| format!("invalid validator key: {}", e))?;
+        let signature = Signature::from_slice(&self.signature)
+            .map_err(|e| format!("invalid vote signature: {}", e))?;
+        key.verify(&Self::sign_bytes(self.height, &self.block_hash), &signature)
+            .map_err(|_| format!("invalid vote signature from {}", self.validator))
+    }
+}
+
+/// Votes from validators holding at least two-thirds of the stake,
+/// proving the block at `height` is final
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommitCertificate {
+    pub height: u64,
+    pub block_hash: String,
+    pub votes: Vec<CommitVote>,
+}
+
+impl CommitCertificate {
+    /// Check every vote and that together they carry a two-thirds majority
+    /// of `voting_power`, the active set that voted on the block. Returns
+    /// the stake that signed.
+    pub fn verify(&self, voting_power: &BTreeMap<String, u64>) -> Result<u64, String> {
+        let mut signed = BTreeMap::new();
+        for vote in &self.votes {
+            if vote.height != self.height || vote.block_hash != self.block_hash {
+                return Err(format!(
+                    "vote from {} is for a different block",
+                    vote.validator
+                ));
+            }
+            vote.verify()?;
+            let power = voting_power
+                .get(&vote.validator)
+                .ok_or_else(|| format!("{} is not an active validator", vote.validator))?;
+            if signed.insert(vote.validator.as_str(), *power).is_some() {
+                return Err(format!("duplicate vote from {}", vote.validator));
+            }
+        }
+
+        let signed_power = signed.values().sum();
+        let total_power = voting_power.values().sum();
+        if !has_quorum(signed_power, total_power) {
+            return Err(format!(
+                "votes carry {} of {} stake, short of two-thirds",
+                signed_power, total_power
+            ));
+        }
+        Ok(signed_power)
+    }
+}
+
+/// Whether `signed` is at least two-thirds of `total`
+pub fn has_quorum(signed: u64, total: u64) -> bool {
+    total > 0 && (signed as u128) * 3 >= (total as u128) * 2
+}
+
+/// Commit votes collected for blocks that are not final yet
+#[derive(Debug, Default)]
+pub struct VotePool {
+    // (height, block hash) -> validator -> vote
+    votes: BTreeMap<(u64, String), BTreeMap<String, CommitVote>>,
+}
+
+impl VotePool {
+    /// Record `vote`, already checked against the active set; returns false
+    /// if the validator had already voted for this block
+    pub fn insert(&mut self, vote: CommitVote) -> bool {
+        let entry = self
+            .votes
+            .entry((vote.height, vote.block_hash.clone()))
+            .or_default();
+        if entry.contains_key(&vote.validator) {
+            return false;
+        }
+        entry.insert(vote.validator.clone(), vote);
+        true
+    }
+
+    /// A certificate for the block, if its votes reach two-thirds of
+    /// `voting_power`
+    pub fn certificate(
+        &self,
+        height: u64,
+        block_hash: &str,
+        voting_power: &BTreeMap<String, u64>,
+    ) -> Option<CommitCertificate> {
+        let votes = self.votes.get(&(height, block_hash.to_string()))?;
+        let signed = votes
+            .keys()
+            .filter_map(|validator| voting_power.get(validator))
+            .sum();
+        if !has_quorum(signed, voting_power.values().sum()) {
+            return None;
+        }
+        Some(CommitCertificate {
+            height,
+            block_hash: block_hash.to_string(),
+            votes: votes.values().cloned().collect(),
+        })
+    }
+
+    /// Drop votes for heights at or below `height`, which are settled
+    pub fn prune_through(&mut self, height: u64) {
+        self.votes = self.votes.split_off(&(height + 1, String::new()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn address(key: &SigningKey) -> String {
+        hex::encode(key.verifying_key().to_bytes())
+    }
+
+    #[test]
+    fn test_quorum_needs_two_thirds_of_stake() {
+        let (a, b, c) = (key(1), key(2), key(3));
+        let power: BTreeMap<String, u64> =
+            [(address(&a), 50), (address(&b), 30), (address(&c), 20)].into();
+        let hash = "ab".repeat(32);
+
+        let mut pool = VotePool::default();
+        assert!(pool.insert(CommitVote::new(5, hash.clone(), &b)));
+        assert!(pool.insert(CommitVote::new(5, hash.clone(), &c)));
+        assert!(!pool.insert(CommitVote::new(5, hash.clone(), &c)));
+        // 50 of 100 is not enough
+        assert!(pool.certificate(5, &hash, &power).is_none());
+
+        pool.insert(CommitVote::new(5, hash.clone(), &a));
+        let certificate = pool.certificate(5, &hash, &power).unwrap();
+        assert_eq!(certificate.verify(&power), Ok(100));
+
+        pool.prune_through(5);
+        assert!(pool.certificate(5, &hash, &power).is_none());
+    }
+
+    #[test]
+    fn test_certificate_rejects_bad_votes() {
+        let (a, b) = (key(1), key(2));
+        let power: BTreeMap<String, u64> = [(address(&a), 70), (address(&b), 30)].into();
+        let hash = "cd".repeat(32);
+
+        let duplicated = CommitCertificate {
+            height: 3,
+            block_hash: hash.clone(),
+            votes: vec![CommitVote::new(3, hash.clone(), &b); 3],
+        };
+        assert!(duplicated.verify(&power).unwrap_err().contains("duplicate"));
+
+        let mut forged = CommitVote::new(3, hash.clone(), &a);
+        forged.block_hash = "ef".repeat(32);
+        let certificate = CommitCertificate {
+            height: 3,
+            block_hash: forged.block_hash.clone(),
+            votes: vec![forged],
+        };
+        assert!(certificate.verify(&power).is_err());
+
+        let outsider = CommitCertificate {
+            height: 3,
+            block_hash: hash.clone(),
+            votes: vec![CommitVote::new(3, hash, &key(9))],
+        };
+        assert!(outsider
+            .verify(&power)
+            .unwrap_err()
+            .contains("not an active validator"));
+    }
+}
@@ -1 +1,4 @@
 pub mod dpos;
+pub mod engine;
+pub mod finality;
+pub mod poa;
@@ -0,0 +1,148 @@
+use crate::config::{GenesisConfig, PoaConfig};
+use crate::consensus::dpos::{schedule_index, SerializableVerifyingKey, Validator};
+use crate::consensus::engine::ConsensusEngine;
+use crate::crypto_utils;
+use crate::staking::{EpochInfo, StakingParams};
+use crate::state::ChainState;
+use std::collections::BTreeSet;
+
+/// Proof of authority: the configured signers take turns producing blocks
+/// by height, with the same fallback ranks as DPoS. The set never changes,
+/// stake plays no part in it, and each signer carries one vote towards
+/// finality.
+#[derive(Debug, Clone)]
+pub struct PoaEngine {
+    signers: Vec<String>,
+    block_interval: u64, // unless changed by governance
+    fallback_timeout_multiplier: u64,
+}
+
+impl PoaEngine {
+    pub fn new(config: &PoaConfig) -> Result<Self, String> {
+        if config.signers.is_empty() {
+            return Err("Proof of authority needs at least one signer".to_string());
+        }
+        let mut seen = BTreeSet::new();
+        for signer in &config.signers {
+            crypto_utils::hex_to_verifying_key(signer)
+                .map_err(|e| format!("Invalid signer {}: {}", signer, e))?;
+            if !seen.insert(signer) {
+                return Err(format!("Signer {} is listed twice", signer));
+            }
+        }
+        Ok(PoaEngine {
+            signers: config.signers.clone(),
+            block_interval: config.block_interval,
+            fallback_timeout_multiplier: config.fallback_timeout_multiplier,
+        })
+    }
+
+    pub fn signers(&self) -> &[String] {
+        &self.signers
+    }
+}
+
+impl ConsensusEngine for PoaEngine {
+    fn name(&self) -> &'static str {
+        "poa"
+    }
+
+    /// Make the signers the active set; genesis validators are ignored
+    fn init_genesis(
+        &self,
+        state: &mut ChainState,
+        _genesis: &GenesisConfig,
+        _params: &StakingParams,
+        timestamp: u64,
+    ) -> Result<(), String> {
+        state.epoch = EpochInfo::default();
+        state.validators.clear();
+        state.voting_power.clear();
+        for signer in &self.signers {
+            let key = crypto_utils::hex_to_verifying_key(signer)?;
+            state.validators.push(Validator {
+                address: SerializableVerifyingKey(key),
+                consensus_key: SerializableVerifyingKey(key),
+                stake: 0,
+                uptime: 1.0,
+                missed_blocks: 0,
+                last_active: timestamp as i64,
+            });
+            state.voting_power.insert(signer.clone(), 1);
+        }
+        Ok(())
+    }
+
+    fn producer(&self, _state: &ChainState, height: u64, rank: u32) -> Option<String> {
+        schedule_index(self.signers.len(), 0, height, rank).map(|index| self.signers[index].clone())
+    }
+
+    fn block_interval(&self, state: &ChainState) -> u64 {
+        state.governance.block_interval(self.block_interval)
+    }
+
+    fn fallback_delay(&self, state: &ChainState, rank: u32) -> u64 {
+        self.block_interval(state)
+            .saturating_mul(self.fallback_timeout_multiplier)
+            .saturating_mul(rank as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use ed25519_dalek::SigningKey;
+
+    fn signer(seed: u8) -> (SigningKey, String) {
+        let key = SigningKey::from_bytes(&[seed; 32]);
+        let address = hex::encode(key.verifying_key().to_bytes());
+        (key, address)
+    }
+
+    #[test]
+    fn test_signers_take_turns() -> Result<(), String> {
+        let (key_a, a) = signer(1);
+        let (_, b) = signer(2);
+        let engine = PoaEngine::new(&PoaConfig {
+            signers: vec![a.clone(), b.clone()],
+            block_interval: 5,
+            fallback_timeout_multiplier: 2,
+        })?;
+        let state = ChainState::default();
+
+        assert_eq!(engine.producer(&state, 4, 0), Some(a.clone()));
+        assert_eq!(engine.producer(&state, 5, 0), Some(b.clone()));
+        assert_eq!(engine.producer(&state, 5, 1), Some(a.clone()));
+        assert_eq!(engine.producer(&state, 5, 2), None);
+        assert_eq!(engine.producer_rank(&state, 5, &a), Some(1));
+
+        let parent = Block::new_at(4, "0".repeat(64), Vec::new(), 100, 0, &key_a);
+        let early = Block::new_at(5, parent.hash(), Vec::new(), 105, 1, &key_a);
+        assert!(engine
+            .verify_block(&state, Some(&parent.header), &early.header)
+            .unwrap_err()
+            .contains("may not produce before timestamp 110"));
+        let fallback = Block::new_at(5, parent.hash(), Vec::new(), 110, 1, &key_a);
+        engine.verify_block(&state, Some(&parent.header), &fallback.header)?;
+        let impostor = Block::new_at(5, parent.hash(), Vec::new(), 110, 0, &key_a);
+        assert!(engine
+            .verify_block(&state, Some(&parent.header), &impostor.header)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_signer_list_is_validated() {
+        let (_, a) = signer(1);
+        let config = |signers: Vec<String>| PoaConfig {
+            signers,
+            ..PoaConfig::default()
+        };
+        assert!(PoaEngine::new(&config(Vec::new())).is_err());
+        assert!(PoaEngine::new(&config(vec!["zz".to_string()])).is_err());
+        assert!(PoaEngine::new(&config(vec![a.clone(), a]))
+            .unwrap_err()
+            .contains("listed twice"));
+    }
+}
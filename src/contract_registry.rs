@@ -132,6 +132,7 @@ impl ContractRegistry {
     }
 
     /// Deploy a new smart contract
+    #[allow(clippy::await_holding_lock)]
     pub async fn deploy_contract(&self, request: DeploymentRequest) -> Result<DeployedContract> {
         let mut contracts = self.contracts.lock().unwrap();
         let mut deployment_count = self.deployment_count.lock().unwrap();
@@ -203,6 +204,7 @@ impl ContractRegistry {
     }
 
     /// Call a function on a deployed contract
+    #[allow(clippy::await_holding_lock)]
     pub async fn call_contract(&self, request: CallRequest) -> Result<CallResponse> {
         // Get the contract
         let contracts = self.contracts.lock().unwrap();
@@ -426,7 +428,7 @@ mod tests {
 
     #[test]
     fn test_contract_registry_creation() {
-        let wasm_bytecode = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        let wasm_bytecode = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
         assert_eq!(&wasm_bytecode[0..4], b"\x00asm");
     }
 }
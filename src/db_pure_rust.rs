@@ -1,105 +1,358 @@
 // Pure Rust database implementation - alternative to RocksDB
 // This avoids the libclang dependency issue
 
-use std::collections::HashMap;
+use crate::storage::{scan_prefix, BatchOp, KvStore, Snapshot, WriteBatch};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
+/// Directory under `database.data_dir` holding the store; the name
+/// predates the move off RocksDB and is kept so existing nodes find it
+pub const STORE_DIR: &str = "rocksdb";
+
+/// Marks a directory whose file names are hex-encoded keys
+const FORMAT_FILE: &str = "FORMAT";
+const FORMAT_VERSION: &str = "1";
+
+/// Batch being applied; replayed on open if a crash interrupted it
+const JOURNAL_FILE: &str = "batch.journal";
+
+/// A batch write as recorded in the journal
+#[derive(Serialize, Deserialize)]
+enum JournalOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    /// Remove a file left by the raw-key layout
+    RemoveLegacy(String),
+}
+
+impl From<BatchOp> for JournalOp {
+    fn from(op: BatchOp) -> Self {
+        match op {
+            BatchOp::Put(key, value) => JournalOp::Put(key, value),
+            BatchOp::Delete(key) => JournalOp::Delete(key),
+        }
+    }
+}
+
+/// File-backed `KvStore`: one file per key, with an in-memory index.
+///
+/// File names are the hex-encoded key so arbitrary binary keys are safe on
+/// every filesystem. Writes go through a temporary file and a rename, so a
+/// crash never leaves a half-written value behind. A batch is first
+/// written to a journal that is renamed into place; once the journal
+/// exists the batch is applied in full, on open if not before.
 pub struct PureRustDatabase {
-    data: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    data: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
     data_dir: String,
 }
 
 impl PureRustDatabase {
-    pub fn new(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(path: &str) -> Result<Self> {
         // Create data directory if it doesn't exist
         fs::create_dir_all(path)?;
 
         let db = PureRustDatabase {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            data: Arc::new(RwLock::new(BTreeMap::new())),
             data_dir: path.to_string(),
         };
 
+        // Finish a batch a crash interrupted; a journal only exists in
+        // the hex-key layout, so the marker may be written afterwards
+        if db.journal_path().exists() {
+            db.replay_journal()?;
+            db.write_format()?;
+        }
+        if !db.format_path().exists() {
+            db.migrate_legacy()?;
+        }
+
         // Load any existing data from files
         db.load_from_disk()?;
 
         Ok(db)
     }
 
-    fn load_from_disk(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Read a directory entry, or `None` for the marker, the journal and
+    /// leftovers from an interrupted write, which are removed
+    fn read_entry(entry: &fs::DirEntry) -> Result<Option<(String, Vec<u8>)>> {
+        let path = entry.path();
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if file_name.ends_with(".tmp") {
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        if file_name == FORMAT_FILE || file_name == JOURNAL_FILE {
+            return Ok(None);
+        }
+
+        let mut file = File::open(&path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(Some((file_name, contents)))
+    }
+
+    /// Open the store kept under a node's `data_dir`
+    pub fn open_in(data_dir: &str) -> Result<Self> {
+        let path = Path::new(data_dir).join(STORE_DIR);
+        Self::new(&path.to_string_lossy())
+    }
+
+    fn load_from_disk(&self) -> Result<()> {
         let mut data = self.data.write().unwrap();
 
-        if Path::new(&self.data_dir).exists() {
-            for entry in fs::read_dir(&self.data_dir)? {
-                let entry = entry?;
-                let path = entry.path();
-
-                if path.is_file() {
-                    let mut file = File::open(&path)?;
-                    let mut contents = Vec::new();
-                    file.read_to_end(&mut contents)?;
-
-                    if let Some(key) = path.file_name() {
-                        let key_str = key.to_string_lossy().into_owned();
-                        data.insert(key_str, contents);
-                    }
-                }
+        for entry in fs::read_dir(&self.data_dir)? {
+            if let Some((file_name, contents)) = Self::read_entry(&entry?)? {
+                let key = hex::decode(&file_name).map_err(|_| {
+                    anyhow!(
+                        "{} in {} is not a hex-encoded key file",
+                        file_name,
+                        self.data_dir
+                    )
+                })?;
+                data.insert(key, contents);
             }
         }
 
         Ok(())
     }
 
-    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let key_str = String::from_utf8_lossy(key).into_owned();
+    /// Rename files written before keys were hex-encoded, which are named
+    /// by the raw key. Runs as a journaled batch, so a crash part way
+    /// through resumes instead of reading half-migrated names as raw keys.
+    fn migrate_legacy(&self) -> Result<()> {
+        let mut ops = Vec::new();
+        for entry in fs::read_dir(&self.data_dir)? {
+            if let Some((file_name, contents)) = Self::read_entry(&entry?)? {
+                ops.push(JournalOp::RemoveLegacy(file_name.clone()));
+                ops.push(JournalOp::Put(file_name.into_bytes(), contents));
+            }
+        }
+        // Removals first: a raw name can also be the hex name of another key
+        ops.sort_by_key(|op| matches!(op, JournalOp::Put(..)));
 
-        // Update in-memory data
-        {
-            let mut data = self.data.write().unwrap();
-            data.insert(key_str.clone(), value.to_vec());
+        if !ops.is_empty() {
+            self.write_journal(&ops)?;
+            self.replay_journal()?;
         }
+        self.write_format()
+    }
 
-        // Write to disk
-        let file_path = Path::new(&self.data_dir).join(&key_str);
-        let mut file = File::create(file_path)?;
-        file.write_all(value)?;
+    fn format_path(&self) -> PathBuf {
+        Path::new(&self.data_dir).join(FORMAT_FILE)
+    }
 
-        Ok(())
+    fn journal_path(&self) -> PathBuf {
+        Path::new(&self.data_dir).join(JOURNAL_FILE)
     }
 
-    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
-        let key_str = String::from_utf8_lossy(key).into_owned();
-        let data = self.data.read().unwrap();
+    fn write_format(&self) -> Result<()> {
+        Self::write_atomic(&self.format_path(), FORMAT_VERSION.as_bytes())
+    }
 
-        Ok(data.get(&key_str).cloned())
+    fn write_journal(&self, ops: &[JournalOp]) -> Result<()> {
+        Self::write_atomic(&self.journal_path(), &bincode::serialize(ops)?)
     }
 
-    pub fn delete(&self, key: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let key_str = String::from_utf8_lossy(key).into_owned();
+    /// Apply the journaled batch to the key files, then drop the journal.
+    /// Every op is idempotent, so replaying a partly applied batch is safe.
+    fn replay_journal(&self) -> Result<()> {
+        let mut bytes = Vec::new();
+        File::open(self.journal_path())?.read_to_end(&mut bytes)?;
+        let ops: Vec<JournalOp> = bincode::deserialize(&bytes)
+            .map_err(|e| anyhow!("Corrupt batch journal in {}: {}", self.data_dir, e))?;
 
-        // Remove from memory
-        {
-            let mut data = self.data.write().unwrap();
-            data.remove(&key_str);
+        for op in &ops {
+            match op {
+                JournalOp::Put(key, value) => self.write_file(key, value)?,
+                JournalOp::Delete(key) => self.remove_file(key)?,
+                JournalOp::RemoveLegacy(name) => {
+                    let path = Path::new(&self.data_dir).join(name);
+                    if path.exists() {
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
         }
+        fs::remove_file(self.journal_path())?;
+        Ok(())
+    }
 
-        // Remove from disk
-        let file_path = Path::new(&self.data_dir).join(&key_str);
+    fn file_path(&self, key: &[u8]) -> PathBuf {
+        Path::new(&self.data_dir).join(hex::encode(key))
+    }
+
+    fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn write_file(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        Self::write_atomic(&self.file_path(key), value)
+    }
+
+    fn remove_file(&self, key: &[u8]) -> Result<()> {
+        let file_path = self.file_path(key);
         if file_path.exists() {
             fs::remove_file(file_path)?;
         }
-
         Ok(())
     }
 
     // Simple health check - just verify directory exists
-    pub fn health_check() -> String {
-        if Path::new("data/rocksdb").exists() {
+    pub fn health_check(&self) -> String {
+        if Path::new(&self.data_dir).exists() {
             "connected".to_string()
         } else {
             "disconnected".to_string()
         }
     }
 }
+
+impl KvStore for PureRustDatabase {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let data = self.data.read().unwrap();
+        Ok(data.get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        // Hold the lock while writing so disk and memory stay in step
+        let mut data = self.data.write().unwrap();
+        self.write_file(key, value)?;
+        data.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        self.remove_file(key)?;
+        data.remove(key);
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        let ops = batch.into_ops();
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        // The batch commits when the journal is renamed into place
+        let journal: Vec<JournalOp> = ops.iter().cloned().map(JournalOp::from).collect();
+        self.write_journal(&journal)?;
+        self.replay_journal()?;
+
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    data.insert(key, value);
+                }
+                BatchOp::Delete(key) => {
+                    data.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let data = self.data.read().unwrap();
+        Ok(scan_prefix(&data, prefix))
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        let data = self.data.read().unwrap();
+        Ok(Snapshot::new(data.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn open_temp() -> (PureRustDatabase, tempfile::TempDir) {
+        let dir = tempdir().unwrap();
+        let db = PureRustDatabase::new(dir.path().to_str().unwrap()).unwrap();
+        (db, dir)
+    }
+
+    crate::kv_store_conformance_tests!(open_temp);
+
+    #[test]
+    fn test_data_survives_reopen() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().to_str().unwrap();
+
+        {
+            let db = PureRustDatabase::new(path)?;
+            let mut batch = WriteBatch::new();
+            batch.put(b"chain:block:\x00\x01", b"block");
+            batch.put(b"gone", b"soon");
+            batch.delete(b"gone");
+            db.write_batch(batch)?;
+        }
+
+        let db = PureRustDatabase::new(path)?;
+        assert_eq!(db.get(b"chain:block:\x00\x01")?, Some(b"block".to_vec()));
+        assert_eq!(db.get(b"gone")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_raw_key_files_are_migrated() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().to_str().unwrap();
+        // Raw-key layout; "abcd" is also valid hex
+        fs::write(dir.path().join("abcd"), b"raw")?;
+        fs::write(dir.path().join("chain:tip"), b"tip")?;
+
+        let db = PureRustDatabase::new(path)?;
+        assert_eq!(db.get(b"abcd")?, Some(b"raw".to_vec()));
+        assert_eq!(db.get(b"chain:tip")?, Some(b"tip".to_vec()));
+        assert_eq!(db.get(&[0xab, 0xcd])?, None);
+        drop(db);
+
+        let db = PureRustDatabase::new(path)?;
+        assert_eq!(db.get(b"abcd")?, Some(b"raw".to_vec()));
+        assert_eq!(db.scan_prefix(b"")?.len(), 2);
+
+        // Once migrated, a stray name is an error rather than a key
+        fs::write(dir.path().join("chain:extra"), b"?")?;
+        assert!(PureRustDatabase::new(path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_batch_is_replayed() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().to_str().unwrap();
+
+        {
+            let db = PureRustDatabase::new(path)?;
+            db.put(b"old", b"value")?;
+            // Crash after the journal is in place, before any key file
+            db.write_journal(&[
+                JournalOp::Put(b"new".to_vec(), b"value".to_vec()),
+                JournalOp::Delete(b"old".to_vec()),
+            ])?;
+        }
+
+        let db = PureRustDatabase::new(path)?;
+        assert_eq!(db.get(b"new")?, Some(b"value".to_vec()));
+        assert_eq!(db.get(b"old")?, None);
+        assert!(!dir.path().join(JOURNAL_FILE).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,414 @@
+// On-chain governance of consensus parameters.
+//
+// Anyone can propose a new value for one governed parameter by sending a
+// `submit_proposal` transaction whose amount is the deposit. Bonded stakers
+// then vote yes or no for `voting_period` blocks, each vote weighing the
+// voter's self-stake plus its delegations at the end of voting. A proposal
+// passes when the votes cast reach `quorum_bps` of all bonded stake and
+// the yes side exceeds `threshold_bps` of them; its value then applies
+// from `activation_delay` blocks later. The deposit is refunded once
+// quorum is reached and burned otherwise.
+
+use crate::config::GovernanceConfig;
+use crate::staking::StakingParams;
+use crate::state::ChainState;
+use crate::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Consensus parameters that proposals can change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Param {
+    ValidatorCount,
+    BlockInterval,
+    StakeThreshold,
+    SlashingPenalty,
+}
+
+impl Param {
+    fn validate(&self, value: u64) -> Result<(), String> {
+        match self {
+            Param::ValidatorCount | Param::BlockInterval | Param::StakeThreshold if value == 0 => {
+                Err(format!("{:?} must be greater than zero", self))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Settings of the governance process itself, fixed at genesis
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GovernanceParams {
+    pub min_deposit: u64,
+    pub voting_period: u64,    // blocks
+    pub quorum_bps: u64,       // share of bonded stake that must vote
+    pub threshold_bps: u64,    // share of cast votes that must be yes
+    pub activation_delay: u64, // blocks between the end of voting and the change
+}
+
+impl From<&GovernanceConfig> for GovernanceParams {
+    fn from(config: &GovernanceConfig) -> Self {
+        GovernanceParams {
+            min_deposit: config.min_deposit,
+            voting_period: config.voting_period.max(1),
+            quorum_bps: config.quorum_bps.min(BPS_DENOMINATOR),
+            threshold_bps: config.threshold_bps.min(BPS_DENOMINATOR),
+            activation_delay: config.activation_delay,
+        }
+    }
+}
+
+/// Payload of a `submit_proposal` transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamChange {
+    pub param: Param,
+    pub value: u64,
+}
+
+/// Payload of a `vote_proposal` transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProposalVote {
+    pub proposal_id: u64,
+    pub approve: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProposalStatus {
+    Voting,
+    Passed, // waiting for its activation height
+    Rejected,
+    Executed,
+}
+
+/// Stake behind each side of a proposal
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tally {
+    pub yes: u64,
+    pub no: u64,
+    pub total_stake: u64, // all bonded stake when counted
+}
+
+impl Tally {
+    pub fn quorum_reached(&self, params: &GovernanceParams) -> bool {
+        meets_bps(self.yes + self.no, self.total_stake, params.quorum_bps)
+    }
+
+    pub fn passes(&self, params: &GovernanceParams) -> bool {
+        let cast = self.yes + self.no;
+        self.quorum_reached(params)
+            && cast > 0
+            && (self.yes as u128) * BPS_DENOMINATOR as u128
+                > (cast as u128) * params.threshold_bps as u128
+    }
+}
+
+fn meets_bps(part: u64, whole: u64, bps: u64) -> bool {
+    whole > 0 && (part as u128) * BPS_DENOMINATOR as u128 >= (whole as u128) * bps as u128
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proposal {
+    pub id: u64,
+    pub proposer: String,
+    pub change: ParamChange,
+    pub deposit: u64,
+    pub submit_height: u64,
+    pub voting_end: u64, // last height at which votes are accepted
+    pub activation_height: u64,
+    pub votes: BTreeMap<String, bool>, // voter -> approve
+    pub status: ProposalStatus,
+    pub final_tally: Option<Tally>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GovernanceState {
+    pub params: GovernanceParams,
+    pub proposals: BTreeMap<u64, Proposal>,
+    pub next_id: u64,
+    pub overrides: BTreeMap<Param, u64>, // accepted values now in force
+}
+
+impl GovernanceState {
+    /// `base` with every accepted change applied
+    pub fn staking_params(&self, base: &StakingParams) -> StakingParams {
+        let mut params = base.clone();
+        for (param, value) in &self.overrides {
+            match param {
+                Param::ValidatorCount => params.validator_count = *value,
+                Param::StakeThreshold => params.stake_threshold = *value,
+                Param::SlashingPenalty => params.slashing_penalty = *value,
+                Param::BlockInterval => {}
+            }
+        }
+        params
+    }
+
+    /// Block interval in force, `default` unless governance changed it
+    pub fn block_interval(&self, default: u64) -> u64 {
+        self.overrides
+            .get(&Param::BlockInterval)
+            .copied()
+            .unwrap_or(default)
+    }
+}
+
+/// Bonded stake voting on behalf of `address`: its self-stake plus what it
+/// has delegated
+pub fn voting_weight(state: &ChainState, address: &str) -> u64 {
+    let self_stake = state
+        .candidates
+        .get(address)
+        .map_or(0, |candidate| candidate.self_stake);
+    let delegated: u64 = state
+        .delegations
+        .get(address)
+        .map_or(0, |by_validator| by_validator.values().sum());
+    self_stake.saturating_add(delegated)
+}
+
+/// Count the votes on `proposal` against the current bonded stake
+pub fn tally(state: &ChainState, proposal: &Proposal) -> Tally {
+    let mut tally = Tally {
+        total_stake: state
+            .candidates
+            .values()
+            .map(|candidate| candidate.self_stake)
+            .chain(
+                state
+                    .delegations
+                    .values()
+                    .flat_map(|by| by.values().copied()),
+            )
+            .fold(0u64, u64::saturating_add),
+        ..Tally::default()
+    };
+    for (voter, approve) in &proposal.votes {
+        let weight = voting_weight(state, voter);
+        if *approve {
+            tally.yes = tally.yes.saturating_add(weight);
+        } else {
+            tally.no = tally.no.saturating_add(weight);
+        }
+    }
+    tally
+}
+
+/// Open a proposal from `tx`, included in the block at `height`, taking
+/// its amount as the deposit
+pub fn submit_proposal(
+    state: &mut ChainState,
+    tx: &Transaction,
+    change: &ParamChange,
+    height: u64,
+) -> Result<(), String> {
+    let params = state.governance.params.clone();
+    if tx.amount < params.min_deposit {
+        return Err(format!(
+            "deposit {} is below the minimum of {}",
+            tx.amount, params.min_deposit
+        ));
+    }
+    change.param.validate(change.value)?;
+    state.debit(&tx.from, tx.amount)?;
+
+    let governance = &mut state.governance;
+    let id = governance.next_id;
+    let voting_end = height + params.voting_period;
+    governance.proposals.insert(
+        id,
+        Proposal {
+            id,
+            proposer: tx.from.clone(),
+            change: change.clone(),
+            deposit: tx.amount,
+            submit_height: height,
+            voting_end,
+            activation_height: voting_end + 1 + params.activation_delay,
+            votes: BTreeMap::new(),
+            status: ProposalStatus::Voting,
+            final_tally: None,
+        },
+    );
+    governance.next_id += 1;
+    Ok(())
+}
+
+/// Record `voter`'s vote; a later vote replaces an earlier one
+pub fn cast_vote(
+    state: &mut ChainState,
+    voter: &str,
+    vote: &ProposalVote,
+    height: u64,
+) -> Result<(), String> {
+    if voting_weight(state, voter) == 0 {
+        return Err("only accounts with bonded stake can vote".to_string());
+    }
+    let proposal = state
+        .governance
+        .proposals
+        .get_mut(&vote.proposal_id)
+        .ok_or_else(|| format!("proposal {} does not exist", vote.proposal_id))?;
+    if proposal.status != ProposalStatus::Voting || height > proposal.voting_end {
+        return Err(format!("voting on proposal {} has closed", proposal.id));
+    }
+    proposal.votes.insert(voter.to_string(), vote.approve);
+    Ok(())
+}
+
+/// Close the proposals whose voting ended before `height` and put the
+/// changes scheduled for `height` into force
+pub fn begin_block(state: &mut ChainState, height: u64) -> Result<(), String> {
+    let params = state.governance.params.clone();
+    let closing: Vec<u64> = state
+        .governance
+        .proposals
+        .values()
+        .filter(|proposal| {
+            proposal.status == ProposalStatus::Voting && proposal.voting_end < height
+        })
+        .map(|proposal| proposal.id)
+        .collect();
+    for id in closing {
+        let result = tally(state, &state.governance.proposals[&id]);
+        let proposal = state.governance.proposals.get_mut(&id).unwrap();
+        proposal.status = if result.passes(&params) {
+            ProposalStatus::Passed
+        } else {
+            ProposalStatus::Rejected
+        };
+        let refund = result.quorum_reached(&params).then_some(proposal.deposit);
+        let proposer = proposal.proposer.clone();
+        proposal.final_tally = Some(result);
+        if let Some(deposit) = refund {
+            state.credit(&proposer, deposit)?;
+        }
+    }
+
+    let governance = &mut state.governance;
+    for proposal in governance.proposals.values_mut() {
+        if proposal.status == ProposalStatus::Passed && proposal.activation_height <= height {
+            governance
+                .overrides
+                .insert(proposal.change.param, proposal.change.value);
+            proposal.status = ProposalStatus::Executed;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staking::Candidate;
+
+    fn state() -> ChainState {
+        let mut state = ChainState::default();
+        state.governance.params = GovernanceParams {
+            min_deposit: 100,
+            voting_period: 10,
+            quorum_bps: 4000,
+            threshold_bps: 5000,
+            activation_delay: 5,
+        };
+        state.balances.insert("proposer".to_string(), 1000);
+        for (validator, stake) in [("a", 600), ("b", 300), ("c", 100)] {
+            state
+                .candidates
+                .insert(validator.to_string(), Candidate::new(stake, 0));
+        }
+        state
+    }
+
+    fn proposal_tx(amount: u64) -> Transaction {
+        Transaction::new("proposer".to_string(), String::new(), amount, None)
+    }
+
+    fn vote(state: &mut ChainState, voter: &str, approve: bool) -> Result<(), String> {
+        let vote = ProposalVote {
+            proposal_id: 0,
+            approve,
+        };
+        cast_vote(state, voter, &vote, 5)
+    }
+
+    #[test]
+    fn test_passed_change_applies_at_activation_height() -> Result<(), String> {
+        let mut state = state();
+        let change = ParamChange {
+            param: Param::ValidatorCount,
+            value: 9,
+        };
+        assert!(submit_proposal(&mut state, &proposal_tx(50), &change, 1).is_err());
+        submit_proposal(&mut state, &proposal_tx(100), &change, 1)?;
+        assert_eq!(state.balance("proposer"), 900);
+
+        vote(&mut state, "b", true)?;
+        vote(&mut state, "c", false)?;
+        assert!(vote(&mut state, "proposer", true).is_err());
+
+        // Voting closes after height 11 with 400 of 1000 stake: 3:1 in favour
+        begin_block(&mut state, 11)?;
+        assert_eq!(
+            state.governance.proposals[&0].status,
+            ProposalStatus::Voting
+        );
+        begin_block(&mut state, 12)?;
+        assert_eq!(
+            state.governance.proposals[&0].status,
+            ProposalStatus::Passed
+        );
+        assert_eq!(state.balance("proposer"), 1000);
+        assert!(vote(&mut state, "a", false).is_err());
+
+        let base = StakingParams {
+            stake_threshold: 1000,
+            validator_count: 21,
+            unbonding_period: 10,
+            epoch_length: 5,
+            slashing_penalty: 400,
+            evidence_reward: 100,
+            missed_slot_window: 10,
+            missed_slot_threshold: 5,
+            jail_cooldown: 20,
+            epoch_reward: 0,
+            commission_bps: 0,
+        };
+        assert_eq!(state.governance.staking_params(&base).validator_count, 21);
+        begin_block(&mut state, 17)?;
+        assert_eq!(
+            state.governance.proposals[&0].status,
+            ProposalStatus::Executed
+        );
+        assert_eq!(state.governance.staking_params(&base).validator_count, 9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_quorum_burns_the_deposit() -> Result<(), String> {
+        let mut state = state();
+        let change = ParamChange {
+            param: Param::BlockInterval,
+            value: 2,
+        };
+        submit_proposal(&mut state, &proposal_tx(100), &change, 1)?;
+        vote(&mut state, "b", true)?;
+        begin_block(&mut state, 12)?;
+
+        let proposal = &state.governance.proposals[&0];
+        assert_eq!(proposal.status, ProposalStatus::Rejected);
+        assert_eq!(proposal.final_tally.as_ref().unwrap().yes, 300);
+        assert_eq!(state.balance("proposer"), 900);
+        assert_eq!(state.governance.block_interval(5), 5);
+
+        let zero = ParamChange {
+            param: Param::BlockInterval,
+            value: 0,
+        };
+        assert!(submit_proposal(&mut state, &proposal_tx(100), &zero, 13).is_err());
+        Ok(())
+    }
+}
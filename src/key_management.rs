@@ -90,28 +90,83 @@ impl KeyManager {
 
     /// Save key to secure storage
     fn save_key(&self, signing_key: &SigningKey) -> Result<()> {
-        let key_bytes = signing_key.to_bytes();
-        fs::write(&self.key_path, key_bytes)?;
+        Self::write_key_file(&self.key_path, signing_key)?;
+
+        info!("Signing key saved to: {}", self.key_path);
+
+        audit_log::log_key_management_event(
+            "Key saved".to_string(),
+            format!("Signing key saved to secure storage: {}", self.key_path),
+            "success".to_string(),
+            None,
+        )?;
+
+        Ok(())
+    }
+
+    fn write_key_file(path: &str, signing_key: &SigningKey) -> Result<()> {
+        fs::write(path, signing_key.to_bytes())?;
 
         // Set secure permissions (Unix-like systems)
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(&self.key_path)?.permissions();
+            let mut perms = fs::metadata(path)?.permissions();
             perms.set_mode(0o600); // Read/write for owner only
-            fs::set_permissions(&self.key_path, perms)?;
+            fs::set_permissions(path, perms)?;
         }
+        Ok(())
+    }
 
-        info!("Signing key saved to: {}", self.key_path);
+    fn next_key_path(&self) -> String {
+        format!("{}.next", self.key_path)
+    }
+
+    /// Generate a key to replace the current one later, keeping the current
+    /// key in use. For consensus keys, which change only once the chain
+    /// has switched to the new key.
+    pub fn stage_next_key(&mut self) -> Result<SigningKey> {
+        let next_key = SigningKey::generate(&mut OsRng);
+        Self::write_key_file(&self.next_key_path(), &next_key)?;
 
         audit_log::log_key_management_event(
-            "Key saved".to_string(),
-            format!("Signing key saved to secure storage: {}", self.key_path),
+            "Key staged".to_string(),
+            format!("Next signing key saved to: {}", self.next_key_path()),
             "success".to_string(),
             None,
         )?;
 
-        Ok(())
+        Ok(next_key)
+    }
+
+    /// The staged key, if any
+    pub fn load_next_key(&self) -> Result<Option<SigningKey>> {
+        let path = self.next_key_path();
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let key_bytes: [u8; 32] = fs::read(&path)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid key length in file"))?;
+        Ok(Some(SigningKey::from_bytes(&key_bytes)))
+    }
+
+    /// Make the staged key the current one
+    pub fn promote_next_key(&mut self) -> Result<SigningKey> {
+        let next_key = self
+            .load_next_key()?
+            .ok_or_else(|| anyhow!("No staged key to promote"))?;
+        fs::rename(self.next_key_path(), &self.key_path)?;
+        self.signing_key = Some(next_key.clone());
+
+        audit_log::log_key_management_event(
+            "Key promoted".to_string(),
+            format!("Staged signing key now in use: {}", self.key_path),
+            "success".to_string(),
+            None,
+        )?;
+
+        Ok(next_key)
     }
 
     /// Get the current signing key
@@ -125,7 +180,9 @@ impl KeyManager {
         Ok(signing_key.verifying_key())
     }
 
-    /// Rotate the signing key (for security purposes)
+    /// Rotate the signing key (for security purposes). The new key is in
+    /// use straight away; a validator's consensus key should be rotated on
+    /// chain with `stage_next_key` instead.
     pub fn rotate_key(&mut self) -> Result<SigningKey> {
         info!("Rotating signing key");
         let mut rng = OsRng;
@@ -214,4 +271,28 @@ mod tests {
         assert_ne!(original_key.to_bytes(), rotated_key.to_bytes());
         Ok(())
     }
+
+    #[test]
+    fn test_staged_key_replaces_current_only_when_promoted() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let key_path = temp_dir.path().join("consensus.key");
+        let key_path = key_path.to_str().unwrap().to_string();
+
+        let mut manager = KeyManager::new(Some(key_path.clone()))?;
+        let current = manager.get_signing_key()?;
+        assert!(manager.load_next_key()?.is_none());
+
+        let next = manager.stage_next_key()?;
+        assert_eq!(manager.get_signing_key()?.to_bytes(), current.to_bytes());
+        assert_eq!(
+            manager.load_next_key()?.unwrap().to_bytes(),
+            next.to_bytes()
+        );
+
+        manager.promote_next_key()?;
+        assert!(manager.load_next_key()?.is_none());
+        let mut reloaded = KeyManager::new(Some(key_path))?;
+        assert_eq!(reloaded.get_signing_key()?.to_bytes(), next.to_bytes());
+        Ok(())
+    }
 }
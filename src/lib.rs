@@ -2,6 +2,7 @@ pub mod api;
 pub mod audit_log;
 pub mod block;
 pub mod blockchain;
+pub mod chain_io;
 pub mod compiler;
 pub mod config;
 pub mod consensus;
@@ -9,12 +10,25 @@ pub mod contract_registry;
 pub mod crypto_utils;
 // Removed db module - using pure Rust implementation instead
 // pub mod db;
+pub mod db_pure_rust;
 pub mod deploy;
+pub mod governance;
 pub mod key_management;
+pub mod merkle;
 pub mod models;
 pub mod network;
 pub mod rate_limiting;
+pub mod rewards;
+pub mod signer;
 pub mod simple_registry;
+pub mod simulation;
+pub mod slashing;
+pub mod snapshot;
+pub mod staking;
+pub mod state;
+pub mod storage;
+#[cfg(test)]
+pub(crate) mod test_support;
 pub mod transaction;
 pub mod vesting;
 pub mod wallet;
@@ -3,15 +3,33 @@
 
 use axum::{
     extract::Path,
-    extract::State,
+    extract::{FromRef, State},
     http::StatusCode,
     response::{Html, IntoResponse},
     routing::{get, post},
     Json, Router,
 };
 use chrono::{SecondsFormat, Utc};
+#[cfg(unix)]
+use owami_network::signer::RemoteSigner;
 use owami_network::{
-    block::Block, blockchain::Blockchain, config::AppConfig, crypto_utils::generate_keypair,
+    block::{Block, BlockHeader},
+    blockchain::{BlockLookup, Blockchain},
+    config::{AppConfig, GenesisValidator, NodeMode},
+    consensus::finality::CommitVote,
+    crypto_utils::generate_keypair,
+    db_pure_rust::PureRustDatabase,
+    governance::{self, Proposal, ProposalStatus},
+    key_management::KeyManager,
+    network::{
+        gossip::Gossip,
+        mobile,
+        p2p::NetworkService,
+        sync::{BlockSync, SyncStatus},
+    },
+    signer::{BlockSigner, LocalSigner},
+    state::ChainState,
+    transaction::{Transaction, TxPayload},
     wallet::Wallet,
 };
 use serde::{Deserialize, Serialize};
@@ -23,10 +41,6 @@ use std::{
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::services::ServeDir;
 
-// Import our pure Rust database instead of RocksDB
-mod db_pure_rust;
-use db_pure_rust::PureRustDatabase;
-
 #[derive(Clone, Serialize, Deserialize)]
 struct SimpleContract {
     pub id: String,
@@ -42,12 +56,24 @@ struct SimpleContract {
 #[derive(Clone)]
 struct SimpleState {
     blockchain: Arc<Mutex<Blockchain>>,
+    operator_key: Arc<ed25519_dalek::SigningKey>, // validator identity: stake, rewards, reports
+    signer: Arc<Mutex<Box<dyn BlockSigner>>>,     // signs blocks and commit votes
+    gossip: Option<Arc<Gossip>>,                  // peer layer, when p2p is enabled
+    sync: Option<Arc<BlockSync>>,
+    #[allow(dead_code)]
     wallets: Arc<HashMap<String, Wallet>>,
     database: Arc<PureRustDatabase>,
     #[allow(dead_code)]
     dapps: Arc<Mutex<HashMap<String, SimpleContract>>>,
 }
 
+// Lets the mobile handlers, which only need the chain, share the app state
+impl FromRef<SimpleState> for Arc<Mutex<Blockchain>> {
+    fn from_ref(state: &SimpleState) -> Self {
+        state.blockchain.clone()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct HealthResponse {
     status: String,
@@ -55,6 +81,8 @@ struct HealthResponse {
     timestamp: String,
     database: String,
     wasm_support: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sync: Option<SyncStatus>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -63,6 +91,9 @@ struct BlockchainInfo {
     latest_block_hash: String,
     difficulty: u32,
     total_transactions: usize,
+    pruned_below: u64,
+    finalized_height: u64,
+    consensus: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -97,6 +128,8 @@ struct AddTransactionResponse {
 struct GetBlockResponse {
     success: bool,
     block: Option<Block>,
+    pruned: bool,
+    header: Option<BlockHeader>, // still available when the body was pruned
     message: String,
 }
 
@@ -104,6 +137,7 @@ struct GetBlockResponse {
 struct GetBlocksResponse {
     success: bool,
     blocks: Vec<Block>,
+    pruned_below: u64,
     message: String,
 }
 
@@ -113,9 +147,9 @@ struct ErrorResponse {
     details: String,
 }
 
-async fn health_check(State(_state): State<SimpleState>) -> Json<HealthResponse> {
+async fn health_check(State(state): State<SimpleState>) -> Json<HealthResponse> {
     // Use our pure Rust database health check instead of RocksDB
-    let db_status = PureRustDatabase::health_check();
+    let db_status = state.database.health_check();
 
     Json(HealthResponse {
         status: "healthy".to_string(),
@@ -123,33 +157,193 @@ async fn health_check(State(_state): State<SimpleState>) -> Json<HealthResponse>
         timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
         database: db_status,
         wasm_support: true,
+        sync: state.sync.as_ref().map(|sync| sync.status()),
     })
 }
 
+/// Connected peers with their height, latency and reputation score
+async fn get_peers(State(state): State<SimpleState>) -> Json<serde_json::Value> {
+    match &state.gossip {
+        Some(gossip) => Json(serde_json::json!({
+            "success": true,
+            "node_id": gossip.network().node_id(),
+            "peers": gossip.network().peers(),
+        })),
+        None => Json(serde_json::json!({
+            "success": false,
+            "message": "The peer network is disabled; set p2p.enabled to turn it on",
+        })),
+    }
+}
+
 async fn blockchain_info(State(state): State<SimpleState>) -> Json<BlockchainInfo> {
     let blockchain = state.blockchain.lock().unwrap();
 
     Json(BlockchainInfo {
-        chain_length: blockchain.blocks.len(),
+        chain_length: blockchain.chain_length() as usize,
         latest_block_hash: blockchain.get_latest_block().hash(),
         difficulty: 4,
         total_transactions: blockchain.blocks.iter().map(|b| b.transactions.len()).sum(),
+        pruned_below: blockchain.pruned_below(),
+        finalized_height: blockchain.finalized_height(),
+        consensus: blockchain.engine().name().to_string(),
     })
 }
 
+/// Consensus signer: the `owami-signer` process at REMOTE_SIGNER_SOCKET if
+/// set, otherwise the key at CONSENSUS_KEY_PATH. Either way the heights
+/// signed are recorded at SIGNER_STATE_PATH.
+fn open_signer() -> anyhow::Result<Box<dyn BlockSigner>> {
+    let state_path =
+        env::var("SIGNER_STATE_PATH").unwrap_or_else(|_| "./keys/signed_heights.json".to_string());
+    if let Ok(socket) = env::var("REMOTE_SIGNER_SOCKET") {
+        #[cfg(unix)]
+        return Ok(Box::new(RemoteSigner::new(socket, state_path)?));
+        #[cfg(not(unix))]
+        anyhow::bail!(
+            "REMOTE_SIGNER_SOCKET={} needs Unix domain sockets, which this platform lacks",
+            socket
+        );
+    }
+    let keys = KeyManager::new(Some(
+        env::var("CONSENSUS_KEY_PATH").unwrap_or_else(|_| "./keys/consensus.key".to_string()),
+    ))?;
+    Ok(Box::new(LocalSigner::new(keys, state_path)?))
+}
+
+/// Switch the signer to its staged consensus key once the chain has
+/// switched this validator to it
+fn sync_consensus_key(
+    signer: &mut dyn BlockSigner,
+    state: &SimpleState,
+    blockchain: &Blockchain,
+) -> Result<(), String> {
+    let operator = hex::encode(state.operator_key.verifying_key().to_bytes());
+    if let Some(next) = signer.next_public_key()? {
+        if blockchain.next_consensus_key(&operator)?.as_deref() == Some(next.as_str()) {
+            signer.promote_next_key()?;
+        }
+    }
+    Ok(())
+}
+
+/// Generate a new consensus key and ask the chain to switch to it at the
+/// next epoch, with a transaction signed by the operator key
+async fn rotate_consensus_key(State(state): State<SimpleState>) -> Json<serde_json::Value> {
+    let staged = state.signer.lock().unwrap().stage_next_key();
+    let next_key = match staged {
+        Ok(key) => key,
+        Err(e) => {
+            return Json(serde_json::json!({
+                "success": false,
+                "message": format!("Failed to generate consensus key: {}", e),
+            }))
+        }
+    };
+
+    let payload = TxPayload::RotateConsensusKey {
+        consensus_key: next_key.clone(),
+    };
+    let mut tx = Transaction::new(
+        hex::encode(state.operator_key.verifying_key().to_bytes()),
+        "staking".to_string(),
+        0,
+        Some(payload.to_data()),
+    );
+    let submitted = tx
+        .sign(&state.operator_key)
+        .map_err(|e| e.to_string())
+        .and_then(|_| queue_transaction(&state, &mut state.blockchain.lock().unwrap(), tx));
+
+    match submitted {
+        Ok(()) => Json(serde_json::json!({
+            "success": true,
+            "next_consensus_key": next_key,
+            "message": "Rotation submitted; the new key signs from the next epoch",
+        })),
+        Err(e) => Json(serde_json::json!({
+            "success": false,
+            "message": format!("Failed to submit key rotation: {}", e),
+        })),
+    }
+}
+
+/// Add `tx` to the pool and gossip it to peers
+fn queue_transaction(
+    state: &SimpleState,
+    blockchain: &mut Blockchain,
+    tx: Transaction,
+) -> Result<(), String> {
+    blockchain.add_transaction(tx.clone())?;
+    if let Some(gossip) = &state.gossip {
+        gossip.publish_transaction(&tx);
+    }
+    Ok(())
+}
+
 async fn mine_block(
     State(state): State<SimpleState>,
     Json(_request): Json<MineBlockRequest>,
 ) -> Json<MineBlockResponse> {
+    // A node behind its peers would fork off a stale tip
+    if state.sync.as_ref().is_some_and(|sync| !sync.is_synced()) {
+        return Json(MineBlockResponse {
+            success: false,
+            block: None,
+            message: "Node is still syncing with its peers".to_string(),
+        });
+    }
     let mut blockchain = state.blockchain.lock().unwrap();
-    let signing_key = owami_network::crypto_utils::default_signing_key();
+    let mut signer = state.signer.lock().unwrap();
+    if let Err(e) = sync_consensus_key(signer.as_mut(), &state, &blockchain) {
+        return Json(MineBlockResponse {
+            success: false,
+            block: None,
+            message: format!("Failed to load consensus key: {}", e),
+        });
+    }
 
-    match blockchain.mine_block(&signing_key) {
-        Ok(block) => Json(MineBlockResponse {
-            success: true,
-            block: Some(block),
-            message: "Block mined successfully".to_string(),
-        }),
+    // Report any double-signs seen since the last block
+    let reporter = hex::encode(state.operator_key.verifying_key().to_bytes());
+    for payload in blockchain.take_detected_evidence() {
+        let mut tx = Transaction::new(
+            reporter.clone(),
+            "evidence".to_string(),
+            0,
+            Some(payload.to_data()),
+        );
+        let submitted = tx
+            .sign(&state.operator_key)
+            .map_err(|e| e.to_string())
+            .and_then(|_| queue_transaction(&state, &mut blockchain, tx));
+        if let Err(e) = submitted {
+            eprintln!("Failed to submit double-sign evidence: {}", e);
+        }
+    }
+
+    let mined = blockchain.mine_block_with(signer.as_mut());
+    if let (Ok(block), None) = (&mined, &state.gossip) {
+        // Without peers the producer still commits to its own block
+        if let Err(e) = blockchain.vote_on_tip_with(signer.as_mut()) {
+            eprintln!("Failed to vote for block {}: {}", block.header.height, e);
+        }
+    }
+    // Gossip takes both locks again to vote
+    drop(signer);
+    drop(blockchain);
+
+    match mined {
+        Ok(block) => {
+            // Peers get the block, then this node's vote for it
+            if let Some(gossip) = &state.gossip {
+                gossip.publish_block(&block);
+            }
+            Json(MineBlockResponse {
+                success: true,
+                block: Some(block),
+                message: "Block mined successfully".to_string(),
+            })
+        }
         Err(e) => Json(MineBlockResponse {
             success: false,
             block: None,
@@ -175,7 +369,7 @@ async fn add_transaction(
     match tx.sign(&signing_key) {
         Ok(_) => {
             let tx_hash = tx.hash();
-            match blockchain.add_transaction(tx) {
+            match queue_transaction(&state, &mut blockchain, tx) {
                 Ok(_) => Json(AddTransactionResponse {
                     success: true,
                     transaction_hash: Some(tx_hash),
@@ -196,21 +390,367 @@ async fn add_transaction(
     }
 }
 
+/// Queue a transaction signed by the client, such as a staking operation
+async fn submit_signed_transaction(
+    State(state): State<SimpleState>,
+    Json(tx): Json<Transaction>,
+) -> Json<AddTransactionResponse> {
+    let mut blockchain = state.blockchain.lock().unwrap();
+    let tx_hash = tx.hash();
+
+    match queue_transaction(&state, &mut blockchain, tx) {
+        Ok(_) => Json(AddTransactionResponse {
+            success: true,
+            transaction_hash: Some(tx_hash),
+            message: "Transaction added successfully".to_string(),
+        }),
+        Err(e) => Json(AddTransactionResponse {
+            success: false,
+            transaction_hash: None,
+            message: format!("Failed to add transaction: {}", e),
+        }),
+    }
+}
+
+/// Accept a commit vote from an active validator
+async fn submit_commit_vote(
+    State(state): State<SimpleState>,
+    Json(vote): Json<CommitVote>,
+) -> Json<serde_json::Value> {
+    let mut blockchain = state.blockchain.lock().unwrap();
+
+    match blockchain.add_commit_vote(vote) {
+        Ok(certificate) => Json(serde_json::json!({
+            "success": true,
+            "finalized": certificate.is_some(),
+            "finalized_height": blockchain.finalized_height(),
+        })),
+        Err(e) => Json(serde_json::json!({
+            "success": false,
+            "message": format!("Failed to add commit vote: {}", e),
+        })),
+    }
+}
+
+async fn get_commit_certificate(
+    State(state): State<SimpleState>,
+    axum::extract::Path(height): axum::extract::Path<u64>,
+) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+
+    match blockchain.commit_certificate(height) {
+        Ok(Some(certificate)) => Json(serde_json::json!({
+            "success": true,
+            "certificate": certificate,
+        })),
+        Ok(None) => Json(serde_json::json!({
+            "success": false,
+            "message": format!("No commit certificate for block {}", height),
+            "finalized_height": blockchain.finalized_height(),
+        })),
+        Err(e) => Json(serde_json::json!({
+            "success": false,
+            "message": e,
+        })),
+    }
+}
+
+async fn get_validators(State(state): State<SimpleState>) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+
+    let active: Vec<String> = chain_state
+        .validators
+        .iter()
+        .map(|v| hex::encode(v.address.0.to_bytes()))
+        .collect();
+    let voting_power = owami_network::staking::voting_power(chain_state);
+    let candidates: Vec<serde_json::Value> = chain_state
+        .candidates
+        .iter()
+        .map(|(address, candidate)| {
+            let window = chain_state
+                .slot_windows
+                .get(address)
+                .cloned()
+                .unwrap_or_default();
+            serde_json::json!({
+                "address": address,
+                "consensus_key": candidate.signing_key(address),
+                "next_consensus_key": candidate.next_consensus_key,
+                "self_stake": candidate.self_stake,
+                "voting_power": voting_power.get(address),
+                "registered_height": candidate.registered_height,
+                "jailed_until": candidate.jailed_until,
+                "uptime": window.uptime(),
+                "missed_slots": window.missed.len(),
+                "active": active.contains(address),
+            })
+        })
+        .collect();
+
+    Json(serde_json::json!({
+        "success": true,
+        "height": blockchain.get_block_height(),
+        "epoch": chain_state.epoch,
+        "active": active,
+        "candidates": candidates,
+        "unbonding": chain_state.unbonding,
+    }))
+}
+
+async fn get_validator_rewards(
+    State(state): State<SimpleState>,
+    Path(address): Path<String>,
+) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+
+    Json(serde_json::json!({
+        "success": true,
+        "validator": address,
+        "last_epoch": chain_state.last_rewards.get(&address),
+        "total_earned": chain_state.rewards_earned.get(&address).copied().unwrap_or(0),
+    }))
+}
+
+async fn get_delegator_rewards(
+    State(state): State<SimpleState>,
+    Path(address): Path<String>,
+) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+
+    let last_epoch: Vec<serde_json::Value> = chain_state
+        .last_rewards
+        .values()
+        .filter_map(|reward| {
+            reward.payouts.get(&address).map(|amount| {
+                serde_json::json!({
+                    "validator": reward.validator,
+                    "epoch": reward.epoch,
+                    "amount": amount,
+                })
+            })
+        })
+        .collect();
+
+    Json(serde_json::json!({
+        "success": true,
+        "delegator": address,
+        "last_epoch": last_epoch,
+        "total_earned": chain_state.rewards_earned.get(&address).copied().unwrap_or(0),
+    }))
+}
+
+/// Proposal with its tally: the final one once voting has closed,
+/// otherwise the current count
+fn proposal_json(chain_state: &ChainState, proposal: &Proposal) -> serde_json::Value {
+    let tally = proposal
+        .final_tally
+        .clone()
+        .unwrap_or_else(|| governance::tally(chain_state, proposal));
+    let params = &chain_state.governance.params;
+    serde_json::json!({
+        "proposal": proposal,
+        "tally": tally,
+        "quorum_reached": tally.quorum_reached(params),
+        "passing": tally.passes(params),
+    })
+}
+
+async fn get_proposals(State(state): State<SimpleState>) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+
+    let proposals: Vec<serde_json::Value> = chain_state
+        .governance
+        .proposals
+        .values()
+        .map(|proposal| proposal_json(chain_state, proposal))
+        .collect();
+
+    Json(serde_json::json!({
+        "success": true,
+        "proposals": proposals,
+    }))
+}
+
+async fn get_proposal(
+    State(state): State<SimpleState>,
+    Path(id): Path<u64>,
+) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+
+    match chain_state.governance.proposals.get(&id) {
+        Some(proposal) => Json(serde_json::json!({
+            "success": true,
+            "proposal": proposal_json(chain_state, proposal),
+        })),
+        None => Json(serde_json::json!({
+            "success": false,
+            "message": format!("Proposal {} not found", id),
+        })),
+    }
+}
+
+/// Governed parameters in force, the governance rules, and accepted
+/// changes still waiting for their activation height
+async fn get_governance_params(State(state): State<SimpleState>) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+    let params = blockchain.staking_params();
+
+    let pending: Vec<serde_json::Value> = chain_state
+        .governance
+        .proposals
+        .values()
+        .filter(|proposal| proposal.status == ProposalStatus::Passed)
+        .map(|proposal| {
+            serde_json::json!({
+                "proposal_id": proposal.id,
+                "param": proposal.change.param,
+                "value": proposal.change.value,
+                "activation_height": proposal.activation_height,
+            })
+        })
+        .collect();
+
+    Json(serde_json::json!({
+        "success": true,
+        "params": {
+            "validator_count": params.validator_count,
+            "block_interval": blockchain.engine().block_interval(chain_state),
+            "stake_threshold": params.stake_threshold,
+            "slashing_penalty": params.slashing_penalty,
+        },
+        "governance": chain_state.governance.params,
+        "pending": pending,
+    }))
+}
+
+/// Vesting status of an investor: what has unlocked and can be spent
+async fn get_vesting_status(
+    State(state): State<SimpleState>,
+    Path(address): Path<String>,
+) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+    let now = chain_state.block_time;
+
+    let schedules: Vec<serde_json::Value> = chain_state
+        .vesting
+        .get(&address)
+        .into_iter()
+        .flatten()
+        .map(|schedule| {
+            serde_json::json!({
+                "total_amount": schedule.total_amount,
+                "start_time": schedule.start_time,
+                "cliff_end": schedule.start_time + schedule.cliff_duration,
+                "end_time": schedule.start_time + schedule.vesting_duration,
+                "vested": schedule.vested_amount(now),
+                "locked": schedule.locked_amount(now),
+            })
+        })
+        .collect();
+
+    Json(serde_json::json!({
+        "success": true,
+        "address": address,
+        "as_of": now,
+        "balance": chain_state.balance(&address),
+        "locked": chain_state.locked_balance(&address),
+        "claimable": chain_state.spendable_balance(&address),
+        "schedules": schedules,
+    }))
+}
+
+async fn get_delegations_by_delegator(
+    State(state): State<SimpleState>,
+    Path(address): Path<String>,
+) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+
+    let delegations: Vec<serde_json::Value> = chain_state
+        .delegations
+        .get(&address)
+        .into_iter()
+        .flatten()
+        .map(|(validator, amount)| serde_json::json!({ "validator": validator, "amount": amount }))
+        .collect();
+    let unbonding: Vec<_> = chain_state
+        .unbonding
+        .iter()
+        .filter(|entry| entry.owner == address)
+        .collect();
+
+    Json(serde_json::json!({
+        "success": true,
+        "delegator": address,
+        "delegations": delegations,
+        "unbonding": unbonding,
+    }))
+}
+
+async fn get_delegations_by_validator(
+    State(state): State<SimpleState>,
+    Path(address): Path<String>,
+) -> Json<serde_json::Value> {
+    let blockchain = state.blockchain.lock().unwrap();
+    let chain_state = &blockchain.state;
+
+    let delegations: Vec<serde_json::Value> = chain_state
+        .delegations_to(&address)
+        .into_iter()
+        .map(|(delegator, amount)| serde_json::json!({ "delegator": delegator, "amount": amount }))
+        .collect();
+    let self_stake = chain_state
+        .candidates
+        .get(&address)
+        .map(|candidate| candidate.self_stake);
+
+    Json(serde_json::json!({
+        "success": true,
+        "validator": address,
+        "self_stake": self_stake,
+        "voting_power": owami_network::staking::voting_power(chain_state).get(&address),
+        "delegations": delegations,
+    }))
+}
+
 async fn get_block(
     State(state): State<SimpleState>,
     axum::extract::Path(block_index): axum::extract::Path<usize>,
 ) -> Json<GetBlockResponse> {
     let blockchain = state.blockchain.lock().unwrap();
 
-    match blockchain.get_block_by_height(block_index as u64) {
-        Some(block) => Json(GetBlockResponse {
+    match blockchain.lookup_block(block_index as u64) {
+        BlockLookup::Found(block) => Json(GetBlockResponse {
             success: true,
             block: Some(block.clone()),
+            pruned: false,
+            header: None,
             message: "Block retrieved successfully".to_string(),
         }),
-        None => Json(GetBlockResponse {
+        BlockLookup::Pruned(header) => Json(GetBlockResponse {
+            success: false,
+            block: None,
+            pruned: true,
+            header: Some(header),
+            message: format!(
+                "Block {} has been pruned: this node only keeps the last {} block bodies",
+                block_index,
+                blockchain.node_config().keep_blocks
+            ),
+        }),
+        BlockLookup::NotFound => Json(GetBlockResponse {
             success: false,
             block: None,
+            pruned: false,
+            header: None,
             message: "Block not found".to_string(),
         }),
     }
@@ -218,12 +758,28 @@ async fn get_block(
 
 async fn get_blocks(State(state): State<SimpleState>) -> Json<GetBlocksResponse> {
     let blockchain = state.blockchain.lock().unwrap();
-    let blocks = blockchain.blocks.clone();
+    let pruned_below = blockchain.pruned_below();
+    let blocks: Vec<Block> = blockchain
+        .blocks
+        .iter()
+        .filter(|block| !blockchain.is_body_pruned(block.header.height))
+        .cloned()
+        .collect();
+
+    let message = if pruned_below > 0 {
+        format!(
+            "Blocks retrieved successfully; blocks below height {} have been pruned",
+            pruned_below
+        )
+    } else {
+        "Blocks retrieved successfully".to_string()
+    };
 
     Json(GetBlocksResponse {
         success: true,
         blocks,
-        message: "Blocks retrieved successfully".to_string(),
+        pruned_below,
+        message,
     })
 }
 
@@ -254,10 +810,12 @@ async fn get_transactions(State(state): State<SimpleState>) -> Json<serde_json::
         }
     }
 
+    // Transactions in pruned blocks are no longer available on this node
     Json(serde_json::json!({
         "success": true,
         "transactions": transactions,
-        "total": transactions.len()
+        "total": transactions.len(),
+        "pruned_below": blockchain.pruned_below()
     }))
 }
 
@@ -275,6 +833,8 @@ async fn get_balance(
     }))
 }
 
+/// Test-token faucet: a transfer from the operator's balance, settled in
+/// the next block like any other transaction
 async fn mint_tokens(
     State(state): State<SimpleState>,
     Json(request): Json<serde_json::Value>,
@@ -289,11 +849,22 @@ async fn mint_tokens(
         }));
     }
 
-    let mut blockchain = state.blockchain.lock().unwrap();
-    match blockchain.mint(address.to_string(), amount) {
-        Ok(_) => Json(serde_json::json!({
+    let mut tx = Transaction::new(
+        hex::encode(state.operator_key.verifying_key().to_bytes()),
+        address.to_string(),
+        amount,
+        None,
+    );
+    let submitted = tx
+        .sign(&state.operator_key)
+        .map_err(|e| e.to_string())
+        .and_then(|_| queue_transaction(&state, &mut state.blockchain.lock().unwrap(), tx.clone()));
+
+    match submitted {
+        Ok(()) => Json(serde_json::json!({
             "success": true,
-            "message": "Tokens minted successfully",
+            "message": "Faucet transfer queued; the tokens arrive with the next block",
+            "transaction_hash": tx.hash(),
             "address": address,
             "amount": amount
         })),
@@ -304,6 +875,7 @@ async fn mint_tokens(
     }
 }
 
+#[allow(dead_code)]
 async fn serve_static(Path(file_path): Path<String>) -> impl IntoResponse {
     let path = std::path::Path::new("landing").join(&file_path);
     match std::fs::read_to_string(&path) {
@@ -426,17 +998,13 @@ async fn interact_dapp(
 ) -> Json<serde_json::Value> {
     let dapp_id = request["dapp_id"].as_str().unwrap_or("");
     let function_name = request["function_name"].as_str().unwrap_or("");
-    let args = request["args"]
-        .as_object()
-        .cloned()
-        .unwrap_or_else(|| serde_json::Map::new());
+    let _args = request["args"].as_object().cloned().unwrap_or_default();
 
-    let mut dapps = state.dapps.lock().unwrap();
+    let dapps = state.dapps.lock().unwrap();
     let dapp = dapps.get(dapp_id);
 
     match dapp {
         Some(dapp) => {
-            let code = dapp.code.clone();
             let result = match function_name {
                 "increment" => {
                     if let serde_json::Value::Object(state) = &dapp.state {
@@ -490,10 +1058,7 @@ fn main() {
         env::set_var("CONFIG_PATH", "config/testnet.toml");
     }
 
-    // Create data directory
-    std::fs::create_dir_all("data/rocksdb").unwrap_or(());
-
-    let config = match AppConfig::load() {
+    let mut config = match AppConfig::load() {
         Ok(cfg) => cfg,
         Err(_) => AppConfig {
             server: owami_network::config::ServerConfig {
@@ -526,25 +1091,124 @@ fn main() {
                     block_interval: 5,
                     stake_threshold: 1000000,
                     slashing_penalty: 5000,
+                    unbonding_period: 100,
+                    epoch_length: 100,
+                    fallback_timeout_multiplier: 2,
+                    evidence_reward: 500,
+                    missed_slot_window: 100,
+                    missed_slot_threshold: 50,
+                    jail_cooldown: 100,
+                    epoch_reward: 10000,
+                    commission_bps: 1000,
                 },
+                poa: Default::default(),
             },
+            node: Default::default(),
+            snapshots: Default::default(),
+            genesis: Default::default(),
+            governance: Default::default(),
+            p2p: Default::default(),
         },
     };
 
-    // Initialize blockchain
-    let blockchain = Arc::new(Mutex::new(owami_network::blockchain::Blockchain::new(
-        &config,
-    )));
+    // The validator is identified by a persistent operator key
+    // (SIGNING_KEY_PATH) and signs blocks with a separate consensus key,
+    // held locally (CONSENSUS_KEY_PATH) or by a signer process
+    // (REMOTE_SIGNER_SOCKET); a chain without configured genesis
+    // validators bonds this pair at genesis
+    let operator_key = KeyManager::new(None)
+        .and_then(|mut keys| keys.load_or_generate_key())
+        .expect("Failed to load operator key");
+    let mut signer = open_signer().expect("Failed to open consensus signer");
+    let consensus_key = signer.public_key().expect("Failed to load consensus key");
+    let signer = Arc::new(Mutex::new(signer));
+    if config.genesis.validators.is_empty() {
+        config.genesis.validators.push(GenesisValidator {
+            address: hex::encode(operator_key.verifying_key().to_bytes()),
+            stake: owami_network::state::DEFAULT_GENESIS_STAKE
+                .max(config.consensus.dpos.stake_threshold),
+            consensus_key: Some(consensus_key.clone()),
+        });
+    }
+    if config.consensus.poa.signers.is_empty() {
+        config.consensus.poa.signers.push(consensus_key);
+    }
+
+    // Initialize pure Rust database instead of RocksDB
+    let database = Arc::new(
+        PureRustDatabase::open_in(&config.database.data_dir)
+            .expect("Failed to initialize database"),
+    );
+
+    // Initialize blockchain, reloading any chain persisted by a previous run
+    let blockchain = Arc::new(Mutex::new(
+        Blockchain::open(&config, database.clone()).expect("Failed to open blockchain"),
+    ));
+
+    // Pruned nodes delete old block bodies in the background
+    if config.node.mode == NodeMode::Pruned {
+        let blockchain = blockchain.clone();
+        let interval = std::time::Duration::from_secs(config.node.prune_interval_secs.max(1));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            match blockchain.lock().unwrap().prune() {
+                Ok(0) => {}
+                Ok(count) => println!("Pruned {} old block bodies", count),
+                Err(e) => eprintln!("Block pruning failed: {}", e),
+            }
+        });
+    }
 
     // Initialize wallets
     let wallets = Arc::new(HashMap::new());
 
-    // Initialize pure Rust database instead of RocksDB
-    let database =
-        Arc::new(PureRustDatabase::new("data/rocksdb").expect("Failed to initialize database"));
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let (gossip, sync) = if config.p2p.enabled {
+        let (genesis_hash, height) = {
+            let blockchain = blockchain.lock().unwrap();
+            (
+                blockchain.genesis_hash().to_string(),
+                blockchain.get_block_height(),
+            )
+        };
+        // Kept apart from the operator and consensus keys: it only
+        // identifies this node to its peers
+        let node_key = KeyManager::new(Some(
+            env::var("NODE_KEY_PATH").unwrap_or_else(|_| "./keys/node.key".to_string()),
+        ))
+        .and_then(|mut keys| keys.load_or_generate_key())
+        .expect("Failed to load node key");
+        let (network, events) = runtime
+            .block_on(NetworkService::start(
+                config.p2p.clone(),
+                node_key,
+                genesis_hash,
+                height,
+            ))
+            .expect("Failed to start peer layer");
+        println!(
+            "Peer layer listening on {} as node {}",
+            network.local_addr(),
+            network.node_id()
+        );
+        let gossip = Arc::new(Gossip::new(network, blockchain.clone()).with_voter(signer.clone()));
+        let sync = Arc::new(BlockSync::new(gossip.clone()));
+        runtime.spawn(sync.clone().run(events));
+        (Some(gossip), Some(sync))
+    } else {
+        (None, None)
+    };
 
     let state = SimpleState {
         blockchain: blockchain.clone(),
+        operator_key: Arc::new(operator_key),
+        signer,
+        gossip,
+        sync,
         wallets,
         database,
         dapps: Arc::new(std::sync::Mutex::new(HashMap::new())),
@@ -562,8 +1226,43 @@ fn main() {
         .route("/health", get(health_check))
         .route("/api/health", get(health_check))
         .route("/api/blockchain/info", get(blockchain_info))
+        .route("/api/network/peers", get(get_peers))
         .route("/api/blockchain/mine", post(mine_block))
         .route("/api/blockchain/transactions", post(add_transaction))
+        .route(
+            "/api/blockchain/transactions/signed",
+            post(submit_signed_transaction),
+        )
+        .route("/api/blockchain/commits", post(submit_commit_vote))
+        .route(
+            "/api/blockchain/commits/:height",
+            get(get_commit_certificate),
+        )
+        .route("/api/validators", get(get_validators))
+        .route(
+            "/api/validators/rotate-consensus-key",
+            post(rotate_consensus_key),
+        )
+        .route(
+            "/api/delegations/delegator/:address",
+            get(get_delegations_by_delegator),
+        )
+        .route(
+            "/api/delegations/validator/:address",
+            get(get_delegations_by_validator),
+        )
+        .route(
+            "/api/rewards/validator/:address",
+            get(get_validator_rewards),
+        )
+        .route(
+            "/api/rewards/delegator/:address",
+            get(get_delegator_rewards),
+        )
+        .route("/api/vesting/:address", get(get_vesting_status))
+        .route("/api/governance/proposals", get(get_proposals))
+        .route("/api/governance/proposals/:id", get(get_proposal))
+        .route("/api/governance/params", get(get_governance_params))
         .route("/api/blockchain/blocks/:block_index", get(get_block))
         .route("/api/blockchain/blocks", get(get_blocks))
         .route("/api/wallet/create", get(create_wallet))
@@ -573,6 +1272,9 @@ fn main() {
         .route("/api/dapps/deploy", post(deploy_dapp))
         .route("/api/dapps/interact", post(interact_dapp))
         .route("/api/dapps", get(get_dapps))
+        .route("/mobile/sync", post(mobile::mobile_sync))
+        .route("/mobile/verify", post(mobile::verify_transaction))
+        .route("/mobile/light", post(mobile::light_client_handler))
         .nest_service("/css", ServeDir::new("landing/css"))
         .nest_service("/js", ServeDir::new("landing/js"))
         .layer(cors)
@@ -580,7 +1282,7 @@ fn main() {
         .fallback(handle_404);
 
     // Start server
-    let server_host = env::var("HOST").unwrap_or_else(|_| config.server.host);
+    let server_host = env::var("HOST").unwrap_or(config.server.host);
     let server_port = env::var("PORT")
         .unwrap_or_else(|_| config.server.port.to_string())
         .parse()
@@ -591,16 +1293,11 @@ fn main() {
         server_host, server_port
     );
 
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(async {
-            let listener =
-                tokio::net::TcpListener::bind(format!("{}:{}", server_host, server_port))
-                    .await
-                    .unwrap();
-
-            axum::serve(listener, app).await.unwrap();
-        });
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(format!("{}:{}", server_host, server_port))
+            .await
+            .unwrap();
+
+        axum::serve(listener, app).await.unwrap();
+    });
 }
@@ -0,0 +1,177 @@
+//! Binary Merkle tree with inclusion proofs. Leaves and inner nodes are
+//! hashed under different one-byte prefixes, so an inner node can never
+//! pass for a leaf. A node left without a sibling moves up a level
+//! unchanged instead of being paired with a copy of itself, so no two
+//! leaf lists share a root.
+
+use serde::{Deserialize, Serialize};
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Root of a tree with no leaves
+pub const EMPTY_ROOT: Hash = [0u8; 32];
+
+pub fn hash_leaf(data: &[u8]) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(data);
+    *hasher.finalize().as_bytes()
+}
+
+pub fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>, // hashed leaves first, the root last
+}
+
+impl MerkleTree {
+    pub fn new<T: AsRef<[u8]>>(leaves: &[T]) -> Self {
+        let mut levels = vec![leaves
+            .iter()
+            .map(|leaf| hash_leaf(leaf.as_ref()))
+            .collect::<Vec<_>>()];
+        while levels.last().is_some_and(|level| level.len() > 1) {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_node(left, right),
+                    [lone] => *lone,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn root(&self) -> Hash {
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .copied()
+            .unwrap_or(EMPTY_ROOT)
+    }
+
+    /// Proof that the leaf at `index` is in the tree
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.leaf_count() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            position /= 2;
+        }
+        Some(MerkleProof {
+            index,
+            leaf_count: self.leaf_count(),
+            siblings,
+        })
+    }
+}
+
+/// Sibling hashes from a leaf up to the root. The leaf's position and the
+/// leaf count say on which side each sibling goes and at which levels the
+/// path has no sibling.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub leaf_count: usize,
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    /// Root of the tree this proof places `leaf` in; `None` if the proof
+    /// does not fit its leaf count
+    pub fn root_for(&self, leaf: &[u8]) -> Option<Hash> {
+        if self.index >= self.leaf_count {
+            return None;
+        }
+        let mut hash = hash_leaf(leaf);
+        let mut siblings = self.siblings.iter();
+        let (mut position, mut width) = (self.index, self.leaf_count);
+        while width > 1 {
+            if position % 2 == 1 {
+                hash = hash_node(siblings.next()?, &hash);
+            } else if position + 1 < width {
+                hash = hash_node(&hash, siblings.next()?);
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        siblings.next().is_none().then_some(hash)
+    }
+
+    pub fn verify(&self, leaf: &[u8], root: &Hash) -> bool {
+        self.root_for(leaf).as_ref() == Some(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i]).collect()
+    }
+
+    #[test]
+    fn test_every_leaf_proves_against_the_root() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let tree = MerkleTree::new(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert!(proof.verify(leaf, &tree.root()), "{} of {}", index, count);
+                assert!(!proof.verify(&[99], &tree.root()));
+            }
+            assert!(tree.proof(count as usize).is_none());
+        }
+        assert_eq!(MerkleTree::new::<Vec<u8>>(&[]).root(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_tree_is_not_malleable() {
+        // Duplicating the last leaf used to give the same root
+        let three = MerkleTree::new(&leaves(3));
+        let mut padded = leaves(3);
+        padded.push(vec![2]);
+        assert_ne!(three.root(), MerkleTree::new(&padded).root());
+
+        // An inner node cannot be passed off as a leaf
+        let two = MerkleTree::new(&leaves(2));
+        let forged: Vec<u8> = [hash_leaf(&[0]), hash_leaf(&[1])].concat();
+        assert_ne!(MerkleTree::new(&[forged]).root(), two.root());
+    }
+
+    #[test]
+    fn test_proof_must_match_its_position() {
+        let leaves = leaves(5);
+        let tree = MerkleTree::new(&leaves);
+        let mut proof = tree.proof(1).unwrap();
+        proof.index = 0;
+        assert!(!proof.verify(&leaves[1], &tree.root()));
+
+        let mut proof = tree.proof(4).unwrap();
+        proof.siblings.push([0u8; 32]);
+        assert!(proof.root_for(&leaves[4]).is_none());
+    }
+}
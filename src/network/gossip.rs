@@ -0,0 +1,649 @@
+//! Flooding gossip for new blocks, transactions and commit votes. Each
+//! message is checked against the local chain before it is relayed to
+//! every other peer; a seen-cache stops it from circulating twice. A block
+//! that arrives before its parent waits for it. Peers that send invalid
+//! data lose reputation, and are banned once it runs out.
+//! A validator node also votes for every block it adds, whether it mined
+//! it, received it by gossip or synced it, and announces the vote.
+
+use super::p2p::{NetworkEvent, NetworkService, PeerId};
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::consensus::dpos::NetworkMessage;
+use crate::consensus::engine;
+use crate::consensus::finality::CommitVote;
+use crate::signer::BlockSigner;
+use crate::transaction::Transaction;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// Message ids remembered for deduplication
+pub const SEEN_CACHE_SIZE: usize = 10_000;
+
+pub const INVALID_TRANSACTION_PENALTY: u32 = 20;
+pub const INVALID_BLOCK_PENALTY: u32 = 50;
+pub const INVALID_VOTE_PENALTY: u32 = 20;
+
+/// Votes held for blocks this node has not received yet
+pub const MAX_EARLY_VOTES: usize = 1_000;
+
+/// Blocks held until their parent arrives
+pub const MAX_ORPHAN_BLOCKS: usize = 64;
+
+/// Bounded set of recently seen message ids; the oldest are forgotten first
+pub struct SeenCache {
+    capacity: usize,
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl SeenCache {
+    pub fn new(capacity: usize) -> Self {
+        SeenCache {
+            capacity: capacity.max(1),
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    /// Remember `id`; false if it was already known
+    pub fn insert(&mut self, id: String) -> bool {
+        if !self.ids.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+}
+
+fn block_id(hash: &str) -> String {
+    format!("block:{}", hash)
+}
+
+fn transaction_id(hash: &str) -> String {
+    format!("tx:{}", hash)
+}
+
+fn vote_id(vote: &CommitVote) -> String {
+    format!(
+        "vote:{}:{}:{}",
+        vote.validator, vote.height, vote.block_hash
+    )
+}
+
+/// A gossiped block above the tip's successor, with the peer that sent it
+struct Orphan {
+    peer: PeerId,
+    hash: String,
+    block: Block,
+}
+
+pub struct Gossip {
+    network: NetworkService,
+    blockchain: Arc<Mutex<Blockchain>>,
+    seen: Mutex<SeenCache>,
+    voter: Option<Arc<Mutex<Box<dyn BlockSigner>>>>, // signs this validator's commit votes
+    early_votes: Mutex<Vec<CommitVote>>,             // for blocks not received yet
+    orphans: Mutex<Vec<Orphan>>,                     // blocks whose parent has not arrived
+}
+
+impl Gossip {
+    pub fn new(network: NetworkService, blockchain: Arc<Mutex<Blockchain>>) -> Self {
+        Gossip {
+            network,
+            blockchain,
+            seen: Mutex::new(SeenCache::new(SEEN_CACHE_SIZE)),
+            voter: None,
+            early_votes: Mutex::new(Vec::new()),
+            orphans: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Vote with `signer` for each block added to the chain
+    pub fn with_voter(mut self, signer: Arc<Mutex<Box<dyn BlockSigner>>>) -> Self {
+        self.voter = Some(signer);
+        self
+    }
+
+    pub fn network(&self) -> &NetworkService {
+        &self.network
+    }
+
+    pub fn blockchain(&self) -> &Arc<Mutex<Blockchain>> {
+        &self.blockchain
+    }
+
+    /// Announce a transaction this node accepted itself
+    pub fn publish_transaction(&self, transaction: &Transaction) {
+        if self.mark_seen(transaction_id(&transaction.hash())) {
+            self.network
+                .broadcast(&NetworkMessage::NewTransaction(transaction.clone()), None);
+        }
+    }
+
+    /// Announce a block this node added to its chain itself, and this
+    /// node's vote for it
+    pub fn publish_block(&self, block: &Block) {
+        if self.note_block(block) {
+            self.network
+                .broadcast(&NetworkMessage::NewBlock(block.clone()), None);
+            self.on_new_tip();
+        }
+    }
+
+    /// Vote for the tip if this node is an active validator there, count
+    /// votes that were waiting for it and connect any block waiting for it
+    pub(super) fn on_new_tip(&self) {
+        loop {
+            self.retry_early_votes();
+            self.vote_on_tip();
+            if !self.connect_orphan() {
+                return;
+            }
+        }
+    }
+
+    fn vote_on_tip(&self) {
+        let Some(voter) = &self.voter else {
+            return;
+        };
+        let voted = {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            let mut signer = voter.lock().unwrap();
+            match signer.public_key() {
+                Ok(key) if !blockchain.may_vote_on_tip(&key) => return,
+                Ok(_) => blockchain.sign_tip_vote(signer.as_mut()).and_then(|vote| {
+                    blockchain.add_commit_vote(vote.clone())?;
+                    Ok(vote)
+                }),
+                Err(e) => Err(e),
+            }
+        };
+        match voted {
+            Ok(vote) => {
+                self.mark_seen(vote_id(&vote));
+                self.network
+                    .broadcast(&NetworkMessage::CommitVote(vote), None);
+            }
+            Err(e) => debug!("Not voting for the tip: {}", e),
+        }
+    }
+
+    /// Record a block added to the chain, so neither it nor its
+    /// transactions are taken from gossip again; false if already known
+    pub(super) fn note_block(&self, block: &Block) -> bool {
+        self.network.set_height(block.header.height);
+        let mut seen = self.seen.lock().unwrap();
+        for transaction in &block.transactions {
+            seen.insert(transaction_id(&transaction.hash()));
+        }
+        seen.insert(block_id(&block.hash()))
+    }
+
+    /// Validate and relay gossip carried by `event`; other events are ignored
+    pub fn handle(&self, event: &NetworkEvent) {
+        match event {
+            NetworkEvent::Message {
+                peer,
+                message: NetworkMessage::NewTransaction(transaction),
+            } => self.on_transaction(*peer, transaction),
+            NetworkEvent::Message {
+                peer,
+                message: NetworkMessage::NewBlock(block),
+            } => self.on_block(*peer, block),
+            NetworkEvent::Message {
+                peer,
+                message: NetworkMessage::CommitVote(vote),
+            } => self.on_vote(*peer, vote),
+            _ => {}
+        }
+    }
+
+    fn on_transaction(&self, peer: PeerId, transaction: &Transaction) {
+        let hash = transaction.hash();
+        if !self.mark_seen(transaction_id(&hash)) {
+            return;
+        }
+        let added = self
+            .blockchain
+            .lock()
+            .unwrap()
+            .add_transaction(transaction.clone());
+        match added {
+            Ok(()) => {
+                self.network.broadcast(
+                    &NetworkMessage::NewTransaction(transaction.clone()),
+                    Some(peer),
+                );
+            }
+            Err(e) => self.network.penalize(
+                peer,
+                INVALID_TRANSACTION_PENALTY,
+                &format!("an invalid transaction {}: {}", hash, e),
+            ),
+        }
+    }
+
+    /// A block is only marked seen once it is added or held as an orphan,
+    /// so one that fails for now can still be taken from a later relay
+    fn on_block(&self, peer: PeerId, block: &Block) {
+        let hash = block.hash();
+        if self.seen.lock().unwrap().contains(&block_id(&hash)) {
+            return;
+        }
+        let height = block.header.height;
+        if let Err(e) = block.check_integrity() {
+            self.network.penalize(
+                peer,
+                INVALID_BLOCK_PENALTY,
+                &format!("an invalid block {}: {}", height, e),
+            );
+            return;
+        }
+
+        let tip_height = self.blockchain.lock().unwrap().get_block_height();
+        if height > tip_height + 1 {
+            self.hold_orphan(peer, hash, block);
+        } else if self.connect_block(peer, block) {
+            self.on_new_tip();
+        }
+    }
+
+    /// Add `block` from `peer` to the chain and relay it; false if it was
+    /// not added
+    fn connect_block(&self, peer: PeerId, block: &Block) -> bool {
+        let height = block.header.height;
+        let added = {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            let tip = blockchain.get_latest_block();
+            let extends_tip =
+                height == tip.header.height + 1 && block.header.previous_hash == tip.hash();
+            // A competing block for the tip's height goes through fork choice
+            let competes = height == tip.header.height && block.hash() != tip.hash();
+            if !extends_tip && !competes {
+                debug!(
+                    "Ignoring block {} from peer {}: it does not connect to tip {}",
+                    height, peer, tip.header.height
+                );
+                return false;
+            }
+            blockchain.add_block(block.clone())
+        };
+        match added {
+            Ok(()) => {
+                self.note_block(block);
+                self.network
+                    .broadcast(&NetworkMessage::NewBlock(block.clone()), Some(peer));
+                true
+            }
+            // The peer's clock may simply be ahead of ours
+            Err(e) if engine::is_ahead_of_clock(&e) => {
+                debug!("Not taking block {} from peer {} yet: {}", height, peer, e);
+                false
+            }
+            // Losing fork choice or failing to store is not the peer's fault
+            Err(e) if e.starts_with("Invalid block") => {
+                self.network.penalize(
+                    peer,
+                    INVALID_BLOCK_PENALTY,
+                    &format!("an invalid block {}: {}", height, e),
+                );
+                false
+            }
+            Err(e) => {
+                debug!("Not relaying block {} from peer {}: {}", height, peer, e);
+                false
+            }
+        }
+    }
+
+    fn hold_orphan(&self, peer: PeerId, hash: String, block: &Block) {
+        let mut orphans = self.orphans.lock().unwrap();
+        if orphans.len() >= MAX_ORPHAN_BLOCKS {
+            debug!(
+                "Dropping block {} from peer {}: too many blocks wait for their parent",
+                block.header.height, peer
+            );
+            return;
+        }
+        self.mark_seen(block_id(&hash));
+        orphans.push(Orphan {
+            peer,
+            hash,
+            block: block.clone(),
+        });
+    }
+
+    /// Connect a held block whose parent is now the tip, dropping those the
+    /// chain has passed; false if none connected
+    fn connect_orphan(&self) -> bool {
+        loop {
+            let (tip_height, tip_hash) = {
+                let blockchain = self.blockchain.lock().unwrap();
+                let tip = blockchain.get_latest_block();
+                (tip.header.height, tip.hash())
+            };
+            let next = {
+                let mut orphans = self.orphans.lock().unwrap();
+                orphans.retain(|orphan| orphan.block.header.height > tip_height);
+                orphans
+                    .iter()
+                    .position(|orphan| orphan.block.header.previous_hash == tip_hash)
+                    .map(|index| orphans.swap_remove(index))
+            };
+            let Some(orphan) = next else {
+                return false;
+            };
+            debug!(
+                "Connecting block {} ({}) that arrived before its parent",
+                orphan.block.header.height, orphan.hash
+            );
+            if self.connect_block(orphan.peer, &orphan.block) {
+                return true;
+            }
+        }
+    }
+
+    fn on_vote(&self, peer: PeerId, vote: &CommitVote) {
+        if !self.mark_seen(vote_id(vote)) {
+            return;
+        }
+        if let Err(e) = vote.verify() {
+            self.network.penalize(
+                peer,
+                INVALID_VOTE_PENALTY,
+                &format!("an invalid commit vote: {}", e),
+            );
+            return;
+        }
+        let added = {
+            let mut blockchain = self.blockchain.lock().unwrap();
+            if vote.height > blockchain.get_block_height() {
+                self.hold_early_vote(vote.clone());
+                return;
+            }
+            blockchain.add_commit_vote(vote.clone())
+        };
+        match added {
+            Ok(_) => {
+                self.network
+                    .broadcast(&NetworkMessage::CommitVote(vote.clone()), Some(peer));
+            }
+            // Late, for another fork or from outside the active set: the
+            // signature is valid, so the peer only passed it on
+            Err(e) => debug!("Not relaying commit vote from peer {}: {}", peer, e),
+        }
+    }
+
+    fn hold_early_vote(&self, vote: CommitVote) {
+        let mut early = self.early_votes.lock().unwrap();
+        if early.len() < MAX_EARLY_VOTES {
+            early.push(vote);
+        } else {
+            debug!("Dropping early commit vote for block {}", vote.height);
+        }
+    }
+
+    /// Count held votes for blocks the chain now has
+    fn retry_early_votes(&self) {
+        let tip = self.blockchain.lock().unwrap().get_block_height();
+        let ready: Vec<CommitVote> = {
+            let mut early = self.early_votes.lock().unwrap();
+            let (ready, waiting) = early.drain(..).partition(|vote| vote.height <= tip);
+            *early = waiting;
+            ready
+        };
+        for vote in ready {
+            let added = self
+                .blockchain
+                .lock()
+                .unwrap()
+                .add_commit_vote(vote.clone());
+            if added.is_ok() {
+                self.network
+                    .broadcast(&NetworkMessage::CommitVote(vote), None);
+            }
+        }
+    }
+
+    fn mark_seen(&self, id: String) -> bool {
+        self.seen.lock().unwrap().insert(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::P2pConfig;
+    use crate::crypto_utils::generate_keypair;
+    use crate::simulation::GENESIS_TIMESTAMP;
+    use crate::test_support::{test_chain, test_config, validator_key};
+    use std::time::Duration;
+
+    struct TestNode {
+        gossip: Arc<Gossip>,
+        blockchain: Arc<Mutex<Blockchain>>,
+    }
+
+    /// Chain whose validator also holds a balance to send
+    fn chain() -> Blockchain {
+        let mut config = test_config();
+        config.genesis.balances.insert(
+            hex::encode(validator_key().verifying_key().to_bytes()),
+            1_000,
+        );
+        test_chain(config)
+    }
+
+    async fn node() -> TestNode {
+        let blockchain = Arc::new(Mutex::new(chain()));
+        let genesis_hash = blockchain.lock().unwrap().genesis_hash().to_string();
+        let config = P2pConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            ..P2pConfig::default()
+        };
+        let (network, mut events) =
+            NetworkService::start(config, generate_keypair().0, genesis_hash, 0)
+                .await
+                .unwrap();
+        let gossip = Arc::new(Gossip::new(network, blockchain.clone()));
+        let handler = gossip.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                handler.handle(&event);
+            }
+        });
+        TestNode { gossip, blockchain }
+    }
+
+    /// Wait up to five seconds for `condition` to hold
+    async fn eventually(condition: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    fn transfer(amount: u64) -> Transaction {
+        let key = validator_key();
+        let mut transaction = Transaction::new(
+            hex::encode(key.verifying_key().to_bytes()),
+            "bob".to_string(),
+            amount,
+            None,
+        );
+        transaction.timestamp = GENESIS_TIMESTAMP;
+        transaction.sign(&key).unwrap();
+        transaction
+    }
+
+    #[test]
+    fn test_seen_cache_forgets_oldest() {
+        let mut seen = SeenCache::new(2);
+        assert!(seen.insert("a".to_string()));
+        assert!(!seen.insert("a".to_string()));
+        assert!(seen.insert("b".to_string()));
+        assert!(seen.insert("c".to_string()));
+        assert!(!seen.contains("a"));
+        assert!(seen.contains("b") && seen.contains("c"));
+    }
+
+    #[tokio::test]
+    async fn test_blocks_and_transactions_cross_a_line_of_peers() {
+        let (a, b, c) = (node().await, node().await, node().await);
+        a.gossip
+            .network()
+            .connect(b.gossip.network().local_addr())
+            .await
+            .unwrap();
+        b.gossip
+            .network()
+            .connect(c.gossip.network().local_addr())
+            .await
+            .unwrap();
+
+        // A transaction submitted at c reaches a through b
+        let transaction = transfer(5);
+        c.blockchain
+            .lock()
+            .unwrap()
+            .add_transaction(transaction.clone())
+            .unwrap();
+        c.gossip.publish_transaction(&transaction);
+        assert!(eventually(|| a.blockchain.lock().unwrap().pending_transactions.len() == 1).await);
+        assert_eq!(b.blockchain.lock().unwrap().pending_transactions.len(), 1);
+
+        // a produces a block with it, which settles the pool everywhere
+        let block = a
+            .blockchain
+            .lock()
+            .unwrap()
+            .mine_block_at(&mut validator_key(), GENESIS_TIMESTAMP + 5)
+            .unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        a.gossip.publish_block(&block);
+        for other in [&b, &c] {
+            assert!(eventually(|| other.blockchain.lock().unwrap().get_block_height() == 1).await);
+            let blockchain = other.blockchain.lock().unwrap();
+            assert_eq!(blockchain.get_latest_block().hash(), block.hash());
+            assert!(blockchain.pending_transactions.is_empty());
+        }
+        // Whichever peer relayed the block to c is now known to be at its height
+        assert!(
+            eventually(|| c
+                .gossip
+                .network()
+                .peers()
+                .iter()
+                .any(|peer| peer.height == 1))
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocks_out_of_order_or_ahead_of_the_clock_are_not_lost() {
+        let (source, node) = (node().await, node().await);
+        source
+            .gossip
+            .network()
+            .connect(node.gossip.network().local_addr())
+            .await
+            .unwrap();
+        let blocks: Vec<Block> = (1..=2)
+            .map(|height| {
+                source
+                    .blockchain
+                    .lock()
+                    .unwrap()
+                    .mine_block_at(&mut validator_key(), GENESIS_TIMESTAMP + 5 * height)
+                    .unwrap()
+            })
+            .collect();
+
+        // The child arrives first and waits for its parent
+        for block in blocks.iter().rev() {
+            source
+                .gossip
+                .network()
+                .broadcast(&NetworkMessage::NewBlock(block.clone()), None);
+        }
+        assert!(eventually(|| node.blockchain.lock().unwrap().get_block_height() == 2).await);
+        assert!(node.gossip.orphans.lock().unwrap().is_empty());
+
+        // A block from a clock running ahead is neither taken nor held
+        // against the peer, and a later relay of it is considered again
+        let now = chrono::Utc::now().timestamp() as u64;
+        let early = Block::new_at(
+            3,
+            blocks[1].hash(),
+            Vec::new(),
+            now + 600,
+            0,
+            &validator_key(),
+        );
+        for _ in 0..2 {
+            source
+                .gossip
+                .network()
+                .broadcast(&NetworkMessage::NewBlock(early.clone()), None);
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(node.blockchain.lock().unwrap().get_block_height(), 2);
+        assert!(!node
+            .gossip
+            .seen
+            .lock()
+            .unwrap()
+            .contains(&block_id(&early.hash())));
+        assert_eq!(node.gossip.network().peers()[0].score, 0);
+    }
+
+    #[tokio::test]
+    async fn test_peer_relaying_invalid_data_is_dropped() {
+        let (honest, liar) = (node().await, node().await);
+        liar.gossip
+            .network()
+            .connect(honest.gossip.network().local_addr())
+            .await
+            .unwrap();
+        assert_eq!(honest.gossip.network().peers()[0].score, 0);
+
+        // Tampering breaks the signature; distinct amounts defeat the seen-cache
+        for amount in 1..=5 {
+            let mut forged = transfer(amount);
+            forged.amount += 100;
+            liar.gossip
+                .network()
+                .broadcast(&NetworkMessage::NewTransaction(forged), None);
+        }
+        assert!(eventually(|| honest.gossip.network().peers().is_empty()).await);
+        assert!(honest
+            .blockchain
+            .lock()
+            .unwrap()
+            .pending_transactions
+            .is_empty());
+
+        // The liar is banned, so the honest node hangs up on it
+        assert!(liar
+            .gossip
+            .network()
+            .connect(honest.gossip.network().local_addr())
+            .await
+            .is_err());
+        assert!(honest.gossip.network().peers().is_empty());
+    }
+}
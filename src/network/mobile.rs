@@ -1,20 +1,38 @@
-use crate::blockchain::Blockchain;
-use crate::consensus::dpos::{self, LightClientRequest, LightClientResponse};
+use crate::block::BlockHeader;
+use crate::blockchain::{BlockLookup, Blockchain};
+use crate::consensus::dpos::{self, LightClientRequest, LightClientResponse, ValidatorInfo};
+use crate::network::sync::MAX_HEADERS_PER_RESPONSE;
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::Json;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::{Arc, Mutex};
+
+/// Headers sent by `mobile_sync` when the client does not say
+pub const DEFAULT_MOBILE_HEADERS: u32 = 100;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MobileSyncRequest {
-    pub last_known_height: Option<u64>,
+    pub last_known_height: Option<u64>, // none for a client starting from genesis
+    #[serde(default)]
+    pub max_headers: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MobileSyncResponse {
-    pub status: String,
+    pub status: String, // "synced" once the headers reach the tip, "behind" otherwise
+    pub current_height: u64,
+    pub headers: Vec<BlockHeader>,
+    pub validator_sets: Vec<ValidatorSet>, // each set the headers were verified against
+}
+
+/// Active set that verified the blocks from `start_height` until the next
+/// set's start. A new set starts at each epoch, and whenever a validator is
+/// jailed or slashed within one.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ValidatorSet {
+    pub start_height: u64,
+    pub validators: Vec<ValidatorInfo>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,31 +41,292 @@ pub struct TransactionProofRequest {
     pub block_height: u64,
 }
 
+/// Merkle path from the transaction to `header.merkle_root`. The leaf is
+/// the transaction hash; `proof` lists the sibling hashes from the leaf up,
+/// and `index` and `leaf_count` place them left or right.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TransactionProofResponse {
     pub proof: Vec<String>,
     pub verified: bool,
+    pub index: usize,
+    pub leaf_count: usize,
+    pub header: BlockHeader,
 }
 
+/// Headers after the client's height, with the active sets to check their
+/// signatures against. Headers stop early at one whose set this node does
+/// not hold.
 pub async fn mobile_sync(
-    State(_blockchain): State<Arc<Mutex<Blockchain>>>,
-    Json(_request): Json<MobileSyncRequest>,
+    State(blockchain): State<Arc<Mutex<Blockchain>>>,
+    Json(request): Json<MobileSyncRequest>,
 ) -> Result<Json<MobileSyncResponse>, StatusCode> {
-    Err(StatusCode::NOT_IMPLEMENTED)
+    let blockchain = blockchain.lock().unwrap();
+    let current_height = blockchain.get_block_height();
+    let from = request.last_known_height.map_or(0, |height| height + 1);
+    let count = request
+        .max_headers
+        .unwrap_or(DEFAULT_MOBILE_HEADERS)
+        .min(MAX_HEADERS_PER_RESPONSE);
+
+    let to = from.saturating_add(count as u64);
+    let sets = blockchain
+        .validator_sets(from, to.saturating_sub(1))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let first_set = sets.first().map(|(start_height, _)| *start_height);
+    let mut headers = Vec::new();
+    for height in from..to {
+        if first_set.is_none_or(|start_height| start_height > height) {
+            break;
+        }
+        let header = match blockchain.lookup_block(height) {
+            BlockLookup::Found(block) => block.header.clone(),
+            BlockLookup::Pruned(header) => header,
+            BlockLookup::NotFound => break,
+        };
+        headers.push(header);
+    }
+    let last = headers.last().map(|header| header.height);
+    let validator_sets = sets
+        .into_iter()
+        .filter(|(start_height, _)| last.is_some_and(|last| *start_height <= last))
+        .map(|(start_height, validators)| ValidatorSet {
+            start_height,
+            validators: validators.iter().map(ValidatorInfo::from).collect(),
+        })
+        .collect();
+    let synced = headers.last().map_or(from > current_height, |header| {
+        header.height == current_height
+    });
+    Ok(Json(MobileSyncResponse {
+        status: if synced { "synced" } else { "behind" }.to_string(),
+        current_height,
+        headers,
+        validator_sets,
+    }))
 }
 
+/// Inclusion proof for a transaction in the block at `block_height`.
+/// Unknown blocks and transactions are `404`; a block whose body this node
+/// has pruned is `410`.
 pub async fn verify_transaction(
-    State(_blockchain): State<Arc<Mutex<Blockchain>>>,
-    Json(_request): Json<TransactionProofRequest>,
+    State(blockchain): State<Arc<Mutex<Blockchain>>>,
+    Json(request): Json<TransactionProofRequest>,
 ) -> Result<Json<TransactionProofResponse>, StatusCode> {
-    Err(StatusCode::NOT_IMPLEMENTED)
+    let blockchain = blockchain.lock().unwrap();
+    let block = match blockchain.lookup_block(request.block_height) {
+        BlockLookup::Found(block) => block,
+        BlockLookup::Pruned(_) => return Err(StatusCode::GONE),
+        BlockLookup::NotFound => return Err(StatusCode::NOT_FOUND),
+    };
+    let proof = block
+        .transaction_proof(&request.transaction_hash)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let leaf = hex::decode(&request.transaction_hash).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let verified = hex::decode(&block.header.merkle_root)
+        .ok()
+        .and_then(|root| <[u8; 32]>::try_from(root).ok())
+        .is_some_and(|root| proof.verify(&leaf, &root));
+    Ok(Json(TransactionProofResponse {
+        proof: proof.siblings.iter().map(hex::encode).collect(),
+        verified,
+        index: proof.index,
+        leaf_count: proof.leaf_count,
+        header: block.header.clone(),
+    }))
 }
 
 pub async fn light_client_handler(
     State(blockchain): State<Arc<Mutex<Blockchain>>>,
     Json(request): Json<LightClientRequest>,
 ) -> Json<LightClientResponse> {
-    let blockchain = blockchain.lock().await;
-    let response = dpos::handle_light_request(request, &blockchain.consensus);
+    let blockchain = blockchain.lock().unwrap();
+    let response = dpos::handle_light_request(request, &blockchain.state.validators);
     Json(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::dpos::schedule_index;
+    use crate::merkle::MerkleProof;
+    use crate::simulation::{bond_genesis_validators, GENESIS_TIMESTAMP};
+    use crate::test_support::{mine_scheduled, test_chain, test_config, validator_key};
+    use crate::transaction::Transaction;
+    use ed25519_dalek::SigningKey;
+
+    /// Chain with one block holding three transfers
+    fn chain() -> (Arc<Mutex<Blockchain>>, Vec<Transaction>) {
+        let key = validator_key();
+        let sender = hex::encode(key.verifying_key().to_bytes());
+        let mut config = test_config();
+        config.genesis.balances.insert(sender.clone(), 1000);
+        let mut blockchain = Blockchain::new(&config);
+        let mut transactions = Vec::new();
+        for i in 0..3 {
+            let mut tx = Transaction::new(sender.clone(), format!("recipient-{}", i), 10, None);
+            tx.sign(&key).unwrap();
+            blockchain.add_transaction(tx.clone()).unwrap();
+            transactions.push(tx);
+        }
+        blockchain.mine_block(&key).unwrap();
+        (Arc::new(Mutex::new(blockchain)), transactions)
+    }
+
+    #[tokio::test]
+    async fn test_proof_checks_against_the_header() {
+        let (blockchain, transactions) = chain();
+        let request = TransactionProofRequest {
+            transaction_hash: transactions[2].hash(),
+            block_height: 1,
+        };
+        let Json(response) = verify_transaction(State(blockchain.clone()), Json(request))
+            .await
+            .unwrap();
+        assert!(response.verified);
+
+        // What a phone holding only the header does
+        let proof = MerkleProof {
+            index: response.index,
+            leaf_count: response.leaf_count,
+            siblings: response
+                .proof
+                .iter()
+                .map(|hash| hex::decode(hash).unwrap().try_into().unwrap())
+                .collect(),
+        };
+        let root: [u8; 32] = hex::decode(&response.header.merkle_root)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert!(proof.verify(&hex::decode(transactions[2].hash()).unwrap(), &root));
+        assert!(!proof.verify(&hex::decode(transactions[1].hash()).unwrap(), &root));
+
+        let missing = TransactionProofRequest {
+            transaction_hash: transactions[0].hash(),
+            block_height: 0,
+        };
+        assert_eq!(
+            verify_transaction(State(blockchain), Json(missing))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mobile_sync_returns_headers_after_the_client() {
+        let (blockchain, _) = chain();
+        let request = MobileSyncRequest {
+            last_known_height: None,
+            max_headers: Some(1),
+        };
+        let Json(response) = mobile_sync(State(blockchain.clone()), Json(request))
+            .await
+            .unwrap();
+        assert_eq!(response.status, "behind");
+        assert_eq!(response.headers.len(), 1);
+        assert_eq!(response.validator_sets.len(), 1);
+        assert_eq!(response.validator_sets[0].start_height, 0);
+        assert_eq!(response.validator_sets[0].validators.len(), 1);
+
+        let request = MobileSyncRequest {
+            last_known_height: Some(0),
+            max_headers: None,
+        };
+        let Json(response) = mobile_sync(State(blockchain), Json(request)).await.unwrap();
+        assert_eq!(response.status, "synced");
+        assert_eq!(response.current_height, 1);
+        assert_eq!(response.headers[0].height, 1);
+    }
+
+    #[tokio::test]
+    async fn test_mobile_sync_sends_the_set_of_each_epoch() {
+        let mut config = test_config();
+        config.consensus.dpos.epoch_length = 2;
+        let mut blockchain = Blockchain::new(&config);
+        for _ in 0..3 {
+            blockchain.mine_block(&validator_key()).unwrap();
+        }
+        let request = MobileSyncRequest {
+            last_known_height: None,
+            max_headers: None,
+        };
+        let Json(response) = mobile_sync(State(Arc::new(Mutex::new(blockchain))), Json(request))
+            .await
+            .unwrap();
+        assert_eq!(response.headers.len(), 4);
+        let starts: Vec<u64> = response
+            .validator_sets
+            .iter()
+            .map(|set| set.start_height)
+            .collect();
+        assert_eq!(starts, vec![0, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_mobile_sync_sends_a_new_set_when_a_validator_is_jailed() {
+        let keys: Vec<SigningKey> = (1..=3u8)
+            .map(|seed| SigningKey::from_bytes(&[seed; 32]))
+            .collect();
+        let mut config = test_config();
+        bond_genesis_validators(&mut config, &keys);
+        config.consensus.dpos.missed_slot_threshold = 1;
+        let mut blockchain = test_chain(config);
+
+        // Block 1 comes from the first fallback, so the scheduled producer
+        // misses its slot and is jailed halfway through the epoch
+        let fallback = blockchain
+            .engine()
+            .producer(&blockchain.state, 1, 1)
+            .unwrap();
+        let mut key = keys
+            .iter()
+            .find(|key| hex::encode(key.verifying_key().to_bytes()) == fallback)
+            .unwrap()
+            .clone();
+        let delay = blockchain.engine().fallback_delay(&blockchain.state, 1);
+        blockchain
+            .mine_block_at(&mut key, GENESIS_TIMESTAMP + delay)
+            .unwrap();
+        assert_eq!(blockchain.state.validators.len(), 2);
+        let key_refs: Vec<&SigningKey> = keys.iter().collect();
+        for _ in 0..3 {
+            mine_scheduled(&mut blockchain, &key_refs).unwrap();
+        }
+
+        let request = MobileSyncRequest {
+            last_known_height: None,
+            max_headers: None,
+        };
+        let Json(response) = mobile_sync(State(Arc::new(Mutex::new(blockchain))), Json(request))
+            .await
+            .unwrap();
+        assert_eq!(response.headers.len(), 5);
+        let starts: Vec<u64> = response
+            .validator_sets
+            .iter()
+            .map(|set| set.start_height)
+            .collect();
+        assert_eq!(starts, vec![0, 2]);
+
+        // What a phone does: each header's producer is the one its set
+        // schedules for the header's height and rank
+        for header in &response.headers[1..] {
+            let set = response
+                .validator_sets
+                .iter()
+                .rev()
+                .find(|set| set.start_height <= header.height)
+                .unwrap();
+            let index = schedule_index(
+                set.validators.len(),
+                set.start_height,
+                header.height,
+                header.fallback_rank,
+            )
+            .unwrap();
+            assert_eq!(set.validators[index].consensus_key, header.producer);
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod gossip;
 pub mod mobile;
 pub mod p2p;
+pub mod peers;
+pub mod session;
+pub mod sync;
@@ -1,22 +1,936 @@
-// Define the NetworkService struct without the problematic Gossipsub reference
+//! TCP transport for `NetworkMessage`. Each connection is an encrypted
+//! `session` between two node ids; a node on a permissioned network only
+//! keeps sessions with the node ids on its allowlist. Once the session is
+//! up both sides send a `StatusUpdate`, the dialer first, and the
+//! connection is dropped unless the network name, genesis hash and
+//! protocol version all match. After that each side pings and repeats its `StatusUpdate` as a
+//! keepalive, and a peer that stays silent past the idle timeout is
+//! disconnected. Peers swap addresses with `GetPeers`/`Peers`, and the
+//! node dials addresses from its `AddressBook` until it has
+//! `max_outbound` connections.
+
+use super::peers::{unix_now, AddressBook, MAX_SHARED_ADDRESSES};
+use super::session::{self, SecureReader, SecureWriter};
+use crate::block::Block;
+use crate::config::P2pConfig;
+use crate::consensus::dpos::NetworkMessage;
+use crate::crypto_utils;
+use crate::transaction::Transaction;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
+use tracing::{debug, info, warn};
+
+/// Sent in `StatusUpdate::version`; peers must match it exactly
+pub const PROTOCOL_VERSION: &str = "owami/2";
+
+/// Penalty for a `Peers` message longer than `MAX_SHARED_ADDRESSES`
+pub const OVERSIZED_PEERS_PENALTY: u32 = 20;
+
+/// How often the node tops up its outbound connections
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Messages queued for one peer before sends to it fail
+const OUTBOX_CAPACITY: usize = 256;
+
+pub type PeerId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub node_id: String, // hex ed25519 key the peer proved it holds
+    pub addr: SocketAddr,
+    pub direction: Direction,
+    pub height: u64,                     // from the peer's latest StatusUpdate
+    pub listen_addr: Option<SocketAddr>, // where the peer accepts connections
+    pub latency_ms: Option<u64>,         // round trip of the latest ping
+    pub score: i32,                      // reputation in the address book
+}
+
+#[derive(Debug)]
+pub enum NetworkEvent {
+    Connected(PeerInfo),
+    /// Any message other than those the transport handles itself:
+    /// `StatusUpdate`, `Ping`, `Pong`, `GetPeers` and `Peers`
+    Message {
+        peer: PeerId,
+        message: NetworkMessage,
+    },
+    Disconnected {
+        peer: PeerId,
+        reason: String,
+    },
+}
+
+struct Peer {
+    info: PeerInfo,
+    /// Address the peer's reputation is kept under: where it listens, or
+    /// the connection's address if it does not
+    key: SocketAddr,
+    outbox: mpsc::Sender<NetworkMessage>,
+}
+
+/// What a peer's `StatusUpdate` says beyond being on our chain
+struct RemoteStatus {
+    height: u64,
+    listen_port: u16,
+}
+
+impl RemoteStatus {
+    fn listen_addr(&self, addr: SocketAddr, direction: Direction) -> Option<SocketAddr> {
+        match direction {
+            Direction::Outbound => Some(addr),
+            Direction::Inbound => {
+                (self.listen_port != 0).then(|| SocketAddr::new(addr.ip(), self.listen_port))
+            }
+        }
+    }
+}
+
+struct Shared {
+    config: P2pConfig,
+    genesis_hash: String,
+    listen_port: u16,
+    identity: SigningKey,
+    allowed: HashSet<VerifyingKey>, // empty lets any node connect
+    height: AtomicU64,
+    next_id: AtomicU64,
+    peers: Mutex<HashMap<PeerId, Peer>>,
+    book: Mutex<AddressBook>,
+    discover: Notify,
+    inbound: Arc<Semaphore>,
+    outbound: Arc<Semaphore>,
+    events: mpsc::Sender<NetworkEvent>,
+}
+
+impl Shared {
+    fn status(&self) -> NetworkMessage {
+        NetworkMessage::StatusUpdate {
+            height: self.height.load(Ordering::Relaxed),
+            network: self.config.network.clone(),
+            version: PROTOCOL_VERSION.to_string(),
+            genesis_hash: self.genesis_hash.clone(),
+            listen_port: self.listen_port,
+        }
+    }
+
+    /// The peer's status if `message` is a status from a node on our chain
+    fn check_status(&self, message: &NetworkMessage) -> Result<RemoteStatus, String> {
+        match message {
+            NetworkMessage::StatusUpdate {
+                height,
+                network,
+                version,
+                genesis_hash,
+                listen_port,
+            } => {
+                if *network != self.config.network {
+                    return Err(format!("peer is on network {:?}", network));
+                }
+                if *genesis_hash != self.genesis_hash {
+                    return Err(format!("peer has genesis {}", genesis_hash));
+                }
+                if version != PROTOCOL_VERSION {
+                    return Err(format!("peer speaks protocol {:?}", version));
+                }
+                Ok(RemoteStatus {
+                    height: *height,
+                    listen_port: *listen_port,
+                })
+            }
+            other => Err(format!("expected StatusUpdate, got {:?}", other)),
+        }
+    }
+
+    fn is_allowed(&self, node: &VerifyingKey) -> bool {
+        self.allowed.is_empty() || self.allowed.contains(node)
+    }
+
+    fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.book.lock().unwrap().is_banned(addr, unix_now())
+    }
+
+    fn penalize(&self, peer: PeerId, points: u32, reason: &str) {
+        let Some(key) = self.peers.lock().unwrap().get(&peer).map(|peer| peer.key) else {
+            return;
+        };
+        let mut book = self.book.lock().unwrap();
+        let banned = book.penalize(key, points, unix_now(), self.config.ban_duration_secs);
+        warn!(
+            "Peer {} at {} sent {} (score {})",
+            peer,
+            key,
+            reason,
+            book.score(&key)
+        );
+        if banned {
+            warn!(
+                "Banning {} for {} seconds",
+                key, self.config.ban_duration_secs
+            );
+            if let Err(e) = book.save() {
+                warn!("Failed to save the address book: {}", e);
+            }
+            drop(book);
+            // Dropping the outbox ends the connection task
+            self.peers.lock().unwrap().remove(&peer);
+        }
+    }
+
+    fn save_book(&self) {
+        if let Err(e) = self.book.lock().unwrap().save() {
+            warn!("Failed to save the address book: {}", e);
+        }
+    }
+}
+
+/// Handle to the peer layer; clones share the same connections
+#[derive(Clone)]
 pub struct NetworkService {
-    // We'll leave this empty for now since we're simplifying the P2P implementation
+    shared: Arc<Shared>,
+    local_addr: SocketAddr,
 }
 
 impl NetworkService {
-    pub async fn publish_block(
-        &mut self,
-        _block_data: Vec<u8>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // Simplified implementation
-        Ok(())
-    }
-
-    pub async fn publish_transaction(
-        &mut self,
-        _tx_data: Vec<u8>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // Simplified implementation
-        Ok(())
+    /// Listen on `config.listen_addr` as the node `identity`, for a chain
+    /// starting at `genesis_hash` whose tip is at `height`, and start
+    /// dialing the bootstrap peers and the address book. Events from
+    /// every peer arrive on the returned receiver.
+    pub async fn start(
+        config: P2pConfig,
+        identity: SigningKey,
+        genesis_hash: String,
+        height: u64,
+    ) -> io::Result<(Self, mpsc::Receiver<NetworkEvent>)> {
+        let allowed = config
+            .allowed_peers
+            .iter()
+            .map(|id| {
+                crypto_utils::hex_to_verifying_key(id).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid allowed peer {}: {}", id, e),
+                    )
+                })
+            })
+            .collect::<io::Result<HashSet<_>>>()?;
+        let mut book = if config.address_book_path.is_empty() {
+            AddressBook::in_memory()
+        } else {
+            AddressBook::open(&config.address_book_path).map_err(io::Error::other)?
+        };
+        for peer in &config.bootstrap_peers {
+            match tokio::net::lookup_host(peer.as_str()).await {
+                Ok(addrs) => addrs.for_each(|addr| {
+                    book.add(addr);
+                }),
+                Err(e) => warn!("Cannot resolve bootstrap peer {}: {}", peer, e),
+            }
+        }
+
+        let listener = TcpListener::bind(&config.listen_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (events, receiver) = mpsc::channel(1024);
+        let shared = Arc::new(Shared {
+            inbound: Arc::new(Semaphore::new(config.max_inbound)),
+            outbound: Arc::new(Semaphore::new(config.max_outbound)),
+            config,
+            genesis_hash,
+            listen_port: local_addr.port(),
+            identity,
+            allowed,
+            height: AtomicU64::new(height),
+            next_id: AtomicU64::new(1),
+            peers: Mutex::new(HashMap::new()),
+            book: Mutex::new(book),
+            discover: Notify::new(),
+            events,
+        });
+        info!(
+            "Listening for peers on {} as node {}",
+            local_addr,
+            session::node_id(&shared.identity.verifying_key())
+        );
+        tokio::spawn(accept_loop(shared.clone(), listener));
+        let service = NetworkService { shared, local_addr };
+        tokio::spawn(discovery_loop(service.clone()));
+        Ok((service, receiver))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Hex public key identifying this node to its peers
+    pub fn node_id(&self) -> String {
+        session::node_id(&self.shared.identity.verifying_key())
+    }
+
+    /// Tip height announced in our `StatusUpdate`s
+    pub fn set_height(&self, height: u64) {
+        self.shared.height.store(height, Ordering::Relaxed);
+    }
+
+    /// Dial `addr` and complete the handshake
+    pub async fn connect(&self, addr: SocketAddr) -> Result<PeerId, String> {
+        if self.shared.is_banned(&addr) {
+            return Err(format!("{} is banned", addr));
+        }
+        let permit = self
+            .shared
+            .outbound
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| "outbound connection limit reached".to_string())?;
+        let timeout = Duration::from_secs(self.shared.config.handshake_timeout_secs);
+        let stream = time::timeout(timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| format!("connecting to {} timed out", addr))?
+            .map_err(|e| format!("failed to connect to {}: {}", addr, e))?;
+        establish(
+            self.shared.clone(),
+            stream,
+            addr,
+            Direction::Outbound,
+            permit,
+        )
+        .await
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let peers = self.shared.peers.lock().unwrap();
+        let book = self.shared.book.lock().unwrap();
+        let mut infos: Vec<PeerInfo> = peers
+            .values()
+            .map(|peer| PeerInfo {
+                score: book.score(&peer.key),
+                ..peer.info.clone()
+            })
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// Queue `message` for `peer`
+    pub fn send(&self, peer: PeerId, message: NetworkMessage) -> Result<(), String> {
+        let peers = self.shared.peers.lock().unwrap();
+        let target = peers
+            .get(&peer)
+            .ok_or_else(|| format!("peer {} is not connected", peer))?;
+        target
+            .outbox
+            .try_send(message)
+            .map_err(|e| format!("cannot queue message for peer {}: {}", peer, e))
+    }
+
+    /// Queue `message` for every peer but `except`; returns how many took it
+    pub fn broadcast(&self, message: &NetworkMessage, except: Option<PeerId>) -> usize {
+        let peers = self.shared.peers.lock().unwrap();
+        peers
+            .iter()
+            .filter(|(id, _)| Some(**id) != except)
+            .filter(|(_, peer)| peer.outbox.try_send(message.clone()).is_ok())
+            .count()
+    }
+
+    /// Close the connection to `peer`
+    pub fn disconnect(&self, peer: PeerId) {
+        // Dropping the outbox ends the connection task
+        self.shared.peers.lock().unwrap().remove(&peer);
+    }
+
+    /// Lower `peer`'s reputation by `points` for sending the invalid
+    /// `reason`; a peer whose score reaches `BAN_SCORE` is disconnected
+    /// and banned for `ban_duration_secs`
+    pub fn penalize(&self, peer: PeerId, points: u32, reason: &str) {
+        self.shared.penalize(peer, points, reason);
+    }
+
+    pub fn publish_block(&self, block: &Block) -> usize {
+        self.broadcast(&NetworkMessage::NewBlock(block.clone()), None)
+    }
+
+    pub fn publish_transaction(&self, transaction: &Transaction) -> usize {
+        self.broadcast(&NetworkMessage::NewTransaction(transaction.clone()), None)
+    }
+
+    /// Dial addresses from the book until `max_outbound` are connected
+    async fn dial_more(&self) {
+        let (outbound, mut connected) = {
+            let peers = self.shared.peers.lock().unwrap();
+            let outbound = peers
+                .values()
+                .filter(|peer| peer.info.direction == Direction::Outbound)
+                .count();
+            let connected: HashSet<SocketAddr> = peers.values().map(|peer| peer.key).collect();
+            (outbound, connected)
+        };
+        let wanted = self.shared.config.max_outbound.saturating_sub(outbound);
+        if wanted == 0 {
+            return;
+        }
+        connected.insert(self.local_addr);
+        let candidates = self
+            .shared
+            .book
+            .lock()
+            .unwrap()
+            .candidates(unix_now(), &connected);
+
+        let mut dialed = 0;
+        for addr in candidates {
+            if dialed == wanted {
+                break;
+            }
+            match self.connect(addr).await {
+                Ok(_) => dialed += 1,
+                Err(e) => {
+                    debug!("Could not reach {}: {}", addr, e);
+                    self.shared.book.lock().unwrap().record_failure(&addr);
+                }
+            }
+        }
+        self.shared.save_book();
+        // Still short of peers: ask the ones we have for more addresses
+        if dialed < wanted {
+            self.broadcast(&NetworkMessage::GetPeers, None);
+        }
+    }
+}
+
+async fn discovery_loop(service: NetworkService) {
+    loop {
+        service.dial_more().await;
+        tokio::select! {
+            _ = time::sleep(DISCOVERY_INTERVAL) => {}
+            _ = service.shared.discover.notified() => {}
+        }
+    }
+}
+
+async fn accept_loop(shared: Arc<Shared>, listener: TcpListener) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept peer connection: {}", e);
+                continue;
+            }
+        };
+        let Ok(permit) = shared.inbound.clone().try_acquire_owned() else {
+            debug!("Refusing {}: inbound connection limit reached", addr);
+            continue;
+        };
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = establish(shared, stream, addr, Direction::Inbound, permit).await {
+                debug!("Handshake with {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Open a session over `stream` and exchange statuses; if the peer is
+/// allowed, on our chain and not banned, register it and run the
+/// connection in the background
+async fn establish(
+    shared: Arc<Shared>,
+    stream: TcpStream,
+    addr: SocketAddr,
+    direction: Direction,
+    permit: OwnedSemaphorePermit,
+) -> Result<PeerId, String> {
+    let timeout = Duration::from_secs(shared.config.handshake_timeout_secs);
+    let our_id = shared.identity.verifying_key();
+    let handshake = async {
+        let (reader, writer) = stream.into_split();
+        let mut session = session::handshake(
+            reader,
+            writer,
+            &shared.identity,
+            direction == Direction::Outbound,
+        )
+        .await?;
+        if session.remote == our_id || !shared.is_allowed(&session.remote) {
+            return Ok((session, None, false));
+        }
+        // The dialer speaks first, so a banned dialer gets no answer
+        if direction == Direction::Outbound {
+            session.writer.send(&shared.status()).await?;
+        }
+        let status = session.reader.recv().await?;
+        let remote = shared.check_status(&status);
+        let banned = remote.as_ref().is_ok_and(|remote| {
+            let listen_addr = remote.listen_addr(addr, direction);
+            shared.is_banned(&listen_addr.unwrap_or(addr))
+        });
+        if direction == Direction::Inbound && !banned {
+            session.writer.send(&shared.status()).await?;
+        }
+        Ok::<_, io::Error>((session, Some(remote), banned))
+    };
+    let (session, remote, banned) = time::timeout(timeout, handshake)
+        .await
+        .map_err(|_| format!("handshake with {} timed out", addr))?
+        .map_err(|e| format!("handshake with {} failed: {}", addr, e))?;
+    let node_id = session::node_id(&session.remote);
+    if session.remote == our_id {
+        // Our own address came back through peer exchange
+        shared.book.lock().unwrap().remove(&addr);
+        return Err(format!("{} is this node", addr));
+    }
+    let Some(remote) = remote else {
+        return Err(format!(
+            "refusing {}: node {} is not allowed",
+            addr, node_id
+        ));
+    };
+    let remote = remote.map_err(|e| format!("refusing {}: {}", addr, e))?;
+    let listen_addr = remote.listen_addr(addr, direction);
+    let key = listen_addr.unwrap_or(addr);
+    if banned {
+        return Err(format!("refusing {}: banned", key));
+    }
+
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let info = PeerInfo {
+        id,
+        node_id,
+        addr,
+        direction,
+        height: remote.height,
+        listen_addr,
+        latency_ms: None,
+        score: 0,
+    };
+    let (outbox, inbox) = mpsc::channel(OUTBOX_CAPACITY);
+    {
+        let mut peers = shared.peers.lock().unwrap();
+        let existing = peers
+            .iter()
+            .find(|(_, peer)| peer.info.node_id == info.node_id)
+            .map(|(id, peer)| (*id, peer.info.direction));
+        if let Some((existing_id, existing_direction)) = existing {
+            // When two nodes dial each other at once, both keep the
+            // connection dialed by the node with the lower id
+            let dialed_by_us = direction == Direction::Outbound;
+            let keep_new = existing_direction != direction
+                && dialed_by_us == (our_id.as_bytes() < session.remote.as_bytes());
+            if !keep_new {
+                return Err(format!("already connected to node {}", info.node_id));
+            }
+            peers.remove(&existing_id);
+        }
+        let _ = outbox.try_send(NetworkMessage::GetPeers);
+        peers.insert(
+            id,
+            Peer {
+                info: info.clone(),
+                key,
+                outbox,
+            },
+        );
+    }
+    if let Some(listen_addr) = listen_addr {
+        shared
+            .book
+            .lock()
+            .unwrap()
+            .record_connected(listen_addr, unix_now());
+    }
+    info!(
+        "Connected to peer {} (node {}) at {} ({:?})",
+        id, info.node_id, addr, direction
+    );
+    let _ = shared.events.send(NetworkEvent::Connected(info)).await;
+    tokio::spawn(run_connection(
+        shared,
+        id,
+        session.reader,
+        session.writer,
+        inbox,
+        permit,
+    ));
+    Ok(id)
+}
+
+/// Handle a message the transport answers itself; false for messages
+/// meant for the node
+fn handle_control(shared: &Shared, id: PeerId, message: &NetworkMessage) -> Result<bool, String> {
+    match message {
+        NetworkMessage::StatusUpdate { .. } => {
+            let remote = shared.check_status(message)?;
+            if let Some(peer) = shared.peers.lock().unwrap().get_mut(&id) {
+                peer.info.height = remote.height;
+            }
+        }
+        NetworkMessage::GetPeers => {
+            let peers = shared.peers.lock().unwrap();
+            if let Some(peer) = peers.get(&id) {
+                let shared_addrs = shared
+                    .book
+                    .lock()
+                    .unwrap()
+                    .shareable(unix_now(), Some(peer.key));
+                let _ = peer.outbox.try_send(NetworkMessage::Peers(shared_addrs));
+            }
+        }
+        NetworkMessage::Peers(addrs) => {
+            if addrs.len() > MAX_SHARED_ADDRESSES {
+                shared.penalize(
+                    id,
+                    OVERSIZED_PEERS_PENALTY,
+                    &format!("{} addresses in one Peers message", addrs.len()),
+                );
+            }
+            let mut book = shared.book.lock().unwrap();
+            let learned = addrs
+                .iter()
+                .take(MAX_SHARED_ADDRESSES)
+                .filter(|addr| addr.port() != 0 && !addr.ip().is_unspecified())
+                .filter(|addr| book.add(**addr))
+                .count();
+            if learned > 0 {
+                debug!("Learned {} peer addresses from peer {}", learned, id);
+                shared.discover.notify_one();
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+async fn run_connection(
+    shared: Arc<Shared>,
+    id: PeerId,
+    mut reader: SecureReader<OwnedReadHalf>,
+    mut writer: SecureWriter<OwnedWriteHalf>,
+    mut inbox: mpsc::Receiver<NetworkMessage>,
+    _permit: OwnedSemaphorePermit,
+) {
+    // Reading a frame is not cancel-safe, so it gets a task of its own
+    let (frames, mut incoming) = mpsc::channel(16);
+    let reader_task = tokio::spawn(async move {
+        loop {
+            let frame = reader.recv().await;
+            let failed = frame.is_err();
+            if frames.send(frame).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let idle_timeout = Duration::from_secs(shared.config.idle_timeout_secs);
+    let mut keepalive = time::interval(Duration::from_secs(
+        shared.config.keepalive_interval_secs.max(1),
+    ));
+    let mut last_heard = Instant::now();
+    let mut ping: Option<(u64, Instant)> = None;
+
+    let reason = loop {
+        tokio::select! {
+            frame = incoming.recv() => match frame {
+                Some(Ok(message)) => {
+                    last_heard = Instant::now();
+                    match &message {
+                        NetworkMessage::Ping(nonce) => {
+                            if let Err(e) = writer.send(&NetworkMessage::Pong(*nonce)).await {
+                                break format!("write failed: {}", e);
+                            }
+                            continue;
+                        }
+                        NetworkMessage::Pong(nonce) => {
+                            if let Some((sent_nonce, sent)) = ping {
+                                if sent_nonce == *nonce {
+                                    let latency = sent.elapsed().as_millis() as u64;
+                                    if let Some(peer) = shared.peers.lock().unwrap().get_mut(&id) {
+                                        peer.info.latency_ms = Some(latency);
+                                    }
+                                    ping = None;
+                                }
+                            }
+                            continue;
+                        }
+                        // A peer relaying a block holds at least that height
+                        NetworkMessage::NewBlock(block) => {
+                            if let Some(peer) = shared.peers.lock().unwrap().get_mut(&id) {
+                                peer.info.height = peer.info.height.max(block.header.height);
+                            }
+                        }
+                        _ => {}
+                    }
+                    match handle_control(&shared, id, &message) {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(e) => break e,
+                    }
+                    let event = NetworkEvent::Message { peer: id, message };
+                    if shared.events.send(event).await.is_err() {
+                        break "node stopped listening".to_string();
+                    }
+                }
+                Some(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    break "peer closed the connection".to_string()
+                }
+                Some(Err(e)) => break format!("read failed: {}", e),
+                None => break "reader stopped".to_string(),
+            },
+            outgoing = inbox.recv() => match outgoing {
+                Some(message) => {
+                    if let Err(e) = writer.send(&message).await {
+                        break format!("write failed: {}", e);
+                    }
+                }
+                None => break "disconnected locally".to_string(),
+            },
+            // The first tick fires at once, measuring latency straight away
+            _ = keepalive.tick() => {
+                let nonce = rand::random();
+                ping = Some((nonce, Instant::now()));
+                let sent = async {
+                    writer.send(&shared.status()).await?;
+                    writer.send(&NetworkMessage::Ping(nonce)).await
+                };
+                if let Err(e) = sent.await {
+                    break format!("write failed: {}", e);
+                }
+            }
+            _ = time::sleep_until(last_heard + idle_timeout) => {
+                break "peer went silent".to_string();
+            }
+        }
+    };
+
+    reader_task.abort();
+    let _ = writer.shutdown().await;
+    shared.peers.lock().unwrap().remove(&id);
+    info!("Disconnected from peer {}: {}", id, reason);
+    let _ = shared
+        .events
+        .send(NetworkEvent::Disconnected { peer: id, reason })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto_utils::generate_keypair;
+
+    fn config() -> P2pConfig {
+        P2pConfig {
+            enabled: true,
+            listen_addr: "127.0.0.1:0".to_string(),
+            network: "owami-test".to_string(),
+            max_inbound: 2,
+            max_outbound: 4,
+            keepalive_interval_secs: 1,
+            idle_timeout_secs: 3,
+            handshake_timeout_secs: 2,
+            ..P2pConfig::default()
+        }
+    }
+
+    async fn node(
+        config: P2pConfig,
+        height: u64,
+    ) -> (NetworkService, mpsc::Receiver<NetworkEvent>) {
+        NetworkService::start(config, generate_keypair().0, "genesis".to_string(), height)
+            .await
+            .unwrap()
+    }
+
+    async fn next_event(events: &mut mpsc::Receiver<NetworkEvent>) -> NetworkEvent {
+        time::timeout(Duration::from_secs(10), events.recv())
+            .await
+            .expect("no event arrived")
+            .expect("event channel closed")
+    }
+
+    async fn next_message(events: &mut mpsc::Receiver<NetworkEvent>) -> (PeerId, NetworkMessage) {
+        loop {
+            if let NetworkEvent::Message { peer, message } = next_event(events).await {
+                return (peer, message);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peers_handshake_and_exchange_messages() {
+        let (a, mut a_events) = node(config(), 5).await;
+        let (b, mut b_events) = node(config(), 9).await;
+        let (c, mut c_events) = node(config(), 7).await;
+
+        let b_id = a.connect(b.local_addr()).await.unwrap();
+        a.connect(c.local_addr()).await.unwrap();
+        assert_eq!(a.peers().len(), 2);
+        assert_eq!(a.peers()[0].height, 9);
+        assert_eq!(a.peers()[0].direction, Direction::Outbound);
+
+        let transaction = Transaction::new("alice".to_string(), "bob".to_string(), 5, None);
+        assert_eq!(a.publish_transaction(&transaction), 2);
+        for events in [&mut b_events, &mut c_events] {
+            match next_message(events).await {
+                (_, NetworkMessage::NewTransaction(received)) => {
+                    assert_eq!(received.hash(), transaction.hash())
+                }
+                (_, other) => panic!("unexpected {:?}", other),
+            }
+        }
+
+        // b answers over the connection a opened
+        let a_on_b = b
+            .peers()
+            .into_iter()
+            .find(|peer| peer.listen_addr == Some(a.local_addr()))
+            .unwrap();
+        assert_eq!(a_on_b.direction, Direction::Inbound);
+        assert_eq!(a_on_b.height, 5);
+        b.send(a_on_b.id, NetworkMessage::ValidatorSetRequest)
+            .unwrap();
+        let (from, message) = next_message(&mut a_events).await;
+        assert_eq!(from, b_id);
+        assert!(matches!(message, NetworkMessage::ValidatorSetRequest));
+
+        a.disconnect(b_id);
+        loop {
+            if let NetworkEvent::Disconnected { .. } = next_event(&mut b_events).await {
+                break;
+            }
+        }
+        assert!(b.peers().iter().all(|peer| peer.id != a_on_b.id));
+        assert!(a.peers().iter().all(|peer| peer.id != b_id));
+    }
+
+    #[tokio::test]
+    async fn test_nodes_find_each_other_through_a_bootstrap_peer() {
+        let (a, _a_events) = node(config(), 0).await;
+        let (b, _b_events) = node(config(), 0).await;
+        b.connect(a.local_addr()).await.unwrap();
+        while a.peers().is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // c only knows a, which tells it about b
+        let mut bootstrapped = config();
+        bootstrapped.bootstrap_peers = vec![a.local_addr().to_string()];
+        let (c, _c_events) = node(bootstrapped, 0).await;
+        let mut found = false;
+        for _ in 0..100 {
+            let peers = c.peers();
+            found = [a.local_addr(), b.local_addr()].iter().all(|addr| {
+                peers
+                    .iter()
+                    .any(|peer| peer.listen_addr == Some(*addr) && peer.latency_ms.is_some())
+            });
+            if found {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(found, "{:?}", c.peers());
+        assert!(c.peers().iter().all(|peer| peer.score == 0));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_other_chains() {
+        let (a, _a_events) = node(config(), 0).await;
+        let (other_genesis, _e1) =
+            NetworkService::start(config(), generate_keypair().0, "fork".to_string(), 0)
+                .await
+                .unwrap();
+        let mut other_network = config();
+        other_network.network = "elsewhere".to_string();
+        let (other_name, _e2) = node(other_network, 0).await;
+
+        let err = a.connect(other_genesis.local_addr()).await.unwrap_err();
+        assert!(err.contains("genesis"), "{}", err);
+        let err = a.connect(other_name.local_addr()).await.unwrap_err();
+        assert!(err.contains("network"), "{}", err);
+        assert!(a.peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_admits_only_listed_nodes() {
+        let (member, _member_events) = node(config(), 0).await;
+        let (outsider, _outsider_events) = node(config(), 0).await;
+        let mut permissioned = config();
+        permissioned.allowed_peers = vec![member.node_id()];
+        let (gate, _gate_events) = node(permissioned, 0).await;
+
+        member.connect(gate.local_addr()).await.unwrap();
+        assert!(outsider.connect(gate.local_addr()).await.is_err());
+        let err = gate.connect(outsider.local_addr()).await.unwrap_err();
+        assert!(err.contains("not allowed"), "{}", err);
+        let peers = gate.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].node_id, member.node_id());
+    }
+
+    #[tokio::test]
+    async fn test_inbound_limit_is_enforced() {
+        let (hub, _hub_events) = node(config(), 0).await;
+        let mut spokes = Vec::new();
+        for _ in 0..3 {
+            spokes.push(node(config(), 0).await);
+        }
+
+        assert!(spokes[0].0.connect(hub.local_addr()).await.is_ok());
+        assert!(spokes[1].0.connect(hub.local_addr()).await.is_ok());
+        // The hub drops the third connection before answering
+        assert!(spokes[2].0.connect(hub.local_addr()).await.is_err());
+        assert_eq!(hub.peers().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_is_dropped() {
+        let (a, mut a_events) = node(config(), 3).await;
+        let (reader, writer) = TcpStream::connect(a.local_addr())
+            .await
+            .unwrap()
+            .into_split();
+        let identity = generate_keypair().0;
+        let mut session = session::handshake(reader, writer, &identity, true)
+            .await
+            .unwrap();
+        assert_eq!(session::node_id(&session.remote), a.node_id());
+        let status = NetworkMessage::StatusUpdate {
+            height: 0,
+            network: "owami-test".to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            genesis_hash: "genesis".to_string(),
+            listen_port: 0,
+        };
+        session.writer.send(&status).await.unwrap();
+        assert!(matches!(
+            session.reader.recv().await.unwrap(),
+            NetworkMessage::StatusUpdate { height: 3, .. }
+        ));
+        assert!(matches!(
+            next_event(&mut a_events).await,
+            NetworkEvent::Connected(_)
+        ));
+
+        // Keepalives keep coming, but the raw socket never answers them
+        match next_event(&mut a_events).await {
+            NetworkEvent::Disconnected { reason, .. } => assert!(reason.contains("silent")),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(a.peers().is_empty());
     }
 }
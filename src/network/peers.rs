@@ -0,0 +1,270 @@
+//! Address book of dialable peers. Each address carries a reputation
+//! score that misbehaviour lowers; at `BAN_SCORE` the address is banned
+//! for a while and its score starts over once the ban ends. The book is
+//! kept in a JSON file so a restarted node can reconnect without its
+//! bootstrap peers.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Score at which an address is banned
+pub const BAN_SCORE: i32 = -100;
+
+/// Addresses kept; the worst are forgotten beyond this
+pub const MAX_ADDRESSES: usize = 1_000;
+
+/// Addresses sent in one `Peers` message
+pub const MAX_SHARED_ADDRESSES: usize = 32;
+
+/// Failed dials after which an address is forgotten
+pub const MAX_FAILURES: u32 = 5;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressEntry {
+    pub score: i32,
+    pub banned_until: u64, // unix seconds; 0 if never banned
+    pub last_connected: u64,
+    pub failures: u32, // failed dials since the last connection
+}
+
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    entries: BTreeMap<SocketAddr, AddressEntry>,
+}
+
+impl AddressBook {
+    /// Load the book kept at `path`; a missing file means an empty book
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let entries = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(AddressBook {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    /// Book that is never written to disk
+    pub fn in_memory() -> Self {
+        AddressBook::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddressEntry> {
+        self.entries.get(addr)
+    }
+
+    /// Remember `addr`; false if it was already known
+    pub fn add(&mut self, addr: SocketAddr) -> bool {
+        if self.entries.contains_key(&addr) {
+            return false;
+        }
+        self.entries.insert(addr, AddressEntry::default());
+        self.evict();
+        true
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.entries.remove(addr);
+    }
+
+    pub fn score(&self, addr: &SocketAddr) -> i32 {
+        self.entries.get(addr).map_or(0, |entry| entry.score)
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr, now: u64) -> bool {
+        self.entries
+            .get(addr)
+            .is_some_and(|entry| entry.banned_until > now)
+    }
+
+    /// Lower `addr`'s score by `points`, banning it until `now + ban_secs`
+    /// once it reaches `BAN_SCORE`; true if this banned it
+    pub fn penalize(&mut self, addr: SocketAddr, points: u32, now: u64, ban_secs: u64) -> bool {
+        let entry = self.entries.entry(addr).or_default();
+        if entry.banned_until > now {
+            return false;
+        }
+        if entry.banned_until != 0 {
+            // The previous ban is over; start again from a clean score
+            entry.banned_until = 0;
+            entry.score = 0;
+        }
+        entry.score = entry
+            .score
+            .saturating_sub(points.min(i32::MAX as u32) as i32);
+        if entry.score > BAN_SCORE {
+            return false;
+        }
+        entry.banned_until = now + ban_secs.max(1);
+        true
+    }
+
+    pub fn record_connected(&mut self, addr: SocketAddr, now: u64) {
+        let entry = self.entries.entry(addr).or_default();
+        entry.last_connected = now;
+        entry.failures = 0;
+        self.evict();
+    }
+
+    /// Count a failed dial, forgetting `addr` after `MAX_FAILURES`
+    pub fn record_failure(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.entries.get_mut(addr) {
+            entry.failures += 1;
+            if entry.failures >= MAX_FAILURES && entry.banned_until == 0 {
+                self.entries.remove(addr);
+            }
+        }
+    }
+
+    /// Addresses worth dialing, best first
+    pub fn candidates(&self, now: u64, exclude: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let mut candidates: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(addr, entry)| entry.banned_until <= now && !exclude.contains(addr))
+            .collect();
+        candidates.sort_by_key(|(_, entry)| {
+            (
+                entry.failures,
+                std::cmp::Reverse(entry.score),
+                std::cmp::Reverse(entry.last_connected),
+            )
+        });
+        candidates.into_iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Addresses to tell other peers about: ones this node has reached
+    /// and holds nothing against
+    pub fn shareable(&self, now: u64, exclude: Option<SocketAddr>) -> Vec<SocketAddr> {
+        let mut shared: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(addr, entry)| {
+                entry.last_connected > 0
+                    && entry.score >= 0
+                    && entry.banned_until <= now
+                    && Some(**addr) != exclude
+            })
+            .collect();
+        shared.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.last_connected));
+        shared
+            .into_iter()
+            .take(MAX_SHARED_ADDRESSES)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Replace the file atomically; a no-op for an in-memory book
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(&self.entries)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Forget the worst unbanned addresses beyond `MAX_ADDRESSES`; bans
+    /// are kept so a banned peer cannot flush itself out
+    fn evict(&mut self) {
+        while self.entries.len() > MAX_ADDRESSES {
+            let worst = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.banned_until == 0)
+                .min_by_key(|(_, entry)| (entry.score, entry.last_connected))
+                .map(|(addr, _)| *addr);
+            match worst {
+                Some(addr) => self.entries.remove(&addr),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_address_book_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers.json");
+        let mut book = AddressBook::open(&path).unwrap();
+        assert!(book.add(addr(1)));
+        assert!(!book.add(addr(1)));
+        book.record_connected(addr(2), 50);
+        book.penalize(addr(1), 30, 50, 600);
+        book.save().unwrap();
+
+        let reopened = AddressBook::open(&path).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.score(&addr(1)), -30);
+        assert_eq!(reopened.shareable(60, None), vec![addr(2)]);
+        assert_eq!(reopened.shareable(60, Some(addr(2))), vec![]);
+    }
+
+    #[test]
+    fn test_ban_is_temporary() {
+        let mut book = AddressBook::in_memory();
+        assert!(!book.penalize(addr(1), 60, 100, 600));
+        assert!(book.penalize(addr(1), 60, 100, 600));
+        assert!(book.is_banned(&addr(1), 699));
+        assert!(book.candidates(699, &HashSet::new()).is_empty());
+        // Further penalties while banned do not extend the ban
+        assert!(!book.penalize(addr(1), 60, 200, 600));
+
+        assert!(!book.is_banned(&addr(1), 700));
+        assert_eq!(book.candidates(700, &HashSet::new()), vec![addr(1)]);
+        assert!(!book.penalize(addr(1), 20, 700, 600));
+        assert_eq!(book.score(&addr(1)), -20);
+    }
+
+    #[test]
+    fn test_failing_addresses_are_forgotten() {
+        let mut book = AddressBook::in_memory();
+        book.add(addr(1));
+        book.add(addr(2));
+        book.record_failure(&addr(1));
+        assert_eq!(book.candidates(0, &HashSet::new()), vec![addr(2), addr(1)]);
+        for _ in 1..MAX_FAILURES {
+            book.record_failure(&addr(1));
+        }
+        assert!(book.get(&addr(1)).is_none());
+    }
+}
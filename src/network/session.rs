@@ -0,0 +1,315 @@
+//! Authenticated, encrypted peer sessions. Each node has a long-lived
+//! ed25519 identity; its public key is the node id. A handshake swaps
+//! fresh x25519 keys in the clear, derives one ChaCha20-Poly1305 key per
+//! direction from their shared secret, and then each side proves it owns
+//! its identity by signing the handshake transcript. Every later frame is
+//! encrypted and authenticated under a counter nonce, so frames that are
+//! altered, dropped, replayed or reordered end the session.
+
+use crate::consensus::dpos::NetworkMessage;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// Largest message accepted from a peer, before encryption
+pub const MAX_FRAME_BYTES: usize = 16 * 1024 * 1024;
+
+/// Added to every encrypted frame by Poly1305
+const TAG_BYTES: usize = 16;
+
+const INITIATOR_KEY_CONTEXT: &str = "owami p2p 2024 initiator to responder";
+const RESPONDER_KEY_CONTEXT: &str = "owami p2p 2024 responder to initiator";
+const AUTH_DOMAIN: &[u8] = b"owami-p2p-auth";
+
+/// Sent in the clear by both sides to open a session
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    node_id: [u8; 32],
+    ephemeral: [u8; 32],
+}
+
+/// Sent encrypted by both sides: the transcript signed with the node key
+#[derive(Serialize, Deserialize)]
+struct Auth {
+    signature: Vec<u8>,
+}
+
+/// Hex node id of `key`
+pub fn node_id(key: &VerifyingKey) -> String {
+    hex::encode(key.to_bytes())
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+async fn write_chunk<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_u32(bytes.len() as u32).await?;
+    writer.write_all(bytes).await?;
+    writer.flush().await
+}
+
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32().await? as usize;
+    if len > max {
+        return Err(invalid(format!("peer sent a frame of {} bytes", len)));
+    }
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// 96-bit nonce for the `counter`th frame in one direction
+fn frame_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::from(nonce)
+}
+
+/// Sending half of a session
+pub struct SecureWriter<W> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl<W: AsyncWrite + Unpin> SecureWriter<W> {
+    fn new(inner: W, key: [u8; 32]) -> Self {
+        SecureWriter {
+            inner,
+            cipher: ChaCha20Poly1305::new(&Key::from(key)),
+            counter: 0,
+        }
+    }
+
+    async fn send_bytes(&mut self, plaintext: &[u8]) -> io::Result<()> {
+        if plaintext.len() > MAX_FRAME_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {} bytes is too large", plaintext.len()),
+            ));
+        }
+        let ciphertext = self
+            .cipher
+            .encrypt(&frame_nonce(self.counter), plaintext)
+            .map_err(|_| invalid("encryption failed"))?;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| invalid("session ran out of nonces"))?;
+        write_chunk(&mut self.inner, &ciphertext).await
+    }
+
+    pub async fn send(&mut self, message: &NetworkMessage) -> io::Result<()> {
+        let bytes = bincode::serialize(message).map_err(invalid_bincode)?;
+        self.send_bytes(&bytes).await
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown().await
+    }
+}
+
+/// Receiving half of a session
+pub struct SecureReader<R> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl<R: AsyncRead + Unpin> SecureReader<R> {
+    fn new(inner: R, key: [u8; 32]) -> Self {
+        SecureReader {
+            inner,
+            cipher: ChaCha20Poly1305::new(&Key::from(key)),
+            counter: 0,
+        }
+    }
+
+    async fn recv_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let ciphertext = read_chunk(&mut self.inner, MAX_FRAME_BYTES + TAG_BYTES).await?;
+        let plaintext = self
+            .cipher
+            .decrypt(&frame_nonce(self.counter), ciphertext.as_slice())
+            .map_err(|_| invalid("frame failed authentication"))?;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| invalid("session ran out of nonces"))?;
+        Ok(plaintext)
+    }
+
+    pub async fn recv(&mut self) -> io::Result<NetworkMessage> {
+        let bytes = self.recv_bytes().await?;
+        bincode::deserialize(&bytes).map_err(invalid_bincode)
+    }
+}
+
+fn invalid_bincode(e: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// An established session with the peer whose identity is `remote`
+pub struct Session<R, W> {
+    pub reader: SecureReader<R>,
+    pub writer: SecureWriter<W>,
+    pub remote: VerifyingKey,
+}
+
+/// What a node signs to prove it holds its identity key: the transcript
+/// of both hellos and the role it played
+fn auth_message(transcript: &[u8; 32], initiator: bool) -> Vec<u8> {
+    let mut message = AUTH_DOMAIN.to_vec();
+    message.push(initiator as u8);
+    message.extend_from_slice(transcript);
+    message
+}
+
+/// Run the handshake over `reader` and `writer` as the dialing side when
+/// `initiator` is set, proving ownership of `identity`
+pub async fn handshake<R, W>(
+    mut reader: R,
+    mut writer: W,
+    identity: &SigningKey,
+    initiator: bool,
+) -> io::Result<Session<R, W>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let hello = Hello {
+        node_id: identity.verifying_key().to_bytes(),
+        ephemeral: PublicKey::from(&secret).to_bytes(),
+    };
+    let ours = bincode::serialize(&hello).map_err(invalid_bincode)?;
+    write_chunk(&mut writer, &ours).await?;
+    let theirs = read_chunk(&mut reader, 1024).await?;
+    let remote_hello: Hello = bincode::deserialize(&theirs).map_err(invalid_bincode)?;
+    let remote = VerifyingKey::from_bytes(&remote_hello.node_id)
+        .map_err(|_| invalid("peer sent an invalid node id"))?;
+
+    let shared = secret.diffie_hellman(&PublicKey::from(remote_hello.ephemeral));
+    if !shared.was_contributory() {
+        return Err(invalid("peer sent a low-order ephemeral key"));
+    }
+    let (initiator_hello, responder_hello) = if initiator {
+        (&ours, &theirs)
+    } else {
+        (&theirs, &ours)
+    };
+    let mut transcript = blake3::Hasher::new();
+    transcript.update(initiator_hello);
+    transcript.update(responder_hello);
+    let transcript = *transcript.finalize().as_bytes();
+
+    let mut material = shared.as_bytes().to_vec();
+    material.extend_from_slice(&transcript);
+    let initiator_key = blake3::derive_key(INITIATOR_KEY_CONTEXT, &material);
+    let responder_key = blake3::derive_key(RESPONDER_KEY_CONTEXT, &material);
+    let (send_key, recv_key) = if initiator {
+        (initiator_key, responder_key)
+    } else {
+        (responder_key, initiator_key)
+    };
+    let mut writer = SecureWriter::new(writer, send_key);
+    let mut reader = SecureReader::new(reader, recv_key);
+
+    let auth = Auth {
+        signature: identity
+            .sign(&auth_message(&transcript, initiator))
+            .to_bytes()
+            .to_vec(),
+    };
+    writer
+        .send_bytes(&bincode::serialize(&auth).map_err(invalid_bincode)?)
+        .await?;
+    let remote_auth: Auth =
+        bincode::deserialize(&reader.recv_bytes().await?).map_err(invalid_bincode)?;
+    let signature = Signature::from_slice(&remote_auth.signature)
+        .map_err(|_| invalid("peer sent a malformed signature"))?;
+    remote
+        .verify(&auth_message(&transcript, !initiator), &signature)
+        .map_err(|_| invalid("peer failed to prove its node id"))?;
+
+    Ok(Session {
+        reader,
+        writer,
+        remote,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[tokio::test]
+    async fn test_handshake_authenticates_both_sides() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (a_read, a_write) = tokio::io::split(a);
+        let (b_read, b_write) = tokio::io::split(b);
+        let (dialer_key, listener_key) = (key(1), key(2));
+        let (dialer, listener) = tokio::join!(
+            handshake(a_read, a_write, &dialer_key, true),
+            handshake(b_read, b_write, &listener_key, false)
+        );
+        let (mut dialer, mut listener) = (dialer.unwrap(), listener.unwrap());
+        assert_eq!(dialer.remote, key(2).verifying_key());
+        assert_eq!(listener.remote, key(1).verifying_key());
+
+        dialer.writer.send(&NetworkMessage::Ping(7)).await.unwrap();
+        listener
+            .writer
+            .send(&NetworkMessage::Pong(7))
+            .await
+            .unwrap();
+        assert!(matches!(
+            listener.reader.recv().await.unwrap(),
+            NetworkMessage::Ping(7)
+        ));
+        assert!(matches!(
+            dialer.reader.recv().await.unwrap(),
+            NetworkMessage::Pong(7)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_altered_or_replayed_frames_are_rejected() {
+        let mut writer = SecureWriter::new(Vec::new(), [3u8; 32]);
+        writer.send(&NetworkMessage::Ping(1)).await.unwrap();
+        let frame = writer.inner.clone();
+        writer.send(&NetworkMessage::Ping(2)).await.unwrap();
+
+        let mut reader = SecureReader::new(writer.inner.as_slice(), [3u8; 32]);
+        assert!(matches!(
+            reader.recv().await.unwrap(),
+            NetworkMessage::Ping(1)
+        ));
+        assert!(matches!(
+            reader.recv().await.unwrap(),
+            NetworkMessage::Ping(2)
+        ));
+
+        // The first frame again, now out of order
+        let mut replayed = writer.inner.clone();
+        replayed.extend_from_slice(&frame);
+        let mut reader = SecureReader::new(replayed.as_slice(), [3u8; 32]);
+        reader.recv().await.unwrap();
+        reader.recv().await.unwrap();
+        assert!(reader.recv().await.is_err());
+
+        let mut altered = frame.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        let mut reader = SecureReader::new(altered.as_slice(), [3u8; 32]);
+        assert!(reader.recv().await.is_err());
+    }
+}
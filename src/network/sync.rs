@@ -0,0 +1,711 @@
+//! Headers-first block sync. A node behind its best peer first fetches
+//! and checks the header chain up to that peer's height, then downloads
+//! the bodies in ranges spread over every peer that has them, and applies
+//! the blocks in order. Once it reaches the best peer's height it is
+//! synced and follows the chain through gossip. Headers are checked
+//! against the producer schedule where the local state knows it, and a
+//! peer is penalized when its claimed height is not backed by headers or
+//! its headers lead to a block the chain rejects.
+
+use super::gossip::{Gossip, INVALID_BLOCK_PENALTY};
+use super::p2p::{NetworkEvent, PeerId};
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::{BlockLookup, Blockchain};
+use crate::consensus::dpos::NetworkMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+/// Most headers sent in one `BlockResponse`
+pub const MAX_HEADERS_PER_RESPONSE: u32 = 2_000;
+
+/// Most blocks sent in one `BlockResponse`
+pub const MAX_BLOCKS_PER_RESPONSE: u32 = 128;
+
+/// Blocks asked of one peer at a time
+pub const BLOCKS_PER_REQUEST: u32 = 16;
+
+/// Most checked headers held above the local tip
+pub const MAX_HEADERS_AHEAD: u64 = 10_000;
+
+/// Penalty for a peer that claims a height it cannot serve headers for
+pub const UNBACKED_HEIGHT_PENALTY: u32 = 20;
+
+/// A request unanswered for this long is given to another peer
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncState {
+    /// No peers to sync from
+    Idle,
+    /// Behind the best peer and downloading
+    Syncing,
+    /// At the best peer's height; new blocks arrive by gossip
+    Synced,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncStatus {
+    pub state: SyncState,
+    pub current_height: u64,
+    pub target_height: u64, // best height announced by a peer
+    pub headers_queued: usize,
+    pub blocks_queued: usize,
+    pub requests_in_flight: usize,
+}
+
+struct Request {
+    from: u64,
+    count: u32,
+    headers_only: bool,
+    sent: Instant,
+}
+
+struct Progress {
+    state: SyncState,
+    target: u64,
+    /// Checked headers above the local tip, waiting for their bodies,
+    /// with the peer each came from
+    headers: BTreeMap<u64, (PeerId, BlockHeader)>,
+    /// Downloaded bodies waiting to be applied
+    blocks: BTreeMap<u64, Block>,
+    /// At most one outstanding request per peer
+    requests: HashMap<PeerId, Request>,
+    /// Claimed heights a peer served no headers towards, ignored until
+    /// it claims another
+    unbacked: HashMap<PeerId, u64>,
+}
+
+pub struct BlockSync {
+    gossip: Arc<Gossip>,
+    progress: Mutex<Progress>,
+}
+
+/// Check that `headers` continue a chain whose block at `from - 1` has
+/// hash `parent_hash`, and that each is signed by its producer. Whether
+/// that producer was scheduled is checked by `Blockchain::check_header_producer`.
+pub fn check_headers(parent_hash: &str, from: u64, headers: &[BlockHeader]) -> Result<(), String> {
+    let mut parent_hash = parent_hash.to_string();
+    for (offset, header) in headers.iter().enumerate() {
+        let height = from + offset as u64;
+        if header.height != height {
+            return Err(format!("expected header {}, got {}", height, header.height));
+        }
+        if header.previous_hash != parent_hash {
+            return Err(format!("header {} does not link to its parent", height));
+        }
+        if !header.verify_signature() {
+            return Err(format!(
+                "header {} has an invalid producer signature",
+                height
+            ));
+        }
+        parent_hash = header.hash();
+    }
+    Ok(())
+}
+
+/// Answer a `BlockRequest` from the local chain
+pub fn serve_request(
+    blockchain: &Blockchain,
+    from: u64,
+    count: u32,
+    headers_only: bool,
+) -> NetworkMessage {
+    let limit = if headers_only {
+        MAX_HEADERS_PER_RESPONSE
+    } else {
+        MAX_BLOCKS_PER_RESPONSE
+    };
+    let mut headers = Vec::new();
+    let mut blocks = Vec::new();
+    for height in from..from.saturating_add(count.min(limit) as u64) {
+        match (blockchain.lookup_block(height), headers_only) {
+            (BlockLookup::Found(block), true) => headers.push(block.header.clone()),
+            (BlockLookup::Pruned(header), true) => headers.push(header),
+            (BlockLookup::Found(block), false) => blocks.push(block.clone()),
+            _ => break,
+        }
+    }
+    NetworkMessage::BlockResponse {
+        from,
+        headers,
+        blocks,
+    }
+}
+
+impl BlockSync {
+    pub fn new(gossip: Arc<Gossip>) -> Self {
+        BlockSync {
+            gossip,
+            progress: Mutex::new(Progress {
+                state: SyncState::Idle,
+                target: 0,
+                headers: BTreeMap::new(),
+                blocks: BTreeMap::new(),
+                requests: HashMap::new(),
+                unbacked: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        let current_height = self.local_height();
+        let progress = self.progress.lock().unwrap();
+        SyncStatus {
+            state: progress.state,
+            current_height,
+            target_height: progress.target.max(current_height),
+            headers_queued: progress.headers.len(),
+            blocks_queued: progress.blocks.len(),
+            requests_in_flight: progress.requests.len(),
+        }
+    }
+
+    /// Whether the node has caught up with its peers
+    pub fn is_synced(&self) -> bool {
+        self.progress.lock().unwrap().state != SyncState::Syncing
+    }
+
+    /// Feed network events to gossip and sync, stepping sync each second,
+    /// until the network stops
+    pub async fn run(self: Arc<Self>, mut events: mpsc::Receiver<NetworkEvent>) {
+        let mut tick = time::interval(TICK);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => {
+                        self.gossip.handle(&event);
+                        self.handle(&event);
+                    }
+                    None => break,
+                },
+                _ = tick.tick() => self.step(),
+            }
+        }
+    }
+
+    /// Serve and absorb `BlockRequest`/`BlockResponse` messages
+    pub fn handle(&self, event: &NetworkEvent) {
+        match event {
+            NetworkEvent::Message {
+                peer,
+                message:
+                    NetworkMessage::BlockRequest {
+                        from,
+                        count,
+                        headers_only,
+                    },
+            } => {
+                let response = serve_request(
+                    &self.gossip.blockchain().lock().unwrap(),
+                    *from,
+                    *count,
+                    *headers_only,
+                );
+                if let Err(e) = self.gossip.network().send(*peer, response) {
+                    debug!("Cannot answer block request from peer {}: {}", peer, e);
+                }
+            }
+            NetworkEvent::Message {
+                peer,
+                message:
+                    NetworkMessage::BlockResponse {
+                        from,
+                        headers,
+                        blocks,
+                    },
+            } => {
+                self.on_response(*peer, *from, headers, blocks);
+                self.step();
+            }
+            NetworkEvent::Connected(_) => self.step(),
+            NetworkEvent::Disconnected { peer, .. } => {
+                let mut progress = self.progress.lock().unwrap();
+                progress.requests.remove(peer);
+                progress.unbacked.remove(peer);
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply what has arrived and hand out the next requests
+    pub fn step(&self) {
+        self.apply_ready();
+
+        let local = self.local_height();
+        let mut peers = self.gossip.network().peers();
+        let mut progress = self.progress.lock().unwrap();
+        let unbacked = &mut progress.unbacked;
+        unbacked.retain(|id, height| {
+            peers
+                .iter()
+                .any(|peer| peer.id == *id && peer.height == *height)
+        });
+        peers.retain(|peer| !unbacked.contains_key(&peer.id));
+        progress.requests.retain(|peer, request| {
+            let alive = request.sent.elapsed() < REQUEST_TIMEOUT;
+            if !alive {
+                debug!("Request to peer {} from {} timed out", peer, request.from);
+            }
+            alive
+        });
+
+        progress.target = peers.iter().map(|peer| peer.height).max().unwrap_or(0);
+        let state = if peers.is_empty() {
+            SyncState::Idle
+        } else if local >= progress.target {
+            SyncState::Synced
+        } else {
+            SyncState::Syncing
+        };
+        if state != progress.state {
+            info!(
+                "Block sync {:?} at height {} of {}",
+                state, local, progress.target
+            );
+            progress.state = state;
+        }
+        if state != SyncState::Syncing {
+            progress.headers.clear();
+            progress.blocks.clear();
+            return;
+        }
+
+        let mut idle: Vec<_> = peers
+            .iter()
+            .filter(|peer| !progress.requests.contains_key(&peer.id))
+            .collect();
+        // Hand ranges to the highest peers first
+        idle.sort_by_key(|peer| std::cmp::Reverse(peer.height));
+
+        // Headers come from the best peer, one batch at a time, and run at
+        // most `MAX_HEADERS_AHEAD` past the local tip
+        let header_tip = progress
+            .headers
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(local);
+        let header_limit = progress.target.min(local + MAX_HEADERS_AHEAD);
+        let fetching_headers = progress.requests.values().any(|r| r.headers_only);
+        if header_tip < header_limit && !fetching_headers {
+            if let Some(index) = idle.iter().position(|peer| peer.height > header_tip) {
+                let peer = idle.remove(index);
+                let count = (header_limit - header_tip).min(MAX_HEADERS_PER_RESPONSE as u64);
+                self.request(&mut progress, peer.id, header_tip + 1, count as u32, true);
+            }
+        }
+
+        // Bodies for checked headers come from every other idle peer
+        let mut next = local + 1;
+        for peer in idle {
+            let Some(from) = next_range(&progress, next) else {
+                break;
+            };
+            if peer.height < from {
+                continue;
+            }
+            let mut count = 0;
+            while count < BLOCKS_PER_REQUEST
+                && from + (count as u64) <= peer.height
+                && progress.headers.contains_key(&(from + count as u64))
+                && !progress.blocks.contains_key(&(from + count as u64))
+            {
+                count += 1;
+            }
+            self.request(&mut progress, peer.id, from, count, false);
+            next = from + count as u64;
+        }
+    }
+
+    fn request(
+        &self,
+        progress: &mut Progress,
+        peer: PeerId,
+        from: u64,
+        count: u32,
+        headers_only: bool,
+    ) {
+        let message = NetworkMessage::BlockRequest {
+            from,
+            count,
+            headers_only,
+        };
+        match self.gossip.network().send(peer, message) {
+            Ok(()) => {
+                progress.requests.insert(
+                    peer,
+                    Request {
+                        from,
+                        count,
+                        headers_only,
+                        sent: Instant::now(),
+                    },
+                );
+            }
+            Err(e) => debug!("Cannot request blocks from peer {}: {}", peer, e),
+        }
+    }
+
+    fn on_response(&self, peer: PeerId, from: u64, headers: &[BlockHeader], blocks: &[Block]) {
+        let claimed = self
+            .gossip
+            .network()
+            .peers()
+            .into_iter()
+            .find(|info| info.id == peer)
+            .map_or(0, |info| info.height);
+        let mut progress = self.progress.lock().unwrap();
+        let matches = progress
+            .requests
+            .get(&peer)
+            .is_some_and(|request| request.from == from);
+        if !matches {
+            debug!("Ignoring unrequested blocks from peer {}", peer);
+            return;
+        }
+        let Some(request) = progress.requests.remove(&peer) else {
+            return;
+        };
+        if headers.len() + blocks.len() > request.count as usize {
+            drop(progress);
+            self.gossip.network().penalize(
+                peer,
+                INVALID_BLOCK_PENALTY,
+                "a block response with more blocks than requested",
+            );
+            return;
+        }
+
+        if request.headers_only {
+            let parent_hash = match from.checked_sub(1) {
+                Some(parent) => progress
+                    .headers
+                    .get(&parent)
+                    .map(|(_, header)| header.hash())
+                    .or_else(|| self.local_hash(parent)),
+                None => Some(String::new()),
+            };
+            let Some(parent_hash) = parent_hash else {
+                return;
+            };
+            // A peer on another fork is not misbehaving
+            if headers
+                .first()
+                .is_some_and(|header| header.previous_hash != parent_hash)
+            {
+                debug!("Peer {} is on another fork from height {}", peer, from);
+                return;
+            }
+            // A peer that started from a snapshot may hold no headers this
+            // low, so a claim met with none only stops counting towards the
+            // target. Stopping short part way means the claim was never real.
+            let backed = from + headers.len() as u64;
+            let owed = claimed.min(from + request.count as u64 - 1);
+            if headers.is_empty() && owed >= from {
+                debug!("Peer {} serves no headers from {}", peer, from);
+                progress.unbacked.insert(peer, claimed);
+                return;
+            }
+            if backed <= owed {
+                drop(progress);
+                self.gossip.network().penalize(
+                    peer,
+                    UNBACKED_HEIGHT_PENALTY,
+                    &format!(
+                        "a claimed height of {} with no headers past {}",
+                        claimed,
+                        backed.saturating_sub(1)
+                    ),
+                );
+                return;
+            }
+            let checked = check_headers(&parent_hash, from, headers).and_then(|()| {
+                let blockchain = self.gossip.blockchain().lock().unwrap();
+                headers
+                    .iter()
+                    .try_for_each(|header| blockchain.check_header_producer(header))
+            });
+            match checked {
+                Ok(()) => {
+                    for header in headers {
+                        progress
+                            .headers
+                            .insert(header.height, (peer, header.clone()));
+                    }
+                }
+                Err(e) => {
+                    drop(progress);
+                    self.gossip.network().penalize(
+                        peer,
+                        INVALID_BLOCK_PENALTY,
+                        &format!("an invalid header chain: {}", e),
+                    );
+                }
+            }
+            return;
+        }
+
+        for block in blocks {
+            let height = block.header.height;
+            let expected = progress
+                .headers
+                .get(&height)
+                .map(|(_, header)| header.hash());
+            if expected.as_deref() != Some(block.hash().as_str()) || !block.has_valid_merkle_root()
+            {
+                drop(progress);
+                self.gossip.network().penalize(
+                    peer,
+                    INVALID_BLOCK_PENALTY,
+                    &format!(
+                        "block {} with a body that does not match its header",
+                        height
+                    ),
+                );
+                return;
+            }
+            progress.blocks.insert(height, block.clone());
+        }
+    }
+
+    /// Add downloaded blocks that extend the local tip
+    fn apply_ready(&self) {
+        loop {
+            let mut progress = self.progress.lock().unwrap();
+            let mut blockchain = self.gossip.blockchain().lock().unwrap();
+            let local = blockchain.get_block_height();
+            // Gossip may have moved the tip past queued blocks
+            progress.headers.retain(|height, _| *height > local);
+            progress.blocks.retain(|height, _| *height > local);
+            let Some(block) = progress.blocks.remove(&(local + 1)) else {
+                return;
+            };
+            match blockchain.add_block(block.clone()) {
+                Ok(()) => {
+                    drop(blockchain);
+                    drop(progress);
+                    self.gossip.note_block(&block);
+                    self.gossip.on_new_tip();
+                }
+                Err(e) => {
+                    // The header chain leads somewhere the local chain cannot
+                    // follow; start again from the local tip, and hold the
+                    // peer that served it responsible
+                    warn!("Synced block {} was rejected: {}", local + 1, e);
+                    let source = progress.headers.get(&(local + 1)).map(|(peer, _)| *peer);
+                    progress.headers.clear();
+                    progress.blocks.clear();
+                    drop(blockchain);
+                    drop(progress);
+                    if let Some(peer) = source {
+                        self.gossip.network().penalize(
+                            peer,
+                            INVALID_BLOCK_PENALTY,
+                            &format!("headers leading to rejected block {}: {}", local + 1, e),
+                        );
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn local_height(&self) -> u64 {
+        self.gossip.blockchain().lock().unwrap().get_block_height()
+    }
+
+    fn local_hash(&self, height: u64) -> Option<String> {
+        match self
+            .gossip
+            .blockchain()
+            .lock()
+            .unwrap()
+            .lookup_block(height)
+        {
+            BlockLookup::Found(block) => Some(block.hash()),
+            BlockLookup::Pruned(header) => Some(header.hash()),
+            BlockLookup::NotFound => None,
+        }
+    }
+}
+
+/// Lowest height from `start` with a checked header, no body and no
+/// request covering it
+fn next_range(progress: &Progress, start: u64) -> Option<u64> {
+    let requested = |height: u64| {
+        progress.requests.values().any(|request| {
+            !request.headers_only
+                && height >= request.from
+                && height < request.from + request.count as u64
+        })
+    };
+    progress
+        .headers
+        .range(start..)
+        .map(|(height, _)| *height)
+        .find(|height| !progress.blocks.contains_key(height) && !requested(*height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::P2pConfig;
+    use crate::crypto_utils::generate_keypair;
+    use crate::network::p2p::NetworkService;
+    use crate::simulation::GENESIS_TIMESTAMP;
+    use crate::test_support::{mine_scheduled, test_chain, test_config, validator_key};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn chain() -> Blockchain {
+        test_chain(test_config())
+    }
+
+    fn mine(blockchain: &mut Blockchain, count: u64) -> Vec<Block> {
+        (0..count)
+            .map(|_| mine_scheduled(blockchain, &[&validator_key()]).unwrap())
+            .collect()
+    }
+
+    async fn node(blockchain: Blockchain) -> Arc<BlockSync> {
+        let height = blockchain.get_block_height();
+        let genesis_hash = blockchain.genesis_hash().to_string();
+        let config = P2pConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            ..P2pConfig::default()
+        };
+        let (network, events) =
+            NetworkService::start(config, generate_keypair().0, genesis_hash, height)
+                .await
+                .unwrap();
+        let gossip = Arc::new(Gossip::new(network, Arc::new(Mutex::new(blockchain))));
+        let sync = Arc::new(BlockSync::new(gossip));
+        tokio::spawn(sync.clone().run(events));
+        sync
+    }
+
+    #[test]
+    fn test_check_headers_rejects_broken_links() {
+        let mut blockchain = chain();
+        let genesis_hash = blockchain.genesis_hash().to_string();
+        let headers: Vec<BlockHeader> = mine(&mut blockchain, 3)
+            .into_iter()
+            .map(|block| block.header)
+            .collect();
+        assert!(check_headers(&genesis_hash, 1, &headers).is_ok());
+        assert!(check_headers(&genesis_hash, 2, &headers).is_err());
+
+        let mut skipped = headers.clone();
+        skipped.remove(1);
+        assert!(check_headers(&genesis_hash, 1, &skipped).is_err());
+
+        let mut forged = headers;
+        forged[2].timestamp += 1;
+        assert!(check_headers(&genesis_hash, 1, &forged)
+            .unwrap_err()
+            .contains("signature"));
+    }
+
+    #[test]
+    fn test_headers_from_unscheduled_producers_are_rejected() {
+        let blockchain = chain();
+        let intruder = SigningKey::from_bytes(&[2u8; 32]);
+        let signed = |height: u64| {
+            let mut block = Block::unsigned_at(
+                height,
+                blockchain.genesis_hash().to_string(),
+                Vec::new(),
+                GENESIS_TIMESTAMP + 5,
+                0,
+                hex::encode(intruder.verifying_key().to_bytes()),
+            );
+            block.set_signature(&intruder.sign(&block.header.signing_hash()));
+            block.header
+        };
+
+        let header = signed(1);
+        assert!(check_headers(blockchain.genesis_hash(), 1, std::slice::from_ref(&header)).is_ok());
+        assert!(blockchain
+            .check_header_producer(&header)
+            .unwrap_err()
+            .contains("not scheduled"));
+        assert!(blockchain
+            .check_header_producer(&signed(2))
+            .unwrap_err()
+            .contains("not in the active set"));
+        // The next epoch's active set is not known yet
+        let epoch_length = blockchain.staking_params().epoch_length;
+        assert!(blockchain
+            .check_header_producer(&signed(epoch_length))
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_late_node_catches_up_from_several_peers() {
+        let mut source = chain();
+        let blocks = mine(&mut source, 40);
+        let mut copy = chain();
+        for block in &blocks {
+            copy.add_block(block.clone()).unwrap();
+        }
+        let tip = source.get_latest_block().hash();
+        let (a, b) = (node(source).await, node(copy).await);
+        let late = node(chain()).await;
+        assert_eq!(late.status().state, SyncState::Idle);
+
+        let network = late.gossip.network();
+        network
+            .connect(a.gossip.network().local_addr())
+            .await
+            .unwrap();
+        network
+            .connect(b.gossip.network().local_addr())
+            .await
+            .unwrap();
+
+        let mut synced = false;
+        for _ in 0..200 {
+            if late.status().state == SyncState::Synced && late.local_height() == 40 {
+                synced = true;
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(synced, "{:?}", late.status());
+        let status = late.status();
+        assert_eq!((status.current_height, status.target_height), (40, 40));
+        assert_eq!(status.blocks_queued, 0);
+        let blockchain = late.gossip.blockchain().lock().unwrap();
+        assert_eq!(blockchain.get_latest_block().hash(), tip);
+    }
+
+    #[test]
+    fn test_serve_request_stops_at_the_tip() {
+        let mut blockchain = chain();
+        mine(&mut blockchain, 3);
+        match serve_request(&blockchain, 2, 10, false) {
+            NetworkMessage::BlockResponse {
+                from,
+                headers,
+                blocks,
+            } => {
+                assert_eq!(from, 2);
+                assert!(headers.is_empty());
+                assert_eq!(blocks.len(), 2);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match serve_request(&blockchain, 0, 10, true) {
+            NetworkMessage::BlockResponse { headers, .. } => assert_eq!(headers.len(), 4),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,43 +1,134 @@
-use std::collections::HashMap;
+use crate::staking::StakingParams;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
+const BPS_DENOMINATOR: u64 = 10_000;
+
+/// How one validator's share of an epoch's rewards was paid out
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidatorReward {
+    pub validator: String,
+    pub epoch: u64,
+    pub total: u64,
+    pub commission: u64,
+    pub payouts: BTreeMap<String, u64>, // staker (validator or delegator) -> amount
+}
+
+/// Splits a fixed reward per epoch between the active validators by voting
+/// power, then each validator's share between its commission and its
+/// stakers. Integer arithmetic only, rounding down; the dust is not minted.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RewardCalculator {
     epoch_rewards: u64,
-    commission_rate: f64,
-    last_distribution_epoch: u64,
+    commission_bps: u64, // basis points of each validator's share
 }
 
 impl RewardCalculator {
-    pub fn new(base_reward: u64, commission: f64) -> Self {
+    pub fn new(epoch_rewards: u64, commission_bps: u64) -> Self {
         RewardCalculator {
-            epoch_rewards: base_reward,
-            commission_rate: commission,
-            last_distribution_epoch: 0,
+            epoch_rewards,
+            commission_bps: commission_bps.min(BPS_DENOMINATOR),
         }
     }
 
-    pub fn calculate_rewards(
-        &mut self,
-        current_epoch: u64,
-        active_validators: &HashMap<[u8; 32], u64>, // validator -> stake amount
-    ) -> HashMap<[u8; 32], u64> {
-        if current_epoch <= self.last_distribution_epoch {
-            return HashMap::new();
+    /// Reward of each validator in `voting_power`, pro rata to its power.
+    /// Nothing is paid when there is no power at all.
+    pub fn calculate_rewards(&self, voting_power: &BTreeMap<String, u64>) -> BTreeMap<String, u64> {
+        let total_power: u128 = voting_power.values().map(|power| *power as u128).sum();
+        if total_power == 0 {
+            return BTreeMap::new();
         }
+        voting_power
+            .iter()
+            .map(|(validator, power)| {
+                let share = self.epoch_rewards as u128 * *power as u128 / total_power;
+                (validator.clone(), share as u64)
+            })
+            .collect()
+    }
+
+    /// Pay `reward` earned by `validator` in `epoch`: the commission goes to
+    /// the validator, the rest to its stakers pro rata, counting the
+    /// validator's self-stake alongside `delegations`
+    pub fn split_reward(
+        &self,
+        validator: &str,
+        epoch: u64,
+        reward: u64,
+        self_stake: u64,
+        delegations: &[(String, u64)],
+    ) -> ValidatorReward {
+        let commission =
+            (reward as u128 * self.commission_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+        let remainder = reward - commission;
 
-        let total_stake: u64 = active_validators.values().sum();
-        let mut rewards = HashMap::new();
+        let stakers = std::iter::once((validator, self_stake)).chain(
+            delegations
+                .iter()
+                .map(|(delegator, amount)| (delegator.as_str(), *amount)),
+        );
+        let total_stake: u128 = stakers.clone().map(|(_, stake)| stake as u128).sum();
 
-        for (validator, stake) in active_validators {
-            let validator_share = *stake as f64 / total_stake as f64;
-            let reward = (self.epoch_rewards as f64 * validator_share) as u64;
-            rewards.insert(*validator, reward);
+        let mut payouts = BTreeMap::new();
+        if commission > 0 {
+            payouts.insert(validator.to_string(), commission);
         }
+        for (staker, stake) in stakers {
+            let amount = (remainder as u128 * stake as u128)
+                .checked_div(total_stake)
+                .unwrap_or(0) as u64;
+            if amount > 0 {
+                *payouts.entry(staker.to_string()).or_insert(0) += amount;
+            }
+        }
+
+        ValidatorReward {
+            validator: validator.to_string(),
+            epoch,
+            total: payouts.values().sum(),
+            commission,
+            payouts,
+        }
+    }
+}
 
-        self.last_distribution_epoch = current_epoch;
-        rewards
+impl From<&StakingParams> for RewardCalculator {
+    fn from(params: &StakingParams) -> Self {
+        RewardCalculator::new(params.epoch_reward, params.commission_bps)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn update_epoch_rewards(&mut self, new_reward: u64) {
-        self.epoch_rewards = new_reward;
+    #[test]
+    fn test_rewards_follow_voting_power() {
+        let calculator = RewardCalculator::new(1000, 1000);
+        let power: BTreeMap<String, u64> = [("a".to_string(), 300), ("b".to_string(), 600)].into();
+        let rewards = calculator.calculate_rewards(&power);
+        assert_eq!(rewards["a"], 333);
+        assert_eq!(rewards["b"], 666);
+
+        assert!(calculator.calculate_rewards(&BTreeMap::new()).is_empty());
+        let idle: BTreeMap<String, u64> = [("a".to_string(), 0)].into();
+        assert!(calculator.calculate_rewards(&idle).is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_commission_and_pro_rata_split() {
+        let calculator = RewardCalculator::new(0, 1000);
+        let delegations = vec![("d1".to_string(), 1000), ("d2".to_string(), 2000)];
+        let reward = calculator.split_reward("val", 4, 1000, 1000, &delegations);
+
+        // 10% commission, then 900 over 4000 of stake
+        assert_eq!(reward.commission, 100);
+        assert_eq!(reward.payouts["val"], 100 + 225);
+        assert_eq!(reward.payouts["d1"], 225);
+        assert_eq!(reward.payouts["d2"], 450);
+        assert_eq!(reward.total, 1000);
+
+        let unstaked = calculator.split_reward("val", 4, 1000, 0, &[]);
+        assert_eq!(unstaked.total, 100);
+    }
+}
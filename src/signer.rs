@@ -0,0 +1,572 @@
+//! Consensus signing. A node signs blocks and commit votes through a
+//! `BlockSigner`: either a `LocalSigner` holding the key file itself, or a
+//! `RemoteSigner` asking a separate signer process (`owami-signer`) over a
+//! Unix socket. Both record the highest block and vote they have signed
+//! and refuse anything that conflicts with it, so a restarted or
+//! duplicated node cannot double-sign. The remote signer and its server
+//! need Unix domain sockets and only exist on Unix.
+
+use crate::block::BlockHeader;
+use crate::consensus::finality::CommitVote;
+use crate::key_management::KeyManager;
+use anyhow::Result;
+use ed25519_dalek::{Signature, Signer, SigningKey};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+#[cfg(unix)]
+use {
+    ed25519_dalek::Verifier,
+    std::io::{BufRead, BufReader},
+    std::os::unix::net::{UnixListener, UnixStream},
+    std::path::Path,
+    std::time::Duration,
+    tracing::{info, warn},
+};
+
+/// How long either end of a signer connection waits for the other
+#[cfg(unix)]
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Signs blocks and commit votes with a validator's consensus key
+pub trait BlockSigner: Send {
+    /// Hex public key signatures are made with
+    fn public_key(&mut self) -> Result<String, String>;
+
+    /// Signature over `header.signing_hash()`
+    fn sign_block(&mut self, header: &BlockHeader) -> Result<Signature, String>;
+
+    /// Commit vote for the block `block_hash` at `height`
+    fn sign_vote(&mut self, height: u64, block_hash: &str) -> Result<CommitVote, String>;
+
+    /// Key staged to replace the current one, if any
+    fn next_public_key(&mut self) -> Result<Option<String>, String> {
+        Ok(None)
+    }
+
+    /// Generate a key to take over later, keeping the current one in use
+    fn stage_next_key(&mut self) -> Result<String, String> {
+        Err("this signer cannot rotate its key".to_string())
+    }
+
+    /// Make the staged key the current one
+    fn promote_next_key(&mut self) -> Result<String, String> {
+        Err("this signer cannot rotate its key".to_string())
+    }
+}
+
+/// A bare key signs anything it is given. For tools and tests; validators
+/// should sign through `LocalSigner` or `RemoteSigner`.
+impl BlockSigner for SigningKey {
+    fn public_key(&mut self) -> Result<String, String> {
+        Ok(hex::encode(self.verifying_key().to_bytes()))
+    }
+
+    fn sign_block(&mut self, header: &BlockHeader) -> Result<Signature, String> {
+        Ok(self.sign(&header.signing_hash()))
+    }
+
+    fn sign_vote(&mut self, height: u64, block_hash: &str) -> Result<CommitVote, String> {
+        Ok(CommitVote::new(height, block_hash.to_string(), self))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignKind {
+    Block,
+    Vote,
+}
+
+/// The last block or vote signed: its height and block hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedAt {
+    pub height: u64,
+    pub block_hash: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SignedHeights {
+    block: Option<SignedAt>,
+    vote: Option<SignedAt>,
+}
+
+/// Persistent record of the highest block and vote signed. A height is
+/// written to disk before its signature is handed out, and from then on
+/// only the same block may be signed again at that height and nothing
+/// below it.
+#[derive(Debug)]
+pub struct HighWaterMark {
+    path: PathBuf,
+    signed: SignedHeights,
+}
+
+impl HighWaterMark {
+    /// Load the marks kept at `path`; a missing file means nothing signed yet
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let signed = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)?
+        } else {
+            SignedHeights::default()
+        };
+        Ok(HighWaterMark { path, signed })
+    }
+
+    pub fn last_signed(&self, kind: SignKind) -> Option<&SignedAt> {
+        match kind {
+            SignKind::Block => self.signed.block.as_ref(),
+            SignKind::Vote => self.signed.vote.as_ref(),
+        }
+    }
+
+    /// Check that signing `block_hash` at `height` conflicts with nothing
+    /// signed before
+    pub fn check(&self, kind: SignKind, height: u64, block_hash: &str) -> Result<(), String> {
+        if let Some(last) = self.last_signed(kind) {
+            if height < last.height {
+                return Err(format!(
+                    "refusing to sign a {:?} at height {}: already signed height {}",
+                    kind, height, last.height
+                ));
+            }
+            if height == last.height && last.block_hash != block_hash {
+                return Err(format!(
+                    "refusing to sign a {:?} for {} at height {}: already signed {}",
+                    kind, block_hash, height, last.block_hash
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that signing `block_hash` at `height` conflicts with nothing
+    /// signed before, and record it
+    pub fn advance(&mut self, kind: SignKind, height: u64, block_hash: &str) -> Result<(), String> {
+        self.check(kind, height, block_hash)?;
+        if self
+            .last_signed(kind)
+            .is_some_and(|last| last.height == height)
+        {
+            return Ok(());
+        }
+
+        let signed = Some(SignedAt {
+            height,
+            block_hash: block_hash.to_string(),
+        });
+        let previous = match kind {
+            SignKind::Block => std::mem::replace(&mut self.signed.block, signed),
+            SignKind::Vote => std::mem::replace(&mut self.signed.vote, signed),
+        };
+        if let Err(e) = self.save() {
+            match kind {
+                SignKind::Block => self.signed.block = previous,
+                SignKind::Vote => self.signed.vote = previous,
+            }
+            return Err(format!("Failed to save signing high-water mark: {}", e));
+        }
+        Ok(())
+    }
+
+    /// Replace the file atomically, so a crash leaves the old or new marks
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&self.signed)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Signs with the consensus key held by a `KeyManager` on this machine
+pub struct LocalSigner {
+    keys: KeyManager,
+    marks: HighWaterMark,
+}
+
+impl LocalSigner {
+    /// Sign with `keys`, keeping the high-water mark at `state_path`
+    pub fn new(keys: KeyManager, state_path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(LocalSigner {
+            keys,
+            marks: HighWaterMark::open(state_path)?,
+        })
+    }
+
+    fn signing_key(&mut self) -> Result<SigningKey, String> {
+        self.keys.get_signing_key().map_err(|e| e.to_string())
+    }
+}
+
+impl BlockSigner for LocalSigner {
+    fn public_key(&mut self) -> Result<String, String> {
+        Ok(hex::encode(self.signing_key()?.verifying_key().to_bytes()))
+    }
+
+    fn sign_block(&mut self, header: &BlockHeader) -> Result<Signature, String> {
+        let key = self.signing_key()?;
+        let public_key = hex::encode(key.verifying_key().to_bytes());
+        if header.producer != public_key {
+            return Err(format!(
+                "block names producer {}, this signer holds {}",
+                header.producer, public_key
+            ));
+        }
+        self.marks
+            .advance(SignKind::Block, header.height, &header.hash())?;
+        Ok(key.sign(&header.signing_hash()))
+    }
+
+    fn sign_vote(&mut self, height: u64, block_hash: &str) -> Result<CommitVote, String> {
+        let key = self.signing_key()?;
+        self.marks.advance(SignKind::Vote, height, block_hash)?;
+        Ok(CommitVote::new(height, block_hash.to_string(), &key))
+    }
+
+    fn next_public_key(&mut self) -> Result<Option<String>, String> {
+        let next = self.keys.load_next_key().map_err(|e| e.to_string())?;
+        Ok(next.map(|key| hex::encode(key.verifying_key().to_bytes())))
+    }
+
+    fn stage_next_key(&mut self) -> Result<String, String> {
+        let next = self.keys.stage_next_key().map_err(|e| e.to_string())?;
+        Ok(hex::encode(next.verifying_key().to_bytes()))
+    }
+
+    fn promote_next_key(&mut self) -> Result<String, String> {
+        let key = self.keys.promote_next_key().map_err(|e| e.to_string())?;
+        Ok(hex::encode(key.verifying_key().to_bytes()))
+    }
+}
+
+/// Request sent to the signer process, one JSON object per line
+#[cfg(unix)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum SignRequest {
+    PublicKey,
+    SignBlock { header: BlockHeader },
+    SignVote { height: u64, block_hash: String },
+    NextPublicKey,
+    StageNextKey,
+    PromoteNextKey,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SignResponse {
+    Key(String),
+    NextKey(Option<String>),
+    Signature(String), // hex
+    Vote(CommitVote),
+}
+
+/// Signs through a signer process listening on a Unix socket. The node
+/// keeps its own high-water mark as well as the signer's, so neither a
+/// restarted node nor a second node sharing the signer gets a conflicting
+/// signature.
+#[cfg(unix)]
+pub struct RemoteSigner {
+    socket_path: PathBuf,
+    marks: HighWaterMark,
+    timeout: Duration,
+}
+
+#[cfg(unix)]
+impl RemoteSigner {
+    /// Sign through the socket at `socket_path`, keeping this node's
+    /// high-water mark at `state_path`
+    pub fn new(socket_path: impl Into<PathBuf>, state_path: impl Into<PathBuf>) -> Result<Self> {
+        Ok(RemoteSigner {
+            socket_path: socket_path.into(),
+            marks: HighWaterMark::open(state_path)?,
+            timeout: SOCKET_TIMEOUT,
+        })
+    }
+
+    fn call(&self, request: &SignRequest) -> Result<SignResponse, String> {
+        let exchange = || -> Result<String> {
+            let mut stream = UnixStream::connect(&self.socket_path)?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            let mut line = serde_json::to_string(request)?;
+            line.push('\n');
+            stream.write_all(line.as_bytes())?;
+            let mut response = String::new();
+            BufReader::new(stream).read_line(&mut response)?;
+            Ok(response)
+        };
+        let response = exchange().map_err(|e| {
+            format!(
+                "signer at {} is unreachable: {}",
+                self.socket_path.display(),
+                e
+            )
+        })?;
+        serde_json::from_str::<Result<SignResponse, String>>(&response)
+            .map_err(|e| format!("invalid response from signer: {}", e))?
+    }
+}
+
+#[cfg(unix)]
+fn unexpected(response: SignResponse) -> String {
+    format!("unexpected response from signer: {:?}", response)
+}
+
+#[cfg(unix)]
+impl BlockSigner for RemoteSigner {
+    fn public_key(&mut self) -> Result<String, String> {
+        match self.call(&SignRequest::PublicKey)? {
+            SignResponse::Key(key) => Ok(key),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn sign_block(&mut self, header: &BlockHeader) -> Result<Signature, String> {
+        let hash = header.hash();
+        self.marks.check(SignKind::Block, header.height, &hash)?;
+        let request = SignRequest::SignBlock {
+            header: header.clone(),
+        };
+        let signature = match self.call(&request)? {
+            SignResponse::Signature(signature) => signature,
+            other => return Err(unexpected(other)),
+        };
+        let signature = hex::decode(&signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| "signer returned a malformed signature".to_string())?;
+        crate::crypto_utils::hex_to_verifying_key(&header.producer)?
+            .verify(&header.signing_hash(), &signature)
+            .map_err(|_| "signer returned a signature that does not verify".to_string())?;
+        // Recorded once the signer has signed; it keeps its own mark too
+        self.marks.advance(SignKind::Block, header.height, &hash)?;
+        Ok(signature)
+    }
+
+    fn sign_vote(&mut self, height: u64, block_hash: &str) -> Result<CommitVote, String> {
+        self.marks.check(SignKind::Vote, height, block_hash)?;
+        let request = SignRequest::SignVote {
+            height,
+            block_hash: block_hash.to_string(),
+        };
+        let vote = match self.call(&request)? {
+            SignResponse::Vote(vote) => vote,
+            other => return Err(unexpected(other)),
+        };
+        if vote.height != height || vote.block_hash != block_hash {
+            return Err("signer voted for a different block".to_string());
+        }
+        vote.verify()?;
+        self.marks.advance(SignKind::Vote, height, block_hash)?;
+        Ok(vote)
+    }
+
+    fn next_public_key(&mut self) -> Result<Option<String>, String> {
+        match self.call(&SignRequest::NextPublicKey)? {
+            SignResponse::NextKey(key) => Ok(key),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn stage_next_key(&mut self) -> Result<String, String> {
+        match self.call(&SignRequest::StageNextKey)? {
+            SignResponse::Key(key) => Ok(key),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn promote_next_key(&mut self) -> Result<String, String> {
+        match self.call(&SignRequest::PromoteNextKey)? {
+            SignResponse::Key(key) => Ok(key),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn handle(signer: &mut dyn BlockSigner, request: SignRequest) -> Result<SignResponse, String> {
+    match request {
+        SignRequest::PublicKey => signer.public_key().map(SignResponse::Key),
+        SignRequest::SignBlock { header } => {
+            let signature = signer.sign_block(&header)?;
+            info!("Signed block {} ({})", header.height, header.hash());
+            Ok(SignResponse::Signature(hex::encode(signature.to_bytes())))
+        }
+        SignRequest::SignVote { height, block_hash } => signer
+            .sign_vote(height, &block_hash)
+            .map(SignResponse::Vote),
+        SignRequest::NextPublicKey => signer.next_public_key().map(SignResponse::NextKey),
+        SignRequest::StageNextKey => signer.stage_next_key().map(SignResponse::Key),
+        SignRequest::PromoteNextKey => signer.promote_next_key().map(SignResponse::Key),
+    }
+}
+
+#[cfg(unix)]
+fn handle_connection(stream: UnixStream, signer: &mut dyn BlockSigner) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let response = serde_json::from_str::<SignRequest>(&line)
+            .map_err(|e| format!("invalid request: {}", e))
+            .and_then(|request| handle(signer, request));
+        if let Err(e) = &response {
+            warn!("Refused signing request: {}", e);
+        }
+        let mut reply = serde_json::to_string(&response)?;
+        reply.push('\n');
+        writer.write_all(reply.as_bytes())?;
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Bind the signer socket at `path`, replacing a stale socket file and
+/// letting only this user connect
+#[cfg(unix)]
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(listener)
+}
+
+/// Answer nodes connecting to `listener` with `signer`, one connection at
+/// a time so requests are signed in order. A connection that stalls for
+/// `SOCKET_TIMEOUT` is dropped so it cannot hold the signer up.
+#[cfg(unix)]
+pub fn serve(listener: &UnixListener, signer: &mut dyn BlockSigner) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+        stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+        if let Err(e) = handle_connection(stream, signer) {
+            warn!("Signer connection failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn local_signer(dir: &Path) -> Result<LocalSigner> {
+        let keys = KeyManager::new(Some(
+            dir.join("consensus.key").to_str().unwrap().to_string(),
+        ))?;
+        LocalSigner::new(keys, dir.join("signed.json"))
+    }
+
+    fn header(signer: &mut dyn BlockSigner, height: u64, timestamp: u64) -> BlockHeader {
+        let producer = signer.public_key().unwrap();
+        Block::unsigned_at(height, "0".repeat(64), Vec::new(), timestamp, 0, producer).header
+    }
+
+    #[test]
+    fn test_local_signer_refuses_conflicting_blocks_after_restart() -> Result<()> {
+        let dir = tempdir()?;
+        let mut signer = local_signer(dir.path())?;
+        let block = header(&mut signer, 5, 100);
+        signer.sign_block(&block).map_err(anyhow::Error::msg)?;
+        // The same block may be signed again, e.g. after a crash
+        signer.sign_block(&block).map_err(anyhow::Error::msg)?;
+        signer
+            .sign_vote(5, &block.hash())
+            .map_err(anyhow::Error::msg)?;
+
+        let mut restarted = local_signer(dir.path())?;
+        let conflicting = header(&mut restarted, 5, 101);
+        assert!(restarted
+            .sign_block(&conflicting)
+            .unwrap_err()
+            .contains("already signed"));
+        let lower = header(&mut restarted, 4, 90);
+        assert!(restarted.sign_block(&lower).is_err());
+        assert!(restarted.sign_vote(5, &conflicting.hash()).is_err());
+        let next = header(&mut restarted, 6, 105);
+        restarted.sign_block(&next).map_err(anyhow::Error::msg)?;
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_signer_signs_through_socket() -> Result<()> {
+        let dir = tempdir()?;
+        let socket = dir.path().join("signer.sock");
+        let listener = bind(&socket)?;
+        let mut local = local_signer(dir.path())?;
+        std::thread::spawn(move || serve(&listener, &mut local));
+
+        let mut remote = RemoteSigner::new(&socket, dir.path().join("node-a.json"))?;
+        let mut block = Block::unsigned_at(
+            7,
+            "0".repeat(64),
+            Vec::new(),
+            100,
+            0,
+            remote.public_key().map_err(anyhow::Error::msg)?,
+        );
+        let signature = remote
+            .sign_block(&block.header)
+            .map_err(anyhow::Error::msg)?;
+        block.set_signature(&signature);
+        assert!(block.header.verify_signature());
+        let vote = remote
+            .sign_vote(7, &block.hash())
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(vote.validator, block.header.producer);
+
+        // A second node sharing the signer has no marks of its own, but
+        // the signer still refuses a conflicting block
+        let mut other = RemoteSigner::new(&socket, dir.path().join("node-b.json"))?;
+        let conflicting = header(&mut other, 7, 101);
+        assert!(other
+            .sign_block(&conflicting)
+            .unwrap_err()
+            .contains("already signed"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_signer_records_nothing_when_the_signer_fails() -> Result<()> {
+        let dir = tempdir()?;
+        let mut remote = RemoteSigner::new(
+            dir.path().join("missing.sock"),
+            dir.path().join("node.json"),
+        )?;
+        let producer = hex::encode(
+            SigningKey::from_bytes(&[3u8; 32])
+                .verifying_key()
+                .to_bytes(),
+        );
+        let first = Block::unsigned_at(7, "0".repeat(64), Vec::new(), 100, 0, producer.clone());
+        let second = Block::unsigned_at(7, "0".repeat(64), Vec::new(), 101, 0, producer);
+        assert!(remote.sign_block(&first.header).is_err());
+        // The unreachable signer produced nothing, so the other block at
+        // the same height is not a conflict
+        let err = remote.sign_block(&second.header).unwrap_err();
+        assert!(!err.contains("already signed"), "{}", err);
+        assert!(remote.marks.last_signed(SignKind::Block).is_none());
+        Ok(())
+    }
+}
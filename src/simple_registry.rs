@@ -16,6 +16,12 @@ pub struct SimpleRegistry {
     contracts: HashMap<String, SimpleContract>,
 }
 
+impl Default for SimpleRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SimpleRegistry {
     pub fn new() -> Self {
         Self {
@@ -24,7 +30,7 @@ impl SimpleRegistry {
     }
 
     pub fn deploy_contract(&mut self, bytecode: Vec<u8>, creator: String) -> String {
-        let hash = blake3::hash(&creator.as_bytes());
+        let hash = blake3::hash(creator.as_bytes());
         let address = format!("0x{}", hex::encode(&hash.as_bytes()[..8]));
         let contract = SimpleContract {
             address: address.clone(),
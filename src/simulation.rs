@@ -0,0 +1,800 @@
+//! Deterministic in-process simulation of a validator network. Each
+//! validator runs its own `Blockchain` and `LocalSigner`; blocks and commit
+//! votes travel over a simulated bus with seeded random delays, and time
+//! only moves when the simulation advances it, so a seed always replays
+//! the same run. Scripted faults cover partitions, crashes with restarts
+//! from the node's own store, and byzantine producers that sign two blocks
+//! per slot.
+//!
+//! Nodes only reconcile a competing block at their tip, as real nodes do,
+//! so a partition that lets both sides build several blocks leaves the
+//! sides on their own forks after it heals; safety must hold regardless.
+
+use crate::block::Block;
+use crate::blockchain::{BlockLookup, Blockchain};
+use crate::config::{AppConfig, GenesisValidator};
+use crate::consensus::finality::CommitVote;
+use crate::key_management::KeyManager;
+use crate::signer::{BlockSigner, LocalSigner};
+use crate::storage::MemoryStore;
+use crate::transaction::Transaction;
+use ed25519_dalek::SigningKey;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tracing::debug;
+
+/// Genesis time of every simulated chain, in unix seconds
+pub const GENESIS_TIMESTAMP: u64 = 1_700_000_000;
+
+/// Make `keys` the genesis validators of `config`, each bonded with a
+/// hundred times the stake threshold
+pub fn bond_genesis_validators(config: &mut AppConfig, keys: &[SigningKey]) {
+    let stake = config.consensus.dpos.stake_threshold.max(1) * 100;
+    config.genesis.validators = keys
+        .iter()
+        .map(|key| GenesisValidator {
+            address: hex::encode(key.verifying_key().to_bytes()),
+            stake,
+            consensus_key: None,
+        })
+        .collect();
+}
+
+/// Most blocks sent in answer to one request for missing blocks
+const MAX_BLOCKS_PER_RESPONSE: u64 = 64;
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub validators: usize,
+    pub seed: u64,
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub tick_ms: u64, // how often validators check whether to produce
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            validators: 4,
+            seed: 1,
+            min_delay_ms: 50,
+            max_delay_ms: 250,
+            tick_ms: 100,
+        }
+    }
+}
+
+/// A scripted change to the network or to one validator
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// Split the validators into groups that only reach their own members
+    Partition(Vec<Vec<usize>>),
+    Heal,
+    /// Stop the validator; messages to it are lost
+    Crash(usize),
+    /// Reopen a crashed validator from its store and signer files
+    Restart(usize),
+    /// From now on the validator signs two blocks for each of its slots
+    Byzantine(usize),
+    /// Set every message's delay to the given range
+    Delay {
+        min_ms: u64,
+        max_ms: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Message {
+    Block(Block),
+    Vote(CommitVote),
+    GetBlocks { from: u64 },
+    Blocks(Vec<Block>),
+}
+
+#[derive(Debug)]
+enum Event {
+    Deliver {
+        from: usize,
+        to: usize,
+        message: Message,
+    },
+    Fault(Fault),
+}
+
+struct SimNode {
+    key: SigningKey, // operator and consensus key
+    store: Arc<MemoryStore>,
+    chain: Option<Blockchain>, // None while crashed
+    signer: Option<LocalSigner>,
+    byzantine: bool,
+    early_votes: Vec<CommitVote>, // for blocks not received yet
+    syncing: bool,                // restarted and waiting to hear from a peer
+    checked_final: u64,
+}
+
+pub struct Simulation {
+    config: AppConfig,
+    options: SimConfig,
+    dir: TempDir, // key and signer files
+    nodes: Vec<SimNode>,
+    events: BTreeMap<(u64, u64), Event>,
+    seq: u64,
+    now_ms: u64,
+    rng: StdRng,
+    groups: Option<Vec<usize>>, // partition group of each node
+    state_roots: BTreeMap<(u64, String), String>,
+    finalized: BTreeMap<u64, String>,
+    violations: Vec<String>,
+}
+
+impl Simulation {
+    /// Start `options.validators` validators with equal stake on a chain
+    /// configured by `config`, whose genesis validators are replaced
+    pub fn new(mut config: AppConfig, options: SimConfig) -> Result<Self, String> {
+        let keys: Vec<SigningKey> = (0..options.validators)
+            .map(|index| SigningKey::from_bytes(&[index as u8 + 1; 32]))
+            .collect();
+        bond_genesis_validators(&mut config, &keys);
+        config.genesis.timestamp = GENESIS_TIMESTAMP;
+        config.snapshots.interval = 0;
+
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let mut nodes = Vec::new();
+        for (index, key) in keys.into_iter().enumerate() {
+            let key_path = dir.path().join(format!("validator-{}.key", index));
+            std::fs::write(&key_path, key.to_bytes()).map_err(|e| e.to_string())?;
+            let store = Arc::new(MemoryStore::new());
+            let chain = Blockchain::open(&config, store.clone())?;
+            let mut node = SimNode {
+                key,
+                store,
+                chain: Some(chain),
+                signer: None,
+                byzantine: false,
+                early_votes: Vec::new(),
+                syncing: false,
+                checked_final: 0,
+            };
+            node.signer = Some(open_signer(&dir, index)?);
+            nodes.push(node);
+        }
+
+        let mut simulation = Simulation {
+            config,
+            rng: StdRng::seed_from_u64(options.seed),
+            options,
+            dir,
+            nodes,
+            events: BTreeMap::new(),
+            seq: 0,
+            now_ms: GENESIS_TIMESTAMP * 1000,
+            groups: None,
+            state_roots: BTreeMap::new(),
+            finalized: BTreeMap::new(),
+            violations: Vec::new(),
+        };
+        for index in 0..simulation.nodes.len() {
+            simulation.record(index);
+        }
+        Ok(simulation)
+    }
+
+    /// Virtual time in milliseconds since the unix epoch
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    /// Apply `fault` `after_ms` from now
+    pub fn schedule(&mut self, after_ms: u64, fault: Fault) {
+        self.push(self.now_ms + after_ms, Event::Fault(fault));
+    }
+
+    /// Chain of validator `index`; `None` while it is crashed
+    pub fn node(&self, index: usize) -> Option<&Blockchain> {
+        self.nodes.get(index).and_then(|node| node.chain.as_ref())
+    }
+
+    /// Hex key validator `index` produces, votes and reports with
+    pub fn validator_key(&self, index: usize) -> String {
+        hex::encode(self.nodes[index].key.verifying_key().to_bytes())
+    }
+
+    /// Advance virtual time by `duration_ms`, delivering messages and
+    /// letting validators produce on every tick
+    pub fn run_for(&mut self, duration_ms: u64) {
+        let end = self.now_ms + duration_ms;
+        while self.now_ms < end {
+            let tick_end = (self.now_ms + self.options.tick_ms.max(1)).min(end);
+            while let Some(entry) = self.events.first_entry() {
+                if entry.key().0 > tick_end {
+                    break;
+                }
+                let ((at, _), event) = entry.remove_entry();
+                self.now_ms = at;
+                self.handle_event(event);
+            }
+            self.now_ms = tick_end;
+            for index in 0..self.nodes.len() {
+                self.try_produce(index);
+            }
+        }
+    }
+
+    /// Fail with every safety violation seen so far: two blocks finalized
+    /// at one height, or one block leading to different states on
+    /// different validators
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let mut violations = self.violations.clone();
+        let running: Vec<&Blockchain> = self
+            .nodes
+            .iter()
+            .filter_map(|node| node.chain.as_ref())
+            .collect();
+        for (a, chain_a) in running.iter().enumerate() {
+            for chain_b in &running[a + 1..] {
+                let final_height = chain_a.finalized_height().min(chain_b.finalized_height());
+                for height in 1..=final_height {
+                    if block_hash(chain_a, height) != block_hash(chain_b, height) {
+                        violations.push(format!(
+                            "validators hold different finalized blocks at height {}",
+                            height
+                        ));
+                    }
+                }
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations.join("; "))
+        }
+    }
+
+    fn push(&mut self, at: u64, event: Event) {
+        self.seq += 1;
+        self.events.insert((at, self.seq), event);
+    }
+
+    fn send(&mut self, from: usize, to: usize, message: Message) {
+        let delay = self
+            .rng
+            .gen_range(self.options.min_delay_ms..=self.options.max_delay_ms);
+        self.push(self.now_ms + delay, Event::Deliver { from, to, message });
+    }
+
+    fn broadcast(&mut self, from: usize, except: Option<usize>, message: Message) {
+        for to in 0..self.nodes.len() {
+            if to != from && Some(to) != except {
+                self.send(from, to, message.clone());
+            }
+        }
+    }
+
+    fn connected(&self, a: usize, b: usize) -> bool {
+        match &self.groups {
+            Some(groups) => groups[a] == groups[b],
+            None => true,
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Deliver { from, to, message } => {
+                if self.connected(from, to) && self.nodes[to].chain.is_some() {
+                    self.receive(to, from, message);
+                }
+            }
+            Event::Fault(fault) => self.apply_fault(fault),
+        }
+    }
+
+    fn apply_fault(&mut self, fault: Fault) {
+        debug!("t={} applying {:?}", self.now_ms, fault);
+        match fault {
+            Fault::Partition(parts) => {
+                // Validators left out of every group are cut off alone
+                let mut groups: Vec<usize> = (0..self.nodes.len())
+                    .map(|index| parts.len() + index)
+                    .collect();
+                for (group, members) in parts.iter().enumerate() {
+                    for &index in members {
+                        groups[index] = group;
+                    }
+                }
+                self.groups = Some(groups);
+            }
+            Fault::Heal => self.groups = None,
+            Fault::Crash(index) => {
+                let node = &mut self.nodes[index];
+                node.chain = None;
+                node.signer = None;
+                node.early_votes.clear();
+            }
+            Fault::Restart(index) => {
+                if self.nodes[index].chain.is_some() {
+                    return;
+                }
+                let reopened = Blockchain::open(&self.config, self.nodes[index].store.clone())
+                    .and_then(|chain| Ok((chain, open_signer(&self.dir, index)?)));
+                match reopened {
+                    Ok((chain, signer)) => {
+                        // Catch up before producing on a stale tip
+                        let tip = chain.get_block_height();
+                        let node = &mut self.nodes[index];
+                        node.chain = Some(chain);
+                        node.signer = Some(signer);
+                        node.syncing = true;
+                        self.broadcast(index, None, Message::GetBlocks { from: tip });
+                    }
+                    Err(e) => self
+                        .violations
+                        .push(format!("validator {} failed to restart: {}", index, e)),
+                }
+            }
+            Fault::Byzantine(index) => self.nodes[index].byzantine = true,
+            Fault::Delay { min_ms, max_ms } => {
+                self.options.min_delay_ms = min_ms;
+                self.options.max_delay_ms = max_ms.max(min_ms);
+            }
+        }
+    }
+
+    fn receive(&mut self, index: usize, from: usize, message: Message) {
+        match message {
+            Message::Block(block) => {
+                if self.accept_block(index, from, block.clone(), true) {
+                    self.broadcast(index, Some(from), Message::Block(block));
+                    self.after_new_tip(index);
+                }
+            }
+            Message::Blocks(blocks) => {
+                self.nodes[index].syncing = false;
+                let mut accepted = false;
+                for block in blocks {
+                    accepted |= self.accept_block(index, from, block, false);
+                }
+                if accepted {
+                    self.after_new_tip(index);
+                }
+            }
+            Message::GetBlocks { from: start } => {
+                let Some(chain) = self.nodes[index].chain.as_ref() else {
+                    return;
+                };
+                let end = chain
+                    .get_block_height()
+                    .min(start + MAX_BLOCKS_PER_RESPONSE - 1);
+                let blocks: Vec<Block> = (start..=end)
+                    .filter_map(|height| chain.get_block_by_height(height).cloned())
+                    .collect();
+                self.send(index, from, Message::Blocks(blocks));
+            }
+            Message::Vote(vote) => self.add_vote(index, vote),
+        }
+    }
+
+    /// Add `block` to validator `index`'s chain, asking `from` for the
+    /// blocks in between when it does not connect
+    fn accept_block(&mut self, index: usize, from: usize, block: Block, fetch: bool) -> bool {
+        let Some(chain) = self.nodes[index].chain.as_mut() else {
+            return false;
+        };
+        let height = block.header.height;
+        let tip = chain.get_block_height();
+        if block_hash(chain, height).as_deref() == Some(block.hash().as_str()) {
+            return false;
+        }
+        let connects = height <= tip
+            || (height == tip + 1 && block.header.previous_hash == chain.get_latest_block().hash());
+        if !connects {
+            if fetch {
+                self.send(index, from, Message::GetBlocks { from: tip });
+            }
+            return false;
+        }
+        match chain.add_block(block) {
+            Ok(()) => {
+                self.record(index);
+                true
+            }
+            Err(e) => {
+                debug!("validator {} rejected block {}: {}", index, height, e);
+                false
+            }
+        }
+    }
+
+    /// Vote for the new tip, then retry votes that arrived before it
+    fn after_new_tip(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        let (Some(chain), Some(signer)) = (node.chain.as_mut(), node.signer.as_mut()) else {
+            return;
+        };
+        let tip = chain.get_latest_block();
+        let (height, hash) = (tip.header.height, tip.hash());
+        let vote = if node.byzantine {
+            node.key.sign_vote(height, &hash)
+        } else {
+            signer.sign_vote(height, &hash)
+        };
+        let early: Vec<CommitVote> = std::mem::take(&mut node.early_votes);
+        if let Ok(vote) = vote {
+            self.add_vote(index, vote.clone());
+            self.broadcast(index, None, Message::Vote(vote));
+        }
+        for vote in early {
+            self.add_vote(index, vote);
+        }
+    }
+
+    fn add_vote(&mut self, index: usize, vote: CommitVote) {
+        let node = &mut self.nodes[index];
+        let Some(chain) = node.chain.as_mut() else {
+            return;
+        };
+        if vote.height > chain.get_block_height() {
+            node.early_votes.push(vote);
+            return;
+        }
+        if chain.add_commit_vote(vote).is_ok() {
+            self.record(index);
+        }
+    }
+
+    fn try_produce(&mut self, index: usize) {
+        let now = self.now_ms / 1000;
+        let producer = self.validator_key(index);
+        let node = &mut self.nodes[index];
+        let Some(chain) = node.chain.as_mut() else {
+            return;
+        };
+        if node.syncing {
+            return;
+        }
+        // Each slot opens a block interval after the parent, and fallback
+        // ranks wait their extra delay on top
+        let engine = chain.engine();
+        let slot_start =
+            chain.get_latest_block().header.timestamp + engine.block_interval(&chain.state);
+        if now < slot_start {
+            return;
+        }
+        let rank = match chain.producer_rank_at(&producer, now) {
+            Ok(Some(rank)) => rank,
+            _ => return,
+        };
+        if now < slot_start + chain.engine().fallback_delay(&chain.state, rank) {
+            return;
+        }
+
+        // Report double-signs seen since the last block, like a real node
+        for payload in chain.take_detected_evidence() {
+            let mut tx = Transaction::new(
+                producer.clone(),
+                "evidence".to_string(),
+                0,
+                Some(payload.to_data()),
+            );
+            tx.timestamp = now;
+            let submitted = tx
+                .sign(&node.key)
+                .map_err(|e| e.to_string())
+                .and_then(|_| chain.add_transaction(tx));
+            if let Err(e) = submitted {
+                debug!("validator {} could not report evidence: {}", index, e);
+            }
+        }
+
+        let mined = if node.byzantine {
+            chain.mine_block_at(&mut node.key.clone(), now)
+        } else {
+            match node.signer.as_mut() {
+                Some(signer) => chain.mine_block_at(signer, now),
+                None => return,
+            }
+        };
+        let block = match mined {
+            Ok(block) => block,
+            Err(e) => {
+                debug!("validator {} could not produce: {}", index, e);
+                return;
+            }
+        };
+        self.record(index);
+
+        if self.nodes[index].byzantine {
+            // A second block for the same slot goes to every other peer
+            let twin = Block::new_at(
+                block.header.height,
+                block.header.previous_hash.clone(),
+                Vec::new(),
+                block.header.timestamp + 1,
+                block.header.fallback_rank,
+                &self.nodes[index].key,
+            );
+            for to in (0..self.nodes.len()).filter(|&to| to != index) {
+                let copy = if to % 2 == 0 { &block } else { &twin };
+                self.send(index, to, Message::Block(copy.clone()));
+            }
+        } else {
+            self.broadcast(index, None, Message::Block(block));
+        }
+        self.after_new_tip(index);
+    }
+
+    /// Note validator `index`'s tip state and newly finalized blocks,
+    /// flagging any disagreement with what other validators reported
+    fn record(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        let Some(chain) = node.chain.as_ref() else {
+            return;
+        };
+        let tip = chain.get_latest_block();
+        let key = (tip.header.height, tip.hash());
+        match chain.state.state_root() {
+            Ok(root) => match self.state_roots.get(&key) {
+                Some(seen) if *seen != root => self.violations.push(format!(
+                    "block {} at height {} led to different states",
+                    key.1, key.0
+                )),
+                Some(_) => {}
+                None => {
+                    self.state_roots.insert(key, root);
+                }
+            },
+            Err(e) => self.violations.push(e),
+        }
+
+        let final_height = chain.finalized_height();
+        for height in node.checked_final + 1..=final_height {
+            let Some(hash) = block_hash(chain, height) else {
+                continue;
+            };
+            match self.finalized.get(&height) {
+                Some(seen) if *seen != hash => self.violations.push(format!(
+                    "conflicting blocks {} and {} finalized at height {}",
+                    seen, hash, height
+                )),
+                Some(_) => {}
+                None => {
+                    self.finalized.insert(height, hash);
+                }
+            }
+        }
+        node.checked_final = node.checked_final.max(final_height);
+    }
+}
+
+fn open_signer(dir: &TempDir, index: usize) -> Result<LocalSigner, String> {
+    let key_path = dir.path().join(format!("validator-{}.key", index));
+    let keys =
+        KeyManager::new(Some(key_path.to_string_lossy().to_string())).map_err(|e| e.to_string())?;
+    LocalSigner::new(keys, dir.path().join(format!("validator-{}.signed", index)))
+        .map_err(|e| e.to_string())
+}
+
+fn block_hash(chain: &Blockchain, height: u64) -> Option<String> {
+    match chain.lookup_block(height) {
+        BlockLookup::Found(block) => Some(block.hash()),
+        BlockLookup::Pruned(header) => Some(header.hash()),
+        BlockLookup::NotFound => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_config;
+
+    fn simulation(seed: u64) -> Simulation {
+        Simulation::new(
+            test_config(),
+            SimConfig {
+                seed,
+                ..SimConfig::default()
+            },
+        )
+        .unwrap()
+    }
+
+    fn tips(simulation: &Simulation) -> Vec<Option<String>> {
+        (0..4)
+            .map(|index| {
+                simulation
+                    .node(index)
+                    .map(|chain| chain.get_latest_block().hash())
+            })
+            .collect()
+    }
+
+    /// Every validator holds the same block at the lowest tip height; the
+    /// newest block may still be on its way to some of them
+    fn assert_chains_agree(simulation: &Simulation) {
+        let chains: Vec<&Blockchain> = (0..4).filter_map(|index| simulation.node(index)).collect();
+        let height = chains
+            .iter()
+            .map(|chain| chain.get_block_height())
+            .min()
+            .unwrap();
+        let hashes: Vec<Option<String>> = chains
+            .iter()
+            .map(|chain| block_hash(chain, height))
+            .collect();
+        assert!(hashes.iter().all(|hash| *hash == hashes[0]), "{:?}", hashes);
+    }
+
+    #[test]
+    fn test_honest_validators_agree_and_finalize() {
+        let mut simulation = simulation(1);
+        simulation.run_for(40_000);
+        simulation.check_invariants().unwrap();
+
+        let chain = simulation.node(0).unwrap();
+        assert!(chain.get_block_height() >= 10);
+        assert!(chain.finalized_height() >= chain.get_block_height() - 2);
+        assert_chains_agree(&simulation);
+    }
+
+    #[test]
+    fn test_same_seed_replays_the_same_run() {
+        let run = |seed| {
+            let mut simulation = simulation(seed);
+            simulation.schedule(
+                5_000,
+                Fault::Delay {
+                    min_ms: 100,
+                    max_ms: 2_500,
+                },
+            );
+            simulation.run_for(25_000);
+            tips(&simulation)
+        };
+        assert_eq!(run(7), run(7));
+    }
+
+    #[test]
+    fn test_crashed_validator_catches_up_after_restart() {
+        let mut simulation = simulation(2);
+        simulation.schedule(8_000, Fault::Crash(2));
+        simulation.schedule(20_000, Fault::Restart(2));
+        simulation.run_for(14_000);
+        let during = simulation.node(0).unwrap().finalized_height();
+        simulation.run_for(24_000);
+        simulation.check_invariants().unwrap();
+
+        // Three of four validators still finalize, and the restarted one
+        // rejoins at the tip
+        let chain = simulation.node(0).unwrap();
+        assert!(chain.finalized_height() > during);
+        assert!(simulation.node(2).unwrap().get_block_height() + 1 >= chain.get_block_height());
+        assert_chains_agree(&simulation);
+    }
+
+    #[test]
+    fn test_partition_never_finalizes_conflicting_blocks() {
+        let mut simulation = simulation(3);
+        simulation.run_for(10_000);
+        let before = simulation.node(0).unwrap().finalized_height();
+        simulation.schedule(0, Fault::Partition(vec![vec![0, 1], vec![2, 3]]));
+        simulation.run_for(20_000);
+        simulation.check_invariants().unwrap();
+
+        // Neither half holds two-thirds of the stake, so both keep
+        // producing but nothing new becomes final
+        for index in 0..4 {
+            let chain = simulation.node(index).unwrap();
+            assert!(chain.finalized_height() <= before + 1);
+            assert!(chain.get_block_height() > before);
+        }
+        simulation.schedule(0, Fault::Heal);
+        simulation.run_for(10_000);
+        simulation.check_invariants().unwrap();
+    }
+
+    /// Validators running the real peer layer: each votes for every block
+    /// it adds, mined or gossiped, and the votes travel as gossip
+    #[tokio::test]
+    async fn test_gossiped_commit_votes_finalize_blocks() {
+        use crate::config::P2pConfig;
+        use crate::crypto_utils::generate_keypair;
+        use crate::network::gossip::Gossip;
+        use crate::network::p2p::NetworkService;
+        use crate::network::sync::BlockSync;
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        let simulation = simulation(5);
+        let mut nodes = Vec::new();
+        for node in &simulation.nodes {
+            let chain = Blockchain::open(&simulation.config, Arc::new(MemoryStore::new())).unwrap();
+            let config = P2pConfig {
+                listen_addr: "127.0.0.1:0".to_string(),
+                ..P2pConfig::default()
+            };
+            let genesis_hash = chain.genesis_hash().to_string();
+            let (network, events) =
+                NetworkService::start(config, generate_keypair().0, genesis_hash, 0)
+                    .await
+                    .unwrap();
+            let signer: Box<dyn BlockSigner> = Box::new(node.key.clone());
+            let gossip = Arc::new(
+                Gossip::new(network, Arc::new(Mutex::new(chain)))
+                    .with_voter(Arc::new(Mutex::new(signer))),
+            );
+            tokio::spawn(Arc::new(BlockSync::new(gossip.clone())).run(events));
+            nodes.push((node.key.clone(), gossip));
+        }
+        for (a, (_, gossip)) in nodes.iter().enumerate() {
+            for (_, other) in &nodes[a + 1..] {
+                let addr = other.network().local_addr();
+                // Peer exchange may have connected them already
+                let _ = gossip.network().connect(addr).await;
+            }
+        }
+
+        let heights = |nodes: &[(SigningKey, Arc<Gossip>)]| -> Vec<(u64, u64)> {
+            nodes
+                .iter()
+                .map(|(_, gossip)| {
+                    let chain = gossip.blockchain().lock().unwrap();
+                    (chain.get_block_height(), chain.finalized_height())
+                })
+                .collect()
+        };
+        for height in 1..=5 {
+            let (producer, gossip) = {
+                let chain = nodes[0].1.blockchain().lock().unwrap();
+                let producer = chain.next_producer().unwrap();
+                nodes
+                    .iter()
+                    .find(|(key, _)| hex::encode(key.verifying_key().to_bytes()) == producer)
+                    .cloned()
+                    .unwrap()
+            };
+            let block = {
+                let mut chain = gossip.blockchain().lock().unwrap();
+                let now = chain.get_latest_block().header.timestamp + 5;
+                chain.mine_block_at(&mut producer.clone(), now).unwrap()
+            };
+            gossip.publish_block(&block);
+            for _ in 0..200 {
+                if heights(&nodes).iter().all(|(tip, _)| *tip == height) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(25)).await;
+            }
+        }
+
+        // Each block needs votes from three of the four validators
+        for _ in 0..200 {
+            if heights(&nodes).iter().all(|(_, finalized)| *finalized == 5) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        assert!(
+            heights(&nodes)
+                .iter()
+                .all(|&(tip, finalized)| (tip, finalized) == (5, 5)),
+            "{:?}",
+            heights(&nodes)
+        );
+    }
+
+    #[test]
+    fn test_byzantine_producer_is_slashed() {
+        let mut simulation = simulation(4);
+        simulation.schedule(0, Fault::Byzantine(1));
+        simulation.run_for(40_000);
+        simulation.check_invariants().unwrap();
+
+        let offender = simulation.validator_key(1);
+        let chain = simulation.node(0).unwrap();
+        assert!(chain.state.candidates[&offender].is_jailed());
+        assert!(chain.finalized_height() > 5);
+        assert_chains_agree(&simulation);
+    }
+}
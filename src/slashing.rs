@@ -0,0 +1,496 @@
+// Double-sign evidence, liveness tracking and jailing.
+//
+// A producer that signs two different headers for the same height can
+// split the network. Anyone holding both headers submits them in an
+// evidence transaction; every node checks the signatures, burns
+// `slashing_penalty` of the stake bonded to the offender, jails it for good,
+// tombstones its address and pays `evidence_reward` out of the slashed
+// amount to the reporter.
+//
+// Every block also records which validators produced or missed their slot.
+// A validator that misses `missed_slot_threshold` slots within the last
+// `missed_slot_window` heights is jailed for `jail_cooldown` blocks, after
+// which it may send an unjail transaction.
+
+use crate::block::BlockHeader;
+use crate::consensus::dpos::schedule_index;
+use crate::staking::{self, StakingParams, UnbondingEntry};
+use crate::state::ChainState;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tracing::warn;
+
+/// Heights at which a validator produced or missed its slot, within the
+/// liveness window
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotWindow {
+    pub produced: VecDeque<u64>,
+    pub missed: VecDeque<u64>,
+}
+
+impl SlotWindow {
+    fn prune_before(&mut self, oldest: u64) {
+        for heights in [&mut self.produced, &mut self.missed] {
+            while heights.front().is_some_and(|height| *height < oldest) {
+                heights.pop_front();
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.produced.is_empty() && self.missed.is_empty()
+    }
+
+    /// Share of assigned slots that were produced, 1.0 with none assigned
+    pub fn uptime(&self) -> f64 {
+        let assigned = self.produced.len() + self.missed.len();
+        if assigned == 0 {
+            return 1.0;
+        }
+        self.produced.len() as f64 / assigned as f64
+    }
+}
+
+/// Record the slots of the block at `height`, produced at fallback `rank`:
+/// the validators at lower ranks missed theirs. Validators reaching the
+/// missed-slot threshold are jailed.
+pub fn record_slots(state: &mut ChainState, height: u64, rank: u32, params: &StakingParams) {
+    let size = state.validators.len();
+    for slot in 0..=rank {
+        let Some(index) = schedule_index(size, state.epoch.start_height, height, slot) else {
+            continue;
+        };
+        let address = hex::encode(state.validators[index].address.0.to_bytes());
+        let window = state.slot_windows.entry(address).or_default();
+        if slot == rank {
+            window.produced.push_back(height);
+        } else {
+            window.missed.push_back(height);
+        }
+    }
+
+    let oldest = (height + 1).saturating_sub(params.missed_slot_window);
+    state.slot_windows.retain(|_, window| {
+        window.prune_before(oldest);
+        !window.is_empty()
+    });
+
+    let mut offenders = Vec::new();
+    for validator in &mut state.validators {
+        let address = hex::encode(validator.address.0.to_bytes());
+        let window = state
+            .slot_windows
+            .get(&address)
+            .cloned()
+            .unwrap_or_default();
+        validator.missed_blocks = window.missed.len() as u64;
+        validator.uptime = window.uptime();
+        if params.missed_slot_threshold > 0
+            && validator.missed_blocks >= params.missed_slot_threshold
+        {
+            offenders.push(address);
+        }
+    }
+
+    for address in offenders {
+        if let Some(candidate) = state.candidates.get_mut(&address) {
+            if candidate.is_jailed() {
+                continue;
+            }
+            candidate.jailed_until = Some(height + params.jail_cooldown);
+        }
+        warn!(
+            "Jailed {} at height {} for missing {} slots",
+            address, height, params.missed_slot_threshold
+        );
+        // The last validator stays so the chain can go on
+        if state.validators.len() > 1 {
+            state.remove_from_active_set(&address);
+        }
+        state.slot_windows.remove(&address);
+    }
+}
+
+/// Release `sender` from jail once its cooldown has passed. It rejoins the
+/// active set at the next epoch if it is still eligible.
+pub fn apply_unjail(state: &mut ChainState, sender: &str, height: u64) -> Result<(), String> {
+    let candidate = state
+        .candidates
+        .get_mut(sender)
+        .ok_or_else(|| "validator is not registered".to_string())?;
+    match candidate.jailed_until {
+        None => Err("validator is not jailed".to_string()),
+        Some(u64::MAX) => Err("a validator jailed for double-signing cannot unjail".to_string()),
+        Some(until) if height < until => Err(format!("validator is jailed until height {}", until)),
+        Some(_) => {
+            candidate.jailed_until = None;
+            Ok(())
+        }
+    }
+}
+
+/// Check that `first` and `second` prove a double-sign, returning the
+/// consensus key that signed both
+pub fn verify_evidence(first: &BlockHeader, second: &BlockHeader) -> Result<String, String> {
+    if first.height != second.height {
+        return Err("headers are for different heights".to_string());
+    }
+    if first.producer != second.producer {
+        return Err("headers are from different producers".to_string());
+    }
+    if first.hash() == second.hash() {
+        return Err("headers are identical".to_string());
+    }
+    if !first.verify_signature() || !second.verify_signature() {
+        return Err("header signature is invalid".to_string());
+    }
+    Ok(first.producer.clone())
+}
+
+/// Apply double-sign evidence reported by `reporter` in the block at
+/// `height`. Evidence is accepted once per offender and height, and only
+/// while the offence is within the unbonding period, so stake bonded to
+/// the offender at the time is either still bonded or in the unbonding
+/// queue. The penalty is shared in proportion between its self-stake, the
+/// stake delegated to it and that stake unbonded since the offence.
+pub fn apply_evidence(
+    state: &mut ChainState,
+    reporter: &str,
+    first: &BlockHeader,
+    second: &BlockHeader,
+    height: u64,
+    params: &StakingParams,
+) -> Result<(), String> {
+    let signer = verify_evidence(first, second)?;
+    let offence_height = first.height;
+    if offence_height > height {
+        return Err(format!(
+            "evidence is from height {}, after this block",
+            offence_height
+        ));
+    }
+    if height - offence_height > params.unbonding_period {
+        return Err(format!(
+            "evidence from height {} is older than the unbonding period",
+            offence_height
+        ));
+    }
+    let offender = state.signer_at(&signer, offence_height).ok_or_else(|| {
+        format!(
+            "{} was no validator's consensus key at height {}",
+            signer, offence_height
+        )
+    })?;
+    let key = (offender.clone(), offence_height);
+    if state.evidence.contains(&key) {
+        return Err(format!(
+            "evidence against {} at height {} was already submitted",
+            offender, offence_height
+        ));
+    }
+
+    let self_stake = state
+        .candidates
+        .get(&offender)
+        .map_or(0, |candidate| candidate.self_stake);
+    let delegations = state.delegations_to(&offender);
+    let unbonding = |entry: &UnbondingEntry| {
+        entry.validator == offender && entry.started_height >= offence_height
+    };
+    let bonded = self_stake as u128
+        + delegations
+            .iter()
+            .map(|(_, amount)| *amount as u128)
+            .sum::<u128>()
+        + state
+            .unbonding
+            .iter()
+            .filter(|entry| unbonding(entry))
+            .map(|entry| entry.amount as u128)
+            .sum::<u128>();
+    if bonded == 0 && !state.candidates.contains_key(&offender) {
+        return Err(format!("{} has no bonded stake to slash", offender));
+    }
+    let penalty = (params.slashing_penalty as u128).min(bonded);
+    let share = |amount: u64| (amount as u128 * penalty / bonded.max(1)) as u64;
+
+    let mut slashed = 0u64;
+    for (delegator, amount) in &delegations {
+        let cut = share(*amount);
+        if cut > 0 {
+            staking::remove_delegation(state, delegator, &offender, cut)?;
+            slashed += cut;
+        }
+    }
+    for entry in state.unbonding.iter_mut().filter(|entry| unbonding(entry)) {
+        let cut = share(entry.amount);
+        entry.amount -= cut;
+        slashed += cut;
+    }
+    state.unbonding.retain(|entry| entry.amount > 0);
+    // Self-stake takes what rounding left of the penalty
+    let self_cut = (penalty as u64).saturating_sub(slashed).min(self_stake);
+    slashed += self_cut;
+    if let Some(candidate) = state.candidates.get_mut(&offender) {
+        candidate.self_stake -= self_cut;
+        candidate.jailed_until = Some(u64::MAX);
+    }
+    // Even the last validator goes: a tombstoned key must not produce,
+    // so the chain halts rather than carry on under it
+    state.remove_from_active_set(&offender);
+    if state.validators.is_empty() {
+        warn!(
+            "Removed {}, the last active validator; block production halts",
+            offender
+        );
+    }
+    state.evidence.insert(key);
+    state.tombstones.insert(offender.clone());
+
+    let reward = params.evidence_reward.min(slashed);
+    if reward > 0 {
+        state.credit(reporter, reward)?;
+    }
+    warn!(
+        "Slashed {} of the stake bonded to {} for double-signing at height {}",
+        slashed, offender, offence_height
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::consensus::dpos::{SerializableVerifyingKey, Validator};
+    use crate::staking::Candidate;
+    use crate::transaction::{Transaction, TxPayload};
+    use ed25519_dalek::SigningKey;
+
+    fn params() -> StakingParams {
+        StakingParams {
+            stake_threshold: 1000,
+            validator_count: 2,
+            unbonding_period: 10,
+            epoch_length: 5,
+            slashing_penalty: 400,
+            evidence_reward: 100,
+            missed_slot_window: 10,
+            missed_slot_threshold: 3,
+            jail_cooldown: 20,
+            epoch_reward: 0,
+            commission_bps: 0,
+        }
+    }
+
+    fn conflicting_headers(key: &SigningKey, height: u64) -> (BlockHeader, BlockHeader) {
+        let first = Block::new_at(height, "0".repeat(64), Vec::new(), 100, 0, key);
+        let second = Block::new_at(height, "0".repeat(64), Vec::new(), 101, 0, key);
+        (first.header, second.header)
+    }
+
+    #[test]
+    fn test_evidence_slashes_and_jails_once() -> Result<(), String> {
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        let offender = hex::encode(key.verifying_key().to_bytes());
+        let mut state = ChainState::default();
+        state
+            .candidates
+            .insert(offender.clone(), Candidate::new(1000, 0));
+
+        let (first, second) = conflicting_headers(&key, 4);
+        apply_evidence(&mut state, "reporter", &first, &second, 6, &params())?;
+        assert_eq!(state.candidates[&offender].self_stake, 600);
+        assert!(state.candidates[&offender].is_jailed());
+        assert_eq!(state.balance("reporter"), 100);
+
+        // The same offence can't be punished twice, in either order
+        let err = apply_evidence(&mut state, "reporter", &second, &first, 7, &params());
+        assert!(err.unwrap_err().contains("already submitted"));
+        assert_eq!(state.candidates[&offender].self_stake, 600);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evidence_slashes_delegated_and_unbonding_stake() -> Result<(), String> {
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        let offender = hex::encode(key.verifying_key().to_bytes());
+        let mut state = ChainState::default();
+        state
+            .candidates
+            .insert(offender.clone(), Candidate::new(600, 0));
+        state
+            .delegations
+            .entry("alice".to_string())
+            .or_default()
+            .insert(offender.clone(), 300);
+        let entry = |started_height| UnbondingEntry {
+            owner: "bob".to_string(),
+            validator: offender.clone(),
+            amount: 100,
+            started_height,
+            release_height: started_height + 10,
+            rebond_to: None,
+        };
+        // Bob left before the offence, then again after it
+        state.unbonding = vec![entry(3), entry(5)];
+
+        // 400 of the 1000 bonded at height 4: everyone loses 40%
+        let (first, second) = conflicting_headers(&key, 4);
+        apply_evidence(&mut state, "reporter", &first, &second, 6, &params())?;
+        assert_eq!(state.candidates[&offender].self_stake, 360);
+        assert_eq!(state.delegation("alice", &offender), 180);
+        let amounts: Vec<u64> = state.unbonding.iter().map(|entry| entry.amount).collect();
+        assert_eq!(amounts, vec![100, 60]);
+        assert!(state.tombstones.contains(&offender));
+        assert_eq!(state.balance("reporter"), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbonding_does_not_escape_evidence() -> Result<(), String> {
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        let offender = hex::encode(key.verifying_key().to_bytes());
+        let mut state = ChainState::default();
+        state.credit(&offender, 1000)?;
+        let staking_tx = |payload: TxPayload, amount| {
+            let tx = Transaction::new(
+                offender.clone(),
+                "staking".to_string(),
+                amount,
+                Some(payload.to_data()),
+            );
+            (tx, payload)
+        };
+        let (register, payload) = staking_tx(TxPayload::RegisterValidator, 1000);
+        staking::apply_staking(&mut state, &register, &payload, 1, &params())?;
+
+        // Double-sign at 4, then unbond everything before being reported
+        let (unbond, payload) = staking_tx(TxPayload::Unbond, 1000);
+        staking::apply_staking(&mut state, &unbond, &payload, 5, &params())?;
+        assert!(!state.candidates.contains_key(&offender));
+        let (first, second) = conflicting_headers(&key, 4);
+        apply_evidence(&mut state, "reporter", &first, &second, 6, &params())?;
+        assert_eq!(state.unbonding[0].amount, 600);
+
+        // The tombstone outlives the candidate
+        state.credit(&offender, 1000)?;
+        let (register, payload) = staking_tx(TxPayload::RegisterValidator, 1000);
+        let err = staking::apply_staking(&mut state, &register, &payload, 7, &params());
+        assert!(err.unwrap_err().contains("cannot register again"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_evidence_is_rejected() {
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        let other = SigningKey::from_bytes(&[4u8; 32]);
+        let (first, second) = conflicting_headers(&key, 4);
+
+        assert_eq!(
+            verify_evidence(&first, &first),
+            Err("headers are identical".to_string())
+        );
+        let (elsewhere, _) = conflicting_headers(&key, 5);
+        assert!(verify_evidence(&first, &elsewhere).is_err());
+        let (foreign, _) = conflicting_headers(&other, 4);
+        assert!(verify_evidence(&first, &foreign).is_err());
+
+        let mut forged = second.clone();
+        forged.timestamp += 1;
+        assert_eq!(
+            verify_evidence(&first, &forged),
+            Err("header signature is invalid".to_string())
+        );
+
+        let mut state = ChainState::default();
+        let err = apply_evidence(&mut state, "reporter", &first, &second, 20, &params());
+        assert!(err.unwrap_err().contains("older than the unbonding period"));
+    }
+
+    #[test]
+    fn test_evidence_removes_the_last_validator() -> Result<(), String> {
+        let key = SigningKey::from_bytes(&[3u8; 32]);
+        let offender = hex::encode(key.verifying_key().to_bytes());
+        let mut state = ChainState::default();
+        state
+            .candidates
+            .insert(offender.clone(), Candidate::new(1000, 0));
+        state.voting_power.insert(offender.clone(), 1000);
+        state.validators.push(Validator {
+            address: SerializableVerifyingKey(key.verifying_key()),
+            consensus_key: SerializableVerifyingKey(key.verifying_key()),
+            stake: 1000,
+            uptime: 1.0,
+            missed_blocks: 0,
+            last_active: 0,
+        });
+
+        let (first, second) = conflicting_headers(&key, 4);
+        apply_evidence(&mut state, "reporter", &first, &second, 6, &params())?;
+        assert!(state.validators.is_empty());
+        assert!(state.voting_power.is_empty());
+        assert!(state.tombstones.contains(&offender));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missed_slots_jail_until_cooldown() -> Result<(), String> {
+        let mut state = ChainState::default();
+        let mut addresses = Vec::new();
+        for seed in [1u8, 2] {
+            let key = SigningKey::from_bytes(&[seed; 32]).verifying_key();
+            let address = hex::encode(key.to_bytes());
+            state
+                .candidates
+                .insert(address.clone(), Candidate::new(1000, 0));
+            state.voting_power.insert(address.clone(), 1000);
+            state.validators.push(Validator {
+                address: SerializableVerifyingKey(key),
+                consensus_key: SerializableVerifyingKey(key),
+                stake: 1000,
+                uptime: 1.0,
+                missed_blocks: 0,
+                last_active: 0,
+            });
+            addresses.push(address);
+        }
+        let params = params();
+
+        // The first validator holds the even heights; rank 1 means it missed
+        for height in 2..=15 {
+            let rank = if height == 2 || height == 4 { 1 } else { 0 };
+            record_slots(&mut state, height, rank, &params);
+        }
+        for height in [16, 18] {
+            record_slots(&mut state, height, 1, &params);
+        }
+        // Heights 2 and 4 have left the window: 10, 12, 14 produced
+        assert_eq!(state.validators[0].missed_blocks, 2);
+        assert_eq!(state.validators[0].uptime, 0.6);
+        assert_eq!(state.validators[1].uptime, 1.0);
+
+        record_slots(&mut state, 20, 1, &params);
+        assert_eq!(state.candidates[&addresses[0]].jailed_until, Some(40));
+        assert_eq!(state.validators.len(), 1);
+        assert!(!state.slot_windows.contains_key(&addresses[0]));
+
+        assert_eq!(
+            apply_unjail(&mut state, &addresses[0], 39),
+            Err("validator is jailed until height 40".to_string())
+        );
+        apply_unjail(&mut state, &addresses[0], 40)?;
+        assert!(!state.candidates[&addresses[0]].is_jailed());
+        assert!(apply_unjail(&mut state, &addresses[0], 41).is_err());
+
+        state
+            .candidates
+            .get_mut(&addresses[1])
+            .unwrap()
+            .jailed_until = Some(u64::MAX);
+        assert!(apply_unjail(&mut state, &addresses[1], 1000)
+            .unwrap_err()
+            .contains("double-signing"));
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+// Verifiable state snapshots for fast sync.
+//
+// A snapshot directory holds the block at the snapshot height, the encoded
+// `ChainState` split into fixed-size chunks, and a manifest committing to
+// the genesis hash, the block hash, the state root and the hash of every
+// chunk. The manifest itself ships with the snapshot, so restoring first
+// checks it against a manifest hash obtained from a trusted source, then
+// checks each piece against the manifest.
+
+use crate::block::Block;
+use crate::state::ChainState;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const BLOCK_FILE: &str = "block.bin";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    pub genesis_hash: String, // chain the snapshot belongs to
+    pub height: u64,
+    pub block_hash: String,
+    pub state_root: String,
+    pub chunk_size: usize,
+    pub total_size: u64,
+    pub chunk_hashes: Vec<String>,
+}
+
+impl SnapshotManifest {
+    /// Hash identifying this snapshot, suitable for publishing out of band
+    pub fn hash(&self) -> Result<String, String> {
+        let bytes = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        Ok(blake3::hash(&bytes).to_hex().to_string())
+    }
+}
+
+/// Contents of a snapshot that passed verification
+#[derive(Debug, Clone)]
+pub struct VerifiedSnapshot {
+    pub manifest: SnapshotManifest,
+    pub block: Block,
+    pub state: ChainState,
+}
+
+fn chunk_file(index: usize) -> String {
+    format!("chunk-{:05}.bin", index)
+}
+
+/// Directory name used for the snapshot at `height` under `root`
+pub fn snapshot_dir(root: &Path, height: u64) -> PathBuf {
+    root.join(format!("height-{:012}", height))
+}
+
+/// Write a snapshot of `state` as of `block` into `root`
+pub fn write_snapshot(
+    root: &Path,
+    genesis_hash: &str,
+    block: &Block,
+    state: &ChainState,
+    chunk_size: usize,
+) -> Result<SnapshotManifest, String> {
+    if chunk_size == 0 {
+        return Err("Snapshot chunk size must be greater than zero".to_string());
+    }
+
+    let encoded = state.encode()?;
+    let manifest = SnapshotManifest {
+        version: SNAPSHOT_FORMAT_VERSION,
+        genesis_hash: genesis_hash.to_string(),
+        height: block.header.height,
+        block_hash: block.hash(),
+        state_root: state.state_root()?,
+        chunk_size,
+        total_size: encoded.len() as u64,
+        chunk_hashes: encoded
+            .chunks(chunk_size)
+            .map(|chunk| blake3::hash(chunk).to_hex().to_string())
+            .collect(),
+    };
+
+    // Build the snapshot next to its final location and rename it into
+    // place, so a crash never leaves a partial snapshot that looks complete
+    let final_dir = snapshot_dir(root, manifest.height);
+    let tmp_dir = root.join(format!(".tmp-height-{:012}", manifest.height));
+    let io_err = |e: std::io::Error| format!("Failed to write snapshot: {}", e);
+
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).map_err(io_err)?;
+    }
+    fs::create_dir_all(&tmp_dir).map_err(io_err)?;
+
+    for (index, chunk) in encoded.chunks(chunk_size).enumerate() {
+        fs::write(tmp_dir.join(chunk_file(index)), chunk).map_err(io_err)?;
+    }
+    let block_bytes = bincode::serialize(block).map_err(|e| e.to_string())?;
+    fs::write(tmp_dir.join(BLOCK_FILE), block_bytes).map_err(io_err)?;
+    let manifest_bytes = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    fs::write(tmp_dir.join(MANIFEST_FILE), manifest_bytes).map_err(io_err)?;
+
+    if final_dir.exists() {
+        fs::remove_dir_all(&final_dir).map_err(io_err)?;
+    }
+    fs::rename(&tmp_dir, &final_dir).map_err(io_err)?;
+
+    Ok(manifest)
+}
+
+/// Read the snapshot in `dir`, rejecting a manifest whose hash is not
+/// `trusted_hash` and anything that doesn't match the manifest
+pub fn read_snapshot(dir: &Path, trusted_hash: &str) -> Result<VerifiedSnapshot, String> {
+    let read = |name: &str| {
+        fs::read(dir.join(name)).map_err(|e| format!("Failed to read snapshot {}: {}", name, e))
+    };
+
+    let manifest: SnapshotManifest = serde_json::from_slice(&read(MANIFEST_FILE)?)
+        .map_err(|e| format!("Invalid snapshot manifest: {}", e))?;
+    if manifest.hash()? != trusted_hash {
+        return Err("Snapshot manifest does not match the trusted hash".to_string());
+    }
+    if manifest.version != SNAPSHOT_FORMAT_VERSION {
+        return Err(format!(
+            "Unsupported snapshot version {} (expected {})",
+            manifest.version, SNAPSHOT_FORMAT_VERSION
+        ));
+    }
+
+    let block: Block = bincode::deserialize(&read(BLOCK_FILE)?)
+        .map_err(|e| format!("Invalid snapshot block: {}", e))?;
+    if block.header.height != manifest.height || block.hash() != manifest.block_hash {
+        return Err("Snapshot block does not match manifest".to_string());
+    }
+
+    let mut encoded = Vec::with_capacity(manifest.total_size as usize);
+    for (index, expected_hash) in manifest.chunk_hashes.iter().enumerate() {
+        let chunk = read(&chunk_file(index))?;
+        if chunk.len() > manifest.chunk_size
+            || blake3::hash(&chunk).to_hex().as_str() != expected_hash
+        {
+            return Err(format!("Snapshot chunk {} does not match manifest", index));
+        }
+        encoded.extend_from_slice(&chunk);
+    }
+    if encoded.len() as u64 != manifest.total_size {
+        return Err("Snapshot size does not match manifest".to_string());
+    }
+
+    let state = ChainState::decode(&encoded)?;
+    if state.state_root()? != manifest.state_root {
+        return Err("Snapshot state root does not match manifest".to_string());
+    }
+
+    Ok(VerifiedSnapshot {
+        manifest,
+        block,
+        state,
+    })
+}
+
+/// Completed snapshot directories under `root`, oldest first
+pub fn list_snapshots(root: &Path) -> Result<Vec<PathBuf>, String> {
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut dirs: Vec<PathBuf> = fs::read_dir(root)
+        .map_err(|e| format!("Failed to list snapshots: {}", e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.is_dir()
+                && path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().starts_with("height-"))
+                    .unwrap_or(false)
+        })
+        .collect();
+    // Zero-padded heights sort correctly as strings
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Delete all but the `keep` most recent snapshots
+pub fn prune_snapshots(root: &Path, keep: usize) -> Result<(), String> {
+    let dirs = list_snapshots(root)?;
+    let excess = dirs.len().saturating_sub(keep);
+    for dir in &dirs[..excess] {
+        fs::remove_dir_all(dir).map_err(|e| format!("Failed to prune snapshot: {}", e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto_utils;
+    use tempfile::tempdir;
+
+    const GENESIS: &str = "genesis";
+
+    fn sample() -> (Block, ChainState) {
+        let signing_key = crypto_utils::default_signing_key();
+        let block = Block::new(7, "0".repeat(64), Vec::new(), &signing_key);
+        let mut state = ChainState::default();
+        for i in 0..50 {
+            state.credit(&format!("account-{}", i), i * 10).unwrap();
+        }
+        (block, state)
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() -> Result<(), String> {
+        let root = tempdir().map_err(|e| e.to_string())?;
+        let (block, state) = sample();
+
+        let manifest = write_snapshot(root.path(), GENESIS, &block, &state, 64)?;
+        assert!(manifest.chunk_hashes.len() > 1);
+
+        let restored = read_snapshot(&snapshot_dir(root.path(), 7), &manifest.hash()?)?;
+        assert_eq!(restored.manifest, manifest);
+        assert_eq!(restored.block.hash(), block.hash());
+        assert_eq!(restored.state, state);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered_chunk_is_rejected() -> Result<(), String> {
+        let root = tempdir().map_err(|e| e.to_string())?;
+        let (block, state) = sample();
+        let trusted = write_snapshot(root.path(), GENESIS, &block, &state, 64)?.hash()?;
+
+        let dir = snapshot_dir(root.path(), 7);
+        let chunk_path = dir.join(chunk_file(1));
+        let mut chunk = fs::read(&chunk_path).unwrap();
+        chunk[0] ^= 0xff;
+        fs::write(&chunk_path, chunk).unwrap();
+
+        let err = read_snapshot(&dir, &trusted).unwrap_err();
+        assert_eq!(err, "Snapshot chunk 1 does not match manifest");
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_with_rewritten_manifest_is_rejected() -> Result<(), String> {
+        let root = tempdir().map_err(|e| e.to_string())?;
+        let (block, mut state) = sample();
+        let trusted = write_snapshot(root.path(), GENESIS, &block, &state, 64)?.hash()?;
+
+        // Tampered state with a manifest that matches it
+        state.credit("attacker", 1_000_000)?;
+        write_snapshot(root.path(), GENESIS, &block, &state, 64)?;
+
+        let err = read_snapshot(&snapshot_dir(root.path(), 7), &trusted).unwrap_err();
+        assert_eq!(err, "Snapshot manifest does not match the trusted hash");
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_keeps_most_recent() -> Result<(), String> {
+        let root = tempdir().map_err(|e| e.to_string())?;
+        let signing_key = crypto_utils::default_signing_key();
+        let state = ChainState::default();
+        for height in [10, 20, 30] {
+            let block = Block::new(height, "0".repeat(64), Vec::new(), &signing_key);
+            write_snapshot(root.path(), GENESIS, &block, &state, 1024)?;
+        }
+
+        prune_snapshots(root.path(), 2)?;
+        assert_eq!(
+            list_snapshots(root.path())?,
+            vec![snapshot_dir(root.path(), 20), snapshot_dir(root.path(), 30)]
+        );
+        Ok(())
+    }
+}
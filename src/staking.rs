@@ -0,0 +1,680 @@
+// Validator registration, bonding, delegation and unbonding.
+//
+// Staking operations arrive as `TxPayload`s in signed transactions and are
+// applied to `ChainState` while a block executes. Bonded stake leaves the
+// sender's balance immediately; unbonded stake is held in an unbonding
+// queue until `unbonding_period` blocks have passed. A validator's voting
+// power is its self-stake plus everything delegated to it.
+
+use crate::config::DposConfig;
+use crate::state::ChainState;
+use crate::transaction::{Transaction, TxPayload};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StakingParams {
+    pub stake_threshold: u64,
+    pub validator_count: u64,
+    pub unbonding_period: u64,
+    pub epoch_length: u64,
+    pub slashing_penalty: u64,
+    pub evidence_reward: u64,
+    pub missed_slot_window: u64,
+    pub missed_slot_threshold: u64,
+    pub jail_cooldown: u64,
+    pub epoch_reward: u64,
+    pub commission_bps: u64,
+}
+
+impl StakingParams {
+    /// Epoch containing `height`; epoch `n` covers heights
+    /// `n * epoch_length .. (n + 1) * epoch_length`
+    pub fn epoch_of(&self, height: u64) -> u64 {
+        height / self.epoch_length.max(1)
+    }
+
+    /// First height of the epoch containing `height`
+    pub fn epoch_start(&self, height: u64) -> u64 {
+        self.epoch_of(height) * self.epoch_length.max(1)
+    }
+
+    pub fn is_epoch_start(&self, height: u64) -> bool {
+        height.is_multiple_of(self.epoch_length.max(1))
+    }
+}
+
+impl From<&DposConfig> for StakingParams {
+    fn from(config: &DposConfig) -> Self {
+        StakingParams {
+            stake_threshold: config.stake_threshold,
+            validator_count: config.validator_count as u64,
+            unbonding_period: config.unbonding_period,
+            epoch_length: config.epoch_length,
+            slashing_penalty: config.slashing_penalty,
+            evidence_reward: config.evidence_reward,
+            missed_slot_window: config.missed_slot_window,
+            missed_slot_threshold: config.missed_slot_threshold,
+            jail_cooldown: config.jail_cooldown,
+            epoch_reward: config.epoch_reward,
+            commission_bps: config.commission_bps,
+        }
+    }
+}
+
+/// The epoch whose active set is recorded in `ChainState::validators`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EpochInfo {
+    pub number: u64,
+    pub start_height: u64,
+}
+
+/// A registered validator candidate, keyed by its operator key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub self_stake: u64,
+    pub registered_height: u64,
+    /// Set while the validator is jailed and kept out of the active set;
+    /// `u64::MAX` for a double-signer, who can never return
+    pub jailed_until: Option<u64>,
+    /// Hex key signing blocks and commit votes; the operator key if unset
+    pub consensus_key: Option<String>,
+    /// Key replacing `consensus_key` at the next epoch
+    pub next_consensus_key: Option<String>,
+}
+
+impl Candidate {
+    pub fn new(self_stake: u64, registered_height: u64) -> Self {
+        Candidate {
+            self_stake,
+            registered_height,
+            jailed_until: None,
+            consensus_key: None,
+            next_consensus_key: None,
+        }
+    }
+
+    pub fn is_jailed(&self) -> bool {
+        self.jailed_until.is_some()
+    }
+
+    /// Key that signs for the candidate with operator key `operator`
+    pub fn signing_key<'a>(&'a self, operator: &'a str) -> &'a str {
+        self.consensus_key.as_deref().unwrap_or(operator)
+    }
+}
+
+/// Validator a separate consensus key signs for, and the heights at which
+/// it may sign: from `active_from` until just before `retired_at`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsensusKeyRecord {
+    pub operator: String,
+    pub active_from: Option<u64>, // unset while staged
+    pub retired_at: Option<u64>,
+}
+
+impl ConsensusKeyRecord {
+    pub fn new(operator: &str, active_from: Option<u64>) -> Self {
+        ConsensusKeyRecord {
+            operator: operator.to_string(),
+            active_from,
+            retired_at: None,
+        }
+    }
+
+    pub fn signs_at(&self, height: u64) -> bool {
+        self.active_from.is_some_and(|from| from <= height)
+            && self.retired_at.is_none_or(|retired| height < retired)
+    }
+}
+
+/// Stake waiting to be released back to its owner's balance, or bonded to
+/// `rebond_to` when it comes from a redelegation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnbondingEntry {
+    pub owner: String,
+    pub validator: String, // validator the stake was bonded to
+    pub amount: u64,
+    pub started_height: u64, // block the stake stopped being bonded in
+    pub release_height: u64,
+    pub rebond_to: Option<String>,
+}
+
+/// Apply the staking payload of `tx`, included in the block at `height`
+pub fn apply_staking(
+    state: &mut ChainState,
+    tx: &Transaction,
+    payload: &TxPayload,
+    height: u64,
+    params: &StakingParams,
+) -> Result<(), String> {
+    let amount = tx.amount;
+    if amount == 0 {
+        return Err("staking amount must be greater than zero".to_string());
+    }
+
+    match payload {
+        TxPayload::RegisterValidator => {
+            if state.candidates.contains_key(&tx.from) {
+                return Err("validator is already registered".to_string());
+            }
+            if state.tombstones.contains(&tx.from) {
+                return Err(
+                    "a validator slashed for double-signing cannot register again".to_string(),
+                );
+            }
+            // A key left behind by this operator's earlier registration is fine
+            if state
+                .consensus_keys
+                .get(&tx.from)
+                .is_some_and(|record| record.operator != tx.from)
+            {
+                return Err("key is already used as a consensus key".to_string());
+            }
+            if amount < params.stake_threshold {
+                return Err(format!(
+                    "self-stake {} is below the stake threshold {}",
+                    amount, params.stake_threshold
+                ));
+            }
+            state.debit(&tx.from, amount)?;
+            state
+                .candidates
+                .insert(tx.from.clone(), Candidate::new(amount, height));
+        }
+        TxPayload::Bond => {
+            if !state.candidates.contains_key(&tx.from) {
+                return Err("validator is not registered".to_string());
+            }
+            state.debit(&tx.from, amount)?;
+            let candidate = state.candidates.get_mut(&tx.from).unwrap();
+            candidate.self_stake = candidate
+                .self_stake
+                .checked_add(amount)
+                .ok_or_else(|| "Stake overflow".to_string())?;
+        }
+        TxPayload::Unbond => {
+            let candidate = state
+                .candidates
+                .get_mut(&tx.from)
+                .ok_or_else(|| "validator is not registered".to_string())?;
+            match candidate.jailed_until {
+                Some(u64::MAX) => {
+                    return Err("a validator slashed for double-signing cannot unbond".to_string())
+                }
+                Some(until) => {
+                    return Err(format!(
+                        "cannot unbond while jailed; unjail from height {} first",
+                        until
+                    ))
+                }
+                None => {}
+            }
+            if amount > candidate.self_stake {
+                return Err(format!(
+                    "cannot unbond {}, only {} is bonded",
+                    amount, candidate.self_stake
+                ));
+            }
+            candidate.self_stake -= amount;
+            if candidate.self_stake == 0 {
+                // Keep the key it signed with pointing at it, so evidence
+                // of an earlier double-sign can still be applied
+                let candidate = state.candidates.remove(&tx.from).unwrap();
+                let signing_key = candidate.signing_key(&tx.from).to_string();
+                state.consensus_keys.entry(signing_key).or_insert_with(|| {
+                    ConsensusKeyRecord::new(&tx.from, Some(candidate.registered_height))
+                });
+            }
+            state.unbonding.push(UnbondingEntry {
+                owner: tx.from.clone(),
+                validator: tx.from.clone(),
+                amount,
+                started_height: height,
+                release_height: height + params.unbonding_period,
+                rebond_to: None,
+            });
+        }
+        TxPayload::Delegate { validator } => {
+            if validator == &tx.from {
+                return Err("use bond to add self-stake".to_string());
+            }
+            if !state.candidates.contains_key(validator) {
+                return Err(format!("{} is not a registered validator", validator));
+            }
+            state.debit(&tx.from, amount)?;
+            add_delegation(state, &tx.from, validator, amount)?;
+        }
+        TxPayload::Undelegate { validator } => {
+            remove_delegation(state, &tx.from, validator, amount)?;
+            state.unbonding.push(UnbondingEntry {
+                owner: tx.from.clone(),
+                validator: validator.clone(),
+                amount,
+                started_height: height,
+                release_height: height + params.unbonding_period,
+                rebond_to: None,
+            });
+        }
+        TxPayload::Redelegate { from, to } => {
+            if from == to {
+                return Err("cannot redelegate to the same validator".to_string());
+            }
+            if to == &tx.from {
+                return Err("use bond to add self-stake".to_string());
+            }
+            if !state.candidates.contains_key(to) {
+                return Err(format!("{} is not a registered validator", to));
+            }
+            remove_delegation(state, &tx.from, from, amount)?;
+            state.unbonding.push(UnbondingEntry {
+                owner: tx.from.clone(),
+                validator: from.clone(),
+                amount,
+                started_height: height,
+                release_height: height + params.unbonding_period,
+                rebond_to: Some(to.clone()),
+            });
+        }
+        TxPayload::Evidence(_)
+        | TxPayload::RotateConsensusKey { .. }
+        | TxPayload::Unjail
+        | TxPayload::CreateVesting(_)
+        | TxPayload::SubmitProposal(_)
+        | TxPayload::VoteProposal(_) => {
+            return Err("not a staking operation".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Stage `consensus_key` as the signing key of `operator`'s validator from
+/// the next epoch. A key is only ever used once, by one validator, so
+/// evidence signed with it always points at the right operator and the
+/// right heights.
+pub fn rotate_consensus_key(
+    state: &mut ChainState,
+    operator: &str,
+    consensus_key: &str,
+) -> Result<(), String> {
+    crate::crypto_utils::hex_to_verifying_key(consensus_key)
+        .map_err(|e| format!("invalid consensus key: {}", e))?;
+    let candidate = state
+        .candidates
+        .get(operator)
+        .ok_or_else(|| "validator is not registered".to_string())?;
+    if candidate.signing_key(operator) == consensus_key {
+        return Err("key is already the validator's consensus key".to_string());
+    }
+    if state.consensus_keys.contains_key(consensus_key)
+        || state.candidates.contains_key(consensus_key)
+    {
+        return Err("key has already been used by a validator".to_string());
+    }
+
+    state.consensus_keys.insert(
+        consensus_key.to_string(),
+        ConsensusKeyRecord::new(operator, None),
+    );
+    let candidate = state.candidates.get_mut(operator).unwrap();
+    candidate.next_consensus_key = Some(consensus_key.to_string());
+    Ok(())
+}
+
+fn add_delegation(
+    state: &mut ChainState,
+    delegator: &str,
+    validator: &str,
+    amount: u64,
+) -> Result<(), String> {
+    let bonded = state
+        .delegations
+        .entry(delegator.to_string())
+        .or_default()
+        .entry(validator.to_string())
+        .or_insert(0);
+    *bonded = bonded
+        .checked_add(amount)
+        .ok_or_else(|| "Stake overflow".to_string())?;
+    Ok(())
+}
+
+pub(crate) fn remove_delegation(
+    state: &mut ChainState,
+    delegator: &str,
+    validator: &str,
+    amount: u64,
+) -> Result<(), String> {
+    let bonded = state.delegation(delegator, validator);
+    if amount > bonded {
+        return Err(format!(
+            "cannot undelegate {}, only {} is delegated to {}",
+            amount, bonded, validator
+        ));
+    }
+
+    let by_validator = state.delegations.get_mut(delegator).unwrap();
+    if bonded == amount {
+        by_validator.remove(validator);
+        if by_validator.is_empty() {
+            state.delegations.remove(delegator);
+        }
+    } else {
+        by_validator.insert(validator.to_string(), bonded - amount);
+    }
+    Ok(())
+}
+
+/// Credit every unbonding entry that has matured by `height`
+pub fn release_unbonded(state: &mut ChainState, height: u64) -> Result<(), String> {
+    let (matured, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut state.unbonding)
+        .into_iter()
+        .partition(|entry| entry.release_height <= height);
+    state.unbonding = pending;
+
+    for entry in matured {
+        match entry.rebond_to {
+            // A validator that left in the meantime can't receive the stake
+            Some(validator) if state.candidates.contains_key(&validator) => {
+                add_delegation(state, &entry.owner, &validator, entry.amount)?
+            }
+            _ => {
+                state.credit(&entry.owner, entry.amount)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Voting power of every candidate: self-stake plus bonded delegations
+pub fn voting_power(state: &ChainState) -> BTreeMap<String, u64> {
+    let mut power: BTreeMap<String, u64> = state
+        .candidates
+        .iter()
+        .map(|(address, candidate)| (address.clone(), candidate.self_stake))
+        .collect();
+    for by_validator in state.delegations.values() {
+        for (validator, amount) in by_validator {
+            if let Some(total) = power.get_mut(validator) {
+                *total = total.saturating_add(*amount);
+            }
+        }
+    }
+    power
+}
+
+/// Candidates eligible to produce blocks, by voting power. Eligibility
+/// needs a self-stake at the threshold and no jail term; ties break on the
+/// address so every node derives the same order.
+pub fn active_set(state: &ChainState, params: &StakingParams) -> Vec<(String, u64)> {
+    let mut eligible: Vec<(String, u64)> = voting_power(state)
+        .into_iter()
+        .filter(|(address, _)| {
+            let candidate = &state.candidates[address];
+            candidate.self_stake >= params.stake_threshold && !candidate.is_jailed()
+        })
+        .collect();
+    eligible.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    eligible.truncate(params.validator_count as usize);
+    eligible
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    fn params() -> StakingParams {
+        StakingParams {
+            stake_threshold: 1000,
+            validator_count: 2,
+            unbonding_period: 10,
+            epoch_length: 5,
+            slashing_penalty: 400,
+            evidence_reward: 100,
+            missed_slot_window: 10,
+            missed_slot_threshold: 5,
+            jail_cooldown: 20,
+            epoch_reward: 0,
+            commission_bps: 0,
+        }
+    }
+
+    fn staking_tx(from: &str, payload: TxPayload, amount: u64) -> (Transaction, TxPayload) {
+        let tx = Transaction::new(
+            from.to_string(),
+            "staking".to_string(),
+            amount,
+            Some(payload.to_data()),
+        );
+        (tx, payload)
+    }
+
+    fn apply(
+        state: &mut ChainState,
+        from: &str,
+        payload: TxPayload,
+        amount: u64,
+        height: u64,
+    ) -> Result<(), String> {
+        let (tx, payload) = staking_tx(from, payload, amount);
+        apply_staking(state, &tx, &payload, height, &params())
+    }
+
+    #[test]
+    fn test_consensus_keys_belong_to_one_validator() -> Result<(), String> {
+        let key = |seed: u8| {
+            hex::encode(
+                SigningKey::from_bytes(&[seed; 32])
+                    .verifying_key()
+                    .to_bytes(),
+            )
+        };
+        let (alice, bob, hot) = (key(1), key(2), key(3));
+        let mut state = ChainState::default();
+        for operator in [&alice, &bob] {
+            state.balances.insert(operator.clone(), 5000);
+            apply(&mut state, operator, TxPayload::RegisterValidator, 1000, 1)?;
+        }
+
+        rotate_consensus_key(&mut state, &alice, &hot)?;
+        assert_eq!(
+            state.candidates[&alice].next_consensus_key,
+            Some(hot.clone())
+        );
+        assert_eq!(state.candidates[&alice].signing_key(&alice), alice);
+        assert_eq!(state.consensus_keys[&hot].operator, alice);
+
+        assert!(rotate_consensus_key(&mut state, &bob, &hot).is_err());
+        assert!(rotate_consensus_key(&mut state, &bob, &alice).is_err());
+        assert!(rotate_consensus_key(&mut state, &bob, &bob).is_err());
+        assert!(rotate_consensus_key(&mut state, &bob, "zz").is_err());
+        assert!(rotate_consensus_key(&mut state, &hot, &key(4)).is_err());
+
+        // A key claimed as a consensus key can't register as an operator
+        state.balances.insert(hot.clone(), 5000);
+        let err = apply(&mut state, &hot, TxPayload::RegisterValidator, 1000, 2);
+        assert!(err.unwrap_err().contains("consensus key"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_enforces_threshold_and_balance() -> Result<(), String> {
+        let mut state = ChainState::default();
+        state.credit("alice", 5000)?;
+
+        assert_eq!(
+            apply(&mut state, "alice", TxPayload::RegisterValidator, 999, 1),
+            Err("self-stake 999 is below the stake threshold 1000".to_string())
+        );
+        assert!(apply(&mut state, "bob", TxPayload::RegisterValidator, 1000, 1).is_err());
+
+        apply(&mut state, "alice", TxPayload::RegisterValidator, 1500, 1)?;
+        apply(&mut state, "alice", TxPayload::Bond, 500, 2)?;
+        assert_eq!(state.balance("alice"), 3000);
+        assert_eq!(state.candidates["alice"].self_stake, 2000);
+        assert!(apply(&mut state, "alice", TxPayload::RegisterValidator, 1000, 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unbonded_stake_is_locked_until_release() -> Result<(), String> {
+        let mut state = ChainState::default();
+        state.credit("alice", 2000)?;
+        apply(&mut state, "alice", TxPayload::RegisterValidator, 2000, 1)?;
+        apply(&mut state, "alice", TxPayload::Unbond, 1500, 5)?;
+
+        // Below the threshold the candidate drops out of the active set
+        assert!(active_set(&state, &params()).is_empty());
+
+        release_unbonded(&mut state, 14)?;
+        assert_eq!(state.balance("alice"), 0);
+        release_unbonded(&mut state, 15)?;
+        assert_eq!(state.balance("alice"), 1500);
+        assert!(state.unbonding.is_empty());
+
+        assert!(apply(&mut state, "alice", TxPayload::Unbond, 501, 16).is_err());
+        apply(&mut state, "alice", TxPayload::Unbond, 500, 16)?;
+        assert!(!state.candidates.contains_key("alice"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_jailed_validator_cannot_unbond() -> Result<(), String> {
+        let mut state = ChainState::default();
+        state.credit("alice", 2000)?;
+        apply(&mut state, "alice", TxPayload::RegisterValidator, 2000, 1)?;
+
+        state.candidates.get_mut("alice").unwrap().jailed_until = Some(30);
+        let err = apply(&mut state, "alice", TxPayload::Unbond, 500, 5).unwrap_err();
+        assert!(err.contains("while jailed"), "{}", err);
+        state.candidates.get_mut("alice").unwrap().jailed_until = Some(u64::MAX);
+        let err = apply(&mut state, "alice", TxPayload::Unbond, 500, 5).unwrap_err();
+        assert!(err.contains("double-signing"), "{}", err);
+        assert_eq!(state.candidates["alice"].self_stake, 2000);
+
+        // Once fully unbonded, a validator may register again
+        state.candidates.get_mut("alice").unwrap().jailed_until = None;
+        apply(&mut state, "alice", TxPayload::Unbond, 2000, 6)?;
+        assert_eq!(state.signer_at("alice", 3), Some("alice".to_string()));
+        release_unbonded(&mut state, 16)?;
+        apply(&mut state, "alice", TxPayload::RegisterValidator, 2000, 17)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_active_set_is_ordered_and_bounded() -> Result<(), String> {
+        let mut state = ChainState::default();
+        for (name, stake) in [
+            ("carol", 3000),
+            ("alice", 2000),
+            ("bob", 2000),
+            ("dave", 500),
+        ] {
+            state.credit(name, stake)?;
+            state
+                .candidates
+                .insert(name.to_string(), Candidate::new(stake, 0));
+        }
+
+        assert_eq!(
+            active_set(&state, &params()),
+            vec![("carol".to_string(), 3000), ("alice".to_string(), 2000)]
+        );
+
+        // Delegations count towards power but not towards eligibility
+        state.credit("erin", 5000)?;
+        apply(&mut state, "erin", delegate("bob"), 1500, 1)?;
+        apply(&mut state, "erin", delegate("dave"), 3000, 1)?;
+        assert_eq!(
+            active_set(&state, &params()),
+            vec![("bob".to_string(), 3500), ("carol".to_string(), 3000)]
+        );
+        assert_eq!(voting_power(&state)["dave"], 3500);
+        Ok(())
+    }
+
+    #[test]
+    fn test_epoch_boundaries() {
+        let params = params();
+        assert_eq!(params.epoch_of(4), 0);
+        assert_eq!(params.epoch_of(5), 1);
+        assert!(params.is_epoch_start(10));
+        assert!(!params.is_epoch_start(11));
+    }
+
+    fn delegate(validator: &str) -> TxPayload {
+        TxPayload::Delegate {
+            validator: validator.to_string(),
+        }
+    }
+
+    fn register(state: &mut ChainState, validator: &str) -> Result<(), String> {
+        state.credit(validator, 1000)?;
+        apply(state, validator, TxPayload::RegisterValidator, 1000, 0)
+    }
+
+    #[test]
+    fn test_delegation_is_backed_by_balance() -> Result<(), String> {
+        let mut state = ChainState::default();
+        register(&mut state, "val")?;
+        state.credit("dan", 800)?;
+
+        assert!(apply(&mut state, "dan", delegate("val"), 801, 1).is_err());
+        assert!(apply(&mut state, "dan", delegate("nobody"), 100, 1).is_err());
+        assert!(apply(&mut state, "val", delegate("val"), 100, 1).is_err());
+        apply(&mut state, "dan", delegate("val"), 300, 1)?;
+        apply(&mut state, "dan", delegate("val"), 200, 2)?;
+
+        assert_eq!(state.balance("dan"), 300);
+        assert_eq!(state.delegation("dan", "val"), 500);
+        assert_eq!(voting_power(&state)["val"], 1500);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undelegate_releases_after_unbonding_period() -> Result<(), String> {
+        let mut state = ChainState::default();
+        register(&mut state, "val")?;
+        state.credit("dan", 500)?;
+        apply(&mut state, "dan", delegate("val"), 500, 1)?;
+
+        let undelegate = TxPayload::Undelegate {
+            validator: "val".to_string(),
+        };
+        assert!(apply(&mut state, "dan", undelegate.clone(), 501, 2).is_err());
+        apply(&mut state, "dan", undelegate, 500, 2)?;
+        assert!(state.delegations.is_empty());
+        assert_eq!(voting_power(&state)["val"], 1000);
+
+        release_unbonded(&mut state, 11)?;
+        assert_eq!(state.balance("dan"), 0);
+        release_unbonded(&mut state, 12)?;
+        assert_eq!(state.balance("dan"), 500);
+        Ok(())
+    }
+
+    #[test]
+    fn test_redelegate_moves_stake_after_unbonding_period() -> Result<(), String> {
+        let mut state = ChainState::default();
+        register(&mut state, "a")?;
+        register(&mut state, "b")?;
+        state.credit("dan", 400)?;
+        apply(&mut state, "dan", delegate("a"), 400, 1)?;
+
+        let redelegate = TxPayload::Redelegate {
+            from: "a".to_string(),
+            to: "b".to_string(),
+        };
+        apply(&mut state, "dan", redelegate, 400, 2)?;
+        assert_eq!(voting_power(&state)["a"], 1000);
+        assert_eq!(voting_power(&state)["b"], 1000);
+
+        release_unbonded(&mut state, 12)?;
+        assert_eq!(state.delegation("dan", "b"), 400);
+        assert_eq!(voting_power(&state)["b"], 1400);
+        assert_eq!(state.balance("dan"), 0);
+        Ok(())
+    }
+}
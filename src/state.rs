@@ -0,0 +1,407 @@
+use crate::config::AppConfig;
+use crate::consensus::dpos::{SerializableVerifyingKey, Validator};
+use crate::consensus::engine::ConsensusEngine;
+use crate::crypto_utils;
+use crate::governance::{self, GovernanceParams, GovernanceState};
+use crate::rewards::{RewardCalculator, ValidatorReward};
+use crate::slashing::{self, SlotWindow};
+use crate::staking::{
+    self, Candidate, ConsensusKeyRecord, EpochInfo, StakingParams, UnbondingEntry,
+};
+use crate::transaction::{Transaction, TxPayload, TX_VALIDITY_SECS};
+use crate::vesting::{self, VestingSchedule};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Self-stake a node bonds for its own key when genesis names no validators
+pub const DEFAULT_GENESIS_STAKE: u64 = 100_000;
+
+/// Ledger and validator state at a given block height.
+///
+/// Collections are ordered so the encoded state, and therefore its root,
+/// is identical on every node holding the same data.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChainState {
+    pub balances: BTreeMap<String, u64>,
+    pub epoch: EpochInfo,
+    pub validators: Vec<Validator>,          // active set for `epoch`
+    pub voting_power: BTreeMap<String, u64>, // active validator -> self-stake plus delegations
+    pub candidates: BTreeMap<String, Candidate>, // every registered validator
+    pub delegations: BTreeMap<String, BTreeMap<String, u64>>, // delegator -> validator -> bonded
+    pub unbonding: Vec<UnbondingEntry>,
+    pub evidence: BTreeSet<(String, u64)>, // (offender, height) of punished double-signs
+    pub tombstones: BTreeSet<String>,      // double-signers, who may never register again
+    pub slot_windows: BTreeMap<String, SlotWindow>, // validator -> recent produced and missed slots
+    pub last_rewards: BTreeMap<String, ValidatorReward>, // validator -> payout for the last epoch
+    pub rewards_earned: BTreeMap<String, u64>, // address -> rewards received so far
+    pub vesting: BTreeMap<String, Vec<VestingSchedule>>, // address -> lockups on its balance
+    pub block_time: u64,                   // timestamp of the block being applied, for vesting
+    pub governance: GovernanceState,
+    pub consensus_keys: BTreeMap<String, ConsensusKeyRecord>, // every separate consensus key used
+    pub recent_transactions: BTreeSet<(u64, String)>, // (timestamp, hash) applied within the validity window
+}
+
+impl ChainState {
+    pub fn balance(&self, address: &str) -> u64 {
+        *self.balances.get(address).unwrap_or(&0)
+    }
+
+    pub fn credit(&mut self, address: &str, amount: u64) -> Result<u64, String> {
+        let balance = self
+            .balance(address)
+            .checked_add(amount)
+            .ok_or_else(|| "Balance overflow".to_string())?;
+        self.balances.insert(address.to_string(), balance);
+        Ok(balance)
+    }
+
+    /// Stake `delegator` has bonded to `validator`
+    pub fn delegation(&self, delegator: &str, validator: &str) -> u64 {
+        self.delegations
+            .get(delegator)
+            .and_then(|by_validator| by_validator.get(validator))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Delegations made to `validator`, as (delegator, amount)
+    pub fn delegations_to(&self, validator: &str) -> Vec<(String, u64)> {
+        self.delegations
+            .iter()
+            .filter_map(|(delegator, by_validator)| {
+                by_validator
+                    .get(validator)
+                    .map(|amount| (delegator.clone(), *amount))
+            })
+            .collect()
+    }
+
+    /// Part of the balance of `address` held back by vesting at block time
+    pub fn locked_balance(&self, address: &str) -> u64 {
+        self.vesting.get(address).map_or(0, |schedules| {
+            vesting::locked_amount(schedules, self.block_time)
+        })
+    }
+
+    pub fn spendable_balance(&self, address: &str) -> u64 {
+        self.balance(address)
+            .saturating_sub(self.locked_balance(address))
+    }
+
+    /// Take `amount` out of the unlocked part of the balance of `address`
+    pub fn debit(&mut self, address: &str, amount: u64) -> Result<u64, String> {
+        let balance = self.balance(address);
+        if balance < amount {
+            return Err(format!(
+                "insufficient balance: {} has {}, needs {}",
+                address, balance, amount
+            ));
+        }
+        let spendable = self.spendable_balance(address);
+        if spendable < amount {
+            return Err(format!(
+                "insufficient unlocked balance: {} has {} unlocked of {}, needs {}",
+                address, spendable, balance, amount
+            ));
+        }
+        self.balances.insert(address.to_string(), balance - amount);
+        Ok(balance - amount)
+    }
+
+    /// Credit `schedule.total_amount` to `address`, locked by `schedule`
+    pub fn add_vesting(&mut self, address: &str, schedule: VestingSchedule) -> Result<(), String> {
+        self.credit(address, schedule.total_amount)?;
+        self.vesting
+            .entry(address.to_string())
+            .or_default()
+            .push(schedule);
+        Ok(())
+    }
+
+    /// State at height 0, built from the `[genesis]` section of `config`,
+    /// with the initial producers installed by `engine`
+    pub fn genesis(
+        config: &AppConfig,
+        timestamp: u64,
+        engine: &dyn ConsensusEngine,
+    ) -> Result<Self, String> {
+        let mut state = ChainState {
+            balances: config.genesis.balances.clone(),
+            block_time: timestamp,
+            ..Default::default()
+        };
+        state.governance.params = GovernanceParams::from(&config.governance);
+        for grant in &config.genesis.vesting {
+            if grant.cliff_secs > grant.duration_secs {
+                return Err(format!(
+                    "Genesis vesting for {} has a cliff longer than its duration",
+                    grant.address
+                ));
+            }
+            let schedule = VestingSchedule {
+                total_amount: grant.amount,
+                start_time: grant.start_time.unwrap_or(timestamp),
+                cliff_duration: grant.cliff_secs,
+                vesting_duration: grant.duration_secs,
+            };
+            state.add_vesting(&grant.address, schedule)?;
+        }
+
+        let params = state.staking_params(&StakingParams::from(&config.consensus.dpos));
+        engine.init_genesis(&mut state, &config.genesis, &params, timestamp)?;
+        Ok(state)
+    }
+
+    /// State changes that happen before the transactions of the block at
+    /// `height`: governance changes scheduled for it take effect, matured
+    /// unbonding entries are released, and at an epoch boundary `engine`
+    /// closes the ending epoch and starts the next one. `params` are the
+    /// configured values, before governance changes.
+    pub fn begin_block(
+        &mut self,
+        height: u64,
+        timestamp: u64,
+        params: &StakingParams,
+        engine: &dyn ConsensusEngine,
+    ) -> Result<(), String> {
+        self.block_time = timestamp;
+        governance::begin_block(self, height)?;
+        let params = &self.staking_params(params);
+        for schedules in self.vesting.values_mut() {
+            schedules.retain(|schedule| !schedule.is_fully_vested(timestamp));
+        }
+        self.vesting.retain(|_, schedules| !schedules.is_empty());
+
+        // Transactions this old are rejected as expired, so their hashes can go
+        self.recent_transactions = self
+            .recent_transactions
+            .split_off(&(timestamp.saturating_sub(TX_VALIDITY_SECS), String::new()));
+        staking::release_unbonded(self, height)?;
+        // Older evidence is rejected anyway, so its record can go
+        self.evidence
+            .retain(|(_, offence)| height.saturating_sub(*offence) <= params.unbonding_period);
+        if height > 0 && params.is_epoch_start(height) {
+            engine.on_epoch_end(self, params)?;
+            engine.on_epoch_start(self, height, timestamp, params)?;
+        }
+        Ok(())
+    }
+
+    /// Mint the rewards of the epoch that is ending to the validators of its
+    /// active set and their delegators
+    pub(crate) fn distribute_rewards(&mut self, params: &StakingParams) -> Result<(), String> {
+        let calculator = RewardCalculator::from(params);
+        let epoch = self.epoch.number;
+        self.last_rewards.clear();
+
+        for (validator, reward) in calculator.calculate_rewards(&self.voting_power) {
+            let self_stake = self
+                .candidates
+                .get(&validator)
+                .map_or(0, |candidate| candidate.self_stake);
+            let delegations = self.delegations_to(&validator);
+            let payout =
+                calculator.split_reward(&validator, epoch, reward, self_stake, &delegations);
+            for (staker, amount) in &payout.payouts {
+                self.credit(staker, *amount)?;
+                let earned = self.rewards_earned.entry(staker.clone()).or_insert(0);
+                *earned = earned.saturating_add(*amount);
+            }
+            self.last_rewards.insert(validator, payout);
+        }
+        Ok(())
+    }
+
+    /// Start the epoch beginning at `height`: staged consensus keys take
+    /// over, and the active set is elected from the candidates, keeping the
+    /// uptime history of validators that stay. With no eligible candidate
+    /// the previous set carries over.
+    pub(crate) fn rotate_validators(
+        &mut self,
+        height: u64,
+        params: &StakingParams,
+        timestamp: u64,
+    ) -> Result<(), String> {
+        self.epoch = EpochInfo {
+            number: params.epoch_of(height),
+            start_height: height,
+        };
+
+        for (operator, candidate) in self.candidates.iter_mut() {
+            let Some(next) = candidate.next_consensus_key.take() else {
+                continue;
+            };
+            let previous = candidate.signing_key(operator).to_string();
+            let registered = candidate.registered_height;
+            self.consensus_keys
+                .entry(previous)
+                .or_insert_with(|| ConsensusKeyRecord::new(operator, Some(registered)))
+                .retired_at = Some(height);
+            if let Some(record) = self.consensus_keys.get_mut(&next) {
+                record.active_from = Some(height);
+            }
+            candidate.consensus_key = Some(next);
+        }
+
+        let active = staking::active_set(self, params);
+        if active.is_empty() {
+            return Ok(());
+        }
+
+        let mut validators = Vec::with_capacity(active.len());
+        for (address, stake) in &active {
+            let key = SerializableVerifyingKey(crypto_utils::hex_to_verifying_key(address)?);
+            let signing_key = self.candidates[address].signing_key(address);
+            let consensus_key =
+                SerializableVerifyingKey(crypto_utils::hex_to_verifying_key(signing_key)?);
+            let validator = match self.validators.iter().find(|v| v.address == key) {
+                Some(existing) => Validator {
+                    stake: *stake,
+                    consensus_key,
+                    ..existing.clone()
+                },
+                None => Validator {
+                    address: key,
+                    consensus_key,
+                    stake: *stake,
+                    uptime: 1.0,
+                    missed_blocks: 0,
+                    last_active: timestamp as i64,
+                },
+            };
+            validators.push(validator);
+        }
+
+        self.validators = validators;
+        self.voting_power = active.into_iter().collect();
+        Ok(())
+    }
+
+    /// Take `address` out of the active set straight away rather than at
+    /// the next epoch
+    pub fn remove_from_active_set(&mut self, address: &str) {
+        self.validators
+            .retain(|validator| hex::encode(validator.address.0.to_bytes()) != address);
+        self.voting_power.remove(address);
+    }
+
+    /// Reject `tx` if it has expired by the current block time or was
+    /// already applied
+    pub fn check_not_replayed(&self, tx: &Transaction) -> Result<(), String> {
+        if tx.timestamp.saturating_add(TX_VALIDITY_SECS) < self.block_time {
+            return Err(format!(
+                "transaction expired: timestamp {} is more than {}s before block time {}",
+                tx.timestamp, TX_VALIDITY_SECS, self.block_time
+            ));
+        }
+        if self
+            .recent_transactions
+            .contains(&(tx.timestamp, tx.hash()))
+        {
+            return Err("transaction was already applied".to_string());
+        }
+        Ok(())
+    }
+
+    /// Apply the effects of `tx`, included in the block at `height`. On
+    /// error the state is left unchanged.
+    pub fn apply_transaction(
+        &mut self,
+        tx: &Transaction,
+        height: u64,
+        params: &StakingParams,
+    ) -> Result<(), String> {
+        self.check_not_replayed(tx)?;
+        if tx.timestamp > self.block_time.saturating_add(TX_VALIDITY_SECS) {
+            return Err(format!(
+                "transaction timestamp {} is more than {}s after block time {}",
+                tx.timestamp, TX_VALIDITY_SECS, self.block_time
+            ));
+        }
+        self.apply_payload(tx, height, params)?;
+        self.recent_transactions.insert((tx.timestamp, tx.hash()));
+        Ok(())
+    }
+
+    fn apply_payload(
+        &mut self,
+        tx: &Transaction,
+        height: u64,
+        params: &StakingParams,
+    ) -> Result<(), String> {
+        match tx.payload()? {
+            Some(TxPayload::Evidence(evidence)) => slashing::apply_evidence(
+                self,
+                &tx.from,
+                &evidence.first,
+                &evidence.second,
+                height,
+                params,
+            ),
+            Some(TxPayload::Unjail) => slashing::apply_unjail(self, &tx.from, height),
+            Some(TxPayload::RotateConsensusKey { consensus_key }) => {
+                staking::rotate_consensus_key(self, &tx.from, &consensus_key)
+            }
+            Some(TxPayload::CreateVesting(grant)) => vesting::apply_grant(self, tx, &grant),
+            Some(TxPayload::SubmitProposal(change)) => {
+                governance::submit_proposal(self, tx, &change, height)
+            }
+            Some(TxPayload::VoteProposal(vote)) => {
+                governance::cast_vote(self, &tx.from, &vote, height)
+            }
+            Some(payload) => staking::apply_staking(self, tx, &payload, height, params),
+            None => self.apply_transfer(tx),
+        }
+    }
+
+    /// Operator of the validator whose consensus key `key` was at `height`.
+    /// An operator key signs for itself until it is first rotated away.
+    pub fn signer_at(&self, key: &str, height: u64) -> Option<String> {
+        match self.consensus_keys.get(key) {
+            Some(record) => record.signs_at(height).then(|| record.operator.clone()),
+            None => self.candidates.contains_key(key).then(|| key.to_string()),
+        }
+    }
+
+    /// Voting power of the active set keyed by consensus key, the keys
+    /// commit votes are signed with
+    pub fn consensus_power(&self) -> BTreeMap<String, u64> {
+        self.validators
+            .iter()
+            .map(|validator| {
+                let operator = hex::encode(validator.address.0.to_bytes());
+                let power = self.voting_power.get(&operator).copied().unwrap_or(0);
+                (hex::encode(validator.consensus_key.0.to_bytes()), power)
+            })
+            .collect()
+    }
+
+    /// `base`, the configured staking parameters, with the changes made
+    /// by governance applied
+    pub fn staking_params(&self, base: &StakingParams) -> StakingParams {
+        self.governance.staking_params(base)
+    }
+
+    /// Move the amount of a plain transaction from its sender to `to`
+    fn apply_transfer(&mut self, tx: &Transaction) -> Result<(), String> {
+        if tx.amount == 0 {
+            return Ok(());
+        }
+        self.debit(&tx.from, tx.amount)?;
+        self.credit(&tx.to, tx.amount)?;
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        bincode::serialize(self).map_err(|e| format!("Failed to encode state: {}", e))
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        bincode::deserialize(bytes).map_err(|e| format!("Failed to decode state: {}", e))
+    }
+
+    /// Commitment to the full state, as a hex blake3 digest of its encoding
+    pub fn state_root(&self) -> Result<String, String> {
+        Ok(blake3::hash(&self.encode()?).to_hex().to_string())
+    }
+}
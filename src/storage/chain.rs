@@ -0,0 +1,429 @@
+use super::{KvStore, MemoryStore, WriteBatch};
+use crate::block::{Block, BlockHeader};
+use crate::consensus::dpos::Validator;
+use crate::consensus::finality::CommitCertificate;
+use crate::state::ChainState;
+use crate::transaction::Transaction;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+const HEADER_PREFIX: &[u8] = b"chain:header:";
+const BODY_PREFIX: &[u8] = b"chain:body:";
+const COMMIT_PREFIX: &[u8] = b"chain:commit:";
+const VOTE_PREFIX: &[u8] = b"chain:vote:";
+const VALIDATORS_PREFIX: &[u8] = b"chain:validators:";
+const PRUNED_BELOW_KEY: &[u8] = b"chain:meta:pruned_below";
+const FINALIZED_KEY: &[u8] = b"chain:meta:finalized_height";
+const GENESIS_KEY: &[u8] = b"chain:meta:genesis_hash";
+const BASE_KEY: &[u8] = b"chain:meta:base_height";
+const LAYOUT_KEY: &[u8] = b"chain:meta:layout_version";
+const STATE_KEY: &[u8] = b"state:current";
+
+/// Version of the key layout above, stored under `LAYOUT_KEY`
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// Header plus producer signature; everything needed to check a pruned block
+#[derive(Serialize, Deserialize)]
+struct StoredHeader {
+    header: BlockHeader,
+    signature: Vec<u8>,
+}
+
+/// Typed view over a `KvStore` holding blocks and ledger state.
+///
+/// Headers and bodies are stored under separate keys so pruned nodes can
+/// drop old bodies while keeping the header chain.
+#[derive(Clone)]
+pub struct ChainStore {
+    kv: Arc<dyn KvStore>,
+}
+
+impl ChainStore {
+    pub fn new(kv: Arc<dyn KvStore>) -> Self {
+        Self { kv }
+    }
+
+    /// Chain store over `kv` after checking its layout version. A new
+    /// store is stamped with it; one in another layout is refused.
+    pub fn open(kv: Arc<dyn KvStore>) -> Result<Self> {
+        let store = Self::new(kv);
+        store.check_layout()?;
+        Ok(store)
+    }
+
+    /// Chain store backed by a fresh `MemoryStore`
+    pub fn in_memory() -> Self {
+        Self::new(Arc::new(MemoryStore::new()))
+    }
+
+    fn check_layout(&self) -> Result<()> {
+        if let Some(bytes) = self.kv.get(LAYOUT_KEY)? {
+            let version = <[u8; 4]>::try_from(bytes.as_slice())
+                .map(u32::from_be_bytes)
+                .map_err(|_| anyhow::anyhow!("Corrupt layout version"))?;
+            if version != LAYOUT_VERSION {
+                bail!(
+                    "Data directory uses chain layout {} but this node reads layout {}; \
+                     restore a snapshot or import a chain export into an empty data directory",
+                    version,
+                    LAYOUT_VERSION
+                );
+            }
+            return Ok(());
+        }
+        self.kv.put(LAYOUT_KEY, &LAYOUT_VERSION.to_be_bytes())
+    }
+
+    pub fn kv(&self) -> &Arc<dyn KvStore> {
+        &self.kv
+    }
+
+    fn height_key(prefix: &[u8], height: u64) -> Vec<u8> {
+        // Big-endian heights keep prefix scans in chain order
+        [prefix, &height.to_be_bytes()[..]].concat()
+    }
+
+    pub fn put_block(&self, block: &Block) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        self.stage_block(&mut batch, block)?;
+        self.kv.write_batch(batch)
+    }
+
+    /// Add a block write to `batch` so it commits together with state changes
+    pub fn stage_block(&self, batch: &mut WriteBatch, block: &Block) -> Result<()> {
+        let height = block.header.height;
+        let header = StoredHeader {
+            header: block.header.clone(),
+            signature: block.signature.clone(),
+        };
+        batch.put(
+            &Self::height_key(HEADER_PREFIX, height),
+            &bincode::serialize(&header)?,
+        );
+        batch.put(
+            &Self::height_key(BODY_PREFIX, height),
+            &bincode::serialize(&block.transactions)?,
+        );
+        Ok(())
+    }
+
+    pub fn get_header(&self, height: u64) -> Result<Option<BlockHeader>> {
+        match self.kv.get(&Self::height_key(HEADER_PREFIX, height))? {
+            Some(bytes) => Ok(Some(bincode::deserialize::<StoredHeader>(&bytes)?.header)),
+            None => Ok(None),
+        }
+    }
+
+    /// Full block at `height`, or `None` if it is unknown or its body was pruned
+    pub fn get_block(&self, height: u64) -> Result<Option<Block>> {
+        let header = match self.kv.get(&Self::height_key(HEADER_PREFIX, height))? {
+            Some(bytes) => bincode::deserialize::<StoredHeader>(&bytes)?,
+            None => return Ok(None),
+        };
+        match self.kv.get(&Self::height_key(BODY_PREFIX, height))? {
+            Some(body) => Ok(Some(Block {
+                header: header.header,
+                transactions: bincode::deserialize::<Vec<Transaction>>(&body)?,
+                signature: header.signature,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn has_blocks(&self) -> Result<bool> {
+        Ok(self.get_header(self.base_height()?)?.is_some())
+    }
+
+    /// Stored blocks from `height` to the tip, in height order. Blocks
+    /// whose body was pruned come back with no transactions; see
+    /// `pruned_below`.
+    pub fn load_blocks_from(&self, height: u64) -> Result<Vec<Block>> {
+        let mut blocks = Vec::new();
+        for height in height.. {
+            let stored = match self.kv.get(&Self::height_key(HEADER_PREFIX, height))? {
+                Some(bytes) => bincode::deserialize::<StoredHeader>(&bytes)?,
+                None => break,
+            };
+            let transactions = match self.kv.get(&Self::height_key(BODY_PREFIX, height))? {
+                Some(body) => bincode::deserialize(&body)?,
+                None => Vec::new(),
+            };
+            blocks.push(Block {
+                header: stored.header,
+                transactions,
+                signature: stored.signature,
+            });
+        }
+        Ok(blocks)
+    }
+
+    /// Height of the first stored block: 0, or the height of the snapshot
+    /// the chain was restored from
+    pub fn base_height(&self) -> Result<u64> {
+        self.get_height_marker(BASE_KEY, "base height")
+    }
+
+    /// Delete the bodies of blocks in `from..height` and record the new
+    /// pruning boundary
+    pub fn prune_bodies_below(&self, from: u64, height: u64) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for pruned in from..height {
+            batch.delete(&Self::height_key(BODY_PREFIX, pruned));
+        }
+        batch.put(PRUNED_BELOW_KEY, &height.to_be_bytes());
+        self.kv.write_batch(batch)
+    }
+
+    /// Height below which block bodies have been pruned, 0 if none were
+    pub fn pruned_below(&self) -> Result<u64> {
+        self.get_height_marker(PRUNED_BELOW_KEY, "pruning marker")
+    }
+
+    fn get_height_marker(&self, key: &[u8], name: &str) -> Result<u64> {
+        match self.kv.get(key)? {
+            Some(bytes) => {
+                let bytes: [u8; 8] = bytes
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Corrupt {}", name))?;
+                Ok(u64::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Store the certificate finalizing a block and advance the finalized
+    /// height to it
+    pub fn put_certificate(&self, certificate: &CommitCertificate) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(
+            &Self::height_key(COMMIT_PREFIX, certificate.height),
+            &bincode::serialize(certificate)?,
+        );
+        batch.put(FINALIZED_KEY, &certificate.height.to_be_bytes());
+        self.kv.write_batch(batch)
+    }
+
+    pub fn get_certificate(&self, height: u64) -> Result<Option<CommitCertificate>> {
+        match self.kv.get(&Self::height_key(COMMIT_PREFIX, height))? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Record that the consensus key `validator` voted for `block_hash`
+    /// at `height`
+    pub fn put_last_vote(&self, validator: &str, height: u64, block_hash: &str) -> Result<()> {
+        let key = [VOTE_PREFIX, validator.as_bytes()].concat();
+        self.kv
+            .put(&key, &bincode::serialize(&(height, block_hash))?)
+    }
+
+    /// Latest (height, block hash) the consensus key `validator` voted for
+    /// on this node
+    pub fn last_vote(&self, validator: &str) -> Result<Option<(u64, String)>> {
+        match self.kv.get(&[VOTE_PREFIX, validator.as_bytes()].concat())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Height of the latest finalized block, 0 (genesis) if none was
+    pub fn finalized_height(&self) -> Result<u64> {
+        self.get_height_marker(FINALIZED_KEY, "finalized height")
+    }
+
+    /// Record the hash of the chain's genesis block
+    pub fn put_genesis_hash(&self, hash: &str) -> Result<()> {
+        self.kv.put(GENESIS_KEY, hash.as_bytes())
+    }
+
+    pub fn genesis_hash(&self) -> Result<Option<String>> {
+        match self.kv.get(GENESIS_KEY)? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Start the chain at a snapshot's block and state. The genesis block
+    /// is never held, so its hash is recorded alongside.
+    pub fn commit_snapshot(
+        &self,
+        block: &Block,
+        state: &ChainState,
+        genesis_hash: &str,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        self.stage_block(&mut batch, block)?;
+        self.stage_state(&mut batch, state)?;
+        // The set that verified the snapshot block itself is not known
+        self.stage_validators(&mut batch, block.header.height + 1, &state.validators)?;
+        batch.put(BASE_KEY, &block.header.height.to_be_bytes());
+        batch.put(GENESIS_KEY, genesis_hash.as_bytes());
+        self.kv.write_batch(batch)
+    }
+
+    /// Persist `block` and the state after applying it in one batch.
+    /// `producers` is the active set the block was verified against. It is
+    /// recorded when the block starts an epoch, and the set in `state` is
+    /// recorded for the next height when the block changed it.
+    pub fn commit_block(
+        &self,
+        block: &Block,
+        producers: &[Validator],
+        state: &ChainState,
+    ) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        self.stage_block(&mut batch, block)?;
+        self.stage_state(&mut batch, state)?;
+        let height = block.header.height;
+        if height == state.epoch.start_height {
+            self.stage_validators(&mut batch, height, producers)?;
+        }
+        if same_schedule(producers, &state.validators) {
+            // A replaced block may have recorded a change this one lacks
+            batch.delete(&Self::height_key(VALIDATORS_PREFIX, height + 1));
+        } else {
+            self.stage_validators(&mut batch, height + 1, &state.validators)?;
+        }
+        self.kv.write_batch(batch)
+    }
+
+    /// Record `validators` as the active set from `height` on
+    fn stage_validators(
+        &self,
+        batch: &mut WriteBatch,
+        height: u64,
+        validators: &[Validator],
+    ) -> Result<()> {
+        batch.put(
+            &Self::height_key(VALIDATORS_PREFIX, height),
+            &bincode::serialize(validators)?,
+        );
+        Ok(())
+    }
+
+    /// Active sets that verified the blocks from `from` to `to`, each with
+    /// the first height it applies to: the set in force at `from`, then
+    /// every set that took over by `to`. A node restored from a snapshot
+    /// holds none before the snapshot, so the first set may start after
+    /// `from`.
+    pub fn validator_sets(&self, from: u64, to: u64) -> Result<Vec<(u64, Vec<Validator>)>> {
+        let mut sets: Vec<(u64, Vec<u8>)> = Vec::new();
+        for (key, value) in self.kv.scan_prefix(VALIDATORS_PREFIX)? {
+            let height = <[u8; 8]>::try_from(&key[VALIDATORS_PREFIX.len()..])
+                .map(u64::from_be_bytes)
+                .map_err(|_| anyhow::anyhow!("Corrupt validator set key"))?;
+            if height > to {
+                break;
+            }
+            if height <= from {
+                sets.clear();
+            }
+            sets.push((height, value));
+        }
+        sets.into_iter()
+            .map(|(height, bytes)| Ok((height, bincode::deserialize(&bytes)?)))
+            .collect()
+    }
+
+    pub fn stage_state(&self, batch: &mut WriteBatch, state: &ChainState) -> Result<()> {
+        batch.put(STATE_KEY, &state.encode().map_err(anyhow::Error::msg)?);
+        Ok(())
+    }
+
+    pub fn load_state(&self) -> Result<Option<ChainState>> {
+        match self.kv.get(STATE_KEY)? {
+            Some(bytes) => Ok(Some(
+                ChainState::decode(&bytes).map_err(anyhow::Error::msg)?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Whether `a` and `b` name the same producers in the same order; uptime
+/// and missed slots do not change who may produce
+fn same_schedule(a: &[Validator], b: &[Validator]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.address == b.address && a.consensus_key == b.consensus_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto_utils;
+
+    #[test]
+    fn test_blocks_load_in_height_order() -> Result<()> {
+        let store = ChainStore::in_memory();
+        let signing_key = crypto_utils::default_signing_key();
+
+        let genesis = Block::new(0, "0".repeat(64), Vec::new(), &signing_key);
+        let next = Block::new(1, genesis.hash(), Vec::new(), &signing_key);
+        store.put_block(&next)?;
+        store.put_block(&genesis)?;
+
+        let blocks = store.load_blocks_from(0)?;
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].hash(), genesis.hash());
+        assert_eq!(blocks[1].hash(), next.hash());
+        assert!(store.get_block(2)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_block_stores_block_and_state() -> Result<()> {
+        let store = ChainStore::in_memory();
+        let signing_key = crypto_utils::default_signing_key();
+        assert!(store.load_state()?.is_none());
+
+        let mut state = ChainState::default();
+        state.credit("alice", 500).map_err(anyhow::Error::msg)?;
+        let block = Block::new(0, "0".repeat(64), Vec::new(), &signing_key);
+        store.commit_block(&block, &[], &state)?;
+
+        assert_eq!(store.load_state()?, Some(state));
+        assert_eq!(store.get_block(0)?.map(|b| b.hash()), Some(block.hash()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pruned_bodies_keep_headers() -> Result<()> {
+        let store = ChainStore::in_memory();
+        let signing_key = crypto_utils::default_signing_key();
+
+        let mut previous_hash = "0".repeat(64);
+        for height in 0..4 {
+            let block = Block::new(height, previous_hash, Vec::new(), &signing_key);
+            previous_hash = block.hash();
+            store.put_block(&block)?;
+        }
+
+        store.prune_bodies_below(0, 2)?;
+        assert_eq!(store.pruned_below()?, 2);
+        assert!(store.get_block(1)?.is_none());
+        assert!(store.get_header(1)?.is_some());
+        assert!(store.get_block(2)?.is_some());
+        assert_eq!(store.load_blocks_from(0)?.len(), 4);
+        assert_eq!(store.load_blocks_from(2)?[0].transactions.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_version_is_checked() -> Result<()> {
+        let signing_key = crypto_utils::default_signing_key();
+        let block = Block::new(5, "0".repeat(64), Vec::new(), &signing_key);
+
+        // A new store gets stamped and opens again
+        let kv: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        ChainStore::open(kv.clone())?.put_block(&block)?;
+        assert!(ChainStore::open(kv.clone())?.get_header(5)?.is_some());
+        kv.put(LAYOUT_KEY, &(LAYOUT_VERSION + 1).to_be_bytes())?;
+        assert!(ChainStore::open(kv).is_err());
+        Ok(())
+    }
+}
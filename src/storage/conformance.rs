@@ -0,0 +1,110 @@
+// Behaviour every `KvStore` implementation must share. Backends pull the
+// suite in with `kv_store_conformance_tests!`, passing a factory that
+// returns the store plus anything that has to outlive it (e.g. a temp dir).
+
+use super::{KvStore, WriteBatch};
+
+/// Generate one `#[test]` per conformance case for a store factory
+#[macro_export]
+macro_rules! kv_store_conformance_tests {
+    ($factory:expr) => {
+        $crate::kv_store_conformance_tests!(@cases $factory;
+            put_then_get,
+            get_missing_key,
+            put_overwrites,
+            delete_removes_key,
+            delete_missing_key_is_ok,
+            batch_applies_in_order,
+            scan_prefix_is_sorted_and_bounded,
+            snapshot_is_isolated_from_writes,
+            binary_keys_round_trip
+        );
+    };
+    (@cases $factory:expr; $($case:ident),*) => {
+        $(
+            #[test]
+            fn $case() {
+                let (store, _guard) = ($factory)();
+                $crate::storage::conformance::$case(&store);
+            }
+        )*
+    };
+}
+
+pub fn put_then_get(store: &dyn KvStore) {
+    store.put(b"alpha", b"1").unwrap();
+    assert_eq!(store.get(b"alpha").unwrap(), Some(b"1".to_vec()));
+}
+
+pub fn get_missing_key(store: &dyn KvStore) {
+    assert_eq!(store.get(b"missing").unwrap(), None);
+}
+
+pub fn put_overwrites(store: &dyn KvStore) {
+    store.put(b"key", b"old").unwrap();
+    store.put(b"key", b"new").unwrap();
+    assert_eq!(store.get(b"key").unwrap(), Some(b"new".to_vec()));
+}
+
+pub fn delete_removes_key(store: &dyn KvStore) {
+    store.put(b"key", b"value").unwrap();
+    store.delete(b"key").unwrap();
+    assert_eq!(store.get(b"key").unwrap(), None);
+}
+
+pub fn delete_missing_key_is_ok(store: &dyn KvStore) {
+    assert!(store.delete(b"never-written").is_ok());
+}
+
+pub fn batch_applies_in_order(store: &dyn KvStore) {
+    store.put(b"a", b"0").unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"a", b"1");
+    batch.put(b"b", b"2");
+    batch.delete(b"a");
+    batch.put(b"c", b"3");
+    batch.put(b"c", b"4");
+    assert_eq!(batch.len(), 5);
+    store.write_batch(batch).unwrap();
+
+    assert_eq!(store.get(b"a").unwrap(), None);
+    assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(store.get(b"c").unwrap(), Some(b"4".to_vec()));
+}
+
+pub fn scan_prefix_is_sorted_and_bounded(store: &dyn KvStore) {
+    store.put(b"block:2", b"two").unwrap();
+    store.put(b"block:1", b"one").unwrap();
+    store.put(b"block:3", b"three").unwrap();
+    store.put(b"blocks", b"other").unwrap();
+    store.put(b"balance:x", b"10").unwrap();
+
+    let entries = store.scan_prefix(b"block:").unwrap();
+    let keys: Vec<&[u8]> = entries.iter().map(|(k, _)| k.as_slice()).collect();
+    assert_eq!(keys, vec![&b"block:1"[..], b"block:2", b"block:3"]);
+    assert_eq!(entries[0].1, b"one".to_vec());
+
+    assert_eq!(store.scan_prefix(b"").unwrap().len(), 5);
+    assert!(store.scan_prefix(b"zzz").unwrap().is_empty());
+}
+
+pub fn snapshot_is_isolated_from_writes(store: &dyn KvStore) {
+    store.put(b"k1", b"v1").unwrap();
+    let snapshot = store.snapshot().unwrap();
+
+    store.put(b"k1", b"changed").unwrap();
+    store.put(b"k2", b"v2").unwrap();
+
+    assert_eq!(snapshot.get(b"k1"), Some(b"v1".to_vec()));
+    assert_eq!(snapshot.get(b"k2"), None);
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(store.get(b"k1").unwrap(), Some(b"changed".to_vec()));
+}
+
+pub fn binary_keys_round_trip(store: &dyn KvStore) {
+    let key = [0u8, 255, b'/', b'.', 10, 0];
+    store.put(&key, &[1, 2, 3]).unwrap();
+    assert_eq!(store.get(&key).unwrap(), Some(vec![1, 2, 3]));
+    assert_eq!(store.scan_prefix(&key[..2]).unwrap().len(), 1);
+}
@@ -0,0 +1,67 @@
+use super::{scan_prefix, BatchOp, KvStore, Snapshot, WriteBatch};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// In-memory `KvStore`, used by tests and by nodes that don't persist data
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let data = self.data.read().unwrap();
+        Ok(data.get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        data.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        data.remove(key);
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut data = self.data.write().unwrap();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Put(key, value) => {
+                    data.insert(key, value);
+                }
+                BatchOp::Delete(key) => {
+                    data.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let data = self.data.read().unwrap();
+        Ok(scan_prefix(&data, prefix))
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        let data = self.data.read().unwrap();
+        Ok(Snapshot::new(data.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    crate::kv_store_conformance_tests!(|| (MemoryStore::new(), ()));
+}
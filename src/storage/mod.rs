@@ -0,0 +1,113 @@
+// Storage abstraction shared by chain, state and contract storage.
+// Every backend implements `KvStore`; the conformance suite in
+// `conformance.rs` runs against each implementation.
+
+mod chain;
+#[cfg(test)]
+pub(crate) mod conformance;
+mod memory;
+
+pub use chain::ChainStore;
+pub use memory::MemoryStore;
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+/// A single write operation inside a `WriteBatch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// Ordered group of writes applied together by `KvStore::write_batch`
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.ops.push(BatchOp::Put(key.to_vec(), value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.ops.push(BatchOp::Delete(key.to_vec()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+/// Read-only, point-in-time copy of a store's contents
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Snapshot {
+    pub fn new(data: BTreeMap<Vec<u8>, Vec<u8>>) -> Self {
+        Self { data }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.data.get(key).cloned()
+    }
+
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        scan_prefix(&self.data, prefix)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Byte-oriented key-value store used for all persistent node data
+pub trait KvStore: Send + Sync {
+    /// Get the value stored under `key`
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Store `value` under `key`, replacing any previous value
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Remove `key`; deleting a missing key is not an error
+    fn delete(&self, key: &[u8]) -> Result<()>;
+
+    /// Apply every operation in `batch`, in order
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// All entries whose key starts with `prefix`, sorted by key
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Point-in-time copy that later writes do not affect
+    fn snapshot(&self) -> Result<Snapshot>;
+}
+
+/// Shared prefix scan over an ordered map, used by the in-memory indexes
+pub(crate) fn scan_prefix(
+    data: &BTreeMap<Vec<u8>, Vec<u8>>,
+    prefix: &[u8],
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    data.range(prefix.to_vec()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
@@ -0,0 +1,43 @@
+// Shared fixtures for unit tests that need a working chain.
+
+use crate::blockchain::Blockchain;
+use crate::config::AppConfig;
+use crate::simulation::{bond_genesis_validators, GENESIS_TIMESTAMP};
+use crate::storage::MemoryStore;
+use ed25519_dalek::SigningKey;
+use std::sync::Arc;
+
+/// Fixed key bonded as the only genesis validator in `test_config`
+pub(crate) fn validator_key() -> SigningKey {
+    SigningKey::from_bytes(&[7u8; 32])
+}
+
+/// Testnet config whose genesis validator is `validator_key`. Epoch
+/// rewards are off so balances only move with the transactions under test.
+pub(crate) fn test_config() -> AppConfig {
+    let mut config: AppConfig = toml::from_str(include_str!("../config/testnet.toml")).unwrap();
+    config.consensus.dpos.epoch_reward = 0;
+    bond_genesis_validators(&mut config, &[validator_key()]);
+    config
+}
+
+/// In-memory chain on `config`, with the simulation's genesis time and no
+/// snapshots, so nodes opened this way share a genesis block
+pub(crate) fn test_chain(mut config: AppConfig) -> Blockchain {
+    config.genesis.timestamp = GENESIS_TIMESTAMP;
+    config.snapshots.interval = 0;
+    Blockchain::open(&config, Arc::new(MemoryStore::new())).unwrap()
+}
+
+/// Mine the next block with whichever of `keys` is scheduled for it
+pub(crate) fn mine_scheduled(
+    blockchain: &mut Blockchain,
+    keys: &[&SigningKey],
+) -> Result<crate::block::Block, String> {
+    let producer = blockchain.next_producer()?;
+    let key = keys
+        .iter()
+        .find(|key| hex::encode(key.verifying_key().to_bytes()) == producer)
+        .ok_or_else(|| format!("no test key for scheduled producer {}", producer))?;
+    blockchain.mine_block(key)
+}
@@ -1,11 +1,51 @@
 use crate::audit_log;
+use crate::block::BlockHeader;
 use crate::crypto_utils;
+use crate::governance::{ParamChange, ProposalVote};
+use crate::vesting::VestingGrant;
 use anyhow::Result;
 use blake3;
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use hex;
 use serde::{Deserialize, Serialize};
 
+/// Typed operation carried in `Transaction.data` as JSON, e.g.
+/// `{"type":"register_validator"}`. The amount comes from the transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TxPayload {
+    RegisterValidator, // become a candidate, bonding `amount` as self-stake
+    Bond,              // add `amount` to the sender's self-stake
+    Unbond,            // start releasing `amount` of the sender's self-stake
+    Delegate { validator: String },
+    Undelegate { validator: String },
+    Redelegate { from: String, to: String }, // moves after the unbonding period
+    RotateConsensusKey { consensus_key: String }, // from the operator key; applies next epoch
+    Unjail,                      // return to the candidates once the jail cooldown has passed
+    CreateVesting(VestingGrant), // give `amount` to a beneficiary under a lockup
+    Evidence(Box<DoubleSign>),
+    SubmitProposal(ParamChange), // open a parameter change, `amount` is the deposit
+    VoteProposal(ProposalVote),
+}
+
+/// Two conflicting headers signed by one producer for the same height
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DoubleSign {
+    pub first: BlockHeader,
+    pub second: BlockHeader,
+}
+
+impl TxPayload {
+    pub fn to_data(&self) -> String {
+        serde_json::to_string(self).expect("payload serialization cannot fail")
+    }
+}
+
+/// How far a transaction's timestamp may be from the time of the block
+/// that includes it. Applied transactions are remembered for this long,
+/// so none can be included twice.
+pub const TX_VALIDITY_SECS: u64 = 3600;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub from: String,
@@ -48,7 +88,7 @@ impl Transaction {
         Ok(())
     }
 
-    fn hash_data(&self) -> Vec<u8> {
+    pub(crate) fn hash_data(&self) -> Vec<u8> {
         let mut hasher = blake3::Hasher::new();
         hasher.update(self.from.as_bytes());
         hasher.update(self.to.as_bytes());
@@ -133,6 +173,28 @@ impl Transaction {
     pub fn hash(&self) -> String {
         hex::encode(self.hash_data())
     }
+
+    /// Typed payload, if `data` holds one. The legacy `"vote"` tag is a
+    /// delegation to `to`; plain transfers and free-form data return `Ok(None)`.
+    pub fn payload(&self) -> std::result::Result<Option<TxPayload>, String> {
+        if self.data.as_deref() == Some("vote") {
+            return Ok(Some(TxPayload::Delegate {
+                validator: self.to.clone(),
+            }));
+        }
+
+        let value = match self
+            .data
+            .as_deref()
+            .and_then(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+        {
+            Some(value) if value.get("type").is_some() => value,
+            _ => return Ok(None),
+        };
+        serde_json::from_value(value)
+            .map(Some)
+            .map_err(|e| format!("Invalid transaction payload: {}", e))
+    }
 }
 
 #[cfg(test)]
@@ -156,4 +218,26 @@ mod tests {
         assert!(tx.verify());
         Ok(())
     }
+
+    #[test]
+    fn test_payload_parsing() {
+        let with_data =
+            |data: Option<&str>| Transaction::new("a".into(), "b".into(), 1, data.map(Into::into));
+
+        let register = with_data(Some(&TxPayload::RegisterValidator.to_data()));
+        assert_eq!(register.payload(), Ok(Some(TxPayload::RegisterValidator)));
+        assert_eq!(with_data(None).payload(), Ok(None));
+        assert_eq!(
+            with_data(Some(r#"{"type":"unjail"}"#)).payload(),
+            Ok(Some(TxPayload::Unjail))
+        );
+        assert_eq!(
+            with_data(Some("vote")).payload(),
+            Ok(Some(TxPayload::Delegate {
+                validator: "b".to_string()
+            }))
+        );
+        assert_eq!(with_data(Some("hello")).payload(), Ok(None));
+        assert!(with_data(Some(r#"{"type":"mystery"}"#)).payload().is_err());
+    }
 }
@@ -1,72 +1,110 @@
-use chrono::{DateTime, Duration, Utc};
+use crate::state::ChainState;
+use crate::transaction::Transaction;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 
-/// Represents a vesting schedule for an investor
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Lockup on tokens already credited to an account. Nothing unlocks before
+/// the cliff; from then on the unlocked amount grows linearly until the
+/// whole amount is free at `start_time + vesting_duration`. Times are unix
+/// seconds compared against block timestamps.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct VestingSchedule {
     pub total_amount: u64,
-    pub start_time: DateTime<Utc>,
-    pub cliff_duration: Duration,
-    pub vesting_duration: Duration,
-    pub claimed_amount: u64,
+    pub start_time: u64,
+    pub cliff_duration: u64,
+    pub vesting_duration: u64,
 }
 
 impl VestingSchedule {
-    /// Calculate the amount currently vested and claimable
-    pub fn claimable_amount(&self, now: DateTime<Utc>) -> u64 {
-        if now < self.start_time + self.cliff_duration {
+    /// Amount unlocked at block time `now`
+    pub fn vested_amount(&self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.start_time);
+        if now < self.start_time || elapsed < self.cliff_duration {
             return 0;
         }
-        let elapsed = now - self.start_time;
         if elapsed >= self.vesting_duration {
-            return self.total_amount - self.claimed_amount;
-        }
-        let vested = (self.total_amount as f64) * (elapsed.num_seconds() as f64)
-            / (self.vesting_duration.num_seconds() as f64);
-        let vested_u64 = vested as u64;
-        if vested_u64 > self.claimed_amount {
-            vested_u64 - self.claimed_amount
-        } else {
-            0
+            return self.total_amount;
         }
+        (self.total_amount as u128 * elapsed as u128 / self.vesting_duration as u128) as u64
+    }
+
+    /// Amount still locked at block time `now`
+    pub fn locked_amount(&self, now: u64) -> u64 {
+        self.total_amount - self.vested_amount(now)
     }
 
-    /// Claim vested tokens
-    pub fn claim(&mut self, now: DateTime<Utc>) -> u64 {
-        let amount = self.claimable_amount(now);
-        self.claimed_amount += amount;
-        amount
+    pub fn is_fully_vested(&self, now: u64) -> bool {
+        self.locked_amount(now) == 0
     }
 }
 
-/// Manages vesting schedules for multiple investors
-#[derive(Default)]
-pub struct VestingManager {
-    pub schedules: HashMap<String, VestingSchedule>, // key: investor address
+/// Lockup terms of a `create_vesting` transaction; the amount comes from
+/// the transaction and the schedule starts at the including block's time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VestingGrant {
+    pub beneficiary: String,
+    #[serde(default)]
+    pub cliff_secs: u64,
+    pub duration_secs: u64,
 }
 
-impl VestingManager {
-    /// Add a new vesting schedule
-    pub fn add_schedule(&mut self, address: String, schedule: VestingSchedule) {
-        self.schedules.insert(address, schedule);
+/// Move `tx.amount` from the sender to the grant's beneficiary, locked
+/// under a schedule starting at the current block time
+pub fn apply_grant(
+    state: &mut ChainState,
+    tx: &Transaction,
+    grant: &VestingGrant,
+) -> Result<(), String> {
+    if tx.amount == 0 {
+        return Err("vesting amount must be greater than zero".to_string());
     }
-
-    /// Claim tokens for an investor
-    pub fn claim_vested_tokens(&mut self, address: &str) -> u64 {
-        if let Some(schedule) = self.schedules.get_mut(address) {
-            schedule.claim(Utc::now())
-        } else {
-            0
-        }
+    if grant.cliff_secs > grant.duration_secs {
+        return Err("vesting cliff is longer than the vesting duration".to_string());
     }
+    state.debit(&tx.from, tx.amount)?;
+    let schedule = VestingSchedule {
+        total_amount: tx.amount,
+        start_time: state.block_time,
+        cliff_duration: grant.cliff_secs,
+        vesting_duration: grant.duration_secs,
+    };
+    state.add_vesting(&grant.beneficiary, schedule)
+}
 
-    /// Get claimable amount for an investor
-    pub fn get_claimable_amount(&self, address: &str) -> u64 {
-        if let Some(schedule) = self.schedules.get(address) {
-            schedule.claimable_amount(Utc::now())
-        } else {
-            0
-        }
+/// Tokens still locked at `now` across all of an account's schedules
+pub fn locked_amount(schedules: &[VestingSchedule], now: u64) -> u64 {
+    schedules
+        .iter()
+        .map(|schedule| schedule.locked_amount(now))
+        .fold(0u64, u64::saturating_add)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cliff_then_linear_unlock() {
+        let schedule = VestingSchedule {
+            total_amount: 1200,
+            start_time: 1000,
+            cliff_duration: 100,
+            vesting_duration: 400,
+        };
+
+        assert_eq!(schedule.vested_amount(900), 0);
+        assert_eq!(schedule.vested_amount(1099), 0);
+        assert_eq!(schedule.vested_amount(1100), 300);
+        assert_eq!(schedule.vested_amount(1200), 600);
+        assert_eq!(schedule.locked_amount(1200), 600);
+        assert_eq!(schedule.vested_amount(1400), 1200);
+        assert!(schedule.is_fully_vested(5000));
+
+        let instant = VestingSchedule {
+            vesting_duration: 0,
+            cliff_duration: 0,
+            ..schedule
+        };
+        assert_eq!(instant.locked_amount(1000), 0);
+        assert_eq!(locked_amount(&[instant, schedule], 1200), 600);
     }
 }
@@ -1,7 +1,7 @@
 use crate::blockchain::Blockchain;
+use crate::storage::{KvStore, MemoryStore, WriteBatch};
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::sync::{Arc, Mutex as StdMutex};
 use wasmtime::*;
 
@@ -90,6 +90,12 @@ pub struct GasMeter {
     pub used: u64,
 }
 
+impl Default for GasMeter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GasMeter {
     pub fn new() -> Self {
         Self {
@@ -98,9 +104,9 @@ impl GasMeter {
         }
     }
 
+    /// Change the limit; gas already used still counts against it
     pub fn set_limit(&mut self, limit: u64) {
         self.limit = limit;
-        self.used = 0;
     }
 
     pub fn record_instruction(&mut self) -> Result<()> {
@@ -150,49 +156,76 @@ impl GasMeter {
     }
 }
 
-/// Contract storage interface
+/// Contract storage interface, keyed by contract address inside a `KvStore`
 #[derive(Clone)]
 pub struct ContractStorage {
-    storage: HashMap<String, HashMap<Vec<u8>, Vec<u8>>>,
+    store: Arc<dyn KvStore>,
+}
+
+impl Default for ContractStorage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ContractStorage {
     pub fn new() -> Self {
-        Self {
-            storage: HashMap::new(),
-        }
+        Self::with_store(Arc::new(MemoryStore::new()))
+    }
+
+    pub fn with_store(store: Arc<dyn KvStore>) -> Self {
+        Self { store }
+    }
+
+    fn contract_prefix(contract_address: &str) -> Vec<u8> {
+        format!("contract:{}:", contract_address).into_bytes()
+    }
+
+    fn storage_key(contract_address: &str, key: &[u8]) -> Vec<u8> {
+        [Self::contract_prefix(contract_address).as_slice(), key].concat()
     }
 
     pub fn get(&self, contract_address: &str, key: &[u8]) -> Option<Vec<u8>> {
-        self.storage
-            .get(contract_address)
-            .and_then(|contract_storage| contract_storage.get(key))
-            .cloned()
+        self.store
+            .get(&Self::storage_key(contract_address, key))
+            .ok()
+            .flatten()
     }
 
     pub fn set(&mut self, contract_address: &str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        self.storage
-            .entry(contract_address.to_string())
-            .or_insert_with(HashMap::new)
-            .insert(key, value);
-        Ok(())
+        self.store
+            .put(&Self::storage_key(contract_address, &key), &value)
     }
 
     pub fn remove(&mut self, contract_address: &str, key: &[u8]) -> Option<Vec<u8>> {
-        self.storage
-            .get_mut(contract_address)
-            .and_then(|contract_storage| contract_storage.remove(key))
+        let storage_key = Self::storage_key(contract_address, key);
+        let previous = self.store.get(&storage_key).ok().flatten()?;
+        self.store.delete(&storage_key).ok()?;
+        Some(previous)
     }
 
     pub fn list_keys(&self, contract_address: &str) -> Vec<Vec<u8>> {
-        self.storage
-            .get(contract_address)
-            .map(|storage| storage.keys().cloned().collect())
+        let prefix = Self::contract_prefix(contract_address);
+        self.store
+            .scan_prefix(&prefix)
+            .map(|entries| {
+                entries
+                    .into_iter()
+                    .map(|(key, _)| key[prefix.len()..].to_vec())
+                    .collect()
+            })
             .unwrap_or_default()
     }
 
     pub fn clear_contract(&mut self, contract_address: &str) {
-        self.storage.remove(contract_address);
+        let prefix = Self::contract_prefix(contract_address);
+        if let Ok(entries) = self.store.scan_prefix(&prefix) {
+            let mut batch = WriteBatch::new();
+            for (key, _) in entries {
+                batch.delete(&key);
+            }
+            let _ = self.store.write_batch(batch);
+        }
     }
 }
 
@@ -249,7 +282,7 @@ impl WasmEngine {
         let mut returns = vec![wasmtime::Val::I32(0); 1];
 
         // Execute with gas metering
-        let _result = func.call_async(&mut self.store, args, &mut returns).await?;
+        func.call_async(&mut self.store, args, &mut returns).await?;
 
         // Convert wasmtime values to Vec
         let values: Vec<wasmtime::Val> = returns.into_iter().collect();
@@ -312,7 +345,7 @@ pub async fn execute_contract(
             Ok(ExecutionResult {
                 success: true,
                 output: output
-                    .get(0)
+                    .first()
                     .map(|v| v.unwrap_i32().to_le_bytes().to_vec())
                     .unwrap_or_default(),
                 gas_used,
@@ -338,12 +371,11 @@ pub async fn execute_contract(
 mod tests {
     use super::*;
     use crate::blockchain::Blockchain;
-    use crate::config::AppConfig;
+    use crate::test_support::test_config;
 
     #[tokio::test]
     async fn test_wasm_engine_creation() -> Result<()> {
-        let config = AppConfig::load().unwrap();
-        let blockchain = Arc::new(Blockchain::new(&config));
+        let blockchain = Arc::new(Blockchain::new(&test_config()));
         let storage = Arc::new(StdMutex::new(ContractStorage::new()));
 
         let _engine = WasmEngine::new(blockchain, storage)?;
@@ -364,6 +396,30 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_contract_storage_is_isolated_per_contract() -> Result<()> {
+        let shared: Arc<dyn KvStore> = Arc::new(MemoryStore::new());
+        let mut storage = ContractStorage::with_store(shared.clone());
+
+        storage.set("contract1", b"a".to_vec(), b"1".to_vec())?;
+        storage.set("contract1", b"b".to_vec(), b"2".to_vec())?;
+        storage.set("contract10", b"a".to_vec(), b"x".to_vec())?;
+
+        assert_eq!(
+            storage.list_keys("contract1"),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+
+        storage.clear_contract("contract1");
+        assert!(storage.list_keys("contract1").is_empty());
+        assert_eq!(
+            ContractStorage::with_store(shared).get("contract10", b"a"),
+            Some(b"x".to_vec())
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_gas_metering() -> Result<()> {
         let mut gas_meter = GasMeter::new();
@@ -1,4 +1,4 @@
-use owami_network::{crypto_utils, vesting::VestingManager, Transaction};
+use owami_network::{crypto_utils, transaction::Transaction, vesting::VestingSchedule};
 
 #[test]
 fn test_token_transfer() -> Result<(), Box<dyn std::error::Error>> {
@@ -20,7 +20,13 @@ fn test_token_transfer() -> Result<(), Box<dyn std::error::Error>> {
 
 #[test]
 fn test_vesting_operations() {
-    let manager = VestingManager::default();
-    // Access the public field directly
-    assert!(manager.schedules.is_empty());
+    let schedule = VestingSchedule {
+        total_amount: 1000,
+        start_time: 0,
+        cliff_duration: 10,
+        vesting_duration: 100,
+    };
+    assert_eq!(schedule.locked_amount(5), 1000);
+    assert_eq!(schedule.vested_amount(50), 500);
+    assert!(schedule.is_fully_vested(100));
 }